@@ -1,31 +1,56 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script generates `memory.x` from `blxlib::layout::LAYOUT`
+//! into a directory where the linker can always find it at build time.
+//!
+//! The application is linked into the primary slot: `.image_header` first,
+//! then the vector table and code. The link fails if the image does not fit
+//! in the slot. Cargo re-runs this script whenever `blxlib` changes.
 
+use blxlib::image_header::HEADER_LENGTH;
+use blxlib::layout::{LAYOUT, SRAM_BASE, SRAM_SIZE};
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+fn memory_x() -> String {
+    let slot = LAYOUT.primary;
+    let header_origin = slot.addr();
+    let header_length = HEADER_LENGTH as u32;
+    let flash_origin = header_origin + header_length;
+    let flash_length = slot.size - header_length;
+    let slot_end = slot.addr() + slot.size;
+
+    format!(
+        "/* Generated by build.rs from blxlib::layout::LAYOUT. Do not edit. */
+MEMORY {{
+    IMAGE_HEADER : ORIGIN = 0x{header_origin:08x}, LENGTH = 0x{header_length:x}
+    FLASH : ORIGIN = 0x{flash_origin:08x}, LENGTH = 0x{flash_length:x}
+    RAM   : ORIGIN = 0x{SRAM_BASE:08x}, LENGTH = 0x{SRAM_SIZE:x}
+}}
+
+SECTIONS {{
+    /* ### Image header */
+    .image_header ORIGIN(IMAGE_HEADER) :
+    {{
+        KEEP(*(.image_header));
+    }} > IMAGE_HEADER
+}} INSERT BEFORE .text;
+
+/* `__veneer_limit` (cortex-m-rt) is the end of everything placed in FLASH */
+ASSERT(__veneer_limit <= 0x{slot_end:08x}, \"
+ERROR(app-blinky): the image (header and FLASH) does not fit in the primary slot\");
+"
+    )
+}
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x().as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
-
-    // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=build.rs");
 }
@@ -4,6 +4,9 @@ set -uex
 
 arch=${arch:-"thumbv6m-none-eabi"}
 debug=${debug:-"debug"}
+key=${key:-"../keys/dev-ed25519.pem"}
+# anti-rollback: the bootloader refuses images with a lower counter than it has accepted
+security_counter=${security_counter:-"0"}
 
 if [[ "X$debug" == "Xrelease" ]]; then
   debug_option="--release"
@@ -15,5 +18,5 @@ cargo build ${debug_option}
 
 arm-none-eabi-objcopy -O binary ../target/${arch}/${debug}/app-blinky ../target/${arch}/${debug}/app-blinky.bin
 cd ../bintool && \
-  cargo run bintool -c all -i ../target/${arch}/${debug}/app-blinky.bin -o ../target/${arch}/${debug}/app-blinky.base && \
+  cargo run bintool -c all -k ${key} -s ${security_counter} -i ../target/${arch}/${debug}/app-blinky.bin -o ../target/${arch}/${debug}/app-blinky.base && \
   cargo run bintool -c info -i ../target/${arch}/${debug}/app-blinky.base
@@ -4,14 +4,21 @@
 #![no_std]
 #![no_main]
 
+use blxlib::app::{BootClient, ImageState};
 use blxlib::image_header::{self, ImageHeader};
-use core::{fmt::Write, ptr};
+use blxlib::layout::LAYOUT;
+use core::fmt::Write;
 use cortex_m_rt::entry;
 use defmt::*;
 use defmt_rtt as _; // used by panic-probe
 use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::watchdog::WatchdogDisable;
 use panic_probe as _;
 
+mod rom_flash;
+
+use rom_flash::RomFlash;
+
 use rp2040_hal::{
     clocks::{init_clocks_and_plls, Clock},
     fugit::RateExtU32, // time calculation library
@@ -36,7 +43,10 @@ pub static IMAGE_HEADER: image_header::ImageHeader = image_header::ImageHeader {
     image_length: 0xe_0000,
     signature: [0u8; 128],
     payload_crc: 0,
-    padding: [0u8; 100],
+    sig_alg: image_header::SIG_ALG_NONE,
+    payload_digest: [0u8; 32],
+    tlv: [0u8; 63],
+    security_counter: 0,
     crc32: 0,
 };
 
@@ -53,14 +63,16 @@ fn ih_print<
     writeln!(uart, "header_magic: {:08x}\r", ih.header_magic).unwrap();
     writeln!(uart, "header_length: {}\r", ih.header_length).unwrap();
     writeln!(uart, "hv: {}.{}\r", ih.hv_major, ih.hv_minor).unwrap();
-    writeln!(
-        uart,
-        "iv: {}.{}.{}-{:08x}\r",
-        ih.iv_major, ih.iv_minor, ih.iv_patch, ih.iv_build
-    )
-    .unwrap();
+    writeln!(uart, "iv: {}\r", ih.version()).unwrap();
     writeln!(uart, "image_length: {:08x}\r", ih.image_length).unwrap();
     writeln!(uart, "payload_crc: {:08x}\r", ih.payload_crc).unwrap();
+    writeln!(uart, "sig_alg: {}\r", ih.sig_alg).unwrap();
+    core::write!(uart, "payload_digest: ").unwrap();
+    for b in ih.payload_digest {
+        core::write!(uart, "{:02x}", b).unwrap();
+    }
+    writeln!(uart, "\r").unwrap();
+    writeln!(uart, "security_counter: {}\r", ih.security_counter).unwrap();
     writeln!(uart, "crc32: {:08x}\r", ih.crc32).unwrap();
 }
 
@@ -116,8 +128,29 @@ fn main() -> ! {
     #[cfg(not(debug_assertions))]
     writeln!(&mut uart, "app-blinky release build\r").unwrap();
 
-    let ih = unsafe { ptr::read_volatile(image_header::APP_BASE_ADDR as *const ImageHeader) };
-    ih_print(&ih, &mut uart);
+    let mut client = BootClient::new(RomFlash::new(), LAYOUT);
+    match client.running_header() {
+        Ok(ih) => {
+            ih_print(&ih, &mut uart);
+            match ih.validate(image_header::payload_from_addr(image_header::APP_BASE_ADDR)) {
+                Ok(()) => writeln!(uart, "image validation pass\r").unwrap(),
+                Err(e) => writeln!(uart, "{}\r", e).unwrap(),
+            }
+        }
+        Err(e) => writeln!(uart, "{}\r", e).unwrap(),
+    }
+
+    // Keep this image: confirm it before the bootloader reverts it, and stop
+    // the watchdog it starts for a trial boot.
+    match client.image_state().unwrap() {
+        ImageState::Trial => {
+            client.confirm().unwrap();
+            writeln!(uart, "image confirmed\r").unwrap();
+        }
+        ImageState::Reverted => writeln!(uart, "update was reverted\r").unwrap(),
+        ImageState::Confirmed => {}
+    }
+    watchdog.disable();
 
     // This is the correct pin on the Raspberry Pico board. On other boards, even if they have an
     // on-board LED, it might need to be changed.
@@ -0,0 +1,96 @@
+//! `blxlib::flash::Flash` on the bootrom flash functions.
+//!
+//! The application runs from XIP, which is unavailable while the flash is
+//! erased or programmed. The bootrom functions are looked up beforehand and
+//! called from `flash_op`, which is placed in SRAM (`.data`) and calls
+//! nothing else. Afterwards XIP is restored in the slow 03h read mode, like
+//! the bootloader leaves it.
+
+use blxlib::flash::Flash;
+use blxlib::layout::{FLASH_BASE, PAGE_SIZE, SECTOR_SIZE};
+use core::convert::Infallible;
+use core::ptr;
+use rp2040_hal::rom_data;
+
+/// 64 KiB block erase, used by `flash_range_erase` where possible.
+const BLOCK_SIZE: u32 = 0x1_0000;
+const BLOCK_ERASE_CMD: u8 = 0xd8;
+
+#[repr(C)]
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    flash_enter_cmd_xip: unsafe extern "C" fn(),
+}
+
+pub struct RomFlash {
+    rom: RomFunctions,
+}
+
+impl Default for RomFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RomFlash {
+    pub fn new() -> Self {
+        RomFlash {
+            rom: RomFunctions {
+                connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+                flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+                flash_range_erase: rom_data::flash_range_erase::ptr(),
+                flash_range_program: rom_data::flash_range_program::ptr(),
+                flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+                flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+            },
+        }
+    }
+}
+
+/// Erases the sector at `offset` if `data` is null, otherwise programs the page.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn flash_op(rom: &RomFunctions, offset: u32, data: *const u8) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if data.is_null() {
+        (rom.flash_range_erase)(offset, SECTOR_SIZE as usize, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    } else {
+        (rom.flash_range_program)(offset, data, PAGE_SIZE as usize);
+    }
+    (rom.flash_flush_cache)();
+    (rom.flash_enter_cmd_xip)();
+}
+
+impl Flash for RomFlash {
+    type Error = Infallible;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Infallible> {
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (FLASH_BASE + offset) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Infallible> {
+        cortex_m::interrupt::free(|_| unsafe { flash_op(&self.rom, offset, ptr::null()) });
+        Ok(())
+    }
+
+    fn program_page(
+        &mut self,
+        offset: u32,
+        data: &[u8; PAGE_SIZE as usize],
+    ) -> Result<(), Infallible> {
+        cortex_m::interrupt::free(|_| unsafe { flash_op(&self.rom, offset, data.as_ptr()) });
+        Ok(())
+    }
+}
@@ -11,10 +11,12 @@ if [[ "X$debug" == "Xrelease" ]]; then
 fi
 
 if [[ "X$update" == "Xupdate" ]]; then
-    base_address="0x10100000"
-else 
-    base_address="0x10020000"
+    slot="secondary"
+else
+    slot="primary"
 fi
+# XIP address of the slot from blxlib::layout::LAYOUT
+base_address=$(cd ../bintool && cargo run -q -- -c layout | awk -v slot=${slot} '$1 == slot { print $3 }')
 
 arch=${arch} debug=${debug} ./build_image.sh
 
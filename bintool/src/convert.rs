@@ -0,0 +1,139 @@
+use crate::sign::{self, SigningKey};
+use blxlib::image_header::{ImageHeader, HEADER_LENGTH};
+use blxlib::mcuboot::{
+    ImageWriter, McubootHeader, McubootImage, TLV_ECDSA_SIG, TLV_ED25519, TLV_KEYHASH, TLV_SEC_CNT,
+    TLV_SHA256,
+};
+use blxlib::suit::{self, SuitImage};
+use blxlib::{crc32, sha256};
+use std::error::Error;
+
+/// An MCUboot image of the payload of `ih`, laid out like
+/// `imgtool sign --header-size 0x100` does: the header padded to
+/// `HEADER_LENGTH`, so that the payload stays where it was linked, the
+/// security counter in the protected TLV area, and the hash, the key hash and
+/// the signature (if `key` is given) in the TLV area.
+pub fn to_mcuboot(
+    ih: &ImageHeader,
+    payload: &[u8],
+    key: Option<&SigningKey>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let header = McubootHeader {
+        load_addr: 0,
+        hdr_size: HEADER_LENGTH,
+        protect_tlv_size: 0,
+        img_size: 0,
+        flags: 0,
+        version: ih.version(),
+    };
+    let mut image = vec![0u8; HEADER_LENGTH as usize + payload.len() + 0x200];
+    let mut writer = ImageWriter::new(&mut image, &header, payload)?;
+    writer.push_protected(TLV_SEC_CNT, &ih.security_counter.to_le_bytes())?;
+
+    let hash = writer.hash();
+    writer.push(TLV_SHA256, &hash)?;
+    if let Some(key) = key {
+        writer.push(TLV_KEYHASH, &sha256::sha256(&sign::public_key_der(key)?))?;
+        let tlv_type = match key {
+            SigningKey::Ed25519(_) => TLV_ED25519,
+            SigningKey::P256(_) => TLV_ECDSA_SIG,
+        };
+        writer.push(tlv_type, &sign::sign_hash(&hash, key)?)?;
+    } else {
+        println!("no key (-k): signature is not set");
+    }
+    let len = writer.finish()?;
+    image.truncate(len);
+    Ok(image)
+}
+
+/// A native image of the payload of an MCUboot image, with the security
+/// counter of the image unless `security_counter` is given. The MCUboot header
+/// must be `HEADER_LENGTH` bytes, otherwise the payload is linked for a
+/// different address.
+pub fn to_native(
+    image: &McubootImage,
+    key: Option<&SigningKey>,
+    security_counter: Option<u32>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if image.header.hdr_size != HEADER_LENGTH {
+        return Err(format!(
+            "mcuboot hdr_size is {:#x}, only {:#x} can be converted",
+            image.header.hdr_size, HEADER_LENGTH
+        )
+        .into());
+    }
+    image.validate()?;
+    let payload = image.payload();
+
+    let mut ih = ImageHeader::new();
+    ih.set_version(image.header.version);
+    ih.security_counter = security_counter.unwrap_or(image.security_counter());
+    ih.image_length = payload.len() as u32;
+    ih.payload_crc = crc32::crc32(payload);
+    ih.payload_digest = sha256::sha256(payload);
+    if let Some(key) = key {
+        sign::sign(&mut ih, key)?;
+    } else {
+        println!("no key (-k): signature is not set");
+    }
+    ih.crc32 = ih.calc_crc32();
+
+    let mut buf = ih.to_bytes().to_vec();
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// A SUIT image of the payload of `ih`: the locator padded to
+/// `HEADER_LENGTH`, the payload and an envelope with a manifest for it, its
+/// digest and a `COSE_Sign1` of the digest (if `key` is given). The sequence
+/// number is the security counter of `ih`.
+pub fn to_suit(
+    ih: &ImageHeader,
+    payload: &[u8],
+    key: Option<&SigningKey>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut manifest = [0u8; 512];
+    let manifest_len = suit::encode_manifest(
+        &mut manifest,
+        ih.security_counter.into(),
+        &sha256::sha256(payload),
+        payload.len() as u64,
+    )?;
+    let manifest = &manifest[..manifest_len];
+    let mut digest = [0u8; 48];
+    let digest_len = suit::encode_digest(&mut digest, &sha256::sha256(manifest))?;
+    let digest = &digest[..digest_len];
+
+    let mut sign1 = [0u8; 160];
+    let sign1_len = if let Some(key) = key {
+        let alg = match key {
+            SigningKey::Ed25519(_) => suit::COSE_ALG_EDDSA,
+            SigningKey::P256(_) => suit::COSE_ALG_ES256,
+        };
+        let mut protected = [0u8; 8];
+        let protected_len = suit::encode_protected(&mut protected, alg)?;
+        let protected = &protected[..protected_len];
+        let mut message = [0u8; 128];
+        let message_len = suit::encode_sig_structure(&mut message, protected, digest)?;
+        let signature = sign::sign_cose(&message[..message_len], key)?;
+        Some(suit::encode_sign1(&mut sign1, protected, &signature)?)
+    } else {
+        println!("no key (-k): signature is not set");
+        None
+    };
+
+    let mut envelope = [0u8; 1024];
+    let envelope_len = suit::encode_envelope(
+        &mut envelope,
+        digest,
+        sign1_len.map(|len| &sign1[..len]),
+        manifest,
+    )?;
+
+    let mut image = SuitImage::locator(u32::try_from(payload.len())?, envelope_len as u32).to_vec();
+    image.resize(HEADER_LENGTH as usize, 0);
+    image.extend_from_slice(payload);
+    image.extend_from_slice(&envelope[..envelope_len]);
+    Ok(image)
+}
@@ -0,0 +1,43 @@
+//! Patches for delta updates (`-c delta`), from bsdiff's output.
+
+use blxlib::delta::{self, PatchHeader};
+use blxlib::sha256::sha256;
+use std::error::Error;
+
+/// A patch from the `source` image to the `target` image.
+pub fn make_patch(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut raw = Vec::new();
+    bsdiff::diff(source, target, &mut raw)?;
+
+    let header = PatchHeader {
+        source_length: source.len().try_into()?,
+        source_digest: sha256(source),
+        target_length: target.len().try_into()?,
+    };
+    let mut patch = header.to_bytes().to_vec();
+    // bsdiff entries: the diff length, extra length and seek as sign-magnitude
+    // i64, then the diff and extra bytes
+    let mut rest = &raw[..];
+    while !rest.is_empty() {
+        let field = |i: usize| {
+            let x = u64::from_le_bytes(rest[i * 8..i * 8 + 8].try_into().unwrap());
+            let magnitude = (x & !(1 << 63)) as i64;
+            if x >> 63 != 0 {
+                -magnitude
+            } else {
+                magnitude
+            }
+        };
+        let (diff_len, extra_len) = (field(0) as usize, field(1) as usize);
+        let (diff, extra) = rest[24..24 + diff_len + extra_len].split_at(diff_len);
+        delta::encode_entry(diff, extra, field(2).try_into()?, &mut patch);
+        rest = &rest[24 + diff_len + extra_len..];
+    }
+
+    let mut out = Vec::new();
+    delta::apply(source, &patch, &mut out)?;
+    if out != target {
+        return Err("patch does not reproduce the target image".into());
+    }
+    Ok(patch)
+}
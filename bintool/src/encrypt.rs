@@ -0,0 +1,106 @@
+use blxlib::encrypt::{
+    self, ImageCipher, PayloadCipher, AES_KEY_LENGTH, WRAPPED_KEY_LENGTH, X25519_KEY_LENGTH,
+};
+use blxlib::image_header::ImageHeader;
+use blxlib::tlv::{TlvWriter, TLV_ENC_X25519};
+use ed25519_dalek::pkcs8::spki::der::pem::{self, LineEnding};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// DER of an X25519 PKCS#8 private key (RFC 8410) up to the 32 key bytes.
+const PRIVATE_KEY_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04, 0x20,
+];
+/// DER of an X25519 `SubjectPublicKeyInfo` up to the 32 key bytes.
+const PUBLIC_KEY_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00,
+];
+
+fn read_pem(key_path: &Path) -> Result<(String, Vec<u8>), Box<dyn Error>> {
+    let pem = fs::read_to_string(key_path)?;
+    let (label, der) = pem::decode_vec(pem.as_bytes()).map_err(|e| e.to_string())?;
+    Ok((label.to_string(), der))
+}
+
+fn key_from_der(der: &[u8], prefix: &[u8]) -> Option<[u8; X25519_KEY_LENGTH]> {
+    der.strip_prefix(prefix)?.try_into().ok()
+}
+
+/// Loads an X25519 private key (PKCS#8 PEM, as written by `keygen -a x25519`
+/// or `openssl genpkey -algorithm x25519`).
+pub fn load_secret(key_path: &Path) -> Result<[u8; X25519_KEY_LENGTH], Box<dyn Error>> {
+    let (label, der) = read_pem(key_path)?;
+    match &*label {
+        "PRIVATE KEY" => key_from_der(&der, &PRIVATE_KEY_PREFIX),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{}: not an X25519 private key", key_path.to_string_lossy()).into())
+}
+
+/// Loads the public key of the device an image is encrypted for, from either
+/// its private key or a public key PEM (`openssl pkey -pubout`).
+pub fn load_public(key_path: &Path) -> Result<[u8; X25519_KEY_LENGTH], Box<dyn Error>> {
+    let (label, der) = read_pem(key_path)?;
+    match &*label {
+        "PRIVATE KEY" => {
+            key_from_der(&der, &PRIVATE_KEY_PREFIX).map(|key| encrypt::public_key(&key))
+        }
+        "PUBLIC KEY" => key_from_der(&der, &PUBLIC_KEY_PREFIX),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{}: not an X25519 key", key_path.to_string_lossy()).into())
+}
+
+pub fn keygen(out_file_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut der = PRIVATE_KEY_PREFIX.to_vec();
+    let mut secret = [0u8; X25519_KEY_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    der.extend_from_slice(&secret);
+    let pem = pem::encode_string("PRIVATE KEY", LineEnding::LF, &der).map_err(|e| e.to_string())?;
+    fs::write(out_file_path, pem.as_bytes())?;
+    Ok(())
+}
+
+/// Encrypts `payload` for the device with `device_public` and adds the wrapped
+/// key to the TLV area. `payload_crc` and `payload_digest` stay those of the
+/// plain text, so they must be set before; the signature and `crc32` after.
+pub fn encrypt_image(
+    ih: &mut ImageHeader,
+    payload: &mut [u8],
+    device_public: &[u8; X25519_KEY_LENGTH],
+) -> Result<(), Box<dyn Error>> {
+    if encrypt::wrapped_key(ih).is_some() {
+        return Err("image is already encrypted".into());
+    }
+    let mut key = [0u8; AES_KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+    let mut ephemeral = [0u8; X25519_KEY_LENGTH];
+    OsRng.fill_bytes(&mut ephemeral);
+    let wrapped = encrypt::wrap_key(&key, device_public, ephemeral);
+    TlvWriter::append(&mut ih.tlv)
+        .push(TLV_ENC_X25519, &wrapped)
+        .map_err(|e| {
+            format!(
+                "{}: the wrapped key needs {} bytes",
+                e,
+                2 + WRAPPED_KEY_LENGTH
+            )
+        })?;
+    ImageCipher::new(&key).apply(0, payload);
+    Ok(())
+}
+
+/// The cipher of an encrypted image for the device with `device_secret`.
+pub fn image_cipher(
+    ih: &ImageHeader,
+    device_secret: &[u8; X25519_KEY_LENGTH],
+) -> Option<ImageCipher> {
+    let wrapped = encrypt::wrapped_key(ih)?;
+    Some(ImageCipher::new(&encrypt::unwrap_key(
+        &wrapped,
+        device_secret,
+    )))
+}
@@ -1,39 +1,182 @@
+use blxlib::compress;
+use blxlib::delta::{PatchHeader, PATCH_MAGIC};
+use blxlib::image::{Image, ImageFormat};
 use blxlib::image_header::ImageHeader;
-use blxlib::{crc32, image_header};
+use blxlib::mcuboot::{self, McubootImage};
+use blxlib::suit::{self, SuitImage};
+use blxlib::tlv::{TlvIter, TlvValue, TlvWriter, TLV_BUILD_TIME, TLV_COMMIT, TLV_LZ4};
+use blxlib::version::ImageVersion;
+use blxlib::ymodem;
+use blxlib::{crc32, image_header, layout, sha256};
 use getopts::Options;
 use regex::Regex;
+use std::cmp::Ordering;
 use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod convert;
+mod delta;
+mod encrypt;
+mod sign;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("bintool: bintool\nUsage: {} [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn run_info(in_file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `SOURCE_DATE_EPOCH` for a reproducible build, otherwise the current time.
+fn build_time() -> Result<u64, Box<dyn Error>> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(secs) => Ok(secs.parse()?),
+        Err(_) => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+    }
+}
+
+/// Sets the build time and the commit (if `commit_hash` is one) in the TLV
+/// area, replacing those of a previous build and keeping the other entries.
+fn set_build_tlvs(ih: &mut ImageHeader, commit_hash: &str) -> Result<(), Box<dyn Error>> {
+    let previous = ih.tlv;
+    let mut tlvs = TlvWriter::new(&mut ih.tlv);
+    for tlv in TlvIter::new(&previous).map_while(Result::ok) {
+        if tlv.tag != TLV_BUILD_TIME && tlv.tag != TLV_COMMIT {
+            tlvs.push(tlv.tag, tlv.value)?;
+        }
+    }
+    tlvs.push_build_time(build_time()?)?;
+    // 40 hex digits from `git rev-parse HEAD`
+    let commit: Option<Vec<u8>> = (0..commit_hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(commit_hash.get(i..i + 2)?, 16).ok())
+        .collect();
+    if let Some(commit) = commit.and_then(|commit| <[u8; 20]>::try_from(commit).ok()) {
+        tlvs.push_commit(&commit)?;
+    }
+    Ok(())
+}
+
+fn print_tlvs(ih: &ImageHeader) {
+    for tlv in ih.tlvs() {
+        match tlv.map(|tlv| tlv.decode()) {
+            Ok(TlvValue::BuildTime(secs)) => println!("tlv build_time: {}", secs),
+            Ok(TlvValue::Flags(flags)) => println!("tlv flags: {:08x}", flags),
+            Ok(TlvValue::Dependency(version)) => println!("tlv dependency: >= {}", version),
+            Ok(TlvValue::Commit(commit)) => println!("tlv commit: {}", hex(commit)),
+            Ok(TlvValue::EncX25519(wrapped)) => println!("tlv enc_x25519: {}", hex(wrapped)),
+            Ok(TlvValue::Lz4(length)) => println!("tlv lz4: {:04x}", length),
+            Ok(TlvValue::Unknown(tag, value)) => println!("tlv {:02x}: {}", tag, hex(value)),
+            Err(e) => println!("tlv: NG: {}", e),
+        }
+    }
+}
+
+fn print_mcuboot_info(buf: &[u8]) -> Result<(), Box<dyn Error>> {
+    let image = McubootImage::parse(buf)?;
+    let header = &image.header;
+    println!("format: mcuboot");
+    println!("load_addr: {:04x}", header.load_addr);
+    println!("hdr_size: {:04x}", header.hdr_size);
+    println!("img_size: {:04x}", header.img_size);
+    println!("flags: {:04x}", header.flags);
+    println!("version: {}", header.version);
+    for (tlv_type, value) in image.protected_tlvs() {
+        println!("protected tlv {:04x}: {}", tlv_type, hex(value));
+    }
+    for (tlv_type, value) in image.tlvs() {
+        println!("tlv {:04x}: {}", tlv_type, hex(value));
+    }
+    println!("security_counter: {}", image.security_counter());
+    match image.validate() {
+        Ok(()) => println!("validation: OK"),
+        Err(e) => println!("validation: NG: {}", e),
+    }
+    Ok(())
+}
+
+fn print_suit_info(buf: &[u8]) -> Result<(), Box<dyn Error>> {
+    let image = SuitImage::parse(buf)?;
+    println!("format: suit");
+    println!("size: {:04x}", image.size());
+    println!("sequence_number: {}", image.manifest.sequence_number);
+    println!(
+        "manifest_digest: {}",
+        hex(image.envelope.manifest_digest()?)
+    );
+    match image.envelope.signature_alg() {
+        Some(alg) => println!("signature_alg: {}", alg),
+        None => println!("signature_alg: none"),
+    }
+    match image.validate() {
+        Ok(report) => println!("validation: OK: image_size={:04x}", report.image_size),
+        Err(e) => println!("validation: NG: {}", e),
+    }
+    Ok(())
+}
+
+fn print_patch_info(buf: &[u8]) -> Result<(), Box<dyn Error>> {
+    let header = PatchHeader::try_from(buf)?;
+    println!("format: delta patch");
+    println!("source_length: {:04x}", header.source_length);
+    println!("source_digest: {}", hex(&header.source_digest));
+    println!("target_length: {:04x}", header.target_length);
+    println!("patch_length: {:04x}", buf.len());
+    Ok(())
+}
+
+fn run_info(in_file_path: &PathBuf, enc_key_path: Option<&PathBuf>) -> Result<(), Box<dyn Error>> {
     println!("\n*** run_info ***\n");
     let mut file = File::open(in_file_path)?;
     let mut buf = Vec::<u8>::new();
     file.read_to_end(&mut buf)?;
 
-    let ih = image_header::load_from_buf(&buf[0..std::mem::size_of::<ImageHeader>()]);
+    if buf.starts_with(&mcuboot::IMAGE_MAGIC.to_le_bytes()) {
+        return print_mcuboot_info(&buf);
+    }
+    if buf.starts_with(&suit::SUIT_IMAGE_MAGIC.to_le_bytes()) {
+        return print_suit_info(&buf);
+    }
+    if buf.starts_with(&PATCH_MAGIC.to_le_bytes()) {
+        return print_patch_info(&buf);
+    }
+    let ih = ImageHeader::try_from(&buf[..])?;
 
     println!("header_magic: {:04x}", ih.header_magic);
     println!("header_length: {}", ih.header_length);
     println!("hv_major: {}", ih.hv_major);
     println!("hv_minor: {}", ih.hv_minor);
-    println!("iv_major: {}", ih.iv_major);
-    println!("iv_minor: {}", ih.iv_minor);
-    println!("iv_patch: {}", ih.iv_patch);
-    println!("iv_build: {:08x}", ih.iv_build);
+    println!("version: {}", ih.version());
     println!("image_length: {:04x}", ih.image_length);
     println!("payload_crc: {:04x}", ih.payload_crc);
+    println!("sig_alg: {}", ih.sig_alg);
+    println!("payload_digest: {}", hex(&ih.payload_digest));
+    print_tlvs(&ih);
+    println!("security_counter: {}", ih.security_counter);
     println!("crc32: {:04x}", ih.crc32);
+    let payload = &buf[image_header::HEADER_LENGTH as usize..];
+    let result = if let Some(length) = compress::compressed_length(&ih) {
+        compress::validate(&ih, payload.iter().copied().take(length as usize))
+    } else if blxlib::encrypt::wrapped_key(&ih).is_none() {
+        ih.validate(payload)
+    } else if let Some(enc_key_path) = enc_key_path {
+        let cipher = encrypt::image_cipher(&ih, &encrypt::load_secret(enc_key_path)?).unwrap();
+        blxlib::encrypt::validate(&ih, payload, &cipher)
+    } else {
+        println!("validation: encrypted, the device key (-e) is required");
+        return Ok(());
+    };
+    match result {
+        Ok(()) => println!("validation: OK"),
+        Err(e) => println!("validation: NG: {}", e),
+    }
     // println!("{:?}",ih);
     Ok(())
 }
@@ -44,44 +187,77 @@ fn run_crc(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Box<dy
     let mut in_buf = Vec::<u8>::new();
     in_file.read_to_end(&mut in_buf)?;
 
-    let header_len = std::mem::size_of::<ImageHeader>();
+    let header_len = image_header::HEADER_LENGTH as usize;
 
-    let buf_ih = &in_buf[0..header_len];
+    let mut ih = ImageHeader::try_from(&in_buf[..])?;
     let buf_payload = &in_buf[header_len..];
 
-    let mut ih = image_header::load_from_buf(buf_ih);
-
     ih.crc32 = ih.calc_crc32();
 
     let mut out_file = File::create(out_file_path)?;
-    out_file.write_all(image_header::as_bytes_with_len(&ih, header_len))?;
+    out_file.write_all(&ih.to_bytes())?;
     out_file.write_all(buf_payload)?;
 
     Ok(())
 }
 
-fn run_sign(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+/// Replaces the payload with its LZ4 block and records the compressed length.
+/// `payload_crc` and `payload_digest` stay those of the decompressed payload.
+fn compress_payload(ih: &mut ImageHeader, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if compress::compressed_length(ih).is_some() {
+        return Err("image is already compressed".into());
+    }
+    let mut block = vec![0u8; payload.len() + payload.len() / 255 + 16];
+    let length = compress::compress(payload, &mut block).ok_or("compression failed")?;
+    block.truncate(length);
+    TlvWriter::append(&mut ih.tlv).push(TLV_LZ4, &(length as u32).to_le_bytes())?;
+    println!("compressed {:#x} -> {:#x} bytes", payload.len(), length);
+    Ok(block)
+}
+
+fn run_sign(
+    in_file_path: &PathBuf,
+    out_file_path: &PathBuf,
+    key_path: Option<&PathBuf>,
+    security_counter: Option<u32>,
+    enc_key_path: Option<&PathBuf>,
+    compress: bool,
+) -> Result<(), Box<dyn Error>> {
     println!("\n*** run_sign ***\n");
     let mut in_file = File::open(in_file_path)?;
     let mut in_buf = Vec::<u8>::new();
     in_file.read_to_end(&mut in_buf)?;
 
-    let header_len = std::mem::size_of::<ImageHeader>();
+    let header_len = image_header::HEADER_LENGTH as usize;
 
-    let buf_ih = &in_buf[0..header_len];
-    let buf_payload = &in_buf[header_len..];
+    let mut ih = ImageHeader::try_from(&in_buf[..])?;
+    let buf_payload = &mut in_buf[header_len..];
     let payload_length = buf_payload.len();
 
-    let mut ih = image_header::load_from_buf(buf_ih);
-
     ih.payload_crc = crc32::crc32(buf_payload);
+    ih.payload_digest = sha256::sha256(buf_payload);
     ih.image_length = payload_length as u32;
+    if let Some(security_counter) = security_counter {
+        ih.security_counter = security_counter;
+    }
+    if let Some(enc_key_path) = enc_key_path {
+        encrypt::encrypt_image(&mut ih, buf_payload, &encrypt::load_public(enc_key_path)?)?;
+    }
+    let compressed = compress
+        .then(|| compress_payload(&mut ih, buf_payload))
+        .transpose()?;
+
+    if let Some(key_path) = key_path {
+        sign::sign(&mut ih, &sign::load_key(key_path)?)?;
+    } else {
+        println!("no key (-k): signature is not set");
+    }
 
     ih.crc32 = ih.calc_crc32();
 
     let mut out_file = File::create(out_file_path)?;
-    out_file.write_all(image_header::as_bytes_with_len(&ih, header_len))?;
-    out_file.write_all(buf_payload)?;
+    out_file.write_all(&ih.to_bytes())?;
+    out_file.write_all(compressed.as_deref().unwrap_or(buf_payload))?;
 
     Ok(())
 }
@@ -92,13 +268,11 @@ fn run_version(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Bo
     let mut in_buf = Vec::<u8>::new();
     let _ = in_file.read_to_end(&mut in_buf)?;
 
-    let header_len = std::mem::size_of::<ImageHeader>();
+    let header_len = image_header::HEADER_LENGTH as usize;
 
-    let buf_ih = &in_buf[0..header_len];
+    let mut ih = ImageHeader::try_from(&in_buf[..])?;
     let buf_payload = &in_buf[header_len..];
 
-    let mut ih = image_header::load_from_buf(buf_ih);
-
     let commit_hash = Command::new("git")
         .args(["rev-parse", "HEAD"])
         .output()
@@ -115,6 +289,7 @@ fn run_version(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Bo
         }
         None => println!("Not found"),
     }
+    set_build_tlvs(&mut ih, &commit_hash)?;
 
     let pkg_info = Command::new("cargo")
         .args(["pkgid", "--manifest-path=../app-blinky/Cargo.toml"])
@@ -140,26 +315,32 @@ fn run_version(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Bo
     ih.crc32 = ih.calc_crc32();
 
     let mut out_file = File::create(out_file_path)?;
-    out_file.write_all(image_header::as_bytes_with_len(&ih, header_len))?;
+    out_file.write_all(&ih.to_bytes())?;
     out_file.write_all(buf_payload)?;
 
     Ok(())
 }
 
-fn run_all(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn run_all(
+    in_file_path: &PathBuf,
+    out_file_path: &PathBuf,
+    key_path: Option<&PathBuf>,
+    security_counter: Option<u32>,
+    enc_key_path: Option<&PathBuf>,
+    compress: bool,
+    commit: bool,
+) -> Result<(), Box<dyn Error>> {
     println!("\n*** run_all ***\n");
     let mut in_file = File::open(in_file_path)?;
     let mut in_buf = Vec::<u8>::new();
     let _ = in_file.read_to_end(&mut in_buf)?;
 
-    let header_len = std::mem::size_of::<ImageHeader>();
+    let header_len = image_header::HEADER_LENGTH as usize;
 
-    let buf_ih = &in_buf[0..header_len];
-    let buf_payload = &in_buf[header_len..];
+    let mut ih = ImageHeader::try_from(&in_buf[..])?;
+    let buf_payload = &mut in_buf[header_len..];
     let payload_length = buf_payload.len();
 
-    let mut ih = image_header::load_from_buf(buf_ih);
-
     // update version
     let commit_hash = Command::new("git")
         .args(["rev-parse", "HEAD"])
@@ -176,6 +357,7 @@ fn run_all(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Box<dy
         }
         None => println!("Not found"),
     }
+    set_build_tlvs(&mut ih, if commit { &commit_hash } else { "" })?;
 
     let pkg_info = Command::new("cargo")
         .args(["pkgid", "--manifest-path=../app-blinky/Cargo.toml"])
@@ -195,17 +377,270 @@ fn run_all(in_file_path: &PathBuf, out_file_path: &PathBuf) -> Result<(), Box<dy
         None => println!("Not found"),
     }
 
-    // update payload_crc
+    // update payload_crc and payload_digest
     ih.payload_crc = crc32::crc32(buf_payload);
+    ih.payload_digest = sha256::sha256(buf_payload);
     ih.image_length = payload_length as u32;
 
+    // update security_counter (signed with the rest of the header)
+    if let Some(security_counter) = security_counter {
+        ih.security_counter = security_counter;
+    }
+
+    // encrypt the payload (the digests above are of the plain text)
+    if let Some(enc_key_path) = enc_key_path {
+        let device_public = encrypt::load_public(enc_key_path)?;
+        encrypt::encrypt_image(&mut ih, buf_payload, &device_public).map_err(|e| {
+            if commit {
+                format!("{} (-n leaves out the commit)", e).into()
+            } else {
+                e
+            }
+        })?;
+    }
+
+    // compress the payload (the digests above are of the decompressed payload)
+    let compressed = compress
+        .then(|| compress_payload(&mut ih, buf_payload))
+        .transpose()?;
+
+    // update signature
+    if let Some(key_path) = key_path {
+        sign::sign(&mut ih, &sign::load_key(key_path)?)?;
+    } else {
+        println!("no key (-k): signature is not set");
+    }
+
     // update header_crc
     ih.crc32 = ih.calc_crc32();
 
     let mut out_file = File::create(out_file_path)?;
-    out_file.write_all(image_header::as_bytes_with_len(&ih, header_len))?;
-    out_file.write_all(buf_payload)?;
+    out_file.write_all(&ih.to_bytes())?;
+    out_file.write_all(compressed.as_deref().unwrap_or(buf_payload))?;
+
+    Ok(())
+}
+
+/// Converts a native image to an MCUboot image (`-c mcuboot`) or a SUIT image
+/// (`-c suit`), or an MCUboot image back (`-c native`).
+fn run_convert(
+    in_file_path: &PathBuf,
+    out_file_path: &PathBuf,
+    format: ImageFormat,
+    key_path: Option<&PathBuf>,
+    security_counter: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_convert ***\n");
+    let mut in_buf = Vec::<u8>::new();
+    File::open(in_file_path)?.read_to_end(&mut in_buf)?;
+    let key = key_path
+        .map(|key_path| sign::load_key(key_path))
+        .transpose()?;
+
+    let out_buf = if format != ImageFormat::Native {
+        let mut ih = ImageHeader::try_from(&in_buf[..])?;
+        if blxlib::encrypt::wrapped_key(&ih).is_some() {
+            return Err("an encrypted image cannot be converted".into());
+        }
+        if compress::compressed_length(&ih).is_some() {
+            return Err("a compressed image cannot be converted".into());
+        }
+        let payload = in_buf
+            .get(image_header::HEADER_LENGTH as usize..)
+            .and_then(|payload| payload.get(..ih.image_length as usize))
+            .ok_or("payload is shorter than image_length")?;
+        if let Some(security_counter) = security_counter {
+            ih.security_counter = security_counter;
+        }
+        if format == ImageFormat::Suit {
+            convert::to_suit(&ih, payload, key.as_ref())?
+        } else {
+            convert::to_mcuboot(&ih, payload, key.as_ref())?
+        }
+    } else {
+        let image = McubootImage::parse(&in_buf)?;
+        convert::to_native(&image, key.as_ref(), security_counter)?
+    };
+
+    File::create(out_file_path)?.write_all(&out_buf)?;
+    Ok(())
+}
+
+/// Version of the image file `base` (in either format), or `base` itself
+/// parsed as a version.
+fn load_version(base: &str) -> Result<ImageVersion, Box<dyn Error>> {
+    if !path::Path::new(base).exists() {
+        return Ok(base.parse()?);
+    }
+    let mut buf = Vec::<u8>::new();
+    File::open(base)?.read_to_end(&mut buf)?;
+    image_version(&buf)
+}
+
+fn image_version(buf: &[u8]) -> Result<ImageVersion, Box<dyn Error>> {
+    Ok(Image::parse(buf)?
+        .info()
+        .version
+        .ok_or("image has no version")?)
+}
+
+fn run_compare(in_file_path: &PathBuf, base: Option<&str>) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_compare ***\n");
+    let base = load_version(base.ok_or("base image or version (-b) is required")?)?;
+    let mut buf = Vec::<u8>::new();
+    File::open(in_file_path)?.read_to_end(&mut buf)?;
+    let update = image_version(&buf)?;
+
+    println!("update: {}", update);
+    println!("base: {}", base);
+    match update.cmp_precedence(&base) {
+        Ordering::Greater => println!("result: upgrade"),
+        Ordering::Less => println!("result: downgrade"),
+        Ordering::Equal if update == base => println!("result: same version"),
+        Ordering::Equal => println!("result: same version, different build"),
+    }
+    Ok(())
+}
+
+/// Writes a patch from the base image (`-b`, the image in the primary slot)
+/// to the input image, for `BootClient::begin_delta_update`.
+fn run_delta(
+    in_file_path: &PathBuf,
+    out_file_path: &PathBuf,
+    base: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_delta ***\n");
+    let mut source = Vec::<u8>::new();
+    File::open(base.ok_or("base image (-b) is required")?)?.read_to_end(&mut source)?;
+    let mut target = Vec::<u8>::new();
+    File::open(in_file_path)?.read_to_end(&mut target)?;
+
+    // the primary slot holds the base image as it was installed
+    let ih = ImageHeader::try_from(&source[..])?;
+    if blxlib::encrypt::wrapped_key(&ih).is_some() || compress::compressed_length(&ih).is_some() {
+        return Err("the base image must not be encrypted or compressed".into());
+    }
+    let source_length = image_header::HEADER_LENGTH as usize + ih.image_length as usize;
+    let source = source
+        .get(..source_length)
+        .ok_or("base payload is shorter than image_length")?;
+    let target_length = image_header::HEADER_LENGTH as usize
+        + ImageHeader::try_from(&target[..])?.image_length as usize;
+    let target = target
+        .get(..target_length)
+        .ok_or("payload is shorter than image_length")?;
+
+    let patch = delta::make_patch(source, target)?;
+    println!(
+        "source: {:04x} {}",
+        source.len(),
+        hex(&sha256::sha256(source))
+    );
+    println!("target: {:04x}", target.len());
+    println!("patch: {:04x}", patch.len());
+    File::create(out_file_path)?.write_all(&patch)?;
+    Ok(())
+}
+
+/// Sends the input image to the bootloader's serial recovery mode with YMODEM.
+/// `port` is a serial port or a pseudo-terminal.
+fn run_ymodem(in_file_path: &PathBuf, port: Option<&str>) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_ymodem ***\n");
+    let mut data = Vec::<u8>::new();
+    File::open(in_file_path)?.read_to_end(&mut data)?;
+    let name = in_file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut port = serialport::new(port.ok_or("serial port (-p) is required")?, 115_200)
+        .timeout(Duration::from_secs(10))
+        .open()?;
+
+    println!("waiting for the receiver");
+    send_ymodem(&mut port, name.as_bytes(), &data)?;
+    println!();
+    Ok(())
+}
+
+/// Sends `data` as the file `name`. Reads from `port` must time out, which
+/// is when the sender repeats its last packet.
+fn send_ymodem<P: Read + Write>(
+    port: &mut P,
+    name: &[u8],
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut sender = ymodem::Sender::new(name, data);
+    let mut buf = [0u8; 64];
+    while !sender.is_done() {
+        let result = match port.read(&mut buf) {
+            Ok(n) => buf[..n].iter().try_for_each(|&b| {
+                let packet = sender.push(b)?;
+                Ok(port.write_all(packet)?)
+            }),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => sender
+                .timeout()
+                .map_err(|e| e.into())
+                .and_then(|packet| Ok(port.write_all(packet)?)),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            port.write_all(ymodem::CANCEL)?;
+            return Err(e);
+        }
+        print!("\rsent: {:06x}/{:06x}", sender.acknowledged(), data.len());
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+fn run_layout() -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_layout ***\n");
+    for (name, p) in layout::LAYOUT.partitions() {
+        println!(
+            "{:<10} 0x{:08x} 0x{:08x} 0x{:08x}",
+            name,
+            p.offset,
+            p.addr(),
+            p.size
+        );
+    }
+    Ok(())
+}
+
+fn run_keygen(alg: &str, out_file_path: &path::Path) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_keygen ***\n");
+    if alg == "x25519" {
+        return encrypt::keygen(out_file_path);
+    }
+    sign::keygen(alg, out_file_path)
+}
+
+fn run_getpub(key_path: Option<&PathBuf>, out_file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_getpub ***\n");
+    let key_path = key_path.ok_or("key file (-k) is required")?;
+    let src = sign::public_key_source(&sign::load_key(key_path)?);
+    if out_file_path.as_os_str().is_empty() {
+        print!("{}", src);
+    } else {
+        File::create(out_file_path)?.write_all(src.as_bytes())?;
+    }
+    Ok(())
+}
 
+/// Writes the device decryption key for the bootloader (`encrypt` feature).
+fn run_getpriv(key_path: Option<&PathBuf>, out_file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+    println!("\n*** run_getpriv ***\n");
+    let key_path = key_path.ok_or("key file (-k) is required")?;
+    let src = sign::const_source(
+        "getpriv",
+        "X25519_PRIVATE_KEY",
+        &encrypt::load_secret(key_path)?,
+    );
+    if out_file_path.as_os_str().is_empty() {
+        print!("{}", src);
+    } else {
+        File::create(out_file_path)?.write_all(src.as_bytes())?;
+    }
     Ok(())
 }
 
@@ -215,9 +650,41 @@ fn main() {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help");
-    opts.optopt("c", "", "sub command", "sign|crc|version|all|info");
+    opts.optopt(
+        "c",
+        "",
+        "sub command",
+        "sign|crc|version|all|info|compare|delta|ymodem|mcuboot|native|suit|keygen|getpub|getpriv|layout",
+    );
     opts.optopt("i", "", "input file", "INFILE");
     opts.optopt("o", "", "output file", "OUTFILE");
+    opts.optopt("k", "", "private key (PEM)", "KEYFILE");
+    opts.optopt("a", "", "key algorithm for keygen", "ed25519|p256|x25519");
+    opts.optopt(
+        "b",
+        "",
+        "base image file or version (major.minor.patch[+build]) for compare, base image for delta",
+        "BASE",
+    );
+    opts.optopt(
+        "s",
+        "",
+        "security counter for sign|all|mcuboot|native|suit (anti-rollback)",
+        "COUNTER",
+    );
+    opts.optopt(
+        "e",
+        "",
+        "device key (X25519 PEM) to encrypt for with sign|all, or to decrypt with info",
+        "ENCKEYFILE",
+    );
+    opts.optflag("z", "", "compress the payload (LZ4) with sign|all");
+    opts.optflag(
+        "n",
+        "",
+        "no commit TLV with all (an encrypted image has no room for it)",
+    );
+    opts.optopt("p", "", "serial port for ymodem", "PORT");
 
     match opts.parse(&args[1..]) {
         Ok(matches) => {
@@ -240,25 +707,118 @@ fn main() {
                 out_file_path = path::Path::new(&out_file_str).to_path_buf();
             }
 
+            let mut key_path = None;
+            if let Some(key_str) = matches.opt_str("k") {
+                let path = path::Path::new(&key_str).to_path_buf();
+                if !path.exists() {
+                    eprintln!("file not found {}", path.to_str().unwrap());
+                    std::process::exit(1);
+                }
+                key_path = Some(path);
+            }
+
+            let mut enc_key_path = None;
+            if let Some(key_str) = matches.opt_str("e") {
+                let path = path::Path::new(&key_str).to_path_buf();
+                if !path.exists() {
+                    eprintln!("file not found {}", path.to_str().unwrap());
+                    std::process::exit(1);
+                }
+                enc_key_path = Some(path);
+            }
+
+            let compress = matches.opt_present("z");
+            if compress && enc_key_path.is_some() {
+                eprintln!("a compressed image (-z) cannot be encrypted (-e)");
+                std::process::exit(1);
+            }
+
+            let security_counter = match matches.opt_get::<u32>("s") {
+                Ok(security_counter) => security_counter,
+                Err(e) => {
+                    eprintln!("security counter (-s): {}", e);
+                    std::process::exit(1);
+                }
+            };
+
             if let Some(command_str) = matches.opt_str("c") {
                 println!("command={}", command_str);
                 println!("in_file_path={}", in_file_path.to_string_lossy());
                 println!("out_file_path={}", out_file_path.to_string_lossy());
                 match &*command_str {
                     "info" => {
-                        run_info(&in_file_path).unwrap();
+                        run_info(&in_file_path, enc_key_path.as_ref()).unwrap();
                     }
                     "crc" => {
                         run_crc(&in_file_path, &out_file_path).unwrap();
                     }
                     "sign" => {
-                        run_sign(&in_file_path, &out_file_path).unwrap();
+                        run_sign(
+                            &in_file_path,
+                            &out_file_path,
+                            key_path.as_ref(),
+                            security_counter,
+                            enc_key_path.as_ref(),
+                            compress,
+                        )
+                        .unwrap();
                     }
                     "version" => {
                         run_version(&in_file_path, &out_file_path).unwrap();
                     }
                     "all" => {
-                        run_all(&in_file_path, &out_file_path).unwrap();
+                        run_all(
+                            &in_file_path,
+                            &out_file_path,
+                            key_path.as_ref(),
+                            security_counter,
+                            enc_key_path.as_ref(),
+                            compress,
+                            !matches.opt_present("n"),
+                        )
+                        .unwrap();
+                    }
+                    "compare" => {
+                        run_compare(&in_file_path, matches.opt_str("b").as_deref()).unwrap();
+                    }
+                    "delta" => {
+                        run_delta(
+                            &in_file_path,
+                            &out_file_path,
+                            matches.opt_str("b").as_deref(),
+                        )
+                        .unwrap();
+                    }
+                    "ymodem" => {
+                        run_ymodem(&in_file_path, matches.opt_str("p").as_deref()).unwrap();
+                    }
+                    "mcuboot" | "native" | "suit" => {
+                        let format = match &*command_str {
+                            "mcuboot" => ImageFormat::Mcuboot,
+                            "suit" => ImageFormat::Suit,
+                            _ => ImageFormat::Native,
+                        };
+                        run_convert(
+                            &in_file_path,
+                            &out_file_path,
+                            format,
+                            key_path.as_ref(),
+                            security_counter,
+                        )
+                        .unwrap();
+                    }
+                    "layout" => {
+                        run_layout().unwrap();
+                    }
+                    "keygen" => {
+                        let alg = matches.opt_str("a").unwrap_or("ed25519".to_string());
+                        run_keygen(&alg, &out_file_path).unwrap();
+                    }
+                    "getpub" => {
+                        run_getpub(key_path.as_ref(), &out_file_path).unwrap();
+                    }
+                    "getpriv" => {
+                        run_getpriv(key_path.as_ref(), &out_file_path).unwrap();
                     }
                     _ => {
                         print_usage(&program, opts);
@@ -276,3 +836,70 @@ fn main() {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serialport::{SerialPort, TTYPort};
+    use std::thread;
+
+    /// The bootloader's side: receives one file on `port` and returns its
+    /// name and data. The first data packet is not acknowledged, so that the
+    /// sender has to time out and send it again.
+    fn receive(port: &mut TTYPort) -> (Vec<u8>, Vec<u8>) {
+        let mut receiver = ymodem::Receiver::new();
+        let (mut name, mut file) = (Vec::new(), Vec::new());
+        let mut dropped = false;
+        let mut buf = [0u8; 64];
+        loop {
+            let n = match port.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    port.write_all(receiver.timeout().unwrap()).unwrap();
+                    continue;
+                }
+                Err(e) => panic!("{}", e),
+            };
+            for &b in &buf[..n] {
+                let Some(event) = receiver.push(b).unwrap() else {
+                    continue;
+                };
+                let reply = event.reply();
+                match event {
+                    ymodem::Event::File {
+                        name: file_name, ..
+                    } => name = file_name.to_vec(),
+                    ymodem::Event::Data(data) => {
+                        file.extend_from_slice(data);
+                        if !dropped {
+                            dropped = true;
+                            continue;
+                        }
+                    }
+                    ymodem::Event::End => {
+                        port.write_all(reply).unwrap();
+                        return (name, file);
+                    }
+                    ymodem::Event::Reply(_) => {}
+                }
+                port.write_all(reply).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_ymodem() {
+        let (mut master, mut slave) = TTYPort::pair().unwrap();
+        // longer than the sender's, so that the sender times out first
+        slave.set_timeout(Duration::from_secs(1)).unwrap();
+        master.set_timeout(Duration::from_millis(200)).unwrap();
+        // the slave stays open until the sender has read the last ACK
+        let receiver = thread::spawn(move || (receive(&mut slave), slave));
+
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        send_ymodem(&mut master, b"app.bin", &data).unwrap();
+        let ((name, file), _) = receiver.join().unwrap();
+        assert_eq!(name, b"app.bin");
+        assert_eq!(file, data);
+    }
+}
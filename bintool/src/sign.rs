@@ -0,0 +1,136 @@
+use blxlib::image_header::{ImageHeader, SIG_ALG_ECDSA_P256, SIG_ALG_ED25519};
+use blxlib::signature::{self, ED25519_SIGNATURE_LENGTH, P256_SIGNATURE_LENGTH};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey};
+use ed25519_dalek::Signer;
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use rand::rngs::OsRng;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub enum SigningKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    P256(p256::ecdsa::SigningKey),
+}
+
+/// Loads a PKCS#8 PEM private key (Ed25519 or P-256), or a SEC1
+/// `EC PRIVATE KEY` PEM as written by `openssl ecparam -genkey`.
+pub fn load_key(key_path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    let pem = fs::read_to_string(key_path)?;
+    if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem) {
+        return Ok(SigningKey::Ed25519(key));
+    }
+    if let Ok(key) = p256::ecdsa::SigningKey::from_pkcs8_pem(&pem) {
+        return Ok(SigningKey::P256(key));
+    }
+    if let Ok(key) = p256::SecretKey::from_sec1_pem(&pem) {
+        return Ok(SigningKey::P256(key.into()));
+    }
+    Err(format!(
+        "{}: not an Ed25519 or P-256 private key",
+        key_path.to_string_lossy()
+    )
+    .into())
+}
+
+/// Sets `ih.sig_alg` and fills `ih.signature` with a signature over the header
+/// (excluding `signature` and `crc32`). The payload is covered by
+/// `ih.payload_digest`, which must already be set.
+pub fn sign(ih: &mut ImageHeader, key: &SigningKey) -> Result<(), Box<dyn Error>> {
+    ih.signature = [0u8; 128];
+    match key {
+        SigningKey::Ed25519(key) => {
+            ih.sig_alg = SIG_ALG_ED25519;
+            let sig = key.sign(&signature::signed_message(ih));
+            ih.signature[..ED25519_SIGNATURE_LENGTH].copy_from_slice(&sig.to_bytes());
+        }
+        SigningKey::P256(key) => {
+            ih.sig_alg = SIG_ALG_ECDSA_P256;
+            let sig: p256::ecdsa::Signature = key.sign_prehash(&signature::prehash_p256(ih))?;
+            ih.signature[..P256_SIGNATURE_LENGTH].copy_from_slice(&sig.to_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Signature of an MCUboot image hash: raw for Ed25519, DER for P-256 like
+/// `imgtool` writes it.
+pub fn sign_hash(hash: &[u8], key: &SigningKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    match key {
+        SigningKey::Ed25519(key) => Ok(key.sign(hash).to_bytes().to_vec()),
+        SigningKey::P256(key) => {
+            let sig: p256::ecdsa::Signature = key.sign_prehash(hash)?;
+            Ok(sig.to_der().as_bytes().to_vec())
+        }
+    }
+}
+
+/// A COSE signature of `message` (`Sig_structure`): EdDSA, or ES256 with the
+/// fixed size r || s encoding.
+pub fn sign_cose(message: &[u8], key: &SigningKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    match key {
+        SigningKey::Ed25519(key) => Ok(key.sign(message).to_bytes().to_vec()),
+        SigningKey::P256(key) => {
+            let sig: p256::ecdsa::Signature = key.sign_prehash(&blxlib::sha256::sha256(message))?;
+            Ok(sig.to_bytes().to_vec())
+        }
+    }
+}
+
+/// The public half of `key` as a DER `SubjectPublicKeyInfo`, which MCUboot hashes
+/// for `TLV_KEYHASH`.
+pub fn public_key_der(key: &SigningKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    let der = match key {
+        SigningKey::Ed25519(key) => key.verifying_key().to_public_key_der()?,
+        SigningKey::P256(key) => key.verifying_key().to_public_key_der()?,
+    };
+    Ok(der.into_vec())
+}
+
+pub fn keygen(alg: &str, out_file_path: &Path) -> Result<(), Box<dyn Error>> {
+    let pem = match alg {
+        "ed25519" => {
+            ed25519_dalek::SigningKey::generate(&mut OsRng).to_pkcs8_pem(LineEnding::LF)?
+        }
+        "p256" => p256::ecdsa::SigningKey::random(&mut OsRng).to_pkcs8_pem(LineEnding::LF)?,
+        _ => return Err(format!("unknown algorithm: {}", alg).into()),
+    };
+    fs::write(out_file_path, pem.as_bytes())?;
+    Ok(())
+}
+
+/// Rust source embedding the public half of `key`, to be compiled into the bootloader.
+pub fn public_key_source(key: &SigningKey) -> String {
+    let (name, bytes) = match key {
+        SigningKey::Ed25519(key) => (
+            "ED25519_PUBLIC_KEY",
+            key.verifying_key().to_bytes().to_vec(),
+        ),
+        SigningKey::P256(key) => (
+            "ECDSA_P256_PUBLIC_KEY",
+            key.verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+        ),
+    };
+    const_source("getpub", name, &bytes)
+}
+
+/// Rust source of a `[u8; N]` constant, written by `bintool -c <command>`.
+/// The bytes are laid out as rustfmt does, 16 to a line.
+pub fn const_source(command: &str, name: &str, bytes: &[u8]) -> String {
+    let mut src = String::new();
+    src.push_str(&format!(
+        "//! Generated by `bintool -c {}`. Do not edit.\n\n",
+        command
+    ));
+    src.push_str(&format!("pub const {}: [u8; {}] = [\n", name, bytes.len()));
+    for line in bytes.chunks(16) {
+        let line: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+        src.push_str(&format!("    {}\n", line.join(" ")));
+    }
+    src.push_str("];\n");
+    src
+}
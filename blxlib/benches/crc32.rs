@@ -0,0 +1,29 @@
+//! `cargo bench -p blxlib --features crc32-slice-by-8`
+
+use blxlib::crc32;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+fn bench_crc32(c: &mut Criterion) {
+    // a full application slot
+    let buf: Vec<u8> = (0..0xe_0000u32).map(|i| (i * 31) as u8).collect();
+
+    let mut group = c.benchmark_group("crc32");
+    group.throughput(Throughput::Bytes(buf.len() as u64));
+    group.bench_function("bytewise", |b| {
+        b.iter(|| crc32::crc32_bytewise(black_box(&buf)))
+    });
+    group.bench_function("crc32", |b| b.iter(|| crc32::crc32(black_box(&buf))));
+    group.bench_function("streaming 256B", |b| {
+        b.iter(|| {
+            let mut crc = crc32::Crc32::new();
+            for chunk in black_box(&buf).chunks(256) {
+                crc.update(chunk);
+            }
+            crc.finalize()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_crc32);
+criterion_main!(benches);
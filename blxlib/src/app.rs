@@ -0,0 +1,665 @@
+//! Bootloader client for the application: the state of the running image,
+//! staging an update in the secondary slot, confirming and rebooting.
+//!
+//! ```ignore
+//! let mut client = BootClient::new(flash, LAYOUT);
+//! client.confirm()?;
+//! let mut update = client.begin_update()?;
+//! update.write(chunk)?; // ... for each received chunk
+//! update.finish()?;
+//! client.mark_pending(SwapType::Test)?;
+//! client.reboot();
+//! ```
+//!
+//! `reboot_to_recovery` stays in the bootloader for serial recovery.
+//!
+//! A patch from `bintool -c delta` is written with `begin_delta_update`
+//! instead, which applies it to the running image.
+
+use crate::compress;
+use crate::delta::{self, Action, DeltaError, PatchHeader};
+use crate::flash::{self, Flash, FlashReader, ERASED};
+use crate::image_header::{HeaderParseError, ImageHeader, ValidationError, HEADER_LENGTH};
+use crate::layout::{FlashLayout, Partition, PAGE_SIZE, SECTOR_SIZE};
+use crate::swap::{self, SwapState, SwapType};
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppError<E> {
+    Flash(E),
+    Parse(HeaderParseError),
+    Validation(ValidationError),
+    /// More data was written than fits in the secondary slot.
+    SlotFull,
+    /// The running image is on trial and the secondary slot holds the image
+    /// to revert to.
+    NotConfirmed,
+    /// The secondary slot holds the image that the last swap moved out, or
+    /// the compressed image that is installed.
+    NoUpdate,
+    Delta(DeltaError),
+}
+
+impl<E: fmt::Debug> fmt::Display for AppError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Flash(e) => write!(f, "flash error: {:?}", e),
+            AppError::Parse(e) => write!(f, "{}", e),
+            AppError::Validation(e) => write!(f, "{}", e),
+            AppError::SlotFull => write!(f, "update does not fit in the secondary slot"),
+            AppError::NotConfirmed => write!(f, "running image is not confirmed"),
+            AppError::NoUpdate => write!(f, "secondary slot holds the previous image"),
+            AppError::Delta(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for AppError<E> {}
+
+impl<E> From<HeaderParseError> for AppError<E> {
+    fn from(e: HeaderParseError) -> Self {
+        AppError::Parse(e)
+    }
+}
+
+impl<E> From<ValidationError> for AppError<E> {
+    fn from(e: ValidationError) -> Self {
+        AppError::Validation(e)
+    }
+}
+
+impl<E> From<DeltaError> for AppError<E> {
+    fn from(e: DeltaError) -> Self {
+        AppError::Delta(e)
+    }
+}
+
+/// What the bootloader did with the running image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageState {
+    /// Installed permanently or confirmed (or never swapped).
+    Confirmed,
+    /// A test image on its trial boot, reverted at the next reset unless confirmed.
+    Trial,
+    /// The bootloader reverted an unconfirmed test image; this is the image
+    /// that ran before it.
+    Reverted,
+}
+
+/// What the bootloader does with the image in the secondary slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateState {
+    /// Installed on the next boot, as `SwapType::Test` unless
+    /// `SwapType::Permanent` was requested.
+    Pending(SwapType),
+    /// The image that the last swap moved out, or the compressed image that
+    /// is installed.
+    Done,
+}
+
+pub struct BootClient<F: Flash> {
+    flash: F,
+    layout: FlashLayout,
+}
+
+impl<F: Flash> BootClient<F> {
+    pub fn new(flash: F, layout: FlashLayout) -> Self {
+        BootClient { flash, layout }
+    }
+
+    /// Header of the running image (`iv_*` is its version).
+    pub fn running_header(&mut self) -> Result<ImageHeader, AppError<F::Error>> {
+        self.read_header(self.layout.primary)
+    }
+
+    pub fn image_state(&mut self) -> Result<ImageState, F::Error> {
+        Ok(match swap::read_state(&mut self.flash, &self.layout)? {
+            SwapState::Complete {
+                status,
+                confirmed: false,
+                ..
+            } if status.swap_type == SwapType::Test => ImageState::Trial,
+            SwapState::Complete { status, .. } if status.swap_type == SwapType::Revert => {
+                ImageState::Reverted
+            }
+            _ => ImageState::Confirmed,
+        })
+    }
+
+    /// Header of the image in the secondary slot.
+    pub fn update_header(&mut self) -> Result<ImageHeader, AppError<F::Error>> {
+        self.read_header(self.layout.secondary)
+    }
+
+    /// State of the image in the secondary slot with header `ih`.
+    pub fn update_state(&mut self, ih: &ImageHeader) -> Result<UpdateState, F::Error> {
+        let state = swap::read_state(&mut self.flash, &self.layout)?;
+        if state.is_swapped_out(ih.crc32) || state.is_installed(ih.crc32) {
+            return Ok(UpdateState::Done);
+        }
+        let swap_type = swap::requested_type(&mut self.flash, &self.layout, ih.crc32)?;
+        Ok(UpdateState::Pending(swap_type))
+    }
+
+    pub(crate) fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    pub(crate) fn layout(&self) -> &FlashLayout {
+        &self.layout
+    }
+
+    /// Keeps the running image. Must be called on a trial boot before the
+    /// next reset (and before the bootloader's watchdog fires).
+    pub fn confirm(&mut self) -> Result<(), F::Error> {
+        swap::confirm(&mut self.flash, &self.layout)
+    }
+
+    /// Starts writing an update image to the secondary slot, from the beginning.
+    pub fn begin_update(&mut self) -> Result<UpdateWriter<'_, F>, AppError<F::Error>> {
+        if self.image_state().map_err(AppError::Flash)? == ImageState::Trial {
+            return Err(AppError::NotConfirmed);
+        }
+        let writer = SlotWriter::new(self.layout.secondary);
+        Ok(UpdateWriter {
+            client: self,
+            writer,
+        })
+    }
+
+    /// Starts applying a patch to the running image, writing the result to
+    /// the secondary slot. The patch is refused unless it was made for the
+    /// running image.
+    pub fn begin_delta_update(&mut self) -> Result<DeltaWriter<'_, F>, AppError<F::Error>> {
+        Ok(DeltaWriter {
+            update: self.begin_update()?,
+            decoder: delta::Decoder::new(),
+        })
+    }
+
+    /// Validates the image in the secondary slot and requests that the
+    /// bootloader installs it as `swap_type` (`Test` or `Permanent`) on the
+    /// next boot. The signature is checked by the bootloader. A compressed
+    /// image is always installed permanently.
+    ///
+    /// Only native images are supported (`AppError::Parse` otherwise). A valid
+    /// MCUboot image is installed as a test anyway, or permanently if its slot
+    /// trailer says so (`imgtool sign --pad --confirm`).
+    pub fn mark_pending(&mut self, swap_type: SwapType) -> Result<ImageHeader, AppError<F::Error>> {
+        let ih = self.read_header(self.layout.secondary)?;
+        if self.update_state(&ih).map_err(AppError::Flash)? == UpdateState::Done {
+            return Err(AppError::NoUpdate);
+        }
+        self.check_payload(&ih)?;
+        swap::request(&mut self.flash, &self.layout, ih.crc32, swap_type)
+            .map_err(AppError::Flash)?;
+        Ok(ih)
+    }
+
+    /// Resets the chip. The bootloader runs first and installs a pending update.
+    #[cfg(target_arch = "arm")]
+    pub fn reboot(self) -> ! {
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    /// Resets the chip into the bootloader's recovery mode (see `trigger`).
+    #[cfg(target_arch = "arm")]
+    pub fn reboot_to_recovery(self) -> ! {
+        let scratch = crate::trigger::SCRATCH_ADDR as *mut u32;
+        unsafe { core::ptr::write_volatile(scratch, crate::trigger::SCRATCH_MAGIC) };
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    fn read_header(&mut self, partition: Partition) -> Result<ImageHeader, AppError<F::Error>> {
+        let mut buf = [0u8; HEADER_LENGTH as usize];
+        self.flash
+            .read(partition.offset, &mut buf)
+            .map_err(AppError::Flash)?;
+        let ih = ImageHeader::try_from(&buf[..])?;
+        let calc_crc32 = ih.calc_crc32();
+        if ih.crc32 != calc_crc32 {
+            return Err(ValidationError::HeaderCrc {
+                expected: ih.crc32,
+                actual: calc_crc32,
+            }
+            .into());
+        }
+        let max_length = partition.size - HEADER_LENGTH as u32;
+        if ih.image_length > max_length {
+            return Err(ValidationError::ImageTooLarge {
+                max: max_length,
+                actual: ih.image_length,
+            }
+            .into());
+        }
+        Ok(ih)
+    }
+
+    /// Validates the image in the secondary slot after `written` bytes of it
+    /// were written.
+    pub(crate) fn check_update(&mut self, written: u32) -> Result<ImageHeader, AppError<F::Error>> {
+        let ih = self.read_header(self.layout.secondary)?;
+        let length = written - (HEADER_LENGTH as u32).min(written);
+        let expected = compress::compressed_length(&ih).unwrap_or(ih.image_length);
+        if length < expected {
+            return Err(ValidationError::PayloadTooShort {
+                expected,
+                actual: length,
+            }
+            .into());
+        }
+        self.check_payload(&ih)?;
+        Ok(ih)
+    }
+
+    /// Checks `payload_crc` and `payload_digest` of the image in the secondary
+    /// slot, decompressing a compressed one.
+    fn check_payload(&mut self, ih: &ImageHeader) -> Result<(), AppError<F::Error>> {
+        let start = self.layout.secondary.offset + HEADER_LENGTH as u32;
+        let (payload_crc, payload_digest) = match compress::compressed_length(ih) {
+            Some(length) => {
+                let length = length.min(self.layout.secondary.end() - start);
+                let mut input = FlashReader::new(start, length);
+                let digests = compress::digests(&mut input.bytes(&mut self.flash), ih.image_length);
+                input.finish().map_err(AppError::Flash)?;
+                digests
+            }
+            None => {
+                flash::digests(&mut self.flash, start, ih.image_length).map_err(AppError::Flash)?
+            }
+        };
+        if ih.payload_crc != payload_crc {
+            return Err(ValidationError::PayloadCrc {
+                expected: ih.payload_crc,
+                actual: payload_crc,
+            }
+            .into());
+        }
+        if ih.payload_digest != payload_digest {
+            return Err(ValidationError::PayloadDigest {
+                expected: ih.payload_digest,
+                actual: payload_digest,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Writes a slot sequentially. Sectors are erased as they are reached. Data
+/// of a partial last page is only written by `flush`.
+pub struct SlotWriter {
+    slot: Partition,
+    written: u32,
+    page: [u8; PAGE_SIZE as usize],
+}
+
+impl SlotWriter {
+    pub fn new(slot: Partition) -> Self {
+        SlotWriter {
+            slot,
+            written: 0,
+            page: [ERASED; PAGE_SIZE as usize],
+        }
+    }
+
+    pub fn write<F: Flash>(
+        &mut self,
+        flash: &mut F,
+        data: &[u8],
+    ) -> Result<(), AppError<F::Error>> {
+        for &b in data {
+            if self.written == self.slot.size {
+                return Err(AppError::SlotFull);
+            }
+            if self.written.is_multiple_of(SECTOR_SIZE) {
+                flash
+                    .erase_sector(self.slot.offset + self.written)
+                    .map_err(AppError::Flash)?;
+            }
+            self.page[(self.written % PAGE_SIZE) as usize] = b;
+            self.written += 1;
+            if self.written.is_multiple_of(PAGE_SIZE) {
+                self.program_page(flash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Writes the partial last page.
+    pub fn flush<F: Flash>(&mut self, flash: &mut F) -> Result<(), AppError<F::Error>> {
+        if !self.written.is_multiple_of(PAGE_SIZE) {
+            self.page[(self.written % PAGE_SIZE) as usize..].fill(ERASED);
+            self.program_page(flash)?;
+        }
+        Ok(())
+    }
+
+    /// Programs the page that holds the last written byte.
+    fn program_page<F: Flash>(&mut self, flash: &mut F) -> Result<(), AppError<F::Error>> {
+        let offset = self.slot.offset + (self.written - 1) / PAGE_SIZE * PAGE_SIZE;
+        flash
+            .program_page(offset, &self.page)
+            .map_err(AppError::Flash)
+    }
+}
+
+/// Writes an image (header and payload) to the secondary slot with a
+/// `SlotWriter`.
+pub struct UpdateWriter<'a, F: Flash> {
+    client: &'a mut BootClient<F>,
+    writer: SlotWriter,
+}
+
+impl<F: Flash> UpdateWriter<'_, F> {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), AppError<F::Error>> {
+        self.writer.write(&mut self.client.flash, data)
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u32 {
+        self.writer.written()
+    }
+
+    /// Writes the partial last page and validates the image.
+    pub fn finish(mut self) -> Result<ImageHeader, AppError<F::Error>> {
+        self.writer.flush(&mut self.client.flash)?;
+        self.client.check_update(self.writer.written())
+    }
+}
+
+/// Applies a patch to the image in the primary slot and writes the result
+/// with an `UpdateWriter`.
+pub struct DeltaWriter<'a, F: Flash> {
+    update: UpdateWriter<'a, F>,
+    decoder: delta::Decoder,
+}
+
+impl<F: Flash> DeltaWriter<'_, F> {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), AppError<F::Error>> {
+        let primary = self.update.client.layout.primary;
+        for &b in data {
+            match self.decoder.push(b)? {
+                None => {}
+                Some(Action::Header(header)) => self.check_source(&header)?,
+                Some(Action::Copy { source, len }) => {
+                    let mut buf = [0u8; 64];
+                    for offset in (source..source + len).step_by(buf.len()) {
+                        let buf = &mut buf[..(source + len - offset).min(64) as usize];
+                        self.read_source(primary.offset + offset, buf)?;
+                        self.update.write(buf)?;
+                    }
+                }
+                Some(Action::Add { source, diff }) => {
+                    let mut buf = [0u8];
+                    self.read_source(primary.offset + source, &mut buf)?;
+                    self.update.write(&[buf[0].wrapping_add(diff)])?;
+                }
+                Some(Action::Insert(b)) => self.update.write(&[b])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes of the update image written so far.
+    pub fn written(&self) -> u32 {
+        self.update.written()
+    }
+
+    /// Checks that the patch is complete, then finishes the update image.
+    pub fn finish(self) -> Result<ImageHeader, AppError<F::Error>> {
+        self.decoder.finish()?;
+        self.update.finish()
+    }
+
+    fn check_source(&mut self, header: &PatchHeader) -> Result<(), AppError<F::Error>> {
+        let primary = self.update.client.layout.primary;
+        if header.source_length > primary.size {
+            return Err(DeltaError::SourceMismatch.into());
+        }
+        let (_, digest) = flash::digests(
+            &mut self.update.client.flash,
+            primary.offset,
+            header.source_length,
+        )
+        .map_err(AppError::Flash)?;
+        if digest != header.source_digest {
+            return Err(DeltaError::SourceMismatch.into());
+        }
+        Ok(())
+    }
+
+    fn read_source(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), AppError<F::Error>> {
+        self.update
+            .client
+            .flash
+            .read(offset, buf)
+            .map_err(AppError::Flash)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::flash::mock::MockFlash;
+    use crate::sha256::sha256;
+    use crate::swap::tests::{boot, write_image, TEST_FLASH_SIZE, TEST_LAYOUT};
+    use crate::tlv::{TlvWriter, TLV_LZ4};
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Header and `image_length` bytes of `seed`ed payload with valid checksums.
+    pub(crate) fn image(image_length: u32, seed: u8) -> Vec<u8> {
+        let payload: Vec<u8> = (0..image_length).map(|i| (i as u8) ^ seed).collect();
+        image_of(&payload, seed as u32)
+    }
+
+    fn image_of(payload: &[u8], iv_build: u32) -> Vec<u8> {
+        let mut ih = ImageHeader::new();
+        ih.image_length = payload.len() as u32;
+        ih.iv_build = iv_build;
+        ih.payload_crc = crc32(payload);
+        ih.payload_digest = sha256(payload);
+        ih.crc32 = ih.calc_crc32();
+        let mut image = ih.to_bytes().to_vec();
+        image.extend_from_slice(payload);
+        image
+    }
+
+    /// `image` with its payload compressed.
+    fn compressed(image: &[u8]) -> Vec<u8> {
+        let mut ih = ImageHeader::try_from(image).unwrap();
+        let plain = &image[HEADER_LENGTH as usize..];
+        let mut block = vec![0u8; plain.len() + plain.len() / 255 + 16];
+        let len = compress::compress(plain, &mut block).unwrap();
+        TlvWriter::new(&mut ih.tlv)
+            .push(TLV_LZ4, &(len as u32).to_le_bytes())
+            .unwrap();
+        ih.crc32 = ih.calc_crc32();
+        let mut image = ih.to_bytes().to_vec();
+        image.extend_from_slice(&block[..len]);
+        image
+    }
+
+    pub(crate) fn client() -> BootClient<MockFlash> {
+        let mut flash = MockFlash::new(TEST_FLASH_SIZE);
+        write_image(&mut flash, TEST_LAYOUT.primary, 0x2800, 0x11);
+        BootClient::new(flash, TEST_LAYOUT)
+    }
+
+    /// Writes `image` in chunks of `chunk` bytes.
+    fn stage(
+        client: &mut BootClient<MockFlash>,
+        image: &[u8],
+        chunk: usize,
+    ) -> Result<ImageHeader, AppError<<MockFlash as Flash>::Error>> {
+        let mut update = client.begin_update()?;
+        for data in image.chunks(chunk) {
+            update.write(data)?;
+        }
+        assert_eq!(update.written(), image.len() as u32);
+        update.finish()
+    }
+
+    #[test]
+    fn test_update() {
+        let mut client = client();
+        assert_eq!(client.running_header().unwrap().iv_build, 0x11);
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+
+        let image = image(0x1c80, 0x22);
+        let ih = stage(&mut client, &image, 100).unwrap();
+        assert_eq!(ih.iv_build, 0x22);
+        let secondary = TEST_LAYOUT.secondary.offset as usize;
+        assert_eq!(
+            &client.flash.data()[secondary..secondary + image.len()],
+            &image[..]
+        );
+        assert_eq!(client.mark_pending(SwapType::Test), Ok(ih));
+
+        assert_eq!(boot(&mut client.flash), Ok(true));
+        assert_eq!(client.running_header(), Ok(ih));
+        assert_eq!(client.image_state(), Ok(ImageState::Trial));
+        // the previous image is needed for a revert
+        assert_eq!(client.begin_update().err(), Some(AppError::NotConfirmed));
+        assert_eq!(client.mark_pending(SwapType::Test), Err(AppError::NoUpdate));
+
+        client.confirm().unwrap();
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+        assert_eq!(boot(&mut client.flash), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+    }
+
+    #[test]
+    fn test_update_permanent() {
+        let mut client = client();
+        let ih = stage(&mut client, &image(0x0800, 0x22), 0x1000).unwrap();
+        client.mark_pending(SwapType::Permanent).unwrap();
+        assert_eq!(boot(&mut client.flash), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+    }
+
+    #[test]
+    fn test_update_compressed() {
+        let mut client = client();
+        let image = compressed(&image(0x2a00, 0x22));
+        assert!(image.len() < 0x1000);
+        let ih = stage(&mut client, &image, 0x100).unwrap();
+        client.mark_pending(SwapType::Test).unwrap();
+        assert_eq!(boot(&mut client.flash), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+        assert_eq!(client.mark_pending(SwapType::Test), Err(AppError::NoUpdate));
+
+        assert_eq!(
+            stage(&mut client, &image[..image.len() - 1], 0x100),
+            Err(AppError::Validation(ValidationError::PayloadTooShort {
+                expected: image.len() as u32 - HEADER_LENGTH as u32,
+                actual: image.len() as u32 - HEADER_LENGTH as u32 - 1,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_update_delta() {
+        let mut client = client();
+        let source = image(0x2800, 0x11);
+        let primary = TEST_LAYOUT.primary.offset as usize;
+        client.flash.data_mut()[primary..primary + source.len()].copy_from_slice(&source);
+        let mut payload = source[HEADER_LENGTH as usize..].to_vec();
+        payload[0x1000..0x1010].fill(0xaa);
+        payload.extend_from_slice(&[0x55; 0x100]);
+        let target = image_of(&payload, 0x12);
+        let patch = delta::tests::simple_patch(&source, &target);
+        assert!(patch.len() < 0x200);
+
+        let mut update = client.begin_delta_update().unwrap();
+        for data in patch.chunks(7) {
+            update.write(data).unwrap();
+        }
+        assert_eq!(update.written(), target.len() as u32);
+        let ih = update.finish().unwrap();
+        let secondary = TEST_LAYOUT.secondary.offset as usize;
+        assert_eq!(
+            &client.flash.data()[secondary..secondary + target.len()],
+            &target[..]
+        );
+        client.mark_pending(SwapType::Permanent).unwrap();
+        assert_eq!(boot(&mut client.flash), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+
+        // the running image is now the target
+        let mut update = client.begin_delta_update().unwrap();
+        assert_eq!(
+            update.write(&patch),
+            Err(AppError::Delta(DeltaError::SourceMismatch))
+        );
+        let mut update = client.begin_delta_update().unwrap();
+        let patch = delta::tests::simple_patch(&target, &image(0x0800, 0x22));
+        update.write(&patch[..patch.len() - 1]).unwrap();
+        assert_eq!(update.finish(), Err(AppError::Delta(DeltaError::Truncated)));
+    }
+
+    #[test]
+    fn test_revert() {
+        let mut client = client();
+        let old = client.running_header().unwrap();
+        stage(&mut client, &image(0x0800, 0x22), 0x100).unwrap();
+        assert_eq!(boot(&mut client.flash), Ok(true));
+        // not confirmed
+        assert_eq!(boot(&mut client.flash), Ok(false));
+        assert_eq!(client.running_header(), Ok(old));
+        assert_eq!(client.image_state(), Ok(ImageState::Reverted));
+        // the failed update stays in the secondary slot but is not installed again
+        assert_eq!(client.mark_pending(SwapType::Test), Err(AppError::NoUpdate));
+
+        let ih = stage(&mut client, &image(0x0900, 0x33), 0x80).unwrap();
+        client.mark_pending(SwapType::Test).unwrap();
+        assert_eq!(boot(&mut client.flash), Ok(true));
+        assert_eq!(client.running_header(), Ok(ih));
+    }
+
+    #[test]
+    fn test_update_errors() {
+        let mut client = client();
+        let mut image = image(0x0800, 0x22);
+
+        assert_eq!(
+            stage(&mut client, &image[..0x0700], 0x100),
+            Err(AppError::Validation(ValidationError::PayloadTooShort {
+                expected: 0x0800,
+                actual: 0x0600
+            }))
+        );
+
+        image[0x0400] ^= 0x01;
+        assert!(matches!(
+            stage(&mut client, &image, 0x100),
+            Err(AppError::Validation(ValidationError::PayloadCrc { .. }))
+        ));
+        assert!(matches!(
+            client.mark_pending(SwapType::Test),
+            Err(AppError::Validation(ValidationError::PayloadCrc { .. }))
+        ));
+
+        image[0x10] ^= 0x01;
+        assert!(matches!(
+            stage(&mut client, &image, 0x100),
+            Err(AppError::Validation(ValidationError::HeaderCrc { .. }))
+        ));
+
+        let mut update = client.begin_update().unwrap();
+        let full = [0u8; 0x1000];
+        for _ in 0..TEST_LAYOUT.secondary.sectors() {
+            update.write(&full).unwrap();
+        }
+        assert_eq!(update.write(&[0]), Err(AppError::SlotFull));
+    }
+}
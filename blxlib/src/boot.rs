@@ -0,0 +1,515 @@
+//! The boot policy as a pure function. The bootloader gathers `BootInputs`
+//! (swap record and its resume count, slot images, recovery trigger and
+//! security counter), carries out the `BootAction` that `decide` returns and
+//! decides again, until it boots the primary image or stops.
+//!
+//! In order:
+//!
+//! 1. an interrupted swap is resumed up to `MAX_ATTEMPTS` times, an
+//!    unconfirmed test image is reverted,
+//! 2. a recovery trigger enters recovery mode,
+//! 3. a valid update image is installed unless it is rolled back or older
+//!    than a valid primary image,
+//! 4. a valid primary image is booted unless it is rolled back or an install
+//!    failed (an overwrite that did not validate, or a swap that did not
+//!    finish after `MAX_ATTEMPTS` resumes), otherwise the bootloader enters
+//!    recovery mode (or halts without it).
+
+use core::cmp::Ordering;
+use core::fmt;
+
+use crate::image::ImageInfo;
+use crate::swap::SwapState;
+use crate::trigger::Trigger;
+use crate::version::ImageVersion;
+
+/// Resumes of an interrupted swap before the bootloader gives up on it.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapPhase {
+    /// No swap, or a completed one.
+    Idle,
+    /// A swap or revert was interrupted by a reset.
+    Interrupted,
+    /// A test image was not confirmed before the reset.
+    NeedsRevert,
+    /// An overwrite did not validate; the primary slot holds no usable image.
+    Failed,
+}
+
+impl From<&SwapState> for SwapPhase {
+    fn from(state: &SwapState) -> Self {
+        match state {
+            SwapState::InProgress { .. } => SwapPhase::Interrupted,
+            SwapState::Failed { .. } => SwapPhase::Failed,
+            state if state.needs_revert() => SwapPhase::NeedsRevert,
+            _ => SwapPhase::Idle,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotState {
+    /// No image that parses.
+    Empty,
+    /// The payload or the signature is not valid.
+    Invalid,
+    /// `version` is `None` for a SUIT image.
+    Valid {
+        version: Option<ImageVersion>,
+        security_counter: u32,
+    },
+    /// Secondary slot only: the image that the last swap moved out, or the
+    /// compressed image that is installed. It is not validated.
+    Installed,
+}
+
+impl SlotState {
+    /// A validated and verified image.
+    pub fn valid(info: &ImageInfo) -> Self {
+        SlotState::Valid {
+            version: info.version,
+            security_counter: info.security_counter,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootInputs {
+    pub swap: SwapPhase,
+    /// Boot counter: times the interrupted swap has been resumed
+    /// (`swap::attempts`).
+    pub attempts: u32,
+    pub trigger: Option<Trigger>,
+    pub primary: SlotState,
+    pub secondary: SlotState,
+    /// Highest accepted security counter.
+    pub min_counter: u32,
+    /// Serial recovery is available.
+    pub recovery: bool,
+}
+
+/// Why the primary image is not booted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    Requested(Trigger),
+    NoImage,
+    Invalid,
+    RolledBack,
+    InstallFailed,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Requested(trigger) => write!(f, "requested by {:?}", trigger),
+            Reason::NoImage => write!(f, "no image"),
+            Reason::Invalid => write!(f, "image validation"),
+            Reason::RolledBack => write!(f, "rollback"),
+            Reason::InstallFailed => write!(f, "update install failed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootAction {
+    /// Finish the interrupted swap or revert.
+    Resume,
+    /// Swap the unconfirmed test image back out.
+    Revert,
+    /// Install the secondary image (the swap type is requested separately).
+    Install,
+    Recovery(Reason),
+    Halt(Reason),
+    Boot,
+}
+
+/// Why the secondary image is not installed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    /// Empty or already installed.
+    NoUpdate,
+    Invalid,
+    /// `security_counter` is lower than the highest accepted one.
+    RolledBack {
+        security_counter: u32,
+        min: u32,
+    },
+    /// Older than the valid primary image.
+    Older {
+        version: ImageVersion,
+        primary: ImageVersion,
+    },
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::NoUpdate => write!(f, "no update image"),
+            UpdateError::Invalid => write!(f, "update image is not valid"),
+            UpdateError::RolledBack {
+                security_counter,
+                min,
+            } => write!(
+                f,
+                "security_counter is too low (rollback): image={} min={}",
+                security_counter, min
+            ),
+            UpdateError::Older { version, primary } => write!(
+                f,
+                "update image is older than the base image: update={} base={}",
+                version, primary
+            ),
+        }
+    }
+}
+
+impl core::error::Error for UpdateError {}
+
+/// Whether the secondary image is installed (when nothing comes first).
+pub fn check_update(inputs: &BootInputs) -> Result<(), UpdateError> {
+    let (version, security_counter) = match inputs.secondary {
+        SlotState::Empty | SlotState::Installed => return Err(UpdateError::NoUpdate),
+        SlotState::Invalid => return Err(UpdateError::Invalid),
+        SlotState::Valid {
+            version,
+            security_counter,
+        } => (version, security_counter),
+    };
+    if security_counter < inputs.min_counter {
+        return Err(UpdateError::RolledBack {
+            security_counter,
+            min: inputs.min_counter,
+        });
+    }
+    // any valid update replaces an invalid primary image, and a SUIT image
+    // only has a sequence number (the security counter)
+    if let (
+        Some(version),
+        SlotState::Valid {
+            version: Some(primary),
+            ..
+        },
+    ) = (version, inputs.primary)
+    {
+        if version.cmp_precedence(&primary) == Ordering::Less {
+            return Err(UpdateError::Older { version, primary });
+        }
+    }
+    Ok(())
+}
+
+pub fn decide(inputs: &BootInputs) -> BootAction {
+    let failed = match inputs.swap {
+        SwapPhase::Interrupted if inputs.attempts < MAX_ATTEMPTS => return BootAction::Resume,
+        SwapPhase::NeedsRevert => return BootAction::Revert,
+        SwapPhase::Interrupted | SwapPhase::Failed => true,
+        SwapPhase::Idle => false,
+    };
+    if let (Some(trigger), true) = (inputs.trigger, inputs.recovery) {
+        return BootAction::Recovery(Reason::Requested(trigger));
+    }
+    if check_update(inputs).is_ok() {
+        return BootAction::Install;
+    }
+    let reason = match inputs.primary {
+        // whatever the primary slot holds, it is not the image that was installed
+        _ if failed => Reason::InstallFailed,
+        SlotState::Valid {
+            security_counter, ..
+        } if security_counter >= inputs.min_counter => return BootAction::Boot,
+        SlotState::Valid { .. } => Reason::RolledBack,
+        SlotState::Empty | SlotState::Installed => Reason::NoImage,
+        SlotState::Invalid => Reason::Invalid,
+    };
+    if inputs.recovery {
+        BootAction::Recovery(reason)
+    } else {
+        BootAction::Halt(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swap::{SwapStatus, SwapType};
+    use SwapPhase::{Failed, Idle, Interrupted, NeedsRevert};
+    use Trigger::{Button, DoubleReset, Scratch};
+
+    const MIN_COUNTER: u32 = 1;
+
+    const fn version(major: u8) -> ImageVersion {
+        ImageVersion {
+            major,
+            minor: 0,
+            patch: 0,
+            build: 0,
+        }
+    }
+
+    const fn valid(version: Option<ImageVersion>, security_counter: u32) -> SlotState {
+        SlotState::Valid {
+            version,
+            security_counter,
+        }
+    }
+
+    /// The slot states, in the order of the rows (primary) and columns
+    /// (secondary) of a `Table`.
+    const SLOTS: [SlotState; 7] = [
+        SlotState::Empty,
+        SlotState::Invalid,
+        // rolled back
+        valid(Some(version(1)), MIN_COUNTER - 1),
+        valid(Some(version(1)), MIN_COUNTER),
+        valid(Some(version(2)), MIN_COUNTER + 1),
+        // SUIT
+        valid(None, MIN_COUNTER),
+        SlotState::Installed,
+    ];
+
+    type Table = [[BootAction; 7]; 7];
+
+    const I: BootAction = BootAction::Install;
+    const B: BootAction = BootAction::Boot;
+    const RS: BootAction = BootAction::Resume;
+    const RV: BootAction = BootAction::Revert;
+    /// Recovery mode requested by the `B`utton, the `S`cratch register or a
+    /// `D`ouble reset.
+    const TB: BootAction = BootAction::Recovery(Reason::Requested(Button));
+    const TS: BootAction = BootAction::Recovery(Reason::Requested(Scratch));
+    const TD: BootAction = BootAction::Recovery(Reason::Requested(DoubleReset));
+    /// `R`ecovery mode or `H`alt for `N`o image, an `I`nvalid image, a
+    /// `R`olled back image or a `F`ailed install.
+    const RN: BootAction = BootAction::Recovery(Reason::NoImage);
+    const RI: BootAction = BootAction::Recovery(Reason::Invalid);
+    const RR: BootAction = BootAction::Recovery(Reason::RolledBack);
+    const RF: BootAction = BootAction::Recovery(Reason::InstallFailed);
+    const HN: BootAction = BootAction::Halt(Reason::NoImage);
+    const HI: BootAction = BootAction::Halt(Reason::Invalid);
+    const HR: BootAction = BootAction::Halt(Reason::RolledBack);
+    const HF: BootAction = BootAction::Halt(Reason::InstallFailed);
+
+    const RESUME: Table = [[RS; 7]; 7];
+    const REVERT: Table = [[RV; 7]; 7];
+    const BUTTON: Table = [[TB; 7]; 7];
+    const SCRATCH: Table = [[TS; 7]; 7];
+    const DOUBLE_RESET: Table = [[TD; 7]; 7];
+
+    #[rustfmt::skip]
+    const IDLE_RECOVERY: Table = [
+        // Empty, Invalid, Low, v1, v2, SUIT, Installed
+        /* Empty */     [RN, RN, RN, I, I, I, RN],
+        /* Invalid */   [RI, RI, RI, I, I, I, RI],
+        /* Low */       [RR, RR, RR, I, I, I, RR],
+        /* v1 */        [B,  B,  B,  I, I, I, B ],
+        /* v2 */        [B,  B,  B,  B, I, I, B ],
+        /* SUIT */      [B,  B,  B,  I, I, I, B ],
+        /* Installed */ [RN, RN, RN, I, I, I, RN],
+    ];
+
+    #[rustfmt::skip]
+    const IDLE_HALT: Table = [
+        // Empty, Invalid, Low, v1, v2, SUIT, Installed
+        /* Empty */     [HN, HN, HN, I, I, I, HN],
+        /* Invalid */   [HI, HI, HI, I, I, I, HI],
+        /* Low */       [HR, HR, HR, I, I, I, HR],
+        /* v1 */        [B,  B,  B,  I, I, I, B ],
+        /* v2 */        [B,  B,  B,  B, I, I, B ],
+        /* SUIT */      [B,  B,  B,  I, I, I, B ],
+        /* Installed */ [HN, HN, HN, I, I, I, HN],
+    ];
+
+    #[rustfmt::skip]
+    const FAILED_RECOVERY: Table = [
+        // Empty, Invalid, Low, v1, v2, SUIT, Installed
+        /* Empty */     [RF, RF, RF, I,  I, I, RF],
+        /* Invalid */   [RF, RF, RF, I,  I, I, RF],
+        /* Low */       [RF, RF, RF, I,  I, I, RF],
+        /* v1 */        [RF, RF, RF, I,  I, I, RF],
+        /* v2 */        [RF, RF, RF, RF, I, I, RF],
+        /* SUIT */      [RF, RF, RF, I,  I, I, RF],
+        /* Installed */ [RF, RF, RF, I,  I, I, RF],
+    ];
+
+    #[rustfmt::skip]
+    const FAILED_HALT: Table = [
+        // Empty, Invalid, Low, v1, v2, SUIT, Installed
+        /* Empty */     [HF, HF, HF, I,  I, I, HF],
+        /* Invalid */   [HF, HF, HF, I,  I, I, HF],
+        /* Low */       [HF, HF, HF, I,  I, I, HF],
+        /* v1 */        [HF, HF, HF, I,  I, I, HF],
+        /* v2 */        [HF, HF, HF, HF, I, I, HF],
+        /* SUIT */      [HF, HF, HF, I,  I, I, HF],
+        /* Installed */ [HF, HF, HF, I,  I, I, HF],
+    ];
+
+    const LAST: u32 = MAX_ATTEMPTS - 1;
+
+    /// Swap phase, attempts, trigger and recovery, and the actions for the
+    /// primary and secondary slot states.
+    #[rustfmt::skip]
+    const CASES: [(SwapPhase, u32, Option<Trigger>, bool, &Table); 40] = [
+        (Idle,        0,            None,              true,  &IDLE_RECOVERY),
+        (Idle,        0,            None,              false, &IDLE_HALT),
+        (Idle,        0,            Some(Button),      true,  &BUTTON),
+        (Idle,        0,            Some(Button),      false, &IDLE_HALT),
+        (Idle,        0,            Some(Scratch),     true,  &SCRATCH),
+        (Idle,        0,            Some(Scratch),     false, &IDLE_HALT),
+        (Idle,        0,            Some(DoubleReset), true,  &DOUBLE_RESET),
+        (Idle,        0,            Some(DoubleReset), false, &IDLE_HALT),
+        (Interrupted, LAST,         None,              true,  &RESUME),
+        (Interrupted, LAST,         None,              false, &RESUME),
+        (Interrupted, LAST,         Some(Button),      true,  &RESUME),
+        (Interrupted, LAST,         Some(Button),      false, &RESUME),
+        (Interrupted, LAST,         Some(Scratch),     true,  &RESUME),
+        (Interrupted, LAST,         Some(Scratch),     false, &RESUME),
+        (Interrupted, LAST,         Some(DoubleReset), true,  &RESUME),
+        (Interrupted, LAST,         Some(DoubleReset), false, &RESUME),
+        (Interrupted, MAX_ATTEMPTS, None,              true,  &FAILED_RECOVERY),
+        (Interrupted, MAX_ATTEMPTS, None,              false, &FAILED_HALT),
+        (Interrupted, MAX_ATTEMPTS, Some(Button),      true,  &BUTTON),
+        (Interrupted, MAX_ATTEMPTS, Some(Button),      false, &FAILED_HALT),
+        (Interrupted, MAX_ATTEMPTS, Some(Scratch),     true,  &SCRATCH),
+        (Interrupted, MAX_ATTEMPTS, Some(Scratch),     false, &FAILED_HALT),
+        (Interrupted, MAX_ATTEMPTS, Some(DoubleReset), true,  &DOUBLE_RESET),
+        (Interrupted, MAX_ATTEMPTS, Some(DoubleReset), false, &FAILED_HALT),
+        (NeedsRevert, 0,            None,              true,  &REVERT),
+        (NeedsRevert, 0,            None,              false, &REVERT),
+        (NeedsRevert, 0,            Some(Button),      true,  &REVERT),
+        (NeedsRevert, 0,            Some(Button),      false, &REVERT),
+        (NeedsRevert, 0,            Some(Scratch),     true,  &REVERT),
+        (NeedsRevert, 0,            Some(Scratch),     false, &REVERT),
+        (NeedsRevert, 0,            Some(DoubleReset), true,  &REVERT),
+        (NeedsRevert, 0,            Some(DoubleReset), false, &REVERT),
+        (Failed,      0,            None,              true,  &FAILED_RECOVERY),
+        (Failed,      0,            None,              false, &FAILED_HALT),
+        (Failed,      0,            Some(Button),      true,  &BUTTON),
+        (Failed,      0,            Some(Button),      false, &FAILED_HALT),
+        (Failed,      0,            Some(Scratch),     true,  &SCRATCH),
+        (Failed,      0,            Some(Scratch),     false, &FAILED_HALT),
+        (Failed,      0,            Some(DoubleReset), true,  &DOUBLE_RESET),
+        (Failed,      0,            Some(DoubleReset), false, &FAILED_HALT),
+    ];
+
+    #[test]
+    fn test_decide() {
+        // every combination has exactly one case
+        let phases = [
+            (Idle, 0),
+            (Interrupted, LAST),
+            (Interrupted, MAX_ATTEMPTS),
+            (NeedsRevert, 0),
+            (Failed, 0),
+        ];
+        let triggers = [None, Some(Button), Some(Scratch), Some(DoubleReset)];
+        for (swap, attempts) in phases {
+            for trigger in triggers {
+                for recovery in [true, false] {
+                    let key = (swap, attempts, trigger, recovery);
+                    let cases = CASES
+                        .iter()
+                        .filter(|case| (case.0, case.1, case.2, case.3) == key);
+                    assert_eq!(cases.count(), 1, "{:?}", key);
+                }
+            }
+        }
+
+        for (swap, attempts, trigger, recovery, table) in CASES {
+            for (row, primary) in SLOTS.into_iter().enumerate() {
+                for (column, secondary) in SLOTS.into_iter().enumerate() {
+                    let inputs = BootInputs {
+                        swap,
+                        attempts,
+                        trigger,
+                        primary,
+                        secondary,
+                        min_counter: MIN_COUNTER,
+                        recovery,
+                    };
+                    assert_eq!(decide(&inputs), table[row][column], "{:?}", inputs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_update() {
+        let inputs = |primary, secondary| BootInputs {
+            swap: SwapPhase::Idle,
+            attempts: 0,
+            trigger: None,
+            primary,
+            secondary,
+            min_counter: MIN_COUNTER,
+            recovery: true,
+        };
+        let v1 = valid(Some(version(1)), MIN_COUNTER);
+        let v2 = valid(Some(version(2)), MIN_COUNTER);
+        assert_eq!(
+            check_update(&inputs(v1, SlotState::Installed)),
+            Err(UpdateError::NoUpdate)
+        );
+        assert_eq!(
+            check_update(&inputs(v1, SlotState::Invalid)),
+            Err(UpdateError::Invalid)
+        );
+        assert_eq!(
+            check_update(&inputs(v1, valid(Some(version(2)), 0))),
+            Err(UpdateError::RolledBack {
+                security_counter: 0,
+                min: MIN_COUNTER
+            })
+        );
+        assert_eq!(
+            check_update(&inputs(v2, v1)),
+            Err(UpdateError::Older {
+                version: version(1),
+                primary: version(2)
+            })
+        );
+        // the same version is installed again, and the build is not part of
+        // the precedence
+        let build = valid(
+            Some(ImageVersion {
+                build: 0x1234,
+                ..version(1)
+            }),
+            MIN_COUNTER,
+        );
+        assert_eq!(check_update(&inputs(build, v1)), Ok(()));
+        assert_eq!(check_update(&inputs(SlotState::Invalid, v1)), Ok(()));
+    }
+
+    #[test]
+    fn test_swap_phase() {
+        let status = SwapStatus {
+            primary_id: 1,
+            secondary_id: 2,
+            sectors: 1,
+            swap_type: SwapType::Test,
+            wrapped_key: None,
+        };
+        let complete = |trial, confirmed| SwapState::Complete {
+            status,
+            trial,
+            confirmed,
+        };
+        assert_eq!(SwapPhase::from(&SwapState::Idle), SwapPhase::Idle);
+        assert_eq!(
+            SwapPhase::from(&SwapState::InProgress { status, step: 0 }),
+            SwapPhase::Interrupted
+        );
+        assert_eq!(SwapPhase::from(&complete(false, false)), SwapPhase::Idle);
+        assert_eq!(
+            SwapPhase::from(&complete(true, false)),
+            SwapPhase::NeedsRevert
+        );
+        assert_eq!(SwapPhase::from(&complete(true, true)), SwapPhase::Idle);
+        assert_eq!(
+            SwapPhase::from(&SwapState::Failed { status }),
+            SwapPhase::Failed
+        );
+    }
+}
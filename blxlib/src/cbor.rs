@@ -0,0 +1,428 @@
+//! Minimal CBOR (RFC 8949) for SUIT manifests (see `suit`): integers, byte and
+//! text strings, arrays, maps, tags and simple values with definite lengths.
+//! Floats and indefinite lengths are not supported.
+
+use core::fmt;
+
+pub const MAJOR_UINT: u8 = 0;
+pub const MAJOR_NINT: u8 = 1;
+pub const MAJOR_BYTES: u8 = 2;
+pub const MAJOR_TEXT: u8 = 3;
+pub const MAJOR_ARRAY: u8 = 4;
+pub const MAJOR_MAP: u8 = 5;
+pub const MAJOR_TAG: u8 = 6;
+pub const MAJOR_SIMPLE: u8 = 7;
+
+pub const SIMPLE_FALSE: u8 = 20;
+pub const SIMPLE_TRUE: u8 = 21;
+pub const SIMPLE_NULL: u8 = 22;
+
+/// Nesting of arrays, maps and tags that `Decoder::skip` follows.
+const MAX_DEPTH: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CborError {
+    /// The item at `offset` extends beyond the input.
+    Truncated {
+        offset: usize,
+    },
+    /// Found an item of major type `actual` at `offset`.
+    UnexpectedType {
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// Indefinite length, float or reserved encoding at `offset`.
+    Unsupported {
+        offset: usize,
+    },
+    /// The integer at `offset` does not fit.
+    Overflow {
+        offset: usize,
+    },
+    InvalidText {
+        offset: usize,
+    },
+    TooDeep,
+    /// The encoder buffer is full.
+    Full,
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborError::Truncated { offset } => write!(f, "cbor item at {} is truncated", offset),
+            CborError::UnexpectedType {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "cbor item at {} has major type {}, expected {}",
+                offset, actual, expected
+            ),
+            CborError::Unsupported { offset } => {
+                write!(f, "cbor item at {} is not supported", offset)
+            }
+            CborError::Overflow { offset } => write!(f, "cbor integer at {} is too large", offset),
+            CborError::InvalidText { offset } => {
+                write!(f, "cbor text at {} is not valid utf-8", offset)
+            }
+            CborError::TooDeep => write!(f, "cbor items are nested too deeply"),
+            CborError::Full => write!(f, "cbor buffer is full"),
+        }
+    }
+}
+
+impl core::error::Error for CborError {}
+
+#[derive(Clone, Debug)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub fn peek_major(&self) -> Result<u8, CborError> {
+        let initial = self
+            .buf
+            .get(self.pos)
+            .ok_or(CborError::Truncated { offset: self.pos })?;
+        Ok(initial >> 5)
+    }
+
+    /// Decodes the initial byte and argument: `(major type, argument)`.
+    fn head(&mut self) -> Result<(u8, u64), CborError> {
+        let offset = self.pos;
+        let truncated = CborError::Truncated { offset };
+        let initial = *self.buf.get(offset).ok_or(truncated)?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let len = match info {
+            0..=23 => 0,
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(CborError::Unsupported { offset }),
+        };
+        // floats share the encoding of simple values with an argument
+        if major == MAJOR_SIMPLE && info > 24 {
+            return Err(CborError::Unsupported { offset });
+        }
+        let arg = self
+            .buf
+            .get(offset + 1..offset + 1 + len)
+            .ok_or(truncated)?;
+        let arg = if len == 0 {
+            info as u64
+        } else {
+            arg.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+        };
+        self.pos = offset + 1 + len;
+        Ok((major, arg))
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<u64, CborError> {
+        let offset = self.pos;
+        let (actual, arg) = self.head()?;
+        if actual != expected {
+            self.pos = offset;
+            return Err(CborError::UnexpectedType {
+                offset,
+                expected,
+                actual,
+            });
+        }
+        Ok(arg)
+    }
+
+    pub fn uint(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_UINT)
+    }
+
+    /// An unsigned or negative integer.
+    pub fn int(&mut self) -> Result<i64, CborError> {
+        let offset = self.pos;
+        let overflow = CborError::Overflow { offset };
+        if self.peek_major()? == MAJOR_NINT {
+            let arg = self.expect(MAJOR_NINT)?;
+            return Ok(-1 - i64::try_from(arg).map_err(|_| overflow)?);
+        }
+        i64::try_from(self.uint()?).map_err(|_| overflow)
+    }
+
+    fn string(&mut self, major: u8) -> Result<&'a [u8], CborError> {
+        let offset = self.pos;
+        let len = self.expect(major)?;
+        let start = self.pos;
+        let value = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.buf.get(start..start.checked_add(len)?))
+            .ok_or(CborError::Truncated { offset })?;
+        self.pos = start + value.len();
+        Ok(value)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], CborError> {
+        self.string(MAJOR_BYTES)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, CborError> {
+        let offset = self.pos;
+        let text = self.string(MAJOR_TEXT)?;
+        core::str::from_utf8(text).map_err(|_| CborError::InvalidText { offset })
+    }
+
+    /// The number of items of an array.
+    pub fn array(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_ARRAY)
+    }
+
+    /// The number of key/value pairs of a map.
+    pub fn map(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_MAP)
+    }
+
+    pub fn tag(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_TAG)
+    }
+
+    /// Skips `tag` if the next item is tagged with it.
+    pub fn optional_tag(&mut self, tag: u64) -> Result<(), CborError> {
+        let offset = self.pos;
+        if self.peek_major()? == MAJOR_TAG && self.tag()? != tag {
+            self.pos = offset;
+        }
+        Ok(())
+    }
+
+    pub fn simple(&mut self) -> Result<u8, CborError> {
+        let offset = self.pos;
+        u8::try_from(self.expect(MAJOR_SIMPLE)?).map_err(|_| CborError::Overflow { offset })
+    }
+
+    /// Skips the next item, including the items it contains.
+    pub fn skip(&mut self) -> Result<(), CborError> {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: u32) -> Result<(), CborError> {
+        if depth > MAX_DEPTH {
+            return Err(CborError::TooDeep);
+        }
+        match self.peek_major()? {
+            MAJOR_BYTES | MAJOR_TEXT => {
+                let major = self.peek_major()?;
+                self.string(major)?;
+            }
+            MAJOR_ARRAY | MAJOR_MAP => {
+                let major = self.peek_major()?;
+                let len = self.head()?.1;
+                let items = if major == MAJOR_MAP {
+                    len.saturating_mul(2)
+                } else {
+                    len
+                };
+                for _ in 0..items {
+                    self.skip_nested(depth + 1)?;
+                }
+            }
+            MAJOR_TAG => {
+                self.head()?;
+                self.skip_nested(depth + 1)?;
+            }
+            _ => {
+                self.head()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes CBOR into a caller provided buffer.
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Encoder { buf, len: 0 }
+    }
+
+    /// The bytes encoded so far.
+    pub fn encoded(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn raw(&mut self, data: &[u8]) -> Result<(), CborError> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(CborError::Full)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Writes the initial byte and argument in the shortest form.
+    fn head(&mut self, major: u8, arg: u64) -> Result<(), CborError> {
+        let major = major << 5;
+        match arg {
+            0..=23 => self.raw(&[major | arg as u8]),
+            24..=0xff => self.raw(&[major | 24, arg as u8]),
+            0x100..=0xffff => {
+                self.raw(&[major | 25])?;
+                self.raw(&(arg as u16).to_be_bytes())
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.raw(&[major | 26])?;
+                self.raw(&(arg as u32).to_be_bytes())
+            }
+            _ => {
+                self.raw(&[major | 27])?;
+                self.raw(&arg.to_be_bytes())
+            }
+        }
+    }
+
+    pub fn uint(&mut self, value: u64) -> Result<(), CborError> {
+        self.head(MAJOR_UINT, value)
+    }
+
+    pub fn int(&mut self, value: i64) -> Result<(), CborError> {
+        if value < 0 {
+            self.head(MAJOR_NINT, (-1 - value) as u64)
+        } else {
+            self.head(MAJOR_UINT, value as u64)
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<(), CborError> {
+        self.head(MAJOR_BYTES, value.len() as u64)?;
+        self.raw(value)
+    }
+
+    pub fn text(&mut self, value: &str) -> Result<(), CborError> {
+        self.head(MAJOR_TEXT, value.len() as u64)?;
+        self.raw(value.as_bytes())
+    }
+
+    /// Starts an array of `len` items, which are encoded next.
+    pub fn array(&mut self, len: u64) -> Result<(), CborError> {
+        self.head(MAJOR_ARRAY, len)
+    }
+
+    /// Starts a map of `len` key/value pairs, which are encoded next.
+    pub fn map(&mut self, len: u64) -> Result<(), CborError> {
+        self.head(MAJOR_MAP, len)
+    }
+
+    pub fn tag(&mut self, tag: u64) -> Result<(), CborError> {
+        self.head(MAJOR_TAG, tag)
+    }
+
+    pub fn simple(&mut self, value: u8) -> Result<(), CborError> {
+        self.head(MAJOR_SIMPLE, value as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = [0u8; 64];
+        let mut enc = Encoder::new(&mut buf);
+        enc.tag(107).unwrap();
+        enc.map(2).unwrap();
+        enc.uint(1).unwrap();
+        enc.array(3).unwrap();
+        enc.int(-16).unwrap();
+        enc.uint(0x1_0000).unwrap();
+        enc.int(i64::MIN).unwrap();
+        enc.text("#app").unwrap();
+        enc.bytes(&[0xaa; 24]).unwrap();
+        let len = enc.encoded().len();
+        assert_eq!(&enc.encoded()[..5], &[0xd8, 0x6b, 0xa2, 0x01, 0x83]);
+        assert_eq!(enc.bytes(&[0; 32]), Err(CborError::Full));
+
+        let mut dec = Decoder::new(&buf[..len]);
+        assert_eq!(dec.tag(), Ok(107));
+        assert_eq!(dec.map(), Ok(2));
+        assert_eq!(dec.uint(), Ok(1));
+        assert_eq!(dec.array(), Ok(3));
+        assert_eq!(dec.int(), Ok(-16));
+        assert_eq!(dec.int(), Ok(0x1_0000));
+        assert_eq!(dec.int(), Ok(i64::MIN));
+        assert_eq!(dec.text(), Ok("#app"));
+        assert_eq!(dec.bytes(), Ok(&[0xaa; 24][..]));
+        assert!(dec.is_empty());
+
+        let mut dec = Decoder::new(&buf[..len]);
+        dec.skip().unwrap();
+        assert!(dec.is_empty());
+        let mut dec = Decoder::new(&buf[..len]);
+        dec.optional_tag(107).unwrap();
+        dec.optional_tag(107).unwrap();
+        assert_eq!(dec.map(), Ok(2));
+    }
+
+    #[test]
+    fn test_errors() {
+        // uint -1 does not fit an i64
+        let mut dec = Decoder::new(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(dec.int(), Err(CborError::Overflow { offset: 0 }));
+
+        let mut dec = Decoder::new(&[0x44, 1, 2, 3]);
+        assert_eq!(dec.bytes(), Err(CborError::Truncated { offset: 0 }));
+        let mut dec = Decoder::new(&[0x19, 1]);
+        assert_eq!(dec.uint(), Err(CborError::Truncated { offset: 0 }));
+
+        let mut dec = Decoder::new(&[0x01]);
+        assert_eq!(
+            dec.bytes(),
+            Err(CborError::UnexpectedType {
+                offset: 0,
+                expected: MAJOR_BYTES,
+                actual: MAJOR_UINT
+            })
+        );
+        // the item is not consumed
+        assert_eq!(dec.uint(), Ok(1));
+
+        // indefinite length and floats
+        assert_eq!(
+            Decoder::new(&[0x9f, 0xff]).skip(),
+            Err(CborError::Unsupported { offset: 0 })
+        );
+        assert_eq!(
+            Decoder::new(&[0xf9, 0x3c, 0x00]).skip(),
+            Err(CborError::Unsupported { offset: 0 })
+        );
+        assert_eq!(
+            Decoder::new(&[0x62, 0xff, 0xfe]).text(),
+            Err(CborError::InvalidText { offset: 0 })
+        );
+
+        assert_eq!(Decoder::new(&[0x81; 32]).skip(), Err(CborError::TooDeep));
+        // a huge length is truncated, not allocated
+        assert_eq!(
+            Decoder::new(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]).skip(),
+            Err(CborError::Truncated { offset: 10 })
+        );
+    }
+}
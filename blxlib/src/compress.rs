@@ -0,0 +1,412 @@
+//! Compressed native images.
+//!
+//! The payload is an LZ4 block (the raw block format, without a frame) whose
+//! match offsets are limited to `WINDOW_SIZE`, so that `Decoder` only keeps
+//! that much of the output in RAM and reads the input as a stream. Any LZ4
+//! decoder can decompress it.
+//!
+//! A `TLV_LZ4` entry holds the compressed length. `image_length`,
+//! `payload_crc` and `payload_digest` are of the decompressed payload, which is
+//! what the primary slot holds once the image is installed (see
+//! `swap::SwapType::Overwrite`).
+
+use crate::crc32::Crc32;
+use crate::image_header::{ImageHeader, ValidationError};
+use crate::sha256::{Sha256, DIGEST_LENGTH};
+use crate::tlv::{self, TLV_LZ4};
+use core::fmt;
+
+/// Largest match offset, and the bytes of output the decoder keeps.
+pub const WINDOW_SIZE: usize = 4096;
+const MIN_MATCH: usize = 4;
+/// The last match starts at least this far from the end of the block and the
+/// last bytes are literals, as the LZ4 block format requires.
+const MF_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+const HASH_BITS: u32 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lz4Error {
+    /// The input ends within a sequence.
+    Truncated,
+    /// A match offset is 0, beyond the window or before the start of the output.
+    BadOffset(u16),
+}
+
+impl fmt::Display for Lz4Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lz4Error::Truncated => write!(f, "compressed payload is truncated"),
+            Lz4Error::BadOffset(offset) => {
+                write!(f, "compressed payload has a bad match offset: {}", offset)
+            }
+        }
+    }
+}
+
+impl core::error::Error for Lz4Error {}
+
+/// The compressed length of a compressed image, `None` if the image is not
+/// compressed.
+pub fn compressed_length(ih: &ImageHeader) -> Option<u32> {
+    let value = tlv::find(&ih.tlv, TLV_LZ4)?.value;
+    Some(u32::from_le_bytes(value.try_into().ok()?))
+}
+
+/// Streaming LZ4 block decoder. The input is passed to each `read`, so that
+/// it can come from the flash that the output goes to.
+pub struct Decoder {
+    window: [u8; WINDOW_SIZE],
+    /// Bytes of output so far.
+    produced: usize,
+    /// Literals left in the current sequence.
+    literals: usize,
+    /// Low nibble of the token, until the match of the sequence is read.
+    match_nibble: Option<u8>,
+    match_len: usize,
+    match_offset: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            window: [0u8; WINDOW_SIZE],
+            produced: 0,
+            literals: 0,
+            match_nibble: None,
+            match_len: 0,
+            match_offset: 0,
+        }
+    }
+
+    /// Fills `buf` with the next bytes of output. Returns fewer bytes only at
+    /// the end of the block.
+    pub fn read(
+        &mut self,
+        input: &mut impl Iterator<Item = u8>,
+        buf: &mut [u8],
+    ) -> Result<usize, Lz4Error> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            match self.next_byte(input)? {
+                Some(value) => *b = value,
+                None => return Ok(i),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn next_byte(&mut self, input: &mut impl Iterator<Item = u8>) -> Result<Option<u8>, Lz4Error> {
+        loop {
+            if self.literals > 0 {
+                self.literals -= 1;
+                let b = input.next().ok_or(Lz4Error::Truncated)?;
+                return Ok(Some(self.emit(b)));
+            }
+            if self.match_len > 0 {
+                self.match_len -= 1;
+                let b = self.window[(self.produced - self.match_offset) % WINDOW_SIZE];
+                return Ok(Some(self.emit(b)));
+            }
+            if let Some(nibble) = self.match_nibble.take() {
+                // the last sequence has no match
+                let Some(low) = input.next() else {
+                    return Ok(None);
+                };
+                let high = input.next().ok_or(Lz4Error::Truncated)?;
+                let offset = u16::from_le_bytes([low, high]);
+                let distance = offset as usize;
+                if distance == 0 || distance > WINDOW_SIZE || distance > self.produced {
+                    return Err(Lz4Error::BadOffset(offset));
+                }
+                self.match_offset = distance;
+                self.match_len = Self::length(input, nibble)? + MIN_MATCH;
+                continue;
+            }
+            let Some(token) = input.next() else {
+                return Ok(None);
+            };
+            self.literals = Self::length(input, token >> 4)?;
+            self.match_nibble = Some(token & 0x0f);
+        }
+    }
+
+    fn emit(&mut self, b: u8) -> u8 {
+        self.window[self.produced % WINDOW_SIZE] = b;
+        self.produced += 1;
+        b
+    }
+
+    /// A length from a token nibble and the bytes that extend it.
+    fn length(input: &mut impl Iterator<Item = u8>, nibble: u8) -> Result<usize, Lz4Error> {
+        let mut len = nibble as usize;
+        if nibble == 0x0f {
+            loop {
+                let b = input.next().ok_or(Lz4Error::Truncated)?;
+                len += b as usize;
+                if b != 0xff {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// `payload_crc` and `payload_digest` of the first `length` bytes that
+/// `input` decompresses to. A corrupt block gives whatever it decoded before
+/// the error, which does not match.
+pub fn digests(input: &mut impl Iterator<Item = u8>, length: u32) -> (u32, [u8; DIGEST_LENGTH]) {
+    let mut decoder = Decoder::new();
+    let mut crc = Crc32::new();
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 256];
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        let buf = &mut buf[..remaining.min(256)];
+        let n = match decoder.read(input, buf) {
+            Ok(n) if n > 0 => n,
+            _ => break,
+        };
+        crc.update(&buf[..n]);
+        hasher.update(&buf[..n]);
+        remaining -= n;
+    }
+    (crc.finalize(), hasher.finalize())
+}
+
+/// `ImageHeader::validate` for a compressed payload, `input` being the
+/// `compressed_length` bytes of it.
+pub fn validate(
+    ih: &ImageHeader,
+    mut input: impl Iterator<Item = u8>,
+) -> Result<(), ValidationError> {
+    // how long the decompressed payload is shows in its digests
+    ih.validate_with(ih.image_length as usize, |len| {
+        digests(&mut input, len as u32)
+    })
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, b: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = b;
+        self.pos += 1;
+        Some(())
+    }
+
+    fn length(&mut self, len: usize) -> Option<()> {
+        if len < 0x0f {
+            return Some(());
+        }
+        let mut rest = len - 0x0f;
+        while rest >= 0xff {
+            self.push(0xff)?;
+            rest -= 0xff;
+        }
+        self.push(rest as u8)
+    }
+
+    /// A sequence of `literals` followed by a match of `(offset, len)`.
+    fn sequence(&mut self, literals: &[u8], matched: Option<(usize, usize)>) -> Option<()> {
+        let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+        self.push(((literals.len().min(0x0f) as u8) << 4) | match_len.min(0x0f) as u8)?;
+        self.length(literals.len())?;
+        for &b in literals {
+            self.push(b)?;
+        }
+        if let Some((offset, _)) = matched {
+            for b in (offset as u16).to_le_bytes() {
+                self.push(b)?;
+            }
+            self.length(match_len)?;
+        }
+        Some(())
+    }
+}
+
+/// Compresses `input` into an LZ4 block in `out` with matches within
+/// `WINDOW_SIZE`. Returns the compressed length, `None` if `out` is too small
+/// (`input.len() + input.len() / 255 + 16` always suffices).
+pub fn compress(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut out = Output { buf: out, pos: 0 };
+    // position + 1 of the last 4 bytes with each hash, 0 if none
+    let mut table = [0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MF_LIMIT <= input.len() {
+        let seq = u32::from_le_bytes(input[i..i + MIN_MATCH].try_into().unwrap());
+        let hash = (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash] as usize;
+        table[hash] = (i + 1) as u32;
+        if candidate == 0
+            || i + 1 - candidate > WINDOW_SIZE
+            || input[candidate - 1..candidate - 1 + MIN_MATCH] != input[i..i + MIN_MATCH]
+        {
+            i += 1;
+            continue;
+        }
+        let start = candidate - 1;
+        let mut len = MIN_MATCH;
+        while i + len < input.len() - LAST_LITERALS && input[start + len] == input[i + len] {
+            len += 1;
+        }
+        out.sequence(&input[anchor..i], Some((i - start, len)))?;
+        i += len;
+        anchor = i;
+    }
+    out.sequence(&input[anchor..], None)?;
+    Some(out.pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::sha256::sha256;
+    use crate::tlv::TlvWriter;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn compress_vec(input: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; input.len() + input.len() / 255 + 16];
+        let len = compress(input, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    fn decompress_vec(block: &[u8], chunk: usize) -> Result<Vec<u8>, Lz4Error> {
+        let mut decoder = Decoder::new();
+        let mut input = block.iter().copied();
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; chunk];
+        loop {
+            let n = decoder.read(&mut input, &mut buf)?;
+            out.extend_from_slice(&buf[..n]);
+            if n < chunk {
+                return Ok(out);
+            }
+        }
+    }
+
+    /// Pseudo-random bytes with repeats near and far.
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        let mut data: Vec<u8> = Vec::with_capacity(len);
+        while data.len() < len {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match x >> 29 {
+                0 | 1 if data.len() > 300 => {
+                    let back = (x >> 8) as usize % (2 * WINDOW_SIZE).min(data.len()) + 1;
+                    let start = data.len() - back;
+                    for j in 0..(x >> 4) as usize % 300 {
+                        data.push(data[start + j % back]);
+                    }
+                }
+                2 => data.extend(core::iter::repeat_n(
+                    (x >> 16) as u8,
+                    (x >> 4) as usize % 600,
+                )),
+                _ => data.push((x >> 16) as u8),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn test_decode_block() {
+        // "abcd" and a match of 11 at offset 4, then 5 literals
+        let block = [
+            0x47, b'a', b'b', b'c', b'd', 0x04, 0x00, 0x50, b'd', b'a', b'b', b'c', b'd',
+        ];
+        let out = decompress_vec(&block, 7).unwrap();
+        assert_eq!(out, b"abcdabcdabcdabcdabcd");
+        assert_eq!(compress_vec(&out), block);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for len in [0, 1, 11, 12, 13, 100, 5000, 100_000] {
+            let data = test_data(len);
+            let block = compress_vec(&data);
+            for chunk in [1, 7, 256] {
+                assert_eq!(decompress_vec(&block, chunk).unwrap(), data, "len={}", len);
+            }
+        }
+        // long literal and match lengths
+        let mut data = test_data(1000);
+        data.extend(core::iter::repeat_n(0x5a, 70_000));
+        data.extend(test_data(600));
+        let block = compress_vec(&data);
+        assert!(block.len() < 2000);
+        assert_eq!(decompress_vec(&block, 256).unwrap(), data);
+
+        assert_eq!(compress(&data, &mut [0u8; 100]), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = test_data(5000);
+        let block = compress_vec(&data);
+        for len in [1, block.len() / 2, block.len() - 1] {
+            assert!(decompress_vec(&block[..len], 256).is_err(), "len={}", len);
+        }
+        // offset 0, then before the start of the output
+        assert_eq!(
+            decompress_vec(&[0x10, b'a', 0x00, 0x00, 0x00], 16),
+            Err(Lz4Error::BadOffset(0))
+        );
+        assert_eq!(
+            decompress_vec(&[0x10, b'a', 0x02, 0x00, 0x00], 16),
+            Err(Lz4Error::BadOffset(2))
+        );
+        // beyond the window
+        let mut block = vec![0u8; 2 * WINDOW_SIZE];
+        let mut out = Output {
+            buf: &mut block,
+            pos: 0,
+        };
+        out.sequence(&[0x11; WINDOW_SIZE + 1], Some((WINDOW_SIZE + 1, 4)))
+            .unwrap();
+        let len = out.pos;
+        assert_eq!(
+            decompress_vec(&block[..len], 256),
+            Err(Lz4Error::BadOffset(WINDOW_SIZE as u16 + 1))
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let plain = test_data(3000);
+        let block = compress_vec(&plain);
+        let mut ih = ImageHeader::new();
+        ih.image_length = plain.len() as u32;
+        ih.payload_crc = crc32(&plain);
+        ih.payload_digest = sha256(&plain);
+        assert_eq!(compressed_length(&ih), None);
+        TlvWriter::new(&mut ih.tlv)
+            .push(TLV_LZ4, &(block.len() as u32).to_le_bytes())
+            .unwrap();
+        ih.crc32 = ih.calc_crc32();
+        assert_eq!(compressed_length(&ih), Some(block.len() as u32));
+
+        assert_eq!(validate(&ih, block.iter().copied()), Ok(()));
+        let mut corrupt = block.clone();
+        corrupt[block.len() / 2] ^= 0x01;
+        assert!(validate(&ih, corrupt.iter().copied()).is_err());
+        assert!(matches!(
+            validate(&ih, block[..block.len() - 1].iter().copied()),
+            Err(ValidationError::PayloadCrc { .. })
+        ));
+    }
+}
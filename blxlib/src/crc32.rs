@@ -28,15 +28,98 @@ const fn get_table() -> [u32; 256] {
 
 const TABLE: [u32; 256] = get_table();
 
-pub fn crc32(buf: &[u8]) -> u32 {
-    let seed = 0u32;
-    let mut out = !seed;
+/// `TABLES[k][i]` is the CRC of byte `i` followed by `k` zero bytes.
+/// 8 KiB of tables, so it is only built with the `crc32-slice-by-8` feature.
+#[cfg(any(test, feature = "crc32-slice-by-8"))]
+const fn get_tables() -> [[u32; 256]; 8] {
+    let mut tables: [[u32; 256]; 8] = [[0u32; 256]; 8];
+    tables[0] = get_table();
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+#[cfg(any(test, feature = "crc32-slice-by-8"))]
+const TABLES: [[u32; 256]; 8] = get_tables();
+
+fn update_bytewise(mut state: u32, buf: &[u8]) -> u32 {
     let mut i = 0usize;
     while i < buf.len() {
-        out = (out >> 8) ^ TABLE[((out & 0xff) ^ (buf[i] as u32)) as usize];
+        state = (state >> 8) ^ TABLE[((state & 0xff) ^ (buf[i] as u32)) as usize];
         i += 1;
     }
-    !out
+    state
+}
+
+#[cfg(any(test, feature = "crc32-slice-by-8"))]
+fn update_slice_by_8(mut state: u32, buf: &[u8]) -> u32 {
+    let mut chunks = buf.chunks_exact(8);
+    for chunk in &mut chunks {
+        let one = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ state;
+        let two = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        state = TABLES[7][(one & 0xff) as usize]
+            ^ TABLES[6][((one >> 8) & 0xff) as usize]
+            ^ TABLES[5][((one >> 16) & 0xff) as usize]
+            ^ TABLES[4][(one >> 24) as usize]
+            ^ TABLES[3][(two & 0xff) as usize]
+            ^ TABLES[2][((two >> 8) & 0xff) as usize]
+            ^ TABLES[1][((two >> 16) & 0xff) as usize]
+            ^ TABLES[0][(two >> 24) as usize];
+    }
+    update_bytewise(state, chunks.remainder())
+}
+
+/// Streaming CRC-32 (IEEE 802.3), for data that arrives in chunks.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        let seed = 0u32;
+        Crc32 { state: !seed }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        #[cfg(feature = "crc32-slice-by-8")]
+        {
+            self.state = update_slice_by_8(self.state, buf);
+        }
+        #[cfg(not(feature = "crc32-slice-by-8"))]
+        {
+            self.state = update_bytewise(self.state, buf);
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.state
+    }
+}
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(buf);
+    crc.finalize()
+}
+
+/// The original byte-at-a-time implementation, kept as a reference.
+pub fn crc32_bytewise(buf: &[u8]) -> u32 {
+    !update_bytewise(!0u32, buf)
 }
 
 #[cfg(test)]
@@ -55,4 +138,49 @@ mod tests {
         let result = crc32(input);
         assert_eq!(result, 0x8B39E45A);
     }
+
+    #[test]
+    fn test_crc32_variants() {
+        let mut buf = [0u8; 1031];
+        let mut x = 0x12345678u32;
+        for b in buf.iter_mut() {
+            // xorshift
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        for len in [0, 1, 7, 8, 9, 15, 16, 17, 255, 1024, 1031] {
+            let expected = crc32_bytewise(&buf[..len]);
+            assert_eq!(crc32(&buf[..len]), expected, "len={}", len);
+            assert_eq!(
+                !update_slice_by_8(!0u32, &buf[..len]),
+                expected,
+                "len={}",
+                len
+            );
+            // unaligned start
+            if len > 0 {
+                assert_eq!(
+                    !update_slice_by_8(!0u32, &buf[1..len]),
+                    crc32_bytewise(&buf[1..len]),
+                    "len={}",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_crc32_streaming() {
+        let buf = [0xa5u8; 100];
+        let expected = crc32(&buf);
+        for chunk in [1, 3, 8, 13, 64] {
+            let mut crc = Crc32::new();
+            for part in buf.chunks(chunk) {
+                crc.update(part);
+            }
+            assert_eq!(crc.finalize(), expected, "chunk={}", chunk);
+        }
+    }
 }
@@ -0,0 +1,507 @@
+//! Delta updates.
+//!
+//! A patch turns the image in the primary slot (the source) into an update
+//! image (the target). The application applies it while it writes the
+//! secondary slot (see `app::BootClient::begin_delta_update`), so only the
+//! patch is sent to the device. `bintool -c delta` makes patches with bsdiff.
+//!
+//! A patch is a `PatchHeader` followed by bsdiff entries. The header holds the
+//! length and SHA-256 of the source image (header and payload), so that a
+//! patch is only applied to the image it was made for. Each entry adds
+//! `diff_len` bytes to the source, appends `extra_len` literal bytes and
+//! moves in the source by `seek`:
+//!
+//! ```text
+//! entry: diff_len extra_len seek    varints, seek zigzag encoded
+//!        runs                       until diff_len bytes are covered
+//!        extra_len bytes
+//! run:   same len                   varints
+//!        len bytes
+//! ```
+//!
+//! Most diff bytes are zero; a run starts with the number of bytes that are
+//! copied unchanged, so the patch stays small without a compressor.
+
+use crate::crc32::crc32;
+use crate::sha256::{sha256, DIGEST_LENGTH};
+use core::fmt;
+
+pub const PATCH_MAGIC: u32 = 0xb007de17;
+pub const PATCH_HEADER_LENGTH: usize = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    BadMagic(u32),
+    HeaderCrc {
+        expected: u32,
+        actual: u32,
+    },
+    /// The source image is not the one the patch was made for.
+    SourceMismatch,
+    /// A varint is too long or a run is longer than its entry.
+    Malformed,
+    /// An entry reads outside the source image.
+    SourceRange,
+    /// The patch produces more than `target_length` bytes.
+    TargetLength,
+    /// The patch ends within the header or an entry, or before the whole
+    /// target is produced.
+    Truncated,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::BadMagic(magic) => write!(f, "not a patch: magic {:08x}", magic),
+            DeltaError::HeaderCrc { expected, actual } => write!(
+                f,
+                "patch header crc32 mismatch: expected {:08x}, actual {:08x}",
+                expected, actual
+            ),
+            DeltaError::SourceMismatch => write!(f, "patch is for another source image"),
+            DeltaError::Malformed => write!(f, "patch is malformed"),
+            DeltaError::SourceRange => write!(f, "patch reads outside the source image"),
+            DeltaError::TargetLength => write!(f, "patch produces too much data"),
+            DeltaError::Truncated => write!(f, "patch is truncated"),
+        }
+    }
+}
+
+impl core::error::Error for DeltaError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PatchHeader {
+    pub source_length: u32,
+    pub source_digest: [u8; DIGEST_LENGTH],
+    pub target_length: u32,
+}
+
+impl PatchHeader {
+    pub fn to_bytes(&self) -> [u8; PATCH_HEADER_LENGTH] {
+        let mut buf = [0u8; PATCH_HEADER_LENGTH];
+        buf[0..4].copy_from_slice(&PATCH_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.source_length.to_le_bytes());
+        buf[8..40].copy_from_slice(&self.source_digest);
+        buf[40..44].copy_from_slice(&self.target_length.to_le_bytes());
+        let crc = crc32(&buf[..44]);
+        buf[44..48].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Checks that `source` is the image the patch was made for.
+    pub fn check_source(&self, source: &[u8]) -> Result<(), DeltaError> {
+        if source.len() != self.source_length as usize || sha256(source) != self.source_digest {
+            return Err(DeltaError::SourceMismatch);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for PatchHeader {
+    type Error = DeltaError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let buf = buf
+            .get(..PATCH_HEADER_LENGTH)
+            .ok_or(DeltaError::Truncated)?;
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if u32_at(0) != PATCH_MAGIC {
+            return Err(DeltaError::BadMagic(u32_at(0)));
+        }
+        let actual = crc32(&buf[..44]);
+        if u32_at(44) != actual {
+            return Err(DeltaError::HeaderCrc {
+                expected: u32_at(44),
+                actual,
+            });
+        }
+        Ok(PatchHeader {
+            source_length: u32_at(4),
+            source_digest: buf[8..40].try_into().unwrap(),
+            target_length: u32_at(40),
+        })
+    }
+}
+
+/// What to do with the source for a byte of the patch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// The header is complete; check the source before anything else.
+    Header(PatchHeader),
+    /// Append `len` bytes of the source at `source`.
+    Copy { source: u32, len: u32 },
+    /// Append the byte of the source at `source` plus `diff`.
+    Add { source: u32, diff: u8 },
+    /// Append the byte.
+    Insert(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Header,
+    /// Field of the entry header.
+    Entry(u8),
+    Same,
+    RunLength,
+    Diff(u32),
+    Extra(u32),
+}
+
+/// Streaming patch decoder. The patch is pushed a byte at a time, and the
+/// source and target are left to the caller, so it needs no buffers.
+pub struct Decoder {
+    state: State,
+    header: [u8; PATCH_HEADER_LENGTH],
+    header_len: usize,
+    source_length: u32,
+    target_length: u32,
+    /// Bytes of target so far.
+    produced: u32,
+    /// Position in the source.
+    position: i64,
+    varint: u32,
+    shift: u32,
+    /// Diff bytes of the entry not yet covered by a run.
+    diff_left: u32,
+    extra_len: u32,
+    seek: i64,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: State::Header,
+            header: [0u8; PATCH_HEADER_LENGTH],
+            header_len: 0,
+            source_length: 0,
+            target_length: 0,
+            produced: 0,
+            position: 0,
+            varint: 0,
+            shift: 0,
+            diff_left: 0,
+            extra_len: 0,
+            seek: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Result<Option<Action>, DeltaError> {
+        match self.state {
+            State::Header => {
+                self.header[self.header_len] = byte;
+                self.header_len += 1;
+                if self.header_len < PATCH_HEADER_LENGTH {
+                    return Ok(None);
+                }
+                let header =
+                    PatchHeader::try_from(&self.header[..]).inspect_err(|_| self.header_len = 0)?;
+                self.source_length = header.source_length;
+                self.target_length = header.target_length;
+                self.state = State::Entry(0);
+                Ok(Some(Action::Header(header)))
+            }
+            State::Entry(field) => {
+                let Some(value) = self.varint(byte)? else {
+                    return Ok(None);
+                };
+                match field {
+                    0 => self.diff_left = value,
+                    1 => self.extra_len = value,
+                    _ => self.seek = (value >> 1) as i64 ^ -((value & 1) as i64),
+                }
+                self.state = if field < 2 {
+                    State::Entry(field + 1)
+                } else {
+                    self.next_run()
+                };
+                Ok(None)
+            }
+            State::Same => {
+                let Some(len) = self.varint(byte)? else {
+                    return Ok(None);
+                };
+                let source = self.take_source(len)?;
+                self.state = State::RunLength;
+                Ok((len > 0).then_some(Action::Copy { source, len }))
+            }
+            State::RunLength => {
+                let Some(len) = self.varint(byte)? else {
+                    return Ok(None);
+                };
+                if len > self.diff_left {
+                    return Err(DeltaError::Malformed);
+                }
+                self.state = if len > 0 {
+                    State::Diff(len)
+                } else {
+                    self.next_run()
+                };
+                Ok(None)
+            }
+            State::Diff(left) => {
+                let source = self.take_source(1)?;
+                self.state = if left > 1 {
+                    State::Diff(left - 1)
+                } else {
+                    self.next_run()
+                };
+                Ok(Some(Action::Add { source, diff: byte }))
+            }
+            State::Extra(left) => {
+                self.produce(1)?;
+                self.state = if left > 1 {
+                    State::Extra(left - 1)
+                } else {
+                    self.end_entry()
+                };
+                Ok(Some(Action::Insert(byte)))
+            }
+        }
+    }
+
+    /// Checks that the patch is complete.
+    pub fn finish(&self) -> Result<(), DeltaError> {
+        if self.state != State::Entry(0) || self.shift != 0 || self.produced != self.target_length {
+            return Err(DeltaError::Truncated);
+        }
+        Ok(())
+    }
+
+    /// LEB128, `None` until the last byte.
+    fn varint(&mut self, byte: u8) -> Result<Option<u32>, DeltaError> {
+        if self.shift > 28 || (self.shift == 28 && byte & 0x70 != 0) {
+            return Err(DeltaError::Malformed);
+        }
+        self.varint |= ((byte & 0x7f) as u32) << self.shift;
+        if byte & 0x80 != 0 {
+            self.shift += 7;
+            return Ok(None);
+        }
+        let value = self.varint;
+        self.varint = 0;
+        self.shift = 0;
+        Ok(Some(value))
+    }
+
+    /// The state after a run or the entry header.
+    fn next_run(&mut self) -> State {
+        if self.diff_left > 0 {
+            State::Same
+        } else if self.extra_len > 0 {
+            State::Extra(self.extra_len)
+        } else {
+            self.end_entry()
+        }
+    }
+
+    fn end_entry(&mut self) -> State {
+        self.position += self.seek;
+        State::Entry(0)
+    }
+
+    /// Takes `len` diff bytes at the source position and returns where they are.
+    fn take_source(&mut self, len: u32) -> Result<u32, DeltaError> {
+        if len > self.diff_left {
+            return Err(DeltaError::Malformed);
+        }
+        if self.position < 0 || self.position + len as i64 > self.source_length as i64 {
+            return Err(DeltaError::SourceRange);
+        }
+        self.produce(len)?;
+        let source = self.position as u32;
+        self.position += len as i64;
+        self.diff_left -= len;
+        Ok(source)
+    }
+
+    fn produce(&mut self, len: u32) -> Result<(), DeltaError> {
+        match self.produced.checked_add(len) {
+            Some(produced) if produced <= self.target_length => {
+                self.produced = produced;
+                Ok(())
+            }
+            _ => Err(DeltaError::TargetLength),
+        }
+    }
+}
+
+fn push_varint(value: u32, out: &mut impl Extend<u8>) {
+    let mut value = value;
+    while value >= 0x80 {
+        out.extend([value as u8 | 0x80]);
+        value >>= 7;
+    }
+    out.extend([value as u8]);
+}
+
+/// Appends an entry with the `diff` bytes (target minus source), the `extra`
+/// bytes and the `seek` after them.
+pub fn encode_entry(diff: &[u8], extra: &[u8], seek: i32, out: &mut impl Extend<u8>) {
+    push_varint(diff.len() as u32, out);
+    push_varint(extra.len() as u32, out);
+    push_varint(((seek << 1) ^ (seek >> 31)) as u32, out);
+    let mut rest = diff;
+    while !rest.is_empty() {
+        let same = rest.iter().take_while(|&&b| b == 0).count();
+        // a short run of zeros between changes costs more than it saves
+        let mut len = 0;
+        while same + len < rest.len() {
+            let zeros = rest[same + len..].iter().take_while(|&&b| b == 0).count();
+            if zeros > 2 || same + len + zeros == rest.len() {
+                break;
+            }
+            len += zeros.max(1);
+        }
+        push_varint(same as u32, out);
+        push_varint(len as u32, out);
+        out.extend(rest[same..same + len].iter().copied());
+        rest = &rest[same + len..];
+    }
+    out.extend(extra.iter().copied());
+}
+
+/// Applies `patch` to `source`, appending the target to `out`.
+pub fn apply(source: &[u8], patch: &[u8], out: &mut impl Extend<u8>) -> Result<(), DeltaError> {
+    let mut decoder = Decoder::new();
+    for &byte in patch {
+        match decoder.push(byte)? {
+            None => {}
+            Some(Action::Header(header)) => header.check_source(source)?,
+            Some(Action::Copy {
+                source: offset,
+                len,
+            }) => out.extend(source[offset as usize..][..len as usize].iter().copied()),
+            Some(Action::Add {
+                source: offset,
+                diff,
+            }) => out.extend([source[offset as usize].wrapping_add(diff)]),
+            Some(Action::Insert(byte)) => out.extend([byte]),
+        }
+    }
+    decoder.finish()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// A patch with a single entry: `target` as a diff against the start of
+    /// `source`, the rest of it as extra bytes.
+    pub fn simple_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let header = PatchHeader {
+            source_length: source.len() as u32,
+            source_digest: sha256(source),
+            target_length: target.len() as u32,
+        };
+        let mut patch = header.to_bytes().to_vec();
+        let n = source.len().min(target.len());
+        let diff: Vec<u8> = (0..n).map(|i| target[i].wrapping_sub(source[i])).collect();
+        encode_entry(&diff, &target[n..], 0, &mut patch);
+        patch
+    }
+
+    fn source() -> Vec<u8> {
+        (0..3000u32).map(|i| (i * 7 / 5) as u8).collect()
+    }
+
+    #[test]
+    fn test_header() {
+        let header = PatchHeader {
+            source_length: 0x1234,
+            source_digest: [0x5a; 32],
+            target_length: 0x2345,
+        };
+        let mut buf = header.to_bytes();
+        assert_eq!(PatchHeader::try_from(&buf[..]), Ok(header));
+        assert_eq!(
+            PatchHeader::try_from(&buf[..47]),
+            Err(DeltaError::Truncated)
+        );
+        buf[40] ^= 0x01;
+        assert!(matches!(
+            PatchHeader::try_from(&buf[..]),
+            Err(DeltaError::HeaderCrc { .. })
+        ));
+        buf[0] ^= 0x01;
+        assert_eq!(
+            PatchHeader::try_from(&buf[..]),
+            Err(DeltaError::BadMagic(0xb007de16))
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let source = source();
+        let mut target = source.clone();
+        target[100] ^= 0x10;
+        target[101] ^= 0x20;
+        target[1500..1504].copy_from_slice(&[1, 2, 3, 4]);
+        target.extend_from_slice(b"new data");
+        let patch = simple_patch(&source, &target);
+        assert!(patch.len() < PATCH_HEADER_LENGTH + 40);
+        let mut out = Vec::new();
+        assert_eq!(apply(&source, &patch, &mut out), Ok(()));
+        assert_eq!(out, target);
+
+        // shorter target, seeks and several entries
+        let target: Vec<u8> = [&source[1000..1200], b"xyz", &source[10..500]].concat();
+        let header = PatchHeader {
+            source_length: source.len() as u32,
+            source_digest: sha256(&source),
+            target_length: target.len() as u32,
+        };
+        let mut patch = header.to_bytes().to_vec();
+        encode_entry(&[], &[], 1000, &mut patch);
+        encode_entry(&[0; 200], b"xyz", -1190, &mut patch);
+        encode_entry(&[0; 490], &[], 0, &mut patch);
+        let mut out = Vec::new();
+        assert_eq!(apply(&source, &patch, &mut out), Ok(()));
+        assert_eq!(out, target);
+    }
+
+    #[test]
+    fn test_errors() {
+        let source = source();
+        let target: Vec<u8> = source.iter().map(|b| b ^ 0x01).collect();
+        let patch = simple_patch(&source, &target);
+        let apply = |source: &[u8], patch: &[u8]| apply(source, patch, &mut Vec::new());
+
+        assert_eq!(apply(&source[1..], &patch), Err(DeltaError::SourceMismatch));
+        let mut other = source.clone();
+        other[0] ^= 0x01;
+        assert_eq!(apply(&other, &patch), Err(DeltaError::SourceMismatch));
+        assert_eq!(apply(&source, &patch[..40]), Err(DeltaError::Truncated));
+        assert_eq!(
+            apply(&source, &patch[..patch.len() - 1]),
+            Err(DeltaError::Truncated)
+        );
+
+        let header = PatchHeader {
+            source_length: source.len() as u32,
+            source_digest: sha256(&source),
+            target_length: 100,
+        };
+        let mut patch = header.to_bytes().to_vec();
+        encode_entry(&[0; 101], &[], 0, &mut patch);
+        assert_eq!(apply(&source, &patch), Err(DeltaError::TargetLength));
+
+        let mut patch = header.to_bytes().to_vec();
+        encode_entry(&[], &[], -1, &mut patch);
+        encode_entry(&[0; 100], &[], 0, &mut patch);
+        assert_eq!(apply(&source, &patch), Err(DeltaError::SourceRange));
+
+        let mut patch = header.to_bytes().to_vec();
+        patch.extend_from_slice(&[100, 0, 0, 0, 101]);
+        assert_eq!(apply(&source, &patch), Err(DeltaError::Malformed));
+        let mut patch = header.to_bytes().to_vec();
+        patch.extend_from_slice(&[0xff; 6]);
+        assert_eq!(apply(&source, &patch), Err(DeltaError::Malformed));
+    }
+}
@@ -0,0 +1,220 @@
+//! Encrypted native images.
+//!
+//! `bintool` encrypts the payload with AES-128-CTR under a random image key,
+//! counting 16 byte blocks from the start of the payload with a zero nonce.
+//! The image key is wrapped for the device in a `TLV_ENC_X25519` entry (ECIES):
+//! an ephemeral X25519 public key followed by the image key encrypted with a
+//! key derived from the shared secret with HKDF-SHA256. The entry is covered
+//! by the signature like the rest of the header; a wrong device key gives a
+//! payload that does not match `payload_digest`.
+//!
+//! `payload_crc` and `payload_digest` are of the plain text. The bootloader
+//! checks the update slot with `validate`, which decrypts on the fly, and
+//! decrypts while swapping it into the primary slot (see `swap`), so only the
+//! primary slot holds plain text.
+
+use crate::crc32::Crc32;
+use crate::image_header::{ImageHeader, ValidationError};
+use crate::sha256::Sha256;
+use crate::tlv::{self, TLV_ENC_X25519};
+
+#[cfg(feature = "encrypt")]
+use crate::sha256::{hmac_sha256, DIGEST_LENGTH};
+
+pub const AES_KEY_LENGTH: usize = 16;
+pub const X25519_KEY_LENGTH: usize = 32;
+/// The value of a `TLV_ENC_X25519` entry.
+pub const WRAPPED_KEY_LENGTH: usize = X25519_KEY_LENGTH + AES_KEY_LENGTH;
+
+#[cfg(feature = "encrypt")]
+const KDF_INFO: &[u8] = b"boot-k image key";
+
+/// Encrypts or decrypts `buf`, which is at `offset` in the payload. Both are
+/// the same in CTR mode.
+pub trait PayloadCipher {
+    fn apply(&self, offset: u32, buf: &mut [u8]);
+}
+
+/// The wrapped image key, `None` if the image is not encrypted.
+pub fn wrapped_key(ih: &ImageHeader) -> Option<[u8; WRAPPED_KEY_LENGTH]> {
+    tlv::find(&ih.tlv, TLV_ENC_X25519)?.value.try_into().ok()
+}
+
+/// `ImageHeader::validate` for an encrypted `payload`.
+pub fn validate(
+    ih: &ImageHeader,
+    payload: &[u8],
+    cipher: &dyn PayloadCipher,
+) -> Result<(), ValidationError> {
+    ih.validate_with(payload.len(), |len| {
+        let mut crc = Crc32::new();
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; 256];
+        for (i, chunk) in payload[..len].chunks(buf.len()).enumerate() {
+            let buf = &mut buf[..chunk.len()];
+            buf.copy_from_slice(chunk);
+            cipher.apply((i * 256) as u32, buf);
+            crc.update(buf);
+            hasher.update(buf);
+        }
+        (crc.finalize(), hasher.finalize())
+    })
+}
+
+/// AES-128-CTR with the image key.
+#[cfg(feature = "encrypt")]
+pub struct ImageCipher {
+    key: [u8; AES_KEY_LENGTH],
+}
+
+#[cfg(feature = "encrypt")]
+impl ImageCipher {
+    pub fn new(key: &[u8; AES_KEY_LENGTH]) -> Self {
+        ImageCipher { key: *key }
+    }
+}
+
+#[cfg(feature = "encrypt")]
+impl PayloadCipher for ImageCipher {
+    fn apply(&self, offset: u32, buf: &mut [u8]) {
+        use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+
+        let mut cipher = ctr::Ctr128BE::<aes::Aes128>::new(&self.key.into(), &[0u8; 16].into());
+        cipher.seek(offset);
+        cipher.apply_keystream(buf);
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869) with a single block of output.
+#[cfg(feature = "encrypt")]
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; DIGEST_LENGTH] {
+    let prk = hmac_sha256(salt, &[ikm]);
+    hmac_sha256(&prk, &[info, &[1]])
+}
+
+/// The key that wraps the image key, from the shared secret and the
+/// ephemeral public key.
+#[cfg(feature = "encrypt")]
+fn key_encryption_key(shared: &[u8; 32], ephemeral: &[u8]) -> ImageCipher {
+    let okm = hkdf_sha256(ephemeral, shared, KDF_INFO);
+    ImageCipher::new(okm[..AES_KEY_LENGTH].try_into().unwrap())
+}
+
+/// The X25519 public key of `secret`.
+#[cfg(feature = "encrypt")]
+pub fn public_key(secret: &[u8; X25519_KEY_LENGTH]) -> [u8; X25519_KEY_LENGTH] {
+    let secret = x25519_dalek::StaticSecret::from(*secret);
+    x25519_dalek::PublicKey::from(&secret).to_bytes()
+}
+
+/// Wraps `key` for the device with `device_public`. `ephemeral_secret` must be
+/// random and is not needed afterwards.
+#[cfg(feature = "encrypt")]
+pub fn wrap_key(
+    key: &[u8; AES_KEY_LENGTH],
+    device_public: &[u8; X25519_KEY_LENGTH],
+    ephemeral_secret: [u8; X25519_KEY_LENGTH],
+) -> [u8; WRAPPED_KEY_LENGTH] {
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    let secret = StaticSecret::from(ephemeral_secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(*device_public));
+    let mut wrapped = [0u8; WRAPPED_KEY_LENGTH];
+    wrapped[..X25519_KEY_LENGTH].copy_from_slice(PublicKey::from(&secret).as_bytes());
+    wrapped[X25519_KEY_LENGTH..].copy_from_slice(key);
+    let (ephemeral, key) = wrapped.split_at_mut(X25519_KEY_LENGTH);
+    key_encryption_key(shared.as_bytes(), ephemeral).apply(0, key);
+    wrapped
+}
+
+/// The image key in `wrapped`, for the device with `device_secret`.
+#[cfg(feature = "encrypt")]
+pub fn unwrap_key(
+    wrapped: &[u8; WRAPPED_KEY_LENGTH],
+    device_secret: &[u8; X25519_KEY_LENGTH],
+) -> [u8; AES_KEY_LENGTH] {
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    let (ephemeral, wrapped_key) = wrapped.split_at(X25519_KEY_LENGTH);
+    let ephemeral_public: [u8; X25519_KEY_LENGTH] = ephemeral.try_into().unwrap();
+    let shared =
+        StaticSecret::from(*device_secret).diffie_hellman(&PublicKey::from(ephemeral_public));
+    let mut key: [u8; AES_KEY_LENGTH] = wrapped_key.try_into().unwrap();
+    key_encryption_key(shared.as_bytes(), ephemeral).apply(0, &mut key);
+    key
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::sha256::sha256;
+    use crate::tlv::TlvWriter;
+    use std::vec::Vec;
+
+    const DEVICE_SECRET: [u8; 32] = [0x5d; 32];
+
+    /// An encrypted image like `bintool -e` writes it, and the plain payload.
+    fn encrypted_image(len: usize) -> (ImageHeader, Vec<u8>, Vec<u8>) {
+        let plain: Vec<u8> = (0..len).map(|i| (i * 13) as u8).collect();
+        let mut ih = ImageHeader::new();
+        ih.image_length = len as u32;
+        ih.payload_crc = crc32(&plain);
+        ih.payload_digest = sha256(&plain);
+        let key = [0x4bu8; AES_KEY_LENGTH];
+        let wrapped = wrap_key(&key, &public_key(&DEVICE_SECRET), [0x77; 32]);
+        let mut tlvs = TlvWriter::new(&mut ih.tlv);
+        tlvs.push_build_time(1_700_000_000).unwrap();
+        tlvs.push(TLV_ENC_X25519, &wrapped).unwrap();
+        ih.crc32 = ih.calc_crc32();
+
+        let mut payload = plain.clone();
+        ImageCipher::new(&key).apply(0, &mut payload);
+        (ih, payload, plain)
+    }
+
+    #[test]
+    fn test_hkdf_sha256() {
+        // RFC 5869 test case 1, the first 32 bytes of the OKM
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let okm = hkdf_sha256(&salt, &[0x0b; 22], &info);
+        assert_eq!(okm[..8], [0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a]);
+        assert_eq!(okm[24..], [0x5d, 0xb0, 0x2d, 0x56, 0xec, 0xc4, 0xc5, 0xbf]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let (ih, payload, plain) = encrypted_image(1000);
+        assert_ne!(payload, plain);
+        assert!(matches!(
+            ih.validate(&payload),
+            Err(ValidationError::PayloadCrc { .. })
+        ));
+
+        let wrapped = wrapped_key(&ih).unwrap();
+        let key = unwrap_key(&wrapped, &DEVICE_SECRET);
+        assert_eq!(key, [0x4bu8; AES_KEY_LENGTH]);
+        let cipher = ImageCipher::new(&key);
+        assert_eq!(validate(&ih, &payload, &cipher), Ok(()));
+
+        // decrypting in pieces at any offset is the same
+        let mut decrypted = payload.clone();
+        for (start, end) in [(0, 17), (17, 256), (256, 700), (700, 1000)] {
+            cipher.apply(start as u32, &mut decrypted[start..end]);
+        }
+        assert_eq!(decrypted, plain);
+        assert_eq!(ih.validate(&decrypted), Ok(()));
+
+        let other = ImageCipher::new(&unwrap_key(&wrapped, &[0x5e; 32]));
+        assert!(matches!(
+            validate(&ih, &payload, &other),
+            Err(ValidationError::PayloadCrc { .. })
+        ));
+        let mut tampered = payload.clone();
+        tampered[999] ^= 0x01;
+        assert!(validate(&ih, &tampered, &cipher).is_err());
+
+        assert_eq!(wrapped_key(&ImageHeader::new()), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Flash storage. Offsets are relative to the start of the flash
+//! (`layout::Partition::offset`), not XIP addresses.
+//!
+//! Like the W25Q16JV, erasing sets a whole `SECTOR_SIZE` sector to `ERASED`
+//! and programming a `PAGE_SIZE` page can only clear bits.
+
+use crate::crc32::Crc32;
+use crate::layout::PAGE_SIZE;
+use crate::sha256::{Sha256, DIGEST_LENGTH};
+use core::fmt;
+
+#[cfg(test)]
+pub mod mock;
+
+/// Value of an erased byte.
+pub const ERASED: u8 = 0xff;
+
+pub trait Flash {
+    type Error: fmt::Debug;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erases the `SECTOR_SIZE` bytes at `offset` (sector aligned).
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+
+    /// Programs the `PAGE_SIZE` bytes at `offset` (page aligned). Bits that
+    /// are already 0 must stay 0 in `data`.
+    fn program_page(
+        &mut self,
+        offset: u32,
+        data: &[u8; PAGE_SIZE as usize],
+    ) -> Result<(), Self::Error>;
+}
+
+/// `payload_crc` and `payload_digest` of the `length` bytes at `offset`.
+pub fn digests<F: Flash>(
+    flash: &mut F,
+    offset: u32,
+    length: u32,
+) -> Result<(u32, [u8; DIGEST_LENGTH]), F::Error> {
+    let mut crc = Crc32::new();
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; PAGE_SIZE as usize];
+    let end = offset + length;
+    for offset in (offset..end).step_by(buf.len()) {
+        let buf = &mut buf[..(end - offset).min(PAGE_SIZE) as usize];
+        flash.read(offset, buf)?;
+        crc.update(buf);
+        hasher.update(buf);
+    }
+    Ok((crc.finalize(), hasher.finalize()))
+}
+
+/// Reads `length` bytes from `offset` a page at a time, as bytes for
+/// `compress::Decoder`. A read error ends the bytes and is returned by
+/// `finish`.
+pub struct FlashReader<E> {
+    offset: u32,
+    end: u32,
+    page: [u8; PAGE_SIZE as usize],
+    loaded: bool,
+    error: Option<E>,
+}
+
+impl<E> FlashReader<E> {
+    pub fn new(offset: u32, length: u32) -> Self {
+        FlashReader {
+            offset,
+            end: offset + length,
+            page: [ERASED; PAGE_SIZE as usize],
+            loaded: false,
+            error: None,
+        }
+    }
+
+    /// The next bytes, read from `flash`.
+    pub fn bytes<'a, F: Flash<Error = E>>(
+        &'a mut self,
+        flash: &'a mut F,
+    ) -> impl Iterator<Item = u8> + 'a {
+        core::iter::from_fn(move || {
+            if self.offset == self.end || self.error.is_some() {
+                return None;
+            }
+            let in_page = self.offset % PAGE_SIZE;
+            if !self.loaded || in_page == 0 {
+                if let Err(e) = flash.read(self.offset - in_page, &mut self.page) {
+                    self.error = Some(e);
+                    return None;
+                }
+                self.loaded = true;
+            }
+            self.offset += 1;
+            Some(self.page[in_page as usize])
+        })
+    }
+
+    pub fn finish(self) -> Result<(), E> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
@@ -0,0 +1,217 @@
+//! RAM-backed `Flash` for host tests. It enforces the NOR rules (alignment,
+//! no 0 -> 1 without an erase) and can lose power after a number of erase and
+//! program operations.
+
+use super::{Flash, ERASED};
+use crate::layout::{PAGE_SIZE, SECTOR_SIZE};
+use std::vec;
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockFlashError {
+    OutOfRange {
+        offset: u32,
+        len: usize,
+    },
+    Misaligned(u32),
+    /// Programming would turn the 0 bits at this offset into 1.
+    NotErased(u32),
+    PowerLoss,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockFlash {
+    mem: Vec<u8>,
+    /// Erase/program operations left before the power is lost.
+    budget: Option<usize>,
+    powered: bool,
+    /// The interrupted operation is left half done instead of not started.
+    pub torn: bool,
+    ops: usize,
+}
+
+impl MockFlash {
+    pub fn new(size: u32) -> Self {
+        MockFlash {
+            mem: vec![ERASED; size as usize],
+            budget: None,
+            powered: true,
+            torn: false,
+            ops: 0,
+        }
+    }
+
+    /// The next erase/program after `ops` more of them fails with `PowerLoss`,
+    /// and so does every access after that until `power_cycle`.
+    pub fn lose_power_after(&mut self, ops: usize) {
+        self.budget = Some(ops);
+    }
+
+    pub fn power_cycle(&mut self) {
+        self.budget = None;
+        self.powered = true;
+    }
+
+    /// Number of completed erase/program operations.
+    pub fn ops(&self) -> usize {
+        self.ops
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+
+    fn check(&self, offset: u32, len: usize, align: u32) -> Result<(), MockFlashError> {
+        if !self.powered {
+            return Err(MockFlashError::PowerLoss);
+        }
+        if offset as usize + len > self.mem.len() {
+            return Err(MockFlashError::OutOfRange { offset, len });
+        }
+        if !offset.is_multiple_of(align) {
+            return Err(MockFlashError::Misaligned(offset));
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes of an operation on `len` bytes get done.
+    fn operate(&mut self, len: usize) -> usize {
+        match self.budget {
+            Some(0) => {
+                self.powered = false;
+                if self.torn {
+                    len / 2
+                } else {
+                    0
+                }
+            }
+            budget => {
+                self.budget = budget.map(|b| b - 1);
+                self.ops += 1;
+                len
+            }
+        }
+    }
+}
+
+impl Flash for MockFlash {
+    type Error = MockFlashError;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), MockFlashError> {
+        self.check(offset, buf.len(), 1)?;
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), MockFlashError> {
+        self.check(offset, SECTOR_SIZE as usize, SECTOR_SIZE)?;
+        let offset = offset as usize;
+        let len = self.operate(SECTOR_SIZE as usize);
+        self.mem[offset..offset + len].fill(ERASED);
+        if self.powered {
+            Ok(())
+        } else {
+            Err(MockFlashError::PowerLoss)
+        }
+    }
+
+    fn program_page(
+        &mut self,
+        offset: u32,
+        data: &[u8; PAGE_SIZE as usize],
+    ) -> Result<(), MockFlashError> {
+        self.check(offset, data.len(), PAGE_SIZE)?;
+        let start = offset as usize;
+        let page = &self.mem[start..start + data.len()];
+        if let Some(i) = page.iter().zip(data).position(|(&m, &d)| m & d != d) {
+            return Err(MockFlashError::NotErased(offset + i as u32));
+        }
+        let len = self.operate(data.len());
+        self.mem[start..start + len].copy_from_slice(&data[..len]);
+        if self.powered {
+            Ok(())
+        } else {
+            Err(MockFlashError::PowerLoss)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nor_rules() {
+        let mut flash = MockFlash::new(0x2000);
+        let mut page = [0x0fu8; PAGE_SIZE as usize];
+        flash.program_page(0x100, &page).unwrap();
+        // clearing more bits is fine, setting them is not
+        page[0] = 0x05;
+        flash.program_page(0x100, &page).unwrap();
+        page[1] = 0x1f;
+        assert_eq!(
+            flash.program_page(0x100, &page),
+            Err(MockFlashError::NotErased(0x101))
+        );
+        flash.erase_sector(0x0000).unwrap();
+        flash.program_page(0x100, &page).unwrap();
+        assert_eq!(&flash.data()[0x100..0x103], &[0x05, 0x1f, 0x0f]);
+
+        assert_eq!(
+            flash.program_page(0x180, &page),
+            Err(MockFlashError::Misaligned(0x180))
+        );
+        assert_eq!(
+            flash.erase_sector(0x800),
+            Err(MockFlashError::Misaligned(0x800))
+        );
+        assert_eq!(
+            flash.erase_sector(0x2000),
+            Err(MockFlashError::OutOfRange {
+                offset: 0x2000,
+                len: SECTOR_SIZE as usize
+            })
+        );
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            flash.read(0x1ffe, &mut buf),
+            Err(MockFlashError::OutOfRange {
+                offset: 0x1ffe,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let page = [0x00u8; PAGE_SIZE as usize];
+        let mut flash = MockFlash::new(0x2000);
+        flash.lose_power_after(1);
+        flash.program_page(0x000, &page).unwrap();
+        assert_eq!(
+            flash.program_page(0x100, &page),
+            Err(MockFlashError::PowerLoss)
+        );
+        let mut buf = [0u8; 1];
+        assert_eq!(flash.read(0, &mut buf), Err(MockFlashError::PowerLoss));
+        assert_eq!(flash.ops(), 1);
+
+        flash.power_cycle();
+        assert_eq!(flash.data()[0x0ff], 0x00);
+        assert_eq!(flash.data()[0x100], ERASED);
+
+        // a torn erase leaves half of the sector
+        flash.program_page(0x900, &page).unwrap();
+        flash.torn = true;
+        flash.lose_power_after(0);
+        assert_eq!(flash.erase_sector(0), Err(MockFlashError::PowerLoss));
+        flash.power_cycle();
+        assert_eq!(flash.data()[0x0ff], ERASED);
+        assert_eq!(flash.data()[0x900], 0x00);
+    }
+}
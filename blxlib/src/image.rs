@@ -0,0 +1,342 @@
+//! The image at the start of a slot in any format: a native image with an
+//! `ImageHeader`, an MCUboot image (see `mcuboot`) or a SUIT image (see `suit`).
+
+use crate::compress;
+use crate::encrypt::{self, PayloadCipher, WRAPPED_KEY_LENGTH};
+use crate::image_header::{
+    HeaderParseError, ImageHeader, ValidationError, APP_SIZE, HEADER_LENGTH, SIG_ALG_ECDSA_P256,
+    SIG_ALG_ED25519, SIG_ALG_NONE,
+};
+use crate::mcuboot::{self, McubootError, McubootImage};
+use crate::suit::{self, SuitError, SuitImage};
+use crate::version::ImageVersion;
+use core::fmt;
+
+#[cfg(feature = "ed25519")]
+use crate::signature::ED25519_PUBLIC_KEY_LENGTH;
+#[cfg(feature = "p256")]
+use crate::signature::P256_PUBLIC_KEY_LENGTH;
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+use crate::signature::{self, SignatureError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Native,
+    Mcuboot,
+    Suit,
+}
+
+/// What the bootloader needs to know about an image, whatever its format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// `None` for a SUIT image, which only has a sequence number.
+    pub version: Option<ImageVersion>,
+    /// The sequence number of a SUIT image, saturated.
+    pub security_counter: u32,
+    /// Identifies the image in the swap status and request: the header
+    /// `crc32` of a native image, the first word of the hash of an MCUboot
+    /// image or of the manifest digest of a SUIT image.
+    pub id: u32,
+    /// Bytes from the start of the slot, including the header.
+    pub size: u32,
+}
+
+impl From<&ImageHeader> for ImageInfo {
+    fn from(ih: &ImageHeader) -> Self {
+        ImageInfo {
+            format: ImageFormat::Native,
+            version: Some(ih.version()),
+            security_counter: ih.security_counter,
+            id: ih.crc32,
+            size: ih.image_length.saturating_add(HEADER_LENGTH as u32),
+        }
+    }
+}
+
+impl From<&McubootImage<'_>> for ImageInfo {
+    fn from(image: &McubootImage) -> Self {
+        ImageInfo {
+            format: ImageFormat::Mcuboot,
+            version: Some(image.header.version),
+            security_counter: image.security_counter(),
+            id: image
+                .hash()
+                .map_or(0, |hash| u32::from_le_bytes(hash[..4].try_into().unwrap())),
+            size: image.size() as u32,
+        }
+    }
+}
+
+impl From<&SuitImage<'_>> for ImageInfo {
+    fn from(image: &SuitImage) -> Self {
+        ImageInfo {
+            format: ImageFormat::Suit,
+            version: None,
+            security_counter: u32::try_from(image.manifest.sequence_number).unwrap_or(u32::MAX),
+            id: image.id(),
+            size: image.size() as u32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    Header(HeaderParseError),
+    Validation(ValidationError),
+    Mcuboot(McubootError),
+    Suit(SuitError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Header(e) => write!(f, "{}", e),
+            ImageError::Validation(e) => write!(f, "{}", e),
+            ImageError::Mcuboot(e) => write!(f, "{}", e),
+            ImageError::Suit(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for ImageError {}
+
+impl From<HeaderParseError> for ImageError {
+    fn from(e: HeaderParseError) -> Self {
+        ImageError::Header(e)
+    }
+}
+
+impl From<ValidationError> for ImageError {
+    fn from(e: ValidationError) -> Self {
+        ImageError::Validation(e)
+    }
+}
+
+impl From<McubootError> for ImageError {
+    fn from(e: McubootError) -> Self {
+        ImageError::Mcuboot(e)
+    }
+}
+
+impl From<SuitError> for ImageError {
+    fn from(e: SuitError) -> Self {
+        ImageError::Suit(e)
+    }
+}
+
+// there are at most two of these, on the stack
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Image<'a> {
+    /// The header and the slot it was read from.
+    Native(ImageHeader, &'a [u8]),
+    Mcuboot(McubootImage<'a>),
+    Suit(SuitImage<'a>),
+}
+
+impl<'a> Image<'a> {
+    /// Parses the header at the start of `slot` in the format its magic
+    /// selects. An MCUboot image must have a `HEADER_LENGTH` header, so that
+    /// its vector table is where the bootloader jumps to.
+    pub fn parse(slot: &'a [u8]) -> Result<Self, ImageError> {
+        if slot.get(..4) == Some(&mcuboot::IMAGE_MAGIC.to_le_bytes()[..]) {
+            let image = McubootImage::parse(slot)?;
+            if image.header.hdr_size != HEADER_LENGTH {
+                return Err(McubootError::BadHeaderSize(image.header.hdr_size).into());
+            }
+            return Ok(Image::Mcuboot(image));
+        }
+        if slot.get(..4) == Some(&suit::SUIT_IMAGE_MAGIC.to_le_bytes()[..]) {
+            return Ok(Image::Suit(SuitImage::parse(slot)?));
+        }
+        Ok(Image::Native(ImageHeader::try_from(slot)?, slot))
+    }
+
+    pub fn info(&self) -> ImageInfo {
+        match self {
+            Image::Native(ih, _) => ImageInfo::from(ih),
+            Image::Mcuboot(image) => ImageInfo::from(image),
+            Image::Suit(image) => ImageInfo::from(image),
+        }
+    }
+
+    /// Checks the integrity of the image (`ImageHeader::validate`,
+    /// `McubootImage::validate` or `SuitImage::validate`).
+    pub fn validate(&self) -> Result<(), ImageError> {
+        match self {
+            Image::Native(ih, slot) => Ok(ih.validate(&slot[HEADER_LENGTH as usize..])?),
+            Image::Mcuboot(image) => Ok(image.validate()?),
+            Image::Suit(image) => image.validate().map(|_| ()).map_err(ImageError::from),
+        }
+    }
+
+    /// `validate` for an image in the update slot whose payload is encrypted
+    /// (`wrapped_key`).
+    pub fn validate_encrypted(&self, cipher: &dyn PayloadCipher) -> Result<(), ImageError> {
+        match self {
+            Image::Native(ih, slot) => Ok(encrypt::validate(
+                ih,
+                &slot[HEADER_LENGTH as usize..],
+                cipher,
+            )?),
+            _ => self.validate(),
+        }
+    }
+
+    /// `validate` for an image in the update slot whose payload is compressed
+    /// (`compressed_length`).
+    pub fn validate_compressed(&self) -> Result<(), ImageError> {
+        match self {
+            Image::Native(ih, slot) => {
+                let length = compress::compressed_length(ih).unwrap_or(0) as usize;
+                let input = slot[HEADER_LENGTH as usize..].iter().copied().take(length);
+                Ok(compress::validate(ih, input)?)
+            }
+            _ => self.validate(),
+        }
+    }
+
+    /// The compressed length of a compressed image. Only native images can be
+    /// compressed.
+    pub fn compressed_length(&self) -> Option<u32> {
+        match self {
+            Image::Native(ih, _) => compress::compressed_length(ih),
+            _ => None,
+        }
+    }
+
+    /// The wrapped key of an encrypted image. Only native images can be
+    /// encrypted.
+    pub fn wrapped_key(&self) -> Option<[u8; WRAPPED_KEY_LENGTH]> {
+        match self {
+            Image::Native(ih, _) => encrypt::wrapped_key(ih),
+            _ => None,
+        }
+    }
+
+    /// The `SIG_ALG_*` the image is signed with. An MCUboot image with
+    /// several signatures reports the first one that is found.
+    pub fn sig_alg(&self) -> u8 {
+        match self {
+            Image::Native(ih, _) => ih.sig_alg,
+            Image::Mcuboot(image) if image.find(mcuboot::TLV_ED25519).is_some() => SIG_ALG_ED25519,
+            Image::Mcuboot(image) if image.find(mcuboot::TLV_ECDSA_SIG).is_some() => {
+                SIG_ALG_ECDSA_P256
+            }
+            Image::Mcuboot(_) => SIG_ALG_NONE,
+            Image::Suit(image) => match image.envelope.signature_alg() {
+                Some(suit::COSE_ALG_EDDSA) => SIG_ALG_ED25519,
+                Some(suit::COSE_ALG_ES256) => SIG_ALG_ECDSA_P256,
+                _ => SIG_ALG_NONE,
+            },
+        }
+    }
+
+    #[cfg(feature = "ed25519")]
+    pub fn verify_ed25519(
+        &self,
+        public_key: &[u8; ED25519_PUBLIC_KEY_LENGTH],
+    ) -> Result<(), SignatureError> {
+        match self {
+            Image::Native(ih, _) => signature::verify_ed25519(ih, public_key),
+            Image::Mcuboot(image) => mcuboot::verify_ed25519(image, public_key),
+            Image::Suit(image) => suit::verify_ed25519(&image.envelope, public_key),
+        }
+    }
+
+    #[cfg(feature = "p256")]
+    pub fn verify_p256(
+        &self,
+        public_key: &[u8; P256_PUBLIC_KEY_LENGTH],
+    ) -> Result<(), SignatureError> {
+        match self {
+            Image::Native(ih, _) => signature::verify_p256(ih, public_key),
+            Image::Mcuboot(image) => mcuboot::verify_p256(image, public_key),
+            Image::Suit(image) => suit::verify_p256(&image.envelope, public_key),
+        }
+    }
+}
+
+/// Returns the slot at `addr` as memory mapped flash (XIP).
+pub fn slot_from_addr(addr: u32) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(addr as *const u8, APP_SIZE as usize) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcuboot::tests::build_image;
+    use crate::sha256::sha256;
+
+    #[test]
+    fn test_parse() {
+        let mut slot = build_image(HEADER_LENGTH, &[0x11; 100], None, &[]);
+        let image = Image::parse(&slot).unwrap();
+        assert_eq!(image.validate(), Ok(()));
+        assert_eq!(image.sig_alg(), SIG_ALG_NONE);
+        let info = image.info();
+        assert_eq!(info.format, ImageFormat::Mcuboot);
+        assert_eq!(info.version, Some(ImageVersion::new(1, 2, 3, 4)));
+        assert_eq!(info.size, 256 + 100 + 40);
+        let hash_offset = 256 + 100 + 8;
+        assert_eq!(info.id.to_le_bytes(), slot[hash_offset..hash_offset + 4]);
+
+        slot[300] ^= 0x01;
+        assert!(matches!(
+            Image::parse(&slot).unwrap().validate(),
+            Err(ImageError::Mcuboot(McubootError::Hash { .. }))
+        ));
+
+        // not bootable at the start of the slot
+        let slot = build_image(32, &[0x11; 100], None, &[]);
+        assert_eq!(
+            Image::parse(&slot),
+            Err(ImageError::Mcuboot(McubootError::BadHeaderSize(32)))
+        );
+
+        let mut ih = ImageHeader::new();
+        ih.image_length = 4;
+        ih.security_counter = 3;
+        ih.crc32 = ih.calc_crc32();
+        let mut slot = ih.to_bytes().to_vec();
+        slot.extend_from_slice(&[1, 2, 3, 4]);
+        let image = Image::parse(&slot).unwrap();
+        assert_eq!(image, Image::Native(ih, &slot));
+        let info = image.info();
+        assert_eq!(info.format, ImageFormat::Native);
+        assert_eq!(
+            (info.id, info.size, info.security_counter),
+            (ih.crc32, 260, 3)
+        );
+
+        let payload = [0x22u8; 64];
+        let mut manifest = [0u8; 512];
+        let manifest_len =
+            suit::encode_manifest(&mut manifest, 1 << 40, &sha256(&payload), 64).unwrap();
+        let manifest = &manifest[..manifest_len];
+        let mut digest = [0u8; 48];
+        let digest_len = suit::encode_digest(&mut digest, &sha256(manifest)).unwrap();
+        let mut envelope = [0u8; 1024];
+        let envelope_len =
+            suit::encode_envelope(&mut envelope, &digest[..digest_len], None, manifest).unwrap();
+        let mut slot = SuitImage::locator(64, envelope_len as u32).to_vec();
+        slot.resize(HEADER_LENGTH as usize, 0);
+        slot.extend_from_slice(&payload);
+        slot.extend_from_slice(&envelope[..envelope_len]);
+        let image = Image::parse(&slot).unwrap();
+        assert_eq!(image.validate(), Ok(()));
+        assert_eq!(image.sig_alg(), SIG_ALG_NONE);
+        let info = image.info();
+        assert_eq!(info.format, ImageFormat::Suit);
+        assert_eq!(
+            (info.version, info.security_counter, info.size),
+            (None, u32::MAX, slot.len() as u32)
+        );
+
+        assert!(matches!(
+            Image::parse(&[0xffu8; 300]),
+            Err(ImageError::Header(HeaderParseError::BadMagic(0xffff_ffff)))
+        ));
+    }
+}
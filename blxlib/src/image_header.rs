@@ -1,17 +1,27 @@
 use crate::crc32::crc32;
+use crate::layout::LAYOUT;
+use crate::sha256::{sha256, DIGEST_LENGTH};
+use crate::tlv::TlvIter;
+use crate::version::ImageVersion;
+use core::fmt;
 use core::ptr;
 
 pub const HEADER_LENGTH: u16 = 256;
 pub const HV_MAJOR: u8 = 0;
-pub const HV_MINOR: u8 = 1;
+pub const HV_MINOR: u8 = 5;
 pub const IMAGE_HEADER_MAGIC: u32 = 0xb00710ad;
 // pub const IMAGE_HEADER_MAGIC: u32 = 0xFFFFFFFF;
-pub const APP_BASE_ADDR: u32 = 0x1002_0000;
-pub const APP_UPDATE_ADDR: u32 = 0x1010_0000;
-pub const APP_SIZE: u32 = 0xe_0000;
+pub const APP_BASE_ADDR: u32 = LAYOUT.primary.addr();
+pub const APP_UPDATE_ADDR: u32 = LAYOUT.secondary.addr();
+pub const APP_SIZE: u32 = LAYOUT.primary.size;
+
+// `ImageHeader.sig_alg`
+pub const SIG_ALG_NONE: u8 = 0;
+pub const SIG_ALG_ED25519: u8 = 1;
+pub const SIG_ALG_ECDSA_P256: u8 = 2;
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ImageHeader {
     pub header_magic: u32,  // 4
     pub header_length: u16, // +2 = 6
@@ -26,24 +36,213 @@ pub struct ImageHeader {
     pub image_length: u32,    // +4 = 20
     pub signature: [u8; 128], // +128 = 148
 
-    pub payload_crc: u32,   // +4 = 152
-    pub padding: [u8; 100], // +100 = 252
+    pub payload_crc: u32,         // +4 = 152
+    pub sig_alg: u8,              // +1 = 153 (since hv 0.2)
+    pub payload_digest: [u8; 32], // +32 = 185 (since hv 0.3)
+    pub tlv: [u8; 63],            // +63 = 248 (since hv 0.5, padding before)
+    pub security_counter: u32,    // +4 = 252 (since hv 0.4)
 
     pub crc32: u32, // +4 = 256
 }
 
-pub fn load_from_addr(addr: u32) -> ImageHeader {
-    unsafe { ptr::read_volatile(addr as *const ImageHeader) }
+// `#[repr(C)]` without padding: the application places the header in flash as is
+const _: () = assert!(core::mem::size_of::<ImageHeader>() == HEADER_LENGTH as usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderParseError {
+    TooShort { len: usize },
+    BadMagic(u32),
+    UnsupportedVersion { major: u8, minor: u8 },
+    BadHeaderLength(u16),
+}
+
+impl fmt::Display for HeaderParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderParseError::TooShort { len } => {
+                write!(f, "header is too short: {} < {}", len, HEADER_LENGTH)
+            }
+            HeaderParseError::BadMagic(magic) => {
+                write!(f, "header_magic is not correct: {:08x}", magic)
+            }
+            HeaderParseError::UnsupportedVersion { major, minor } => {
+                write!(f, "header version is not supported: {}.{}", major, minor)
+            }
+            HeaderParseError::BadHeaderLength(len) => {
+                write!(f, "header_length is not correct: {}", len)
+            }
+        }
+    }
 }
 
-pub fn load_from_buf(buf: &[u8]) -> ImageHeader {
-    unsafe { *(buf.as_ptr() as *const ImageHeader) }
+impl core::error::Error for HeaderParseError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    BadMagic {
+        expected: u32,
+        actual: u32,
+    },
+    BadHeaderLength {
+        expected: u16,
+        actual: u16,
+    },
+    ImageTooLarge {
+        max: u32,
+        actual: u32,
+    },
+    PayloadTooShort {
+        expected: u32,
+        actual: u32,
+    },
+    HeaderCrc {
+        expected: u32,
+        actual: u32,
+    },
+    PayloadCrc {
+        expected: u32,
+        actual: u32,
+    },
+    PayloadDigest {
+        expected: [u8; DIGEST_LENGTH],
+        actual: [u8; DIGEST_LENGTH],
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::BadMagic { expected, actual } => write!(
+                f,
+                "header_magic is not correct: expected={:08x} actual={:08x}",
+                expected, actual
+            ),
+            ValidationError::BadHeaderLength { expected, actual } => write!(
+                f,
+                "header_length is not correct: expected={} actual={}",
+                expected, actual
+            ),
+            ValidationError::ImageTooLarge { max, actual } => write!(
+                f,
+                "image_length is too large: max={:08x} actual={:08x}",
+                max, actual
+            ),
+            ValidationError::PayloadTooShort { expected, actual } => write!(
+                f,
+                "payload is too short: expected={:08x} actual={:08x}",
+                expected, actual
+            ),
+            ValidationError::HeaderCrc { expected, actual } => write!(
+                f,
+                "crc32 is not correct: header={:08x} calc={:08x}",
+                expected, actual
+            ),
+            ValidationError::PayloadCrc { expected, actual } => write!(
+                f,
+                "payload_crc is not correct: header={:08x} calc={:08x}",
+                expected, actual
+            ),
+            ValidationError::PayloadDigest { expected, actual } => {
+                write!(f, "payload_digest is not correct: header=")?;
+                write_hex(f, expected)?;
+                write!(f, " calc=")?;
+                write_hex(f, actual)
+            }
+        }
+    }
 }
 
-pub fn as_bytes_with_len<T: ?Sized>(t: &T, len: usize) -> &[u8] {
+impl core::error::Error for ValidationError {}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
+/// Reads a header from memory mapped flash (XIP) at `addr`.
+pub fn load_from_addr(addr: u32) -> Result<ImageHeader, HeaderParseError> {
+    let buf = unsafe { ptr::read_volatile(addr as *const [u8; HEADER_LENGTH as usize]) };
+    ImageHeader::try_from(&buf[..])
+}
+
+/// Returns the payload area of the slot at `addr` as memory mapped flash (XIP).
+pub fn payload_from_addr(addr: u32) -> &'static [u8] {
     unsafe {
-        let p = t as *const _ as *const u8;
-        &*ptr::slice_from_raw_parts(p, len)
+        core::slice::from_raw_parts(
+            (addr + HEADER_LENGTH as u32) as *const u8,
+            (APP_SIZE - HEADER_LENGTH as u32) as usize,
+        )
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+impl TryFrom<&[u8]> for ImageHeader {
+    type Error = HeaderParseError;
+
+    /// Decodes the little-endian on-flash representation. `buf` may be longer
+    /// than the header (e.g. a whole image); only the first 256 bytes are used.
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        if buf.len() < HEADER_LENGTH as usize {
+            return Err(HeaderParseError::TooShort { len: buf.len() });
+        }
+        let header_magic = read_u32(buf, 0);
+        if header_magic != IMAGE_HEADER_MAGIC {
+            return Err(HeaderParseError::BadMagic(header_magic));
+        }
+        let hv_major = buf[6];
+        let hv_minor = buf[7];
+        // an older header lacks fields that validation needs
+        // (payload_digest, tlv, security_counter)
+        if hv_major != HV_MAJOR || hv_minor != HV_MINOR {
+            return Err(HeaderParseError::UnsupportedVersion {
+                major: hv_major,
+                minor: hv_minor,
+            });
+        }
+        let header_length = read_u16(buf, 4);
+        if header_length != HEADER_LENGTH {
+            return Err(HeaderParseError::BadHeaderLength(header_length));
+        }
+
+        let mut signature = [0u8; 128];
+        signature.copy_from_slice(&buf[20..148]);
+        let mut payload_digest = [0u8; DIGEST_LENGTH];
+        payload_digest.copy_from_slice(&buf[153..185]);
+        let mut tlv = [0u8; 63];
+        tlv.copy_from_slice(&buf[185..248]);
+
+        Ok(ImageHeader {
+            header_magic,
+            header_length,
+            hv_major,
+            hv_minor,
+            iv_major: buf[8],
+            iv_minor: buf[9],
+            iv_patch: read_u16(buf, 10),
+            iv_build: read_u32(buf, 12),
+            image_length: read_u32(buf, 16),
+            signature,
+            payload_crc: read_u32(buf, 148),
+            sig_alg: buf[152],
+            payload_digest,
+            tlv,
+            security_counter: read_u32(buf, 248),
+            crc32: read_u32(buf, 252),
+        })
     }
 }
 
@@ -67,13 +266,118 @@ impl ImageHeader {
             image_length: 0xe_0000,
             signature: [0u8; 128],
             payload_crc: 0,
-            padding: [0u8; 100],
+            sig_alg: SIG_ALG_NONE,
+            payload_digest: [0u8; DIGEST_LENGTH],
+            tlv: [0u8; 63],
+            security_counter: 0,
             crc32: 0,
         }
     }
+
+    /// Encodes the header in its little-endian on-flash representation.
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH as usize] {
+        let mut buf = [0u8; HEADER_LENGTH as usize];
+        buf[0..4].copy_from_slice(&self.header_magic.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.header_length.to_le_bytes());
+        buf[6] = self.hv_major;
+        buf[7] = self.hv_minor;
+        buf[8] = self.iv_major;
+        buf[9] = self.iv_minor;
+        buf[10..12].copy_from_slice(&self.iv_patch.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.iv_build.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.image_length.to_le_bytes());
+        buf[20..148].copy_from_slice(&self.signature);
+        buf[148..152].copy_from_slice(&self.payload_crc.to_le_bytes());
+        buf[152] = self.sig_alg;
+        buf[153..185].copy_from_slice(&self.payload_digest);
+        buf[185..248].copy_from_slice(&self.tlv);
+        buf[248..252].copy_from_slice(&self.security_counter.to_le_bytes());
+        buf[252..256].copy_from_slice(&self.crc32.to_le_bytes());
+        buf
+    }
+
+    pub fn version(&self) -> ImageVersion {
+        ImageVersion::new(self.iv_major, self.iv_minor, self.iv_patch, self.iv_build)
+    }
+
+    pub fn set_version(&mut self, version: ImageVersion) {
+        self.iv_major = version.major;
+        self.iv_minor = version.minor;
+        self.iv_patch = version.patch;
+        self.iv_build = version.build;
+    }
+
+    /// Entries of the TLV area (see `tlv`).
+    pub fn tlvs(&self) -> TlvIter<'_> {
+        TlvIter::new(&self.tlv)
+    }
+
     pub fn calc_crc32(&self) -> u32 {
-        let buf = as_bytes_with_len(self, HEADER_LENGTH as usize - 4);
-        crc32(buf)
+        crc32(&self.to_bytes()[..HEADER_LENGTH as usize - 4])
+    }
+
+    /// Checks the header and the payload that follows it. `payload` may be
+    /// longer than `image_length` (e.g. a whole slot); the excess is ignored.
+    pub fn validate(&self, payload: &[u8]) -> Result<(), ValidationError> {
+        self.validate_with(payload.len(), |len| {
+            (crc32(&payload[..len]), sha256(&payload[..len]))
+        })
+    }
+
+    /// `validate` with `digests` returning the crc32 and SHA-256 of the first
+    /// `len` bytes of a payload of `payload_length` bytes, for a payload that
+    /// is not plain text (`encrypt::validate`).
+    pub fn validate_with(
+        &self,
+        payload_length: usize,
+        digests: impl FnOnce(usize) -> (u32, [u8; DIGEST_LENGTH]),
+    ) -> Result<(), ValidationError> {
+        if self.header_magic != IMAGE_HEADER_MAGIC {
+            return Err(ValidationError::BadMagic {
+                expected: IMAGE_HEADER_MAGIC,
+                actual: self.header_magic,
+            });
+        }
+        if self.header_length != HEADER_LENGTH {
+            return Err(ValidationError::BadHeaderLength {
+                expected: HEADER_LENGTH,
+                actual: self.header_length,
+            });
+        }
+        let calc_crc32 = self.calc_crc32();
+        if self.crc32 != calc_crc32 {
+            return Err(ValidationError::HeaderCrc {
+                expected: self.crc32,
+                actual: calc_crc32,
+            });
+        }
+        let max_length = APP_SIZE - HEADER_LENGTH as u32;
+        if self.image_length > max_length {
+            return Err(ValidationError::ImageTooLarge {
+                max: max_length,
+                actual: self.image_length,
+            });
+        }
+        if (payload_length as u32) < self.image_length {
+            return Err(ValidationError::PayloadTooShort {
+                expected: self.image_length,
+                actual: payload_length as u32,
+            });
+        }
+        let (payload_crc, payload_digest) = digests(self.image_length as usize);
+        if self.payload_crc != payload_crc {
+            return Err(ValidationError::PayloadCrc {
+                expected: self.payload_crc,
+                actual: payload_crc,
+            });
+        }
+        if self.payload_digest != payload_digest {
+            return Err(ValidationError::PayloadDigest {
+                expected: self.payload_digest,
+                actual: payload_digest,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -95,10 +399,186 @@ mod tests {
         ih.image_length = 0;
         ih.signature = [0u8; 128];
         ih.payload_crc = 0;
-        ih.padding = [0u8; 100];
+        ih.sig_alg = 0;
+        ih.payload_digest = [0u8; 32];
+        ih.tlv = [0u8; 63];
+        ih.security_counter = 0;
 
         let crc32 = ih.calc_crc32();
         // https://crccalc.com/?crc=0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00&method=crc32&datatype=hex&outtype=0
         assert_eq!(crc32, 0xA66359F1);
     }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let mut ih = ImageHeader::new();
+        ih.iv_major = 1;
+        ih.iv_minor = 2;
+        ih.iv_patch = 0x0304;
+        ih.iv_build = 0xdeadbeef;
+        ih.image_length = 0x1234;
+        ih.signature[0] = 0x55;
+        ih.signature[127] = 0xaa;
+        ih.payload_crc = 0x01020304;
+        ih.sig_alg = SIG_ALG_ED25519;
+        ih.payload_digest[0] = 0x66;
+        ih.tlv[62] = 0x77;
+        ih.security_counter = 0x0a0b0c0d;
+        ih.crc32 = ih.calc_crc32();
+
+        assert_eq!(ih.version(), ImageVersion::new(1, 2, 0x0304, 0xdeadbeef));
+
+        let buf = ih.to_bytes();
+        assert_eq!(&buf[0..4], &[0xad, 0x10, 0x07, 0xb0]);
+        assert_eq!(&buf[4..6], &[0x00, 0x01]);
+        assert_eq!(&buf[10..12], &[0x04, 0x03]);
+        assert_eq!(&buf[16..20], &[0x34, 0x12, 0x00, 0x00]);
+        assert_eq!(buf[20], 0x55);
+        assert_eq!(buf[147], 0xaa);
+        assert_eq!(buf[152], SIG_ALG_ED25519);
+        assert_eq!(buf[153], 0x66);
+        assert_eq!(buf[247], 0x77);
+        assert_eq!(&buf[248..252], &[0x0d, 0x0c, 0x0b, 0x0a]);
+        assert_eq!(&buf[252..256], &ih.crc32.to_le_bytes());
+
+        assert_eq!(ImageHeader::try_from(&buf[..]), Ok(ih));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let buf = ImageHeader::new().to_bytes();
+        assert_eq!(
+            ImageHeader::try_from(&buf[..255]),
+            Err(HeaderParseError::TooShort { len: 255 })
+        );
+
+        assert_eq!(
+            ImageHeader::try_from(&[0xffu8; 256][..]),
+            Err(HeaderParseError::BadMagic(0xffffffff))
+        );
+
+        let mut bad = buf;
+        bad[6] = HV_MAJOR + 1;
+        assert_eq!(
+            ImageHeader::try_from(&bad[..]),
+            Err(HeaderParseError::UnsupportedVersion {
+                major: HV_MAJOR + 1,
+                minor: HV_MINOR
+            })
+        );
+
+        for minor in [HV_MINOR - 1, HV_MINOR + 1] {
+            let mut bad = buf;
+            bad[7] = minor;
+            assert_eq!(
+                ImageHeader::try_from(&bad[..]),
+                Err(HeaderParseError::UnsupportedVersion {
+                    major: HV_MAJOR,
+                    minor
+                })
+            );
+        }
+
+        let mut bad = buf;
+        bad[4] = 0x80;
+        assert_eq!(
+            ImageHeader::try_from(&bad[..]),
+            Err(HeaderParseError::BadHeaderLength(0x180))
+        );
+    }
+
+    fn signed_image(payload: &[u8]) -> ImageHeader {
+        let mut ih = ImageHeader::new();
+        ih.image_length = payload.len() as u32;
+        ih.payload_crc = crc32(payload);
+        ih.payload_digest = sha256(payload);
+        ih.crc32 = ih.calc_crc32();
+        ih
+    }
+
+    #[test]
+    fn test_validate() {
+        let payload = [0x5au8; 300];
+        let ih = signed_image(&payload);
+        assert_eq!(ih.validate(&payload), Ok(()));
+        // trailing bytes after image_length are ignored
+        assert_eq!(ih.validate(&[0x5au8; 400][..]), Ok(()));
+
+        assert_eq!(
+            ih.validate(&payload[..299]),
+            Err(ValidationError::PayloadTooShort {
+                expected: 300,
+                actual: 299
+            })
+        );
+
+        let mut corrupted = payload;
+        corrupted[10] = 0;
+        assert_eq!(
+            ih.validate(&corrupted),
+            Err(ValidationError::PayloadCrc {
+                expected: crc32(&payload),
+                actual: crc32(&corrupted)
+            })
+        );
+
+        let mut bad = ih;
+        bad.payload_digest[31] ^= 1;
+        bad.crc32 = bad.calc_crc32();
+        assert_eq!(
+            bad.validate(&payload),
+            Err(ValidationError::PayloadDigest {
+                expected: bad.payload_digest,
+                actual: ih.payload_digest
+            })
+        );
+
+        let mut bad = ih;
+        bad.iv_build = 1;
+        assert_eq!(
+            bad.validate(&payload),
+            Err(ValidationError::HeaderCrc {
+                expected: ih.crc32,
+                actual: bad.calc_crc32()
+            })
+        );
+
+        let mut bad = ih;
+        bad.header_magic = 0xffffffff;
+        assert_eq!(
+            bad.validate(&payload),
+            Err(ValidationError::BadMagic {
+                expected: IMAGE_HEADER_MAGIC,
+                actual: 0xffffffff
+            })
+        );
+
+        let mut bad = ih;
+        bad.header_length = 0;
+        assert_eq!(
+            bad.validate(&payload),
+            Err(ValidationError::BadHeaderLength {
+                expected: HEADER_LENGTH,
+                actual: 0
+            })
+        );
+
+        let mut bad = ih;
+        bad.image_length = APP_SIZE;
+        bad.crc32 = bad.calc_crc32();
+        assert_eq!(
+            bad.validate(&payload),
+            Err(ValidationError::ImageTooLarge {
+                max: APP_SIZE - HEADER_LENGTH as u32,
+                actual: APP_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_unaligned() {
+        let mut buf = [0u8; 257];
+        buf[1..].copy_from_slice(&ImageHeader::new().to_bytes());
+        assert_eq!(ImageHeader::try_from(&buf[1..]), Ok(ImageHeader::new()));
+    }
 }
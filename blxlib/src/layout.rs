@@ -0,0 +1,167 @@
+//! Flash partitions. Every address used by the bootloader, the application,
+//! the linker scripts and the tools is derived from `LAYOUT`.
+
+/// XIP base address of the QSPI flash.
+pub const FLASH_BASE: u32 = 0x1000_0000;
+/// W25Q16JV on the Raspberry Pi Pico
+pub const FLASH_SIZE: u32 = 0x20_0000;
+/// Erase granularity
+pub const SECTOR_SIZE: u32 = 0x1000;
+/// Program granularity
+pub const PAGE_SIZE: u32 = 0x100;
+/// boot2 occupies the first 256 bytes of the bootloader partition.
+pub const BOOT2_SIZE: u32 = 0x100;
+
+/// Striped SRAM0..3 (SRAM4/5 are not used).
+pub const SRAM_BASE: u32 = 0x2000_0000;
+pub const SRAM_SIZE: u32 = 0x4_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// Offset from the start of the flash (as used by the flash commands).
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    pub const fn new(offset: u32, size: u32) -> Self {
+        Partition { offset, size }
+    }
+
+    /// XIP address of the first byte.
+    pub const fn addr(&self) -> u32 {
+        FLASH_BASE + self.offset
+    }
+
+    /// Offset of the first byte after the partition.
+    pub const fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    pub const fn is_aligned(&self) -> bool {
+        self.offset.is_multiple_of(SECTOR_SIZE) && self.size.is_multiple_of(SECTOR_SIZE)
+    }
+
+    pub const fn overlaps(&self, other: &Partition) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+
+    pub const fn sectors(&self) -> u32 {
+        self.size / SECTOR_SIZE
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashLayout {
+    /// boot2 + bootloader
+    pub bootloader: Partition,
+    /// The image that is executed (`.image_header` + application).
+    pub primary: Partition,
+    /// The update image.
+    pub secondary: Partition,
+    /// Work area for swapping the primary and secondary images
+    /// (first sector: buffer, last three sectors: swap request and status).
+    pub scratch: Partition,
+    /// Highest accepted security counter (two sectors, see `rollback`).
+    pub rollback: Partition,
+}
+
+impl FlashLayout {
+    pub const fn partitions(&self) -> [(&'static str, Partition); 5] {
+        [
+            ("bootloader", self.bootloader),
+            ("primary", self.primary),
+            ("secondary", self.secondary),
+            ("scratch", self.scratch),
+            ("rollback", self.rollback),
+        ]
+    }
+
+    /// Panics (at compile time when used in a const context) if the layout is inconsistent.
+    pub const fn check(&self) {
+        let partitions = self.partitions();
+        let mut i = 0;
+        while i < partitions.len() {
+            let p = partitions[i].1;
+            assert!(p.size > 0, "empty partition");
+            assert!(p.is_aligned(), "partition is not sector aligned");
+            assert!(p.end() <= FLASH_SIZE, "partition exceeds the flash");
+            let mut j = i + 1;
+            while j < partitions.len() {
+                assert!(!p.overlaps(&partitions[j].1), "partitions overlap");
+                j += 1;
+            }
+            i += 1;
+        }
+        assert!(
+            self.bootloader.offset == 0,
+            "bootloader must start at the beginning of the flash (boot2)"
+        );
+        assert!(
+            self.primary.size == self.secondary.size,
+            "primary and secondary slots must have the same size"
+        );
+        assert!(
+            self.scratch.sectors() >= 4,
+            "scratch needs a swap buffer sector, a request sector and two status sectors"
+        );
+        assert!(
+            self.rollback.sectors() == 2,
+            "rollback needs exactly two sectors"
+        );
+    }
+}
+
+pub const LAYOUT: FlashLayout = FlashLayout {
+    bootloader: Partition::new(0x0000_0000, 0x2_0000),
+    primary: Partition::new(0x0002_0000, 0xe_0000),
+    secondary: Partition::new(0x0010_0000, 0xe_0000),
+    scratch: Partition::new(0x001e_0000, 0x1_e000),
+    rollback: Partition::new(0x001f_e000, 0x2000),
+};
+
+const _: () = LAYOUT.check();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(LAYOUT.bootloader.addr(), 0x1000_0000);
+        assert_eq!(LAYOUT.primary.addr(), 0x1002_0000);
+        assert_eq!(LAYOUT.secondary.addr(), 0x1010_0000);
+        assert_eq!(LAYOUT.scratch.addr(), 0x101e_0000);
+        assert_eq!(LAYOUT.rollback.addr(), 0x101f_e000);
+        assert_eq!(LAYOUT.rollback.end(), FLASH_SIZE);
+        assert_eq!(LAYOUT.primary.sectors(), 224);
+    }
+
+    #[test]
+    fn test_partition() {
+        let a = Partition::new(0x1000, 0x2000);
+        assert!(a.is_aligned());
+        assert!(!Partition::new(0x1100, 0x1000).is_aligned());
+        assert!(!Partition::new(0x1000, 0x800).is_aligned());
+        assert!(a.overlaps(&Partition::new(0x2000, 0x1000)));
+        assert!(!a.overlaps(&Partition::new(0x3000, 0x1000)));
+        assert!(!a.overlaps(&Partition::new(0x0000, 0x1000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "partitions overlap")]
+    fn test_check_overlap() {
+        let mut layout = LAYOUT;
+        layout.secondary.offset = 0x000f_f000;
+        layout.check();
+    }
+
+    #[test]
+    #[should_panic(expected = "not sector aligned")]
+    fn test_check_alignment() {
+        let mut layout = LAYOUT;
+        layout.scratch.offset += 0x100;
+        layout.scratch.size -= 0x1000;
+        layout.check();
+    }
+}
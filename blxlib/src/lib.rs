@@ -1,4 +1,29 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
+pub mod app;
+pub mod boot;
+pub mod cbor;
+pub mod compress;
 pub mod crc32;
+pub mod delta;
+pub mod encrypt;
+pub mod flash;
+pub mod image;
 pub mod image_header;
+pub mod layout;
+pub mod mcuboot;
+pub mod recovery;
+pub mod rollback;
+pub mod sha256;
+pub mod shell;
+pub mod signature;
+pub mod smp;
+pub mod suit;
+pub mod swap;
+pub mod tlv;
+pub mod trigger;
+pub mod version;
+pub mod ymodem;
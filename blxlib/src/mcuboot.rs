@@ -0,0 +1,812 @@
+//! MCUboot image format, so that images built and signed with MCUboot's
+//! `imgtool` can be booted and updated like native images.
+//!
+//! An image is a 32 byte header (padded to `hdr_size`), the payload, an
+//! optional protected TLV area and the TLV area. The `TLV_SHA256` entry is the
+//! hash of everything before the (unprotected) TLV area, so the protected
+//! entries such as `TLV_SEC_CNT` are covered by it. The signature entries sign
+//! that hash.
+//!
+//! The trailer at the end of the slot marks an image as pending (`magic`) and
+//! as to be kept (`image_ok`).
+//!
+//! `ImageWriter` lays out an image the same way, for `bintool` and tests.
+
+use crate::sha256::{Sha256, DIGEST_LENGTH};
+use crate::version::ImageVersion;
+use core::fmt;
+
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+use crate::signature::SignatureError;
+#[cfg(feature = "ed25519")]
+use crate::signature::ED25519_PUBLIC_KEY_LENGTH;
+#[cfg(feature = "p256")]
+use crate::signature::P256_PUBLIC_KEY_LENGTH;
+
+pub const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+pub const IMAGE_HEADER_SIZE: usize = 32;
+pub const TLV_INFO_MAGIC: u16 = 0x6907;
+pub const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+pub const TLV_INFO_SIZE: usize = 4;
+const TLV_ENTRY_HEADER_SIZE: usize = 4;
+
+/// SHA-256 of the public key that signed the image.
+pub const TLV_KEYHASH: u16 = 0x01;
+pub const TLV_SHA256: u16 = 0x10;
+/// DER encoded ECDSA P-256 signature of the hash.
+pub const TLV_ECDSA_SIG: u16 = 0x22;
+pub const TLV_ED25519: u16 = 0x24;
+/// Security counter (u32), only valid in the protected area.
+pub const TLV_SEC_CNT: u16 = 0x50;
+
+pub const BOOT_MAX_ALIGN: u32 = 8;
+pub const BOOT_MAGIC: [u8; 16] = [
+    0x77, 0xc2, 0x95, 0xf3, 0x60, 0xd2, 0xef, 0x7f, 0x35, 0x52, 0x50, 0x0f, 0x2c, 0xb6, 0x79, 0x80,
+];
+pub const BOOT_FLAG_SET: u8 = 0x01;
+/// The trailer fields from `swap_size` to the end of the slot.
+pub const TRAILER_SIZE: u32 = 4 * BOOT_MAX_ALIGN + BOOT_MAGIC.len() as u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum McubootError {
+    TooShort {
+        len: usize,
+    },
+    BadMagic(u32),
+    BadHeaderSize(u16),
+    /// No valid TLV info (area header) at `offset`.
+    BadTlvInfo {
+        offset: usize,
+    },
+    /// The TLV entry at `offset` extends beyond its area.
+    TruncatedTlv {
+        offset: usize,
+    },
+    MissingHash,
+    /// The buffer of `ImageWriter` is too small, or an area exceeds 64 KiB.
+    Full,
+    Hash {
+        expected: [u8; DIGEST_LENGTH],
+        actual: [u8; DIGEST_LENGTH],
+    },
+}
+
+impl fmt::Display for McubootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            McubootError::TooShort { len } => write!(f, "mcuboot image is too short: {}", len),
+            McubootError::BadMagic(magic) => {
+                write!(f, "mcuboot magic is not correct: {:08x}", magic)
+            }
+            McubootError::BadHeaderSize(size) => {
+                write!(f, "mcuboot hdr_size is not correct: {}", size)
+            }
+            McubootError::BadTlvInfo { offset } => {
+                write!(f, "mcuboot tlv info at {:08x} is not correct", offset)
+            }
+            McubootError::TruncatedTlv { offset } => {
+                write!(f, "mcuboot tlv at {:08x} is truncated", offset)
+            }
+            McubootError::MissingHash => write!(f, "mcuboot image has no sha256 tlv"),
+            McubootError::Full => write!(f, "mcuboot image buffer is full"),
+            McubootError::Hash { expected, actual } => {
+                write!(f, "mcuboot hash is not correct: tlv=")?;
+                for b in expected {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, " calc=")?;
+                for b in actual {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl core::error::Error for McubootError {}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// `struct image_header` of MCUboot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct McubootHeader {
+    pub load_addr: u32,
+    pub hdr_size: u16,
+    /// Size of the protected TLV area including its TLV info, 0 if there is none.
+    pub protect_tlv_size: u16,
+    pub img_size: u32,
+    pub flags: u32,
+    pub version: ImageVersion,
+}
+
+impl TryFrom<&[u8]> for McubootHeader {
+    type Error = McubootError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, McubootError> {
+        if buf.len() < IMAGE_HEADER_SIZE {
+            return Err(McubootError::TooShort { len: buf.len() });
+        }
+        let magic = read_u32(buf, 0);
+        if magic != IMAGE_MAGIC {
+            return Err(McubootError::BadMagic(magic));
+        }
+        let hdr_size = read_u16(buf, 8);
+        if (hdr_size as usize) < IMAGE_HEADER_SIZE {
+            return Err(McubootError::BadHeaderSize(hdr_size));
+        }
+        Ok(McubootHeader {
+            load_addr: read_u32(buf, 4),
+            hdr_size,
+            protect_tlv_size: read_u16(buf, 10),
+            img_size: read_u32(buf, 12),
+            flags: read_u32(buf, 16),
+            version: ImageVersion::new(buf[20], buf[21], read_u16(buf, 22), read_u32(buf, 24)),
+        })
+    }
+}
+
+impl McubootHeader {
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_SIZE] {
+        let mut buf = [0u8; IMAGE_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.load_addr.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.hdr_size.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.protect_tlv_size.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.img_size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.flags.to_le_bytes());
+        buf[20] = self.version.major;
+        buf[21] = self.version.minor;
+        buf[22..24].copy_from_slice(&self.version.patch.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.version.build.to_le_bytes());
+        buf
+    }
+
+    /// Offset of the TLV areas: the header and the payload. `None` if it
+    /// does not fit in 32 bits.
+    pub fn tlv_offset(&self) -> Option<usize> {
+        let offset = (self.hdr_size as u32).checked_add(self.img_size)?;
+        usize::try_from(offset).ok()
+    }
+}
+
+/// Iterates over `(type, value)` of the entries of a TLV area that
+/// `McubootImage::parse` has checked.
+#[derive(Clone, Debug)]
+pub struct TlvIter<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entries = self.entries;
+        if entries.len() < TLV_ENTRY_HEADER_SIZE {
+            return None;
+        }
+        let end = TLV_ENTRY_HEADER_SIZE + read_u16(entries, 2) as usize;
+        let value = entries.get(TLV_ENTRY_HEADER_SIZE..end)?;
+        self.entries = &entries[end..];
+        Some((read_u16(entries, 0), value))
+    }
+}
+
+/// Checks the TLV area of `magic` at `offset` of `buf` and returns its entries.
+fn tlv_area(buf: &[u8], offset: usize, magic: u16) -> Result<&[u8], McubootError> {
+    let bad_info = McubootError::BadTlvInfo { offset };
+    let info = buf.get(offset..offset + TLV_INFO_SIZE).ok_or(bad_info)?;
+    let tlv_tot = read_u16(info, 2) as usize;
+    if read_u16(info, 0) != magic || tlv_tot < TLV_INFO_SIZE {
+        return Err(bad_info);
+    }
+    let entries = buf
+        .get(offset + TLV_INFO_SIZE..offset + tlv_tot)
+        .ok_or(McubootError::TruncatedTlv { offset })?;
+    let mut pos = 0;
+    while pos < entries.len() {
+        let len = entries
+            .get(pos + 2..pos + TLV_ENTRY_HEADER_SIZE)
+            .map(|len| read_u16(len, 0) as usize);
+        match len {
+            Some(len) if pos + TLV_ENTRY_HEADER_SIZE + len <= entries.len() => {
+                pos += TLV_ENTRY_HEADER_SIZE + len;
+            }
+            _ => {
+                return Err(McubootError::TruncatedTlv {
+                    offset: offset + TLV_INFO_SIZE + pos,
+                })
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// An MCUboot image at the start of a slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct McubootImage<'a> {
+    pub header: McubootHeader,
+    /// Header, payload and protected TLV area, i.e. what the hash covers.
+    hashed: &'a [u8],
+    protected: &'a [u8],
+    unprotected: &'a [u8],
+}
+
+impl<'a> McubootImage<'a> {
+    /// Parses the header and the TLV areas. `buf` may be longer than the
+    /// image (e.g. a whole slot).
+    pub fn parse(buf: &'a [u8]) -> Result<Self, McubootError> {
+        let header = McubootHeader::try_from(buf)?;
+        let tlv_offset = header
+            .tlv_offset()
+            .filter(|&offset| offset <= buf.len())
+            .ok_or(McubootError::TooShort { len: buf.len() })?;
+        let protect_tlv_size = header.protect_tlv_size as usize;
+        let protected = if protect_tlv_size == 0 {
+            &[]
+        } else {
+            let protected = tlv_area(buf, tlv_offset, TLV_PROT_INFO_MAGIC)?;
+            if protected.len() + TLV_INFO_SIZE != protect_tlv_size {
+                return Err(McubootError::BadTlvInfo { offset: tlv_offset });
+            }
+            protected
+        };
+        let hashed_len = tlv_offset
+            .checked_add(protect_tlv_size)
+            .ok_or(McubootError::BadTlvInfo { offset: tlv_offset })?;
+        let unprotected = tlv_area(buf, hashed_len, TLV_INFO_MAGIC)?;
+        Ok(McubootImage {
+            header,
+            hashed: &buf[..hashed_len],
+            protected,
+            unprotected,
+        })
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        let end = self.hashed.len() - self.header.protect_tlv_size as usize;
+        &self.hashed[self.header.hdr_size as usize..end]
+    }
+
+    /// Bytes from the start of the header to the end of the TLV area.
+    pub fn size(&self) -> usize {
+        self.hashed.len() + TLV_INFO_SIZE + self.unprotected.len()
+    }
+
+    pub fn protected_tlvs(&self) -> TlvIter<'a> {
+        TlvIter {
+            entries: self.protected,
+        }
+    }
+
+    pub fn tlvs(&self) -> TlvIter<'a> {
+        TlvIter {
+            entries: self.unprotected,
+        }
+    }
+
+    /// Looks up the first entry of `tlv_type` in the protected, then in the
+    /// unprotected area.
+    pub fn find(&self, tlv_type: u16) -> Option<&'a [u8]> {
+        self.protected_tlvs()
+            .chain(self.tlvs())
+            .find(|&(t, _)| t == tlv_type)
+            .map(|(_, value)| value)
+    }
+
+    /// `TLV_SEC_CNT` of the protected area, 0 if there is none.
+    pub fn security_counter(&self) -> u32 {
+        self.protected_tlvs()
+            .find(|&(t, value)| t == TLV_SEC_CNT && value.len() == 4)
+            .map_or(0, |(_, value)| read_u32(value, 0))
+    }
+
+    /// The `TLV_SHA256` entry.
+    pub fn hash(&self) -> Option<&'a [u8; DIGEST_LENGTH]> {
+        self.tlvs()
+            .find(|&(t, _)| t == TLV_SHA256)
+            .and_then(|(_, value)| value.try_into().ok())
+    }
+
+    pub fn calc_hash(&self) -> [u8; DIGEST_LENGTH] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hashed);
+        hasher.finalize()
+    }
+
+    /// Checks the hash of the image. Signatures are checked with
+    /// `verify_ed25519` or `verify_p256` afterwards.
+    pub fn validate(&self) -> Result<(), McubootError> {
+        let expected = self.hash().ok_or(McubootError::MissingHash)?;
+        let actual = self.calc_hash();
+        if *expected != actual {
+            return Err(McubootError::Hash {
+                expected: *expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Writes an image into a buffer like `imgtool sign` lays it out:
+///
+/// ```ignore
+/// let mut writer = ImageWriter::new(&mut buf, &header, payload)?;
+/// writer.push_protected(TLV_SEC_CNT, &security_counter.to_le_bytes())?;
+/// let hash = writer.hash();
+/// writer.push(TLV_SHA256, &hash)?;
+/// writer.push(TLV_ED25519, &sign(&hash))?;
+/// let len = writer.finish();
+/// ```
+pub struct ImageWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    /// Offsets of the TLV info of the protected and the unprotected area.
+    protected: Option<usize>,
+    unprotected: Option<usize>,
+}
+
+impl<'a> ImageWriter<'a> {
+    /// Writes `header` (with `img_size` set to the payload) padded to
+    /// `hdr_size`, and `payload`.
+    pub fn new(
+        buf: &'a mut [u8],
+        header: &McubootHeader,
+        payload: &[u8],
+    ) -> Result<Self, McubootError> {
+        let hdr_size = header.hdr_size as usize;
+        let len = hdr_size + payload.len();
+        if hdr_size < IMAGE_HEADER_SIZE || buf.len() < len {
+            return Err(McubootError::Full);
+        }
+        let header = McubootHeader {
+            protect_tlv_size: 0,
+            img_size: u32::try_from(payload.len()).map_err(|_| McubootError::Full)?,
+            ..*header
+        };
+        buf[..IMAGE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        buf[IMAGE_HEADER_SIZE..hdr_size].fill(0);
+        buf[hdr_size..len].copy_from_slice(payload);
+        Ok(ImageWriter {
+            buf,
+            len,
+            protected: None,
+            unprotected: None,
+        })
+    }
+
+    /// Adds an entry to the protected TLV area, which the hash covers. Must
+    /// come before `hash` and `push`.
+    pub fn push_protected(&mut self, tlv_type: u16, value: &[u8]) -> Result<(), McubootError> {
+        if self.unprotected.is_some() {
+            return Err(McubootError::BadTlvInfo { offset: self.len });
+        }
+        if self.protected.is_none() {
+            self.protected = Some(self.begin_area(TLV_PROT_INFO_MAGIC)?);
+        }
+        self.push_entry(tlv_type, value)?;
+        let tlv_tot = self.end_area(self.protected)?;
+        // the header records the size of the protected area
+        self.buf[10..12].copy_from_slice(&tlv_tot.to_le_bytes());
+        Ok(())
+    }
+
+    /// SHA-256 of the header, the payload and the protected TLV area.
+    pub fn hash(&self) -> [u8; DIGEST_LENGTH] {
+        let end = self.unprotected.unwrap_or(self.len);
+        let mut hasher = Sha256::new();
+        hasher.update(&self.buf[..end]);
+        hasher.finalize()
+    }
+
+    /// Adds an entry to the (unprotected) TLV area.
+    pub fn push(&mut self, tlv_type: u16, value: &[u8]) -> Result<(), McubootError> {
+        if self.unprotected.is_none() {
+            self.unprotected = Some(self.begin_area(TLV_INFO_MAGIC)?);
+        }
+        self.push_entry(tlv_type, value)?;
+        self.end_area(self.unprotected)?;
+        Ok(())
+    }
+
+    /// Length of the image.
+    pub fn finish(mut self) -> Result<usize, McubootError> {
+        if self.unprotected.is_none() {
+            self.unprotected = Some(self.begin_area(TLV_INFO_MAGIC)?);
+        }
+        Ok(self.len)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), McubootError> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(McubootError::Full)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn begin_area(&mut self, magic: u16) -> Result<usize, McubootError> {
+        let offset = self.len;
+        self.write(&magic.to_le_bytes())?;
+        self.write(&(TLV_INFO_SIZE as u16).to_le_bytes())?;
+        Ok(offset)
+    }
+
+    fn push_entry(&mut self, tlv_type: u16, value: &[u8]) -> Result<(), McubootError> {
+        let len = u16::try_from(value.len()).map_err(|_| McubootError::Full)?;
+        self.write(&tlv_type.to_le_bytes())?;
+        self.write(&len.to_le_bytes())?;
+        self.write(value)
+    }
+
+    /// Updates `tlv_tot` of the area at `offset` and returns it.
+    fn end_area(&mut self, offset: Option<usize>) -> Result<u16, McubootError> {
+        let offset = offset.unwrap_or(self.len);
+        let tlv_tot = u16::try_from(self.len - offset).map_err(|_| McubootError::Full)?;
+        self.buf[offset + 2..offset + 4].copy_from_slice(&tlv_tot.to_le_bytes());
+        Ok(tlv_tot)
+    }
+}
+
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+fn signed_hash<'a>(image: &McubootImage<'a>) -> Result<&'a [u8; DIGEST_LENGTH], SignatureError> {
+    image.hash().ok_or(SignatureError::Mismatch)
+}
+
+/// Checks the `TLV_ED25519` signature of the hash, which is only meaningful
+/// for an image that has passed `McubootImage::validate`.
+#[cfg(feature = "ed25519")]
+pub fn verify_ed25519(
+    image: &McubootImage,
+    public_key: &[u8; ED25519_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let sig = image.find(TLV_ED25519).ok_or(SignatureError::Missing)?;
+    let sig = Signature::from_slice(sig).map_err(|_| SignatureError::Mismatch)?;
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    key.verify_strict(signed_hash(image)?, &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// Checks the `TLV_ECDSA_SIG` signature of the hash, see `verify_ed25519`.
+#[cfg(feature = "p256")]
+pub fn verify_p256(
+    image: &McubootImage,
+    public_key: &[u8; P256_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use p256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    let sig = image.find(TLV_ECDSA_SIG).ok_or(SignatureError::Missing)?;
+    let sig = der_signature(sig).ok_or(SignatureError::Mismatch)?;
+    let sig = Signature::from_slice(&sig).map_err(|_| SignatureError::Mismatch)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    key.verify_prehash(signed_hash(image)?, &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// Decodes a DER `SEQUENCE { INTEGER r, INTEGER s }` into the fixed size
+/// `r || s` encoding.
+#[cfg(feature = "p256")]
+fn der_signature(der: &[u8]) -> Option<[u8; 64]> {
+    fn integer<'a>(der: &'a [u8], out: &mut [u8]) -> Option<&'a [u8]> {
+        let (&[0x02, len], rest) = der.split_first_chunk()? else {
+            return None;
+        };
+        let (value, rest) = rest.split_at_checked(len as usize)?;
+        // positive integers may have a leading zero byte
+        let value = value.strip_prefix(&[0]).unwrap_or(value);
+        let pad = out.len().checked_sub(value.len())?;
+        out[pad..].copy_from_slice(value);
+        Some(rest)
+    }
+
+    let (&[0x30, len], body) = der.split_first_chunk()? else {
+        return None;
+    };
+    if body.len() != len as usize {
+        return None;
+    }
+    let mut sig = [0u8; 64];
+    let (r, s) = sig.split_at_mut(32);
+    let rest = integer(body, r)?;
+    integer(rest, s)?.is_empty().then_some(sig)
+}
+
+/// The fields of the trailer at the end of a slot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Trailer {
+    /// `BOOT_MAGIC` is present: the image is pending or has been installed.
+    pub magic: bool,
+    pub image_ok: bool,
+    pub copy_done: bool,
+    pub swap_info: u8,
+}
+
+impl Trailer {
+    /// Decodes the trailer from the end of `slot`, which must be at least
+    /// `TRAILER_SIZE` bytes.
+    pub fn from_slot(slot: &[u8]) -> Self {
+        let end = slot.len();
+        let magic = end - BOOT_MAGIC.len();
+        let field = |n: usize| slot[magic - n * BOOT_MAX_ALIGN as usize];
+        Trailer {
+            magic: slot[magic..] == BOOT_MAGIC,
+            image_ok: field(1) == BOOT_FLAG_SET,
+            copy_done: field(2) == BOOT_FLAG_SET,
+            swap_info: field(3),
+        }
+    }
+
+    /// A pending image that MCUboot would install permanently.
+    pub fn is_permanent(&self) -> bool {
+        self.magic && self.image_ok
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// An image like `imgtool sign --header-size 0x100 --security-counter`
+    /// writes it, with the unprotected `signatures` after the hash.
+    pub(crate) fn build_image(
+        hdr_size: u16,
+        payload: &[u8],
+        security_counter: Option<u32>,
+        signatures: &[(u16, &[u8])],
+    ) -> Vec<u8> {
+        let header = McubootHeader {
+            load_addr: 0,
+            hdr_size,
+            protect_tlv_size: 0,
+            img_size: 0,
+            flags: 0,
+            version: ImageVersion::new(1, 2, 3, 4),
+        };
+        let mut buf = vec![0u8; hdr_size as usize + payload.len() + 0x200];
+        let mut writer = ImageWriter::new(&mut buf, &header, payload).unwrap();
+        if let Some(security_counter) = security_counter {
+            writer
+                .push_protected(TLV_SEC_CNT, &security_counter.to_le_bytes())
+                .unwrap();
+        }
+        let hash = writer.hash();
+        writer.push(TLV_SHA256, &hash).unwrap();
+        for &(tlv_type, value) in signatures {
+            writer.push(tlv_type, value).unwrap();
+        }
+        let len = writer.finish().unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn test_parse() {
+        let payload = [0x5au8; 1000];
+        let mut image = build_image(0x100, &payload, Some(7), &[]);
+        let size = image.len();
+        image.resize(0x2000, 0xff);
+
+        let parsed = McubootImage::parse(&image).unwrap();
+        assert_eq!(parsed.header.version, ImageVersion::new(1, 2, 3, 4));
+        assert_eq!(parsed.payload(), &payload[..]);
+        assert_eq!(parsed.size(), size);
+        assert_eq!(parsed.security_counter(), 7);
+        assert_eq!(parsed.hash(), Some(&parsed.calc_hash()));
+        assert_eq!(parsed.protected_tlvs().count(), 1);
+        assert_eq!(parsed.tlvs().count(), 1);
+        assert_eq!(parsed.find(TLV_SEC_CNT), Some(&7u32.to_le_bytes()[..]));
+        assert_eq!(parsed.find(TLV_ED25519), None);
+        assert_eq!(parsed.validate(), Ok(()));
+        assert_eq!(
+            McubootHeader::try_from(&parsed.header.to_bytes()[..]),
+            Ok(parsed.header)
+        );
+
+        // the hash covers the protected TLVs
+        let mut tampered = image.clone();
+        tampered[0x100 + 1000 + TLV_INFO_SIZE + TLV_ENTRY_HEADER_SIZE] = 8;
+        let parsed = McubootImage::parse(&tampered).unwrap();
+        assert_eq!(parsed.security_counter(), 8);
+        assert!(matches!(parsed.validate(), Err(McubootError::Hash { .. })));
+
+        let mut tampered = image.clone();
+        tampered[0x100] ^= 0x01;
+        assert!(matches!(
+            McubootImage::parse(&tampered).unwrap().validate(),
+            Err(McubootError::Hash { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let image = build_image(0x100, &[0x5au8; 100], Some(0), &[]);
+        let tlv_offset = 0x100 + 100;
+        assert_eq!(
+            McubootImage::parse(&image[..16]),
+            Err(McubootError::TooShort { len: 16 })
+        );
+        assert_eq!(
+            McubootImage::parse(&[0xffu8; 64]),
+            Err(McubootError::BadMagic(0xffff_ffff))
+        );
+
+        let mut bad = image.clone();
+        bad[8] = 16;
+        bad[9] = 0;
+        assert_eq!(
+            McubootImage::parse(&bad),
+            Err(McubootError::BadHeaderSize(16))
+        );
+
+        let mut bad = image.clone();
+        bad[tlv_offset] = 0;
+        assert_eq!(
+            McubootImage::parse(&bad),
+            Err(McubootError::BadTlvInfo { offset: tlv_offset })
+        );
+
+        assert_eq!(
+            McubootImage::parse(&image[..image.len() - 1]),
+            Err(McubootError::TruncatedTlv {
+                offset: tlv_offset + 12
+            })
+        );
+
+        // an entry longer than its area
+        let mut bad = image.clone();
+        bad[tlv_offset + TLV_INFO_SIZE + 2] = 5;
+        assert_eq!(
+            McubootImage::parse(&bad),
+            Err(McubootError::TruncatedTlv {
+                offset: tlv_offset + TLV_INFO_SIZE
+            })
+        );
+
+        let mut no_hash = image[..tlv_offset + 12].to_vec();
+        no_hash.extend_from_slice(&TLV_INFO_MAGIC.to_le_bytes());
+        no_hash.extend_from_slice(&(TLV_INFO_SIZE as u16).to_le_bytes());
+        let parsed = McubootImage::parse(&no_hash).unwrap();
+        assert_eq!(parsed.validate(), Err(McubootError::MissingHash));
+
+        // hdr_size + img_size does not fit in 32 bits
+        let mut bad = image.clone();
+        bad[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            McubootImage::parse(&bad),
+            Err(McubootError::TooShort { len: bad.len() })
+        );
+        let mut header = McubootHeader::try_from(&image[..]).unwrap();
+        header.img_size = u32::MAX;
+        assert_eq!(header.tlv_offset(), None);
+    }
+
+    #[test]
+    fn test_writer() {
+        let header = McubootHeader::try_from(&build_image(32, &[], None, &[])[..]).unwrap();
+        let mut buf = [0u8; 64];
+        assert!(ImageWriter::new(&mut buf, &header, &[0; 33]).is_err());
+
+        let mut writer = ImageWriter::new(&mut buf, &header, &[0x5a; 16]).unwrap();
+        let hash = writer.hash();
+        writer.push(TLV_SHA256, &[0; 8]).unwrap();
+        // the hash covers only what is before the TLV area
+        assert_eq!(writer.hash(), hash);
+        assert_eq!(
+            writer.push_protected(TLV_SEC_CNT, &[0; 4]),
+            Err(McubootError::BadTlvInfo { offset: 64 })
+        );
+        assert_eq!(writer.push(TLV_SHA256, &[0; 1]), Err(McubootError::Full));
+        assert_eq!(writer.finish(), Ok(64));
+        let parsed = McubootImage::parse(&buf).unwrap();
+        assert_eq!(parsed.payload(), &[0x5a; 16]);
+        assert_eq!(
+            parsed.tlvs().collect::<Vec<_>>(),
+            [(TLV_SHA256, &[0; 8][..])]
+        );
+    }
+
+    #[test]
+    fn test_trailer() {
+        let mut slot = [0xffu8; 0x1000];
+        assert_eq!(
+            Trailer::from_slot(&slot),
+            Trailer {
+                magic: false,
+                image_ok: false,
+                copy_done: false,
+                swap_info: 0xff
+            }
+        );
+
+        slot[0x1000 - 16..].copy_from_slice(&BOOT_MAGIC);
+        let trailer = Trailer::from_slot(&slot);
+        assert!(trailer.magic && !trailer.is_permanent());
+        slot[0x1000 - 24] = BOOT_FLAG_SET;
+        slot[0x1000 - 40] = 0x02;
+        let trailer = Trailer::from_slot(&slot);
+        assert!(trailer.is_permanent());
+        assert_eq!(trailer.swap_info, 0x02);
+        assert!(!trailer.copy_done);
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_verify_ed25519() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let unsigned = build_image(0x100, &[0x5au8; 100], Some(3), &[]);
+        let hash = McubootImage::parse(&unsigned).unwrap().calc_hash();
+        let sig = key.sign(&hash).to_bytes();
+        let image = build_image(0x100, &[0x5au8; 100], Some(3), &[(TLV_ED25519, &sig)]);
+
+        let parsed = McubootImage::parse(&image).unwrap();
+        assert_eq!(parsed.validate(), Ok(()));
+        assert_eq!(verify_ed25519(&parsed, &public_key), Ok(()));
+        let wrong_key = SigningKey::from_bytes(&[8u8; 32])
+            .verifying_key()
+            .to_bytes();
+        assert_eq!(
+            verify_ed25519(&parsed, &wrong_key),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify_ed25519(&McubootImage::parse(&unsigned).unwrap(), &public_key),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_verify_p256() {
+        use p256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let public_key: [u8; P256_PUBLIC_KEY_LENGTH] = point.as_bytes().try_into().unwrap();
+        let unsigned = build_image(0x100, &[0x5au8; 100], Some(3), &[]);
+        let hash = McubootImage::parse(&unsigned).unwrap().calc_hash();
+        let sig: Signature = key.sign_prehash(&hash).unwrap();
+
+        // DER encoding with the leading zeros of positive integers
+        let mut der = Vec::new();
+        for half in [&sig.to_bytes()[..32], &sig.to_bytes()[32..]] {
+            let zero = half[0] >= 0x80;
+            der.extend_from_slice(&[0x02, 32 + zero as u8]);
+            if zero {
+                der.push(0);
+            }
+            der.extend_from_slice(half);
+        }
+        der.splice(0..0, [0x30, der.len() as u8]);
+        assert_eq!(der_signature(&der), Some(sig.to_bytes().into()));
+        assert_eq!(der_signature(&der[..der.len() - 1]), None);
+
+        let image = build_image(0x100, &[0x5au8; 100], Some(3), &[(TLV_ECDSA_SIG, &der)]);
+        let parsed = McubootImage::parse(&image).unwrap();
+        assert_eq!(verify_p256(&parsed, &public_key), Ok(()));
+
+        let mut tampered = image.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert_eq!(
+            verify_p256(&McubootImage::parse(&tampered).unwrap(), &public_key),
+            Err(SignatureError::Mismatch)
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Serial recovery in the bootloader: an image sent with YMODEM (or
+//! XMODEM-1K) is written to the secondary slot and installed permanently on
+//! the next boot.
+//!
+//! Unlike `BootClient::begin_update`, recovery is allowed while a test image
+//! is unconfirmed, because a trigger can enter recovery mode right after the
+//! test image is swapped in. The previous image that the test image would be
+//! reverted to is then overwritten. The slot is only touched once the
+//! transfer starts.
+
+use core::fmt;
+
+use crate::app::{AppError, BootClient, SlotWriter};
+use crate::flash::Flash;
+use crate::image_header::ImageHeader;
+use crate::swap::SwapType;
+use crate::ymodem::{Event, ModemError, Receiver};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryError<E> {
+    /// The transfer failed.
+    Modem(ModemError),
+    /// The image could not be written or is not valid.
+    Update(AppError<E>),
+}
+
+impl<E: fmt::Debug> fmt::Display for RecoveryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryError::Modem(e) => write!(f, "{}", e),
+            RecoveryError::Update(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for RecoveryError<E> {}
+
+impl<E> From<ModemError> for RecoveryError<E> {
+    fn from(e: ModemError) -> Self {
+        RecoveryError::Modem(e)
+    }
+}
+
+impl<E> From<AppError<E>> for RecoveryError<E> {
+    fn from(e: AppError<E>) -> Self {
+        RecoveryError::Update(e)
+    }
+}
+
+/// What to send to the sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    Reply(&'static [u8]),
+    /// The file is complete: send the reply, then call `Recovery::finish`.
+    End(&'static [u8]),
+}
+
+pub struct Recovery {
+    receiver: Receiver,
+    writer: Option<SlotWriter>,
+    started: bool,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recovery {
+    pub fn new() -> Self {
+        Recovery {
+            receiver: Receiver::new(),
+            writer: None,
+            started: false,
+        }
+    }
+
+    /// True once the sender has started the transfer. Before that the input
+    /// may be something else, such as SMP frames.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Nothing arrived for a while (a second), see `Receiver::timeout`.
+    pub fn timeout(&mut self) -> Result<&'static [u8], ModemError> {
+        self.receiver.timeout()
+    }
+
+    /// Handles a byte from the sender.
+    pub fn push<F: Flash>(
+        &mut self,
+        client: &mut BootClient<F>,
+        byte: u8,
+    ) -> Result<Option<Received>, RecoveryError<F::Error>> {
+        let Some(event) = self.receiver.push(byte)? else {
+            return Ok(None);
+        };
+        self.started = true;
+        let reply = event.reply();
+        let secondary = client.layout().secondary;
+        match event {
+            Event::File {
+                size: Some(size), ..
+            } if size > secondary.size => return Err(AppError::SlotFull.into()),
+            Event::File { .. } => self.writer = Some(SlotWriter::new(secondary)),
+            // an XMODEM transfer starts with the data; the sender waits for
+            // the ACK while the flash is written
+            Event::Data(data) => self
+                .writer
+                .get_or_insert_with(|| SlotWriter::new(secondary))
+                .write(client.flash(), data)?,
+            Event::End => return Ok(Some(Received::End(reply))),
+            Event::Reply(_) => {}
+        }
+        Ok(Some(Received::Reply(reply)))
+    }
+
+    /// Writes the partial last page, validates the image and requests that
+    /// it is installed permanently.
+    pub fn finish<F: Flash>(
+        self,
+        client: &mut BootClient<F>,
+    ) -> Result<ImageHeader, AppError<F::Error>> {
+        let mut writer = self
+            .writer
+            .unwrap_or_else(|| SlotWriter::new(client.layout().secondary));
+        writer.flush(client.flash())?;
+        client.check_update(writer.written())?;
+        client.mark_pending(SwapType::Permanent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tests::{client, image};
+    use crate::app::ImageState;
+    use crate::flash::mock::MockFlash;
+    use crate::image::ImageInfo;
+    use crate::swap::tests::{boot, TEST_LAYOUT};
+    use crate::swap::{self, SwapStatus};
+    use crate::ymodem::Sender;
+    use std::vec::Vec;
+
+    /// Sends `image` from a `Sender` until the receiver has the whole file.
+    fn transfer(
+        recovery: &mut Recovery,
+        client: &mut BootClient<MockFlash>,
+        image: &[u8],
+    ) -> Result<(), RecoveryError<<MockFlash as Flash>::Error>> {
+        let mut sender = Sender::new(b"recovery.bin", image);
+        let mut to_sender = recovery.timeout()?.to_vec();
+        loop {
+            let mut to_receiver = Vec::new();
+            for &b in &to_sender {
+                to_receiver.extend_from_slice(sender.push(b)?);
+            }
+            to_sender.clear();
+            for &b in &to_receiver {
+                match recovery.push(client, b)? {
+                    None => {}
+                    Some(Received::Reply(reply)) => to_sender.extend_from_slice(reply),
+                    Some(Received::End(_)) => return Ok(()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        let mut client = client();
+        let image = image(0x0900, 0x33);
+        let mut recovery = Recovery::new();
+        transfer(&mut recovery, &mut client, &image).unwrap();
+        let ih = recovery.finish(&mut client).unwrap();
+        assert_eq!(boot(client.flash()), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+
+        let mut recovery = Recovery::new();
+        assert_eq!(
+            transfer(&mut recovery, &mut client, &[0u8; 0x3001]),
+            Err(RecoveryError::Update(AppError::SlotFull))
+        );
+        let mut recovery = Recovery::new();
+        transfer(&mut recovery, &mut client, &image[..0x0800]).unwrap();
+        assert!(recovery.finish(&mut client).is_err());
+    }
+
+    /// A trigger enters recovery mode right after a test image is swapped
+    /// in, before its trial boot.
+    #[test]
+    fn test_recovery_trial() {
+        let mut client = client();
+        let update = image(0x0800, 0x22);
+        let mut writer = client.begin_update().unwrap();
+        writer.write(&update).unwrap();
+        let test = writer.finish().unwrap();
+        let primary = ImageInfo::from(&client.running_header().unwrap());
+        let status = SwapStatus::new(
+            &TEST_LAYOUT,
+            Some(&primary),
+            &ImageInfo::from(&test),
+            SwapType::Test,
+        );
+        swap::swap(client.flash(), &TEST_LAYOUT, &status, None).unwrap();
+        assert_eq!(client.image_state(), Ok(ImageState::Trial));
+        assert_eq!(client.begin_update().err(), Some(AppError::NotConfirmed));
+
+        // the slot is not touched before the transfer starts
+        let before = client.flash().clone();
+        let mut recovery = Recovery::new();
+        for &b in b"\r\nhello\r\n" {
+            assert_eq!(recovery.push(&mut client, b), Ok(None));
+        }
+        assert!(!recovery.is_started());
+        assert_eq!(client.flash().data(), before.data());
+
+        let image = image(0x0900, 0x33);
+        transfer(&mut recovery, &mut client, &image).unwrap();
+        let ih = recovery.finish(&mut client).unwrap();
+        assert_eq!(boot(client.flash()), Ok(false));
+        assert_eq!(client.running_header(), Ok(ih));
+        assert_eq!(client.image_state(), Ok(ImageState::Confirmed));
+    }
+}
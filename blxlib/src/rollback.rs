@@ -0,0 +1,121 @@
+//! Anti-rollback protection. The bootloader stores the highest
+//! `security_counter` of the images it has accepted and refuses images with a
+//! lower one.
+//!
+//! The counter is a record in one of the two sectors of the rollback
+//! partition. A new value is written to the other sector, so a power loss
+//! while it is written leaves the previous record intact. The valid record
+//! with the highest counter is the current one.
+
+use crate::crc32::crc32;
+use crate::flash::{Flash, ERASED};
+use crate::layout::{FlashLayout, PAGE_SIZE, SECTOR_SIZE};
+
+pub const ROLLBACK_MAGIC: u32 = 0x524f_4c4c; // "ROLL"
+const RECORD_LENGTH: usize = 12;
+
+const fn record_offsets(layout: &FlashLayout) -> [u32; 2] {
+    let offset = layout.rollback.offset;
+    [offset, offset + SECTOR_SIZE]
+}
+
+/// The sector with the highest valid counter: `(offset, counter)`.
+fn current<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<Option<(u32, u32)>, F::Error> {
+    let mut current: Option<(u32, u32)> = None;
+    for offset in record_offsets(layout) {
+        let mut buf = [0u8; RECORD_LENGTH];
+        flash.read(offset, &mut buf)?;
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if word(0) != ROLLBACK_MAGIC || word(8) != crc32(&buf[..8]) {
+            continue;
+        }
+        let counter = word(4);
+        if current.is_none_or(|(_, current)| counter > current) {
+            current = Some((offset, counter));
+        }
+    }
+    Ok(current)
+}
+
+/// The highest accepted security counter, 0 if none is stored yet.
+pub fn read_counter<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<u32, F::Error> {
+    Ok(current(flash, layout)?.map_or(0, |(_, counter)| counter))
+}
+
+/// Raises the stored counter to `counter`. Does nothing if it is not higher.
+pub fn update_counter<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    counter: u32,
+) -> Result<(), F::Error> {
+    let [first, second] = record_offsets(layout);
+    let offset = match current(flash, layout)? {
+        Some((_, current)) if counter <= current => return Ok(()),
+        Some((offset, _)) if offset == first => second,
+        _ => first,
+    };
+    flash.erase_sector(offset)?;
+    let mut page = [ERASED; PAGE_SIZE as usize];
+    page[0..4].copy_from_slice(&ROLLBACK_MAGIC.to_le_bytes());
+    page[4..8].copy_from_slice(&counter.to_le_bytes());
+    let crc = crc32(&page[..8]);
+    page[8..RECORD_LENGTH].copy_from_slice(&crc.to_le_bytes());
+    flash.program_page(offset, &page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::{MockFlash, MockFlashError};
+    use crate::swap::tests::{TEST_FLASH_SIZE, TEST_LAYOUT};
+
+    #[test]
+    fn test_update_counter() {
+        let mut flash = MockFlash::new(TEST_FLASH_SIZE);
+        assert_eq!(read_counter(&mut flash, &TEST_LAYOUT), Ok(0));
+
+        let [first, second] = record_offsets(&TEST_LAYOUT);
+        update_counter(&mut flash, &TEST_LAYOUT, 3).unwrap();
+        assert_eq!(read_counter(&mut flash, &TEST_LAYOUT), Ok(3));
+        assert_eq!(current(&mut flash, &TEST_LAYOUT), Ok(Some((first, 3))));
+        update_counter(&mut flash, &TEST_LAYOUT, 5).unwrap();
+        assert_eq!(current(&mut flash, &TEST_LAYOUT), Ok(Some((second, 5))));
+        update_counter(&mut flash, &TEST_LAYOUT, 6).unwrap();
+        assert_eq!(current(&mut flash, &TEST_LAYOUT), Ok(Some((first, 6))));
+
+        // never lowered
+        let ops = flash.ops();
+        update_counter(&mut flash, &TEST_LAYOUT, 4).unwrap();
+        update_counter(&mut flash, &TEST_LAYOUT, 6).unwrap();
+        assert_eq!(flash.ops(), ops);
+        assert_eq!(read_counter(&mut flash, &TEST_LAYOUT), Ok(6));
+
+        // a corrupt record is ignored
+        flash.data_mut()[first as usize + 4] ^= 0x01;
+        assert_eq!(read_counter(&mut flash, &TEST_LAYOUT), Ok(5));
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let mut before = MockFlash::new(TEST_FLASH_SIZE);
+        update_counter(&mut before, &TEST_LAYOUT, 1).unwrap();
+        update_counter(&mut before, &TEST_LAYOUT, 2).unwrap();
+        for torn in [false, true] {
+            for ops in 0..2 {
+                let mut flash = before.clone();
+                flash.torn = torn;
+                flash.lose_power_after(ops);
+                assert_eq!(
+                    update_counter(&mut flash, &TEST_LAYOUT, 7),
+                    Err(MockFlashError::PowerLoss)
+                );
+                flash.power_cycle();
+                // a torn program may have written the whole record
+                let counter = read_counter(&mut flash, &TEST_LAYOUT).unwrap();
+                assert!(counter == 2 || counter == 7, "counter {}", counter);
+                update_counter(&mut flash, &TEST_LAYOUT, 7).unwrap();
+                assert_eq!(read_counter(&mut flash, &TEST_LAYOUT), Ok(7));
+            }
+        }
+    }
+}
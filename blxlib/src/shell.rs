@@ -0,0 +1,580 @@
+//! Line-oriented command shell of the bootloader, for a device on the bench.
+//!
+//! ```text
+//! help                   lists the commands
+//! info                   headers and states of both slots
+//! validate <slot>        checks payload and signature (done by the bootloader)
+//! erase <slot>           erases a slot
+//! swap [permanent]       installs the update image on the next boot
+//! confirm                keeps the running test image
+//! boot                   leaves the shell and boots
+//! reboot                 resets the chip
+//! hexdump <addr> <len>   flash at an XIP address
+//! ```
+//!
+//! `<slot>` is `0` (`primary`) or `1` (`secondary`). Numbers are decimal or
+//! hex with `0x`. What needs the hardware or the keys is returned as an
+//! `Action`.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+use crate::app::{AppError, BootClient, ImageState, UpdateState};
+use crate::flash::Flash;
+use crate::image_header::ImageHeader;
+use crate::layout::{Partition, FLASH_BASE, SECTOR_SIZE};
+use crate::rollback;
+use crate::swap::SwapType;
+
+pub const PROMPT: &str = "blx> ";
+pub const MAX_LINE_LENGTH: usize = 64;
+
+const HELP: &str = "commands: help, info, validate <slot>, erase <slot>, swap [permanent], \
+                    confirm, boot, reboot, hexdump <addr> <len>\r\n";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    Primary,
+    Secondary,
+}
+
+impl Slot {
+    pub fn partition<F: Flash>(&self, client: &BootClient<F>) -> Partition {
+        match self {
+            Slot::Primary => client.layout().primary,
+            Slot::Secondary => client.layout().secondary,
+        }
+    }
+}
+
+impl FromStr for Slot {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" | "primary" => Ok(Slot::Primary),
+            "1" | "secondary" => Ok(Slot::Secondary),
+            _ => Err(ParseError::BadArgument),
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Slot::Primary => write!(f, "0 (primary)"),
+            Slot::Secondary => write!(f, "1 (secondary)"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Info,
+    Validate(Slot),
+    Erase(Slot),
+    Swap(SwapType),
+    Confirm,
+    Boot,
+    Reboot,
+    Hexdump { addr: u32, len: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownCommand,
+    MissingArgument,
+    BadArgument,
+    TooManyArguments,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand => write!(f, "unknown command"),
+            ParseError::MissingArgument => write!(f, "missing argument"),
+            ParseError::BadArgument => write!(f, "bad argument"),
+            ParseError::TooManyArguments => write!(f, "too many arguments"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+impl FromStr for Command {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_ascii_whitespace();
+        let name = words.next().unwrap_or("help");
+        let mut arg = || words.next().ok_or(ParseError::MissingArgument);
+        let command = match name {
+            "help" | "?" => Command::Help,
+            "info" => Command::Info,
+            "validate" => Command::Validate(arg()?.parse()?),
+            "erase" => Command::Erase(arg()?.parse()?),
+            "swap" => match arg() {
+                Err(_) => Command::Swap(SwapType::Test),
+                Ok("permanent") => Command::Swap(SwapType::Permanent),
+                Ok(_) => return Err(ParseError::BadArgument),
+            },
+            "confirm" => Command::Confirm,
+            "boot" => Command::Boot,
+            "reboot" => Command::Reboot,
+            "hexdump" => Command::Hexdump {
+                addr: parse_number(arg()?)?,
+                len: parse_number(arg()?)?,
+            },
+            _ => return Err(ParseError::UnknownCommand),
+        };
+        if words.next().is_some() {
+            return Err(ParseError::TooManyArguments);
+        }
+        Ok(command)
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, ParseError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| ParseError::BadArgument)
+}
+
+/// What the caller does after a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Validate and verify the image in the slot, and print the result.
+    Validate(Slot),
+    /// Leave the shell and continue booting.
+    Boot,
+    Reboot,
+}
+
+/// Echo of an input byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input<'a> {
+    None,
+    Echo(u8),
+    /// The last character was removed (`"\x08 \x08"` erases it on a terminal).
+    Erase,
+    /// Enter was pressed.
+    Line(&'a str),
+}
+
+/// Collects a line of printable ASCII. Backspace (`BS` or `DEL`) removes the
+/// last character; characters beyond `MAX_LINE_LENGTH` are dropped.
+pub struct LineEditor {
+    buf: [u8; MAX_LINE_LENGTH],
+    len: usize,
+    /// The line ended with `CR`, a following `LF` is skipped.
+    cr: bool,
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            buf: [0u8; MAX_LINE_LENGTH],
+            len: 0,
+            cr: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Input<'_> {
+        let cr = core::mem::replace(&mut self.cr, byte == b'\r');
+        match byte {
+            b'\n' if cr => Input::None,
+            b'\r' | b'\n' => {
+                let len = core::mem::take(&mut self.len);
+                // only ASCII is stored
+                Input::Line(core::str::from_utf8(&self.buf[..len]).unwrap())
+            }
+            0x08 | 0x7f if self.len > 0 => {
+                self.len -= 1;
+                Input::Erase
+            }
+            0x20..0x7f if self.len < MAX_LINE_LENGTH => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                Input::Echo(byte)
+            }
+            _ => Input::None,
+        }
+    }
+}
+
+/// Runs commands with a `BootClient` and writes the output (lines end with
+/// `"\r\n"`) to `out`.
+pub struct Shell<F: Flash> {
+    client: BootClient<F>,
+}
+
+impl<F: Flash> Shell<F> {
+    pub fn new(client: BootClient<F>) -> Self {
+        Shell { client }
+    }
+
+    pub fn client(&mut self) -> &mut BootClient<F> {
+        &mut self.client
+    }
+
+    pub fn run(&mut self, line: &str, out: &mut impl Write) -> Result<Action, fmt::Error> {
+        if line.trim().is_empty() {
+            return Ok(Action::None);
+        }
+        let command = match line.parse() {
+            Ok(command) => command,
+            Err(e) => {
+                write!(out, "{}\r\n{}", e, HELP)?;
+                return Ok(Action::None);
+            }
+        };
+        match self.execute(command, out) {
+            Ok(action) => Ok(action),
+            Err(ShellError::Write(e)) => Err(e),
+            Err(ShellError::App(e)) => {
+                write!(out, "error: {}\r\n", e)?;
+                Ok(Action::None)
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        command: Command,
+        out: &mut impl Write,
+    ) -> Result<Action, ShellError<F::Error>> {
+        match command {
+            Command::Help => out.write_str(HELP)?,
+            Command::Info => self.info(out)?,
+            Command::Validate(slot) => return Ok(Action::Validate(slot)),
+            Command::Erase(slot) => {
+                let partition = slot.partition(&self.client);
+                for sector in 0..partition.sectors() {
+                    self.client
+                        .flash()
+                        .erase_sector(partition.offset + sector * SECTOR_SIZE)
+                        .map_err(AppError::Flash)?;
+                }
+                write!(out, "slot {} erased\r\n", slot)?;
+            }
+            Command::Swap(swap_type) => {
+                let ih = self.client.mark_pending(swap_type)?;
+                write!(
+                    out,
+                    "{} is installed on the next boot ({:?})\r\n",
+                    ih.version(),
+                    swap_type
+                )?;
+            }
+            Command::Confirm => {
+                self.client.confirm().map_err(AppError::Flash)?;
+                out.write_str("confirmed\r\n")?;
+            }
+            Command::Boot => return Ok(Action::Boot),
+            Command::Reboot => return Ok(Action::Reboot),
+            Command::Hexdump { addr, len } => self.hexdump(addr, len, out)?,
+        }
+        Ok(Action::None)
+    }
+
+    fn info(&mut self, out: &mut impl Write) -> Result<(), ShellError<F::Error>> {
+        for slot in [Slot::Primary, Slot::Secondary] {
+            write!(
+                out,
+                "slot {}: {:08x}\r\n",
+                slot,
+                slot.partition(&self.client).addr()
+            )?;
+            let header = match slot {
+                Slot::Primary => self.client.running_header(),
+                Slot::Secondary => self.client.update_header(),
+            };
+            let ih = match header {
+                Ok(ih) => ih,
+                Err(AppError::Flash(e)) => return Err(AppError::Flash(e).into()),
+                Err(e) => {
+                    write!(out, "{}\r\n", e)?;
+                    continue;
+                }
+            };
+            print_header(&ih, out)?;
+            match slot {
+                Slot::Primary => {
+                    let state = self.client.image_state().map_err(AppError::Flash)?;
+                    write!(out, "state: {:?}\r\n", state)?;
+                    if state == ImageState::Trial {
+                        out.write_str("(reverted on the next boot unless confirmed)\r\n")?;
+                    }
+                }
+                Slot::Secondary => match self.client.update_state(&ih).map_err(AppError::Flash)? {
+                    UpdateState::Pending(swap_type) => {
+                        write!(out, "state: Pending ({:?})\r\n", swap_type)?
+                    }
+                    UpdateState::Done => out.write_str("state: Done\r\n")?,
+                },
+            }
+        }
+        let layout = *self.client.layout();
+        let counter =
+            rollback::read_counter(self.client.flash(), &layout).map_err(AppError::Flash)?;
+        write!(out, "security counter: {}\r\n", counter)?;
+        Ok(())
+    }
+
+    /// 16 bytes per line with their ASCII characters.
+    fn hexdump(
+        &mut self,
+        addr: u32,
+        len: u32,
+        out: &mut impl Write,
+    ) -> Result<(), ShellError<F::Error>> {
+        let flash_end = self
+            .client
+            .layout()
+            .partitions()
+            .iter()
+            .map(|(_, p)| p.end())
+            .max()
+            .unwrap();
+        let Some(offset) = addr
+            .checked_sub(FLASH_BASE)
+            .filter(|&offset| offset.checked_add(len).is_some_and(|end| end <= flash_end))
+        else {
+            write!(
+                out,
+                "not in flash: {:08x}..{:08x}\r\n",
+                FLASH_BASE,
+                FLASH_BASE + flash_end
+            )?;
+            return Ok(());
+        };
+        for line in (0..len).step_by(16) {
+            let mut buf = [0u8; 16];
+            let buf = &mut buf[..(len - line).min(16) as usize];
+            self.client
+                .flash()
+                .read(offset + line, buf)
+                .map_err(AppError::Flash)?;
+            write!(out, "{:08x}:", addr + line)?;
+            for i in 0..16 {
+                match buf.get(i) {
+                    Some(b) => write!(out, " {:02x}", b)?,
+                    None => out.write_str("   ")?,
+                }
+            }
+            out.write_str("  ")?;
+            for &b in buf.iter() {
+                out.write_char(if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                })?;
+            }
+            out.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+enum ShellError<E> {
+    App(AppError<E>),
+    Write(fmt::Error),
+}
+
+impl<E> From<AppError<E>> for ShellError<E> {
+    fn from(e: AppError<E>) -> Self {
+        ShellError::App(e)
+    }
+}
+
+impl<E> From<fmt::Error> for ShellError<E> {
+    fn from(e: fmt::Error) -> Self {
+        ShellError::Write(e)
+    }
+}
+
+pub fn print_header(ih: &ImageHeader, out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "header_magic: {:08x}\r", ih.header_magic)?;
+    writeln!(out, "header_length: {}\r", ih.header_length)?;
+    writeln!(out, "hv: {}.{}\r", ih.hv_major, ih.hv_minor)?;
+    writeln!(out, "iv: {}\r", ih.version())?;
+    writeln!(out, "image_length: {:08x}\r", ih.image_length)?;
+    writeln!(out, "payload_crc: {:08x}\r", ih.payload_crc)?;
+    writeln!(out, "sig_alg: {}\r", ih.sig_alg)?;
+    write!(out, "payload_digest: ")?;
+    for b in ih.payload_digest {
+        write!(out, "{:02x}", b)?;
+    }
+    writeln!(out, "\r")?;
+    writeln!(out, "security_counter: {}\r", ih.security_counter)?;
+    writeln!(out, "crc32: {:08x}\r", ih.crc32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tests::{client, image};
+    use crate::flash::mock::MockFlash;
+    use crate::swap::tests::{boot, TEST_LAYOUT};
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn run(shell: &mut Shell<MockFlash>, line: &str) -> (Action, String) {
+        let mut out = String::new();
+        let action = shell.run(line, &mut out).unwrap();
+        (action, out)
+    }
+
+    #[test]
+    fn test_parse() {
+        let hexdump = Command::Hexdump {
+            addr: 0x1000_1000,
+            len: 32,
+        };
+        let cases = [
+            ("help", Ok(Command::Help)),
+            ("?", Ok(Command::Help)),
+            (" info ", Ok(Command::Info)),
+            ("validate 0", Ok(Command::Validate(Slot::Primary))),
+            ("validate secondary", Ok(Command::Validate(Slot::Secondary))),
+            ("erase 1", Ok(Command::Erase(Slot::Secondary))),
+            ("swap", Ok(Command::Swap(SwapType::Test))),
+            ("swap permanent", Ok(Command::Swap(SwapType::Permanent))),
+            ("confirm", Ok(Command::Confirm)),
+            ("boot", Ok(Command::Boot)),
+            ("reboot", Ok(Command::Reboot)),
+            ("hexdump 0x10001000 32", Ok(hexdump)),
+            ("hexdump\t268439552  0x20", Ok(hexdump)),
+            ("bot", Err(ParseError::UnknownCommand)),
+            ("validate", Err(ParseError::MissingArgument)),
+            ("erase 2", Err(ParseError::BadArgument)),
+            ("swap test", Err(ParseError::BadArgument)),
+            ("hexdump 0x10001000", Err(ParseError::MissingArgument)),
+            ("hexdump 0x1000100g 1", Err(ParseError::BadArgument)),
+            ("hexdump 0 0x100000000", Err(ParseError::BadArgument)),
+            ("boot now", Err(ParseError::TooManyArguments)),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(line.parse::<Command>(), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_line_editor() {
+        let mut editor = LineEditor::new();
+        let mut lines = Vec::new();
+        let mut echo = Vec::new();
+        for &b in b"\x08infx\x7fo\r\n\x1b[Aboot\n\r\n" {
+            match editor.push(b) {
+                Input::None => {}
+                Input::Echo(b) => echo.push(b),
+                Input::Erase => echo.push(b'<'),
+                Input::Line(line) => lines.push(String::from(line)),
+            }
+        }
+        assert_eq!(lines, ["info", "[Aboot", ""]);
+        assert_eq!(echo, b"infx<o[Aboot");
+
+        for _ in 0..MAX_LINE_LENGTH + 1 {
+            assert_ne!(editor.push(b'a'), Input::Line(""));
+        }
+        assert_eq!(
+            editor.push(b'\n'),
+            Input::Line(&"a".repeat(MAX_LINE_LENGTH))
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut shell = Shell::new(client());
+        let (action, out) = run(&mut shell, "info");
+        assert_eq!(action, Action::None);
+        assert!(out.starts_with("slot 0 (primary): 10001000\r\nheader_magic: "));
+        assert!(out.contains("iv: 0.0.0+00000011\r\n"));
+        assert!(out.contains("state: Confirmed\r\nslot 1 (secondary): 10004000\r\n"));
+        assert!(
+            out.ends_with("header_magic is not correct: ffffffff\r\nsecurity counter: 0\r\n"),
+            "{}",
+            out
+        );
+
+        let (_, out) = run(&mut shell, "swap");
+        assert!(out.starts_with("error: "), "{}", out);
+
+        let image = image(0x1c80, 0x22);
+        let mut update = shell.client().begin_update().unwrap();
+        update.write(&image).unwrap();
+        update.finish().unwrap();
+        let (_, out) = run(&mut shell, "info");
+        assert!(out.contains("iv: 0.0.0+00000022\r\n"));
+        assert!(out.contains("state: Pending (Test)\r\n"));
+        assert_eq!(
+            run(&mut shell, "swap permanent").1,
+            "0.0.0+00000022 is installed on the next boot (Permanent)\r\n"
+        );
+        assert!(run(&mut shell, "info")
+            .1
+            .contains("state: Pending (Permanent)\r\n"));
+        assert_eq!(
+            run(&mut shell, "swap").1,
+            "0.0.0+00000022 is installed on the next boot (Test)\r\n"
+        );
+
+        assert_eq!(boot(shell.client().flash()), Ok(true));
+        let (_, out) = run(&mut shell, "info");
+        assert!(out.contains("state: Trial\r\n(reverted on the next boot unless confirmed)\r\n"));
+        assert!(out.contains("state: Done\r\n"));
+        assert_eq!(run(&mut shell, "confirm").1, "confirmed\r\n");
+        assert!(run(&mut shell, "info").1.contains("state: Confirmed\r\n"));
+
+        assert_eq!(
+            run(&mut shell, "erase 1").1,
+            "slot 1 (secondary) erased\r\n"
+        );
+        let secondary = TEST_LAYOUT.secondary;
+        let data = shell.client().flash().data();
+        assert!(data[secondary.offset as usize..secondary.end() as usize]
+            .iter()
+            .all(|&b| b == 0xff));
+
+        assert_eq!(
+            run(&mut shell, "validate 1"),
+            (Action::Validate(Slot::Secondary), String::new())
+        );
+        assert_eq!(run(&mut shell, "boot").0, Action::Boot);
+        assert_eq!(run(&mut shell, "reboot").0, Action::Reboot);
+        assert_eq!(run(&mut shell, "  "), (Action::None, String::new()));
+        let (_, out) = run(&mut shell, "erase");
+        assert!(out.starts_with("missing argument\r\ncommands: help, "));
+        assert_eq!(run(&mut shell, "help").1, HELP);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut shell = Shell::new(client());
+        let primary = TEST_LAYOUT.primary.offset as usize;
+        shell.client().flash().data_mut()[primary + 0x100..primary + 0x114]
+            .copy_from_slice(b"\x00\x01bootloader \x7f\xff\x0a!!!\x10");
+        assert_eq!(
+            run(&mut shell, "hexdump 0x10001100 20").1,
+            "10001100: 00 01 62 6f 6f 74 6c 6f 61 64 65 72 20 7f ff 0a  ..bootloader ...\r\n\
+             10001110: 21 21 21 10                                      !!!.\r\n"
+        );
+        assert_eq!(run(&mut shell, "hexdump 0x1000cff0 0").1, "");
+        assert_eq!(run(&mut shell, "hexdump 0x1000cff0 16").1.len(), 77);
+        let error = "not in flash: 10000000..1000d000\r\n";
+        assert_eq!(run(&mut shell, "hexdump 0x1000cff0 17").1, error);
+        assert_eq!(run(&mut shell, "hexdump 0x0fffffff 1").1, error);
+        assert_eq!(run(&mut shell, "hexdump 0xffffffff 0xffffffff").1, error);
+    }
+}
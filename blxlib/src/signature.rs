@@ -0,0 +1,227 @@
+use core::fmt;
+
+#[cfg(feature = "p256")]
+use crate::image_header::SIG_ALG_ECDSA_P256;
+#[cfg(feature = "ed25519")]
+use crate::image_header::SIG_ALG_ED25519;
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+use crate::image_header::{ImageHeader, HEADER_LENGTH};
+
+pub const ED25519_PUBLIC_KEY_LENGTH: usize = 32;
+pub const ED25519_SIGNATURE_LENGTH: usize = 64;
+// SEC1 uncompressed point
+pub const P256_PUBLIC_KEY_LENGTH: usize = 65;
+pub const P256_SIGNATURE_LENGTH: usize = 64;
+
+// `ImageHeader.signature` and `ImageHeader.crc32` are not covered by the signature.
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+const SIGNATURE_OFFSET: usize = 20;
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+const SIGNATURE_END: usize = 148;
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+const CRC32_OFFSET: usize = HEADER_LENGTH as usize - 4;
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+pub const SIGNED_MESSAGE_LENGTH: usize = SIGNATURE_OFFSET + CRC32_OFFSET - SIGNATURE_END;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    UnsupportedAlgorithm(u8),
+    InvalidPublicKey,
+    Mismatch,
+    /// The image has no signature of the expected algorithm.
+    Missing,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::UnsupportedAlgorithm(alg) => {
+                write!(f, "sig_alg is not supported: {}", alg)
+            }
+            SignatureError::InvalidPublicKey => write!(f, "public key is not valid"),
+            SignatureError::Mismatch => write!(f, "signature is not correct"),
+            SignatureError::Missing => write!(f, "signature is missing"),
+        }
+    }
+}
+
+impl core::error::Error for SignatureError {}
+
+/// The signed message: the header without `signature` and `crc32`.
+/// The payload is covered through `payload_digest`, so a signature is only
+/// meaningful for a header that has passed `ImageHeader::validate`.
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+pub fn signed_message(ih: &ImageHeader) -> [u8; SIGNED_MESSAGE_LENGTH] {
+    let buf = ih.to_bytes();
+    let mut msg = [0u8; SIGNED_MESSAGE_LENGTH];
+    msg[..SIGNATURE_OFFSET].copy_from_slice(&buf[..SIGNATURE_OFFSET]);
+    msg[SIGNATURE_OFFSET..].copy_from_slice(&buf[SIGNATURE_END..CRC32_OFFSET]);
+    msg
+}
+
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+fn check_alg(ih: &ImageHeader, sig_alg: u8) -> Result<(), SignatureError> {
+    if ih.sig_alg != sig_alg {
+        return Err(SignatureError::UnsupportedAlgorithm(ih.sig_alg));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+pub fn verify_ed25519(
+    ih: &ImageHeader,
+    public_key: &[u8; ED25519_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    check_alg(ih, SIG_ALG_ED25519)?;
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    let mut sig = [0u8; ED25519_SIGNATURE_LENGTH];
+    sig.copy_from_slice(&ih.signature[..ED25519_SIGNATURE_LENGTH]);
+    let sig = Signature::from_bytes(&sig);
+    key.verify_strict(&signed_message(ih), &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// SHA-256 of the signed message. `bintool` signs exactly this digest.
+#[cfg(feature = "p256")]
+pub fn prehash_p256(ih: &ImageHeader) -> [u8; crate::sha256::DIGEST_LENGTH] {
+    crate::sha256::sha256(&signed_message(ih))
+}
+
+#[cfg(feature = "p256")]
+pub fn verify_p256(
+    ih: &ImageHeader,
+    public_key: &[u8; P256_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use p256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    check_alg(ih, SIG_ALG_ECDSA_P256)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    let sig = Signature::from_slice(&ih.signature[..P256_SIGNATURE_LENGTH])
+        .map_err(|_| SignatureError::Mismatch)?;
+    key.verify_prehash(&prehash_p256(ih), &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+#[cfg(all(test, feature = "ed25519"))]
+mod tests_ed25519 {
+    use super::*;
+    use crate::image_header::SIG_ALG_NONE;
+    use crate::sha256::sha256;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed_image(key: &SigningKey, payload: &[u8]) -> ImageHeader {
+        let mut ih = ImageHeader::new();
+        ih.image_length = payload.len() as u32;
+        ih.payload_digest = sha256(payload);
+        ih.sig_alg = SIG_ALG_ED25519;
+        let sig = key.sign(&signed_message(&ih));
+        ih.signature[..ED25519_SIGNATURE_LENGTH].copy_from_slice(&sig.to_bytes());
+        ih.crc32 = ih.calc_crc32();
+        ih
+    }
+
+    #[test]
+    fn test_verify_ed25519() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let payload = [0xa5u8; 1000];
+        let ih = signed_image(&key, &payload);
+
+        assert_eq!(verify_ed25519(&ih, &public_key), Ok(()));
+
+        // crc32 is outside the signed region
+        let mut other = ih;
+        other.crc32 = 0;
+        assert_eq!(verify_ed25519(&other, &public_key), Ok(()));
+
+        // the payload is covered through payload_digest
+        let mut tampered = payload;
+        tampered[999] ^= 1;
+        let mut other = ih;
+        other.payload_digest = sha256(&tampered);
+        assert_eq!(
+            verify_ed25519(&other, &public_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        let mut other = ih;
+        other.iv_major += 1;
+        assert_eq!(
+            verify_ed25519(&other, &public_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        let mut other = ih;
+        other.sig_alg = SIG_ALG_NONE;
+        assert_eq!(
+            verify_ed25519(&other, &public_key),
+            Err(SignatureError::UnsupportedAlgorithm(SIG_ALG_NONE))
+        );
+
+        let wrong_key = SigningKey::from_bytes(&[8u8; 32])
+            .verifying_key()
+            .to_bytes();
+        assert_eq!(
+            verify_ed25519(&ih, &wrong_key),
+            Err(SignatureError::Mismatch)
+        );
+    }
+}
+
+#[cfg(all(test, feature = "p256"))]
+mod tests_p256 {
+    use super::*;
+    use crate::sha256::sha256;
+    use p256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+
+    fn signed_image(key: &SigningKey, payload: &[u8]) -> ImageHeader {
+        let mut ih = ImageHeader::new();
+        ih.image_length = payload.len() as u32;
+        ih.payload_digest = sha256(payload);
+        ih.sig_alg = SIG_ALG_ECDSA_P256;
+        let sig: Signature = key.sign_prehash(&prehash_p256(&ih)).unwrap();
+        ih.signature[..P256_SIGNATURE_LENGTH].copy_from_slice(&sig.to_bytes());
+        ih.crc32 = ih.calc_crc32();
+        ih
+    }
+
+    fn sec1_public_key(key: &SigningKey) -> [u8; P256_PUBLIC_KEY_LENGTH] {
+        let point = key.verifying_key().to_encoded_point(false);
+        point.as_bytes().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_verify_p256() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key = sec1_public_key(&key);
+        let payload = [0x3cu8; 1000];
+        let ih = signed_image(&key, &payload);
+
+        assert_eq!(verify_p256(&ih, &public_key), Ok(()));
+
+        let mut other = ih;
+        other.payload_digest[0] ^= 0x80;
+        assert_eq!(
+            verify_p256(&other, &public_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        let mut other = ih;
+        other.iv_build = 1;
+        assert_eq!(
+            verify_p256(&other, &public_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        let wrong_key = sec1_public_key(&SigningKey::from_slice(&[8u8; 32]).unwrap());
+        assert_eq!(verify_p256(&ih, &wrong_key), Err(SignatureError::Mismatch));
+
+        assert_eq!(
+            verify_p256(&ih, &[0u8; P256_PUBLIC_KEY_LENGTH]),
+            Err(SignatureError::InvalidPublicKey)
+        );
+    }
+}
@@ -0,0 +1,1025 @@
+//! mcumgr's Simple Management Protocol (SMP) over a serial console, for the
+//! bootloader.
+//!
+//! A packet is sent as lines of base64 text of at most `MAX_FRAME_LENGTH`
+//! bytes. `len` (big endian) counts `data` and the CRC-16/XMODEM after it.
+//!
+//! ```text
+//! 06 09 base64(len data crc ...) \n      first frame
+//! 04 14 base64(... data crc) \n          continuation frames
+//! ```
+//!
+//! `data` is an 8-byte header and a CBOR map. The server handles:
+//!
+//! ```text
+//! group    id        op           request                      response
+//! 0 os     0 echo    read, write  {"d": text}                  {"r": text}
+//! 0 os     5 reset   write        {}                           {}, then reset
+//! 1 image  0 state   read         {}                           {"images": [...]}
+//! 1 image  0 state   write        {"hash": bytes,              {"images": [...]}
+//!                                  "confirm": bool}
+//! 1 image  1 upload  write        {"off": 0, "len", "data"}    {"rc": 0, "off"}
+//!                                 {"off", "data"}
+//! ```
+//!
+//! A failed request is answered with `{"rc": ErrorCode}`. `hash` of an image
+//! is its `payload_digest`. An image in the secondary slot is `pending` until
+//! the bootloader installed it; it is installed as a test image unless it is
+//! `permanent`.
+
+use core::fmt::{self, Write};
+
+use crate::app::{AppError, BootClient, ImageState, SlotWriter, UpdateState};
+use crate::cbor::{CborError, Decoder, Encoder, SIMPLE_FALSE, SIMPLE_TRUE};
+use crate::flash::Flash;
+use crate::image_header::ImageHeader;
+use crate::swap::SwapType;
+use crate::ymodem::crc16;
+
+pub const FRAME_START: [u8; 2] = [0x06, 0x09];
+pub const FRAME_CONTINUE: [u8; 2] = [0x04, 0x14];
+/// Longest line, including the marker and the newline.
+pub const MAX_FRAME_LENGTH: usize = 127;
+/// Longest packet (header and CBOR map) that is received or sent.
+pub const MTU: usize = 1024;
+pub const HEADER_LENGTH: usize = 8;
+
+pub const OP_READ: u8 = 0;
+pub const OP_READ_RSP: u8 = 1;
+pub const OP_WRITE: u8 = 2;
+pub const OP_WRITE_RSP: u8 = 3;
+
+pub const GROUP_OS: u16 = 0;
+pub const GROUP_IMAGE: u16 = 1;
+pub const OS_ECHO: u8 = 0;
+pub const OS_RESET: u8 = 5;
+pub const IMAGE_STATE: u8 = 0;
+pub const IMAGE_UPLOAD: u8 = 1;
+
+/// Bytes of a packet in a frame: the base64 text of a whole number of
+/// 3-byte groups that fits in a line.
+const FRAME_DATA_LENGTH: usize = (MAX_FRAME_LENGTH - 3) / 4 * 3;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmpError {
+    /// Shorter than the header or the length in it.
+    Truncated,
+    /// A response or unknown op; it is not answered.
+    NotRequest(u8),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::Truncated => write!(f, "smp packet is truncated"),
+            SmpError::NotRequest(op) => write!(f, "smp op {} is not a request", op),
+        }
+    }
+}
+
+impl core::error::Error for SmpError {}
+
+/// `rc` of a failed request (mcumgr's `MGMT_ERR_*`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown = 1,
+    NoMemory = 2,
+    Invalid = 3,
+    NoEntry = 5,
+    BadState = 6,
+    MessageSize = 7,
+    NotSupported = 8,
+    Corrupt = 9,
+}
+
+impl From<CborError> for ErrorCode {
+    fn from(e: CborError) -> Self {
+        match e {
+            CborError::Full => ErrorCode::MessageSize,
+            _ => ErrorCode::Invalid,
+        }
+    }
+}
+
+impl<E> From<AppError<E>> for ErrorCode {
+    fn from(e: AppError<E>) -> Self {
+        match e {
+            AppError::Flash(_) => ErrorCode::Unknown,
+            AppError::Parse(_) | AppError::Validation(_) => ErrorCode::Corrupt,
+            AppError::SlotFull => ErrorCode::NoMemory,
+            AppError::NotConfirmed | AppError::NoUpdate => ErrorCode::BadState,
+            AppError::Delta(_) => ErrorCode::Invalid,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub op: u8,
+    /// 0 for SMP version 1, 1 for version 2. Responses use the version of
+    /// the request.
+    pub version: u8,
+    pub flags: u8,
+    /// Length of the CBOR map that follows.
+    pub length: u16,
+    pub group: u16,
+    pub seq: u8,
+    pub id: u8,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let [length0, length1] = self.length.to_be_bytes();
+        let [group0, group1] = self.group.to_be_bytes();
+        [
+            (self.version & 3) << 3 | self.op & 7,
+            self.flags,
+            length0,
+            length1,
+            group0,
+            group1,
+            self.seq,
+            self.id,
+        ]
+    }
+}
+
+impl TryFrom<&[u8]> for Header {
+    type Error = SmpError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        if buf.len() < HEADER_LENGTH {
+            return Err(SmpError::Truncated);
+        }
+        Ok(Header {
+            op: buf[0] & 7,
+            version: buf[0] >> 3 & 3,
+            flags: buf[1],
+            length: u16::from_be_bytes([buf[2], buf[3]]),
+            group: u16::from_be_bytes([buf[4], buf[5]]),
+            seq: buf[6],
+            id: buf[7],
+        })
+    }
+}
+
+/// Collects the packet from the frames in a byte stream. Bytes outside of
+/// frames, such as log output, are skipped. A packet with a bad CRC or one
+/// that does not fit is dropped.
+pub struct FrameDecoder {
+    /// `len`, packet and CRC.
+    buf: [u8; MTU + 4],
+    len: usize,
+    /// base64 characters of the group being decoded.
+    group: [u8; 4],
+    group_len: usize,
+    prev: u8,
+    in_frame: bool,
+    /// A first frame was received and more may follow.
+    started: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buf: [0u8; MTU + 4],
+            len: 0,
+            group: [0u8; 4],
+            group_len: 0,
+            prev: 0,
+            in_frame: false,
+            started: false,
+        }
+    }
+
+    /// Returns the packet (header and CBOR map) when `byte` ends its last
+    /// frame.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if !self.in_frame {
+            let marker = [self.prev, byte];
+            self.prev = byte;
+            if marker == FRAME_START {
+                self.len = 0;
+                self.started = true;
+            } else if marker != FRAME_CONTINUE || !self.started {
+                // continuation frames follow the previous frame directly
+                self.started &= byte == FRAME_CONTINUE[0];
+                return None;
+            }
+            self.in_frame = true;
+            self.group_len = 0;
+            return None;
+        }
+        match byte {
+            b'\n' => {
+                self.in_frame = false;
+                self.prev = byte;
+                self.end_frame()
+            }
+            b'\r' => None,
+            _ if self.group_len < 4 && (byte == b'=' || base64_value(byte).is_some()) => {
+                self.group[self.group_len] = byte;
+                self.group_len += 1;
+                if self.group_len == 4 {
+                    self.group_len = 0;
+                    self.decode_group();
+                }
+                None
+            }
+            _ => {
+                self.abort();
+                None
+            }
+        }
+    }
+
+    /// Inside a frame, or between the frames of a packet.
+    pub fn is_receiving(&self) -> bool {
+        self.in_frame || self.started
+    }
+
+    fn decode_group(&mut self) {
+        let padding = self.group.iter().filter(|&&c| c == b'=').count();
+        let mut bits = 0u32;
+        for &c in &self.group {
+            bits = bits << 6 | base64_value(c).unwrap_or(0) as u32;
+        }
+        let n = 3 - padding.min(2);
+        if self.len + n > self.buf.len() {
+            self.abort();
+            return;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&bits.to_be_bytes()[1..1 + n]);
+        self.len += n;
+    }
+
+    fn end_frame(&mut self) -> Option<&[u8]> {
+        if !self.started || self.group_len != 0 || self.len < 2 {
+            self.abort();
+            return None;
+        }
+        let expected = 2 + u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if self.len < expected {
+            return None;
+        }
+        self.started = false;
+        if self.len > expected || expected < 4 || crc16(&self.buf[2..self.len]) != 0 {
+            return None;
+        }
+        Some(&self.buf[2..self.len - 2])
+    }
+
+    fn abort(&mut self) {
+        self.in_frame = false;
+        self.started = false;
+    }
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Encodes `input` as base64 with padding to `out`, which must hold
+/// `input.len().div_ceil(3) * 4` bytes. Returns the length.
+fn base64_encode(input: &[u8], out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for group in input.chunks(3) {
+        let mut bytes = [0u8; 4];
+        bytes[1..1 + group.len()].copy_from_slice(group);
+        let bits = u32::from_be_bytes(bytes);
+        for i in 0..4 {
+            out[len + i] = if i <= group.len() {
+                BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize]
+            } else {
+                b'='
+            };
+        }
+        len += 4;
+    }
+    len
+}
+
+/// Sends `packet` as frames, calling `write` with each line.
+pub fn encode_frames(packet: &[u8], mut write: impl FnMut(&[u8])) {
+    let length = (packet.len() as u16 + 2).to_be_bytes();
+    let crc = crc16(packet).to_be_bytes();
+    let mut bytes = length.iter().chain(packet).chain(&crc).copied();
+    let mut data = [0u8; FRAME_DATA_LENGTH];
+    let mut line = [0u8; MAX_FRAME_LENGTH];
+    let mut marker = FRAME_START;
+    loop {
+        let mut n = 0;
+        for b in bytes.by_ref().take(data.len()) {
+            data[n] = b;
+            n += 1;
+        }
+        if n == 0 {
+            break;
+        }
+        line[..2].copy_from_slice(&marker);
+        let len = 2 + base64_encode(&data[..n], &mut line[2..]);
+        line[len] = b'\n';
+        write(&line[..len + 1]);
+        marker = FRAME_CONTINUE;
+    }
+}
+
+/// What to do with the response to a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Response {
+    /// Length of the response packet.
+    pub length: usize,
+    /// Reset after the response is sent.
+    pub reset: bool,
+}
+
+struct Upload {
+    writer: SlotWriter,
+    length: u32,
+}
+
+/// Handles requests with a `BootClient`. An upload is written to the
+/// secondary slot over several requests.
+pub struct Server<F: Flash> {
+    client: BootClient<F>,
+    upload: Option<Upload>,
+}
+
+impl<F: Flash> Server<F> {
+    pub fn new(client: BootClient<F>) -> Self {
+        Server {
+            client,
+            upload: None,
+        }
+    }
+
+    pub fn client(&mut self) -> &mut BootClient<F> {
+        &mut self.client
+    }
+
+    /// Handles the request `packet` and writes the response packet to `buf`,
+    /// which should hold `MTU` bytes.
+    pub fn handle(&mut self, packet: &[u8], buf: &mut [u8]) -> Result<Response, SmpError> {
+        let header = Header::try_from(packet)?;
+        if header.op != OP_READ && header.op != OP_WRITE {
+            return Err(SmpError::NotRequest(header.op));
+        }
+        let body = packet[HEADER_LENGTH..]
+            .get(..header.length as usize)
+            .ok_or(SmpError::Truncated)?;
+        let (head, rest) = buf.split_at_mut(HEADER_LENGTH);
+        let mut encoder = Encoder::new(rest);
+        let result = match (header.group, header.id, header.op) {
+            (GROUP_OS, OS_ECHO, _) => echo(body, &mut encoder),
+            (GROUP_OS, OS_RESET, OP_WRITE) => encoder.map(0).map_err(ErrorCode::from),
+            (GROUP_IMAGE, IMAGE_STATE, OP_READ) => self.image_list(&mut encoder),
+            (GROUP_IMAGE, IMAGE_STATE, OP_WRITE) => self.image_test(body, &mut encoder),
+            (GROUP_IMAGE, IMAGE_UPLOAD, OP_WRITE) => self.upload(body, &mut encoder),
+            _ => Err(ErrorCode::NotSupported),
+        };
+        let length = match result {
+            Ok(()) => encoder.encoded().len(),
+            Err(code) => {
+                let mut encoder = Encoder::new(rest);
+                encoder.map(1).unwrap();
+                encoder.text("rc").unwrap();
+                encoder.uint(code as u64).unwrap();
+                encoder.encoded().len()
+            }
+        };
+        let response = Header {
+            op: header.op + 1,
+            flags: 0,
+            length: length as u16,
+            ..header
+        };
+        head.copy_from_slice(&response.to_bytes());
+        Ok(Response {
+            length: HEADER_LENGTH + length,
+            reset: result.is_ok() && (header.group, header.id) == (GROUP_OS, OS_RESET),
+        })
+    }
+
+    fn image_list(&mut self, encoder: &mut Encoder) -> Result<(), ErrorCode> {
+        let primary = optional(self.client.running_header())?;
+        let secondary = optional(self.client.update_header())?;
+        let confirmed =
+            self.client.image_state().map_err(|_| ErrorCode::Unknown)? != ImageState::Trial;
+        let (mut pending, mut permanent) = (false, false);
+        if let Some(ih) = &secondary {
+            let state = self
+                .client
+                .update_state(ih)
+                .map_err(|_| ErrorCode::Unknown)?;
+            pending = state != UpdateState::Done;
+            permanent = state == UpdateState::Pending(SwapType::Permanent);
+        }
+        encoder.map(1)?;
+        encoder.text("images")?;
+        encoder.array(primary.is_some() as u64 + secondary.is_some() as u64)?;
+        if let Some(ih) = &primary {
+            encode_image(encoder, 0, ih, [true, false, confirmed, false])?;
+        }
+        if let Some(ih) = &secondary {
+            encode_image(encoder, 1, ih, [false, pending, false, permanent])?;
+        }
+        Ok(())
+    }
+
+    fn image_test(&mut self, body: &[u8], encoder: &mut Encoder) -> Result<(), ErrorCode> {
+        let mut hash = None;
+        let mut confirm = false;
+        for_each_entry(body, |key, decoder| {
+            match key {
+                "hash" => hash = Some(decoder.bytes()?),
+                "confirm" => confirm = boolean(decoder)?,
+                _ => decoder.skip()?,
+            }
+            Ok(())
+        })?;
+        let primary = optional(self.client.running_header())?;
+        let secondary = optional(self.client.update_header())?;
+        match hash {
+            None if confirm => self.client.confirm().map_err(|_| ErrorCode::Unknown)?,
+            None => return Err(ErrorCode::Invalid),
+            Some(hash) if primary.is_some_and(|ih| ih.payload_digest == hash) => {
+                if confirm {
+                    self.client.confirm().map_err(|_| ErrorCode::Unknown)?;
+                }
+            }
+            Some(hash) if secondary.is_some_and(|ih| ih.payload_digest == hash) => {
+                let swap_type = if confirm {
+                    SwapType::Permanent
+                } else {
+                    SwapType::Test
+                };
+                self.client.mark_pending(swap_type)?;
+            }
+            Some(_) => return Err(ErrorCode::NoEntry),
+        }
+        self.image_list(encoder)
+    }
+
+    fn upload(&mut self, body: &[u8], encoder: &mut Encoder) -> Result<(), ErrorCode> {
+        let mut image = 0;
+        let mut offset = None;
+        let mut length = None;
+        let mut data: &[u8] = &[];
+        for_each_entry(body, |key, decoder| {
+            match key {
+                "image" => image = decoder.uint()?,
+                "off" => offset = Some(decoder.uint()?),
+                "len" => length = Some(decoder.uint()?),
+                "data" => data = decoder.bytes()?,
+                _ => decoder.skip()?,
+            }
+            Ok(())
+        })?;
+        let offset = offset.ok_or(ErrorCode::Invalid)?;
+        if image != 0 {
+            return Err(ErrorCode::Invalid);
+        }
+        if offset == 0 {
+            let length = length.ok_or(ErrorCode::Invalid)?;
+            let secondary = self.client.layout().secondary;
+            if length > secondary.size as u64 {
+                return Err(ErrorCode::NoMemory);
+            }
+            if self.client.image_state().map_err(|_| ErrorCode::Unknown)? == ImageState::Trial {
+                return Err(ErrorCode::BadState);
+            }
+            self.upload = Some(Upload {
+                writer: SlotWriter::new(secondary),
+                length: length as u32,
+            });
+        }
+        let upload = self.upload.as_mut().ok_or(ErrorCode::BadState)?;
+        // a chunk at another offset is answered with the offset to resume from
+        if offset == upload.writer.written() as u64 {
+            if data.len() as u64 > (upload.length - upload.writer.written()) as u64 {
+                return Err(ErrorCode::Invalid);
+            }
+            let flash = self.client.flash();
+            upload.writer.write(flash, data)?;
+            if upload.writer.written() == upload.length {
+                upload.writer.flush(flash)?;
+            }
+        }
+        let written = upload.writer.written();
+        if written == upload.length {
+            self.upload = None;
+        }
+        encoder.map(2)?;
+        encoder.text("rc")?;
+        encoder.uint(0)?;
+        encoder.text("off")?;
+        encoder.uint(written as u64)?;
+        Ok(())
+    }
+}
+
+/// An image that fails to parse or validate is left out.
+fn optional<E>(result: Result<ImageHeader, AppError<E>>) -> Result<Option<ImageHeader>, ErrorCode> {
+    match result {
+        Ok(ih) => Ok(Some(ih)),
+        Err(AppError::Flash(_)) => Err(ErrorCode::Unknown),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Encodes an entry of `images` with the flags `active`, `pending`,
+/// `confirmed` and `permanent`.
+fn encode_image(
+    encoder: &mut Encoder,
+    slot: u64,
+    ih: &ImageHeader,
+    flags: [bool; 4],
+) -> Result<(), CborError> {
+    let mut version = TextBuf::default();
+    write!(version, "{}", ih.version()).unwrap();
+    encoder.map(8)?;
+    encoder.text("slot")?;
+    encoder.uint(slot)?;
+    encoder.text("version")?;
+    encoder.text(version.as_str())?;
+    encoder.text("hash")?;
+    encoder.bytes(&ih.payload_digest)?;
+    encoder.text("bootable")?;
+    encoder.simple(SIMPLE_TRUE)?;
+    for (name, flag) in ["active", "pending", "confirmed", "permanent"]
+        .into_iter()
+        .zip(flags)
+    {
+        encoder.text(name)?;
+        encoder.simple(if flag { SIMPLE_TRUE } else { SIMPLE_FALSE })?;
+    }
+    Ok(())
+}
+
+fn echo(body: &[u8], encoder: &mut Encoder) -> Result<(), ErrorCode> {
+    let mut text = "";
+    for_each_entry(body, |key, decoder| {
+        match key {
+            "d" => text = decoder.text()?,
+            _ => decoder.skip()?,
+        }
+        Ok(())
+    })?;
+    encoder.map(1)?;
+    encoder.text("r")?;
+    encoder.text(text)?;
+    Ok(())
+}
+
+/// Calls `f` with each key of the request map, which reads or skips the
+/// value. An empty request is an empty map.
+fn for_each_entry<'a>(
+    body: &'a [u8],
+    mut f: impl FnMut(&'a str, &mut Decoder<'a>) -> Result<(), CborError>,
+) -> Result<(), ErrorCode> {
+    if body.is_empty() {
+        return Ok(());
+    }
+    let mut decoder = Decoder::new(body);
+    for _ in 0..decoder.map()? {
+        let key = decoder.text()?;
+        f(key, &mut decoder)?;
+    }
+    Ok(())
+}
+
+fn boolean(decoder: &mut Decoder) -> Result<bool, CborError> {
+    let offset = decoder.position();
+    match decoder.simple()? {
+        SIMPLE_TRUE => Ok(true),
+        SIMPLE_FALSE => Ok(false),
+        _ => Err(CborError::Unsupported { offset }),
+    }
+}
+
+/// Text of up to 32 bytes, enough for an `ImageVersion`.
+#[derive(Default)]
+struct TextBuf {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl TextBuf {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl Write for TextBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let buf = self
+            .buf
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(fmt::Error)?;
+        buf.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tests::{client, image};
+    use crate::cbor::MAJOR_TEXT;
+    use crate::flash::mock::MockFlash;
+    use crate::swap::tests::boot;
+    use std::string::String;
+    use std::vec::Vec;
+
+    // recorded from mcumgr's serial framing
+    const ECHO_REQUEST: &[u8] = b"\x06\x09ABMCAAAJAAAqAKFhZGVoZWxsb40o\n";
+    const ECHO_RESPONSE: &[u8] = b"\x06\x09ABMDAAAJAAAqAKFhcmVoZWxsbwuU\n";
+    /// Version 2 echo of "0123456789" x 20 in three frames.
+    const LONG_ECHO_REQUEST: &[u8] = b"\x06\x09ANcKAADNAAAHAKFhZHjIMDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3\n\x04\x14ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkw\n\x04\x14MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkL1g==\n";
+    const LONG_ECHO_RESPONSE: &[u8] = b"\x06\x09ANcLAADNAAAHAKFhcnjIMDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3\n\x04\x14ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkw\n\x04\x14MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODm28Q==\n";
+    const STATE_READ_REQUEST: &[u8] = b"\x06\x09AAsAAAABAAEBAKCxzg==\n";
+    const RESET_REQUEST: &[u8] = b"\x06\x09AAsCAAABAAACBaCnuA==\n";
+    const RESET_RESPONSE: &[u8] = b"\x06\x09AAsDAAABAAACBaBMmw==\n";
+    /// A read of group 9.
+    const UNKNOWN_REQUEST: &[u8] = b"\x06\x09AAsAAAABAAkDAaBpXA==\n";
+    const UNKNOWN_RESPONSE: &[u8] = b"\x06\x09AA8BAAAFAAkDAaFicmMIHq4=\n";
+
+    fn server() -> Server<MockFlash> {
+        Server::new(client())
+    }
+
+    /// Feeds `frames` to a decoder and the packets to `server`. Returns the
+    /// response frames and whether to reset.
+    fn exchange(server: &mut Server<MockFlash>, frames: &[u8]) -> (Vec<u8>, bool) {
+        let mut decoder = FrameDecoder::new();
+        let mut output = Vec::new();
+        let mut reset = false;
+        for &b in frames {
+            if let Some(packet) = decoder.push(b) {
+                let mut buf = [0u8; MTU];
+                let response = server.handle(packet, &mut buf).unwrap();
+                encode_frames(&buf[..response.length], |line| {
+                    output.extend_from_slice(line)
+                });
+                reset |= response.reset;
+            }
+        }
+        (output, reset)
+    }
+
+    /// Sends a request with the CBOR map `body` and returns the response body.
+    fn request(server: &mut Server<MockFlash>, op: u8, group: u16, id: u8, body: &[u8]) -> Vec<u8> {
+        let header = Header {
+            op,
+            version: 1,
+            flags: 0,
+            length: body.len() as u16,
+            group,
+            seq: 0x80,
+            id,
+        };
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(body);
+        let mut frames = Vec::new();
+        encode_frames(&packet, |line| {
+            assert!(line.len() <= MAX_FRAME_LENGTH);
+            frames.extend_from_slice(line);
+        });
+        let (output, _) = exchange(server, &frames);
+        let mut decoder = FrameDecoder::new();
+        let response: Vec<_> = output
+            .iter()
+            .filter_map(|&b| decoder.push(b).map(|p| p.to_vec()))
+            .collect();
+        assert_eq!(response.len(), 1);
+        let header = Header::try_from(&response[0][..]).unwrap();
+        assert_eq!((header.op, header.group, header.id), (op + 1, group, id));
+        response[0][HEADER_LENGTH..].to_vec()
+    }
+
+    fn rc(body: &[u8]) -> Option<u64> {
+        let mut rc = None;
+        for_each_entry(body, |key, decoder| {
+            match key {
+                "rc" => rc = Some(decoder.uint()?),
+                _ => decoder.skip()?,
+            }
+            Ok(())
+        })
+        .unwrap();
+        rc
+    }
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Image {
+        slot: u64,
+        version: String,
+        hash: Vec<u8>,
+        /// `active`, `pending`, `confirmed` and `permanent`.
+        flags: [bool; 4],
+    }
+
+    fn images(body: &[u8]) -> Vec<Image> {
+        let mut decoder = Decoder::new(body);
+        assert_eq!(decoder.map(), Ok(1));
+        assert_eq!(decoder.text(), Ok("images"));
+        let count = decoder.array().unwrap();
+        (0..count)
+            .map(|_| {
+                let mut image = Image::default();
+                for _ in 0..decoder.map().unwrap() {
+                    let key = decoder.text().unwrap();
+                    let names = ["active", "pending", "confirmed", "permanent"];
+                    match key {
+                        "slot" => image.slot = decoder.uint().unwrap(),
+                        "version" => image.version = decoder.text().unwrap().into(),
+                        "hash" => image.hash = decoder.bytes().unwrap().to_vec(),
+                        _ if names.contains(&key) => {
+                            let i = names.iter().position(|&n| n == key).unwrap();
+                            image.flags[i] = boolean(&mut decoder).unwrap();
+                        }
+                        _ => decoder.skip().unwrap(),
+                    }
+                }
+                image
+            })
+            .collect()
+    }
+
+    fn upload_chunk(off: usize, len: Option<usize>, data: &[u8]) -> Vec<u8> {
+        let mut buf = [0u8; MTU];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.map(2 + len.is_some() as u64).unwrap();
+        encoder.text("off").unwrap();
+        encoder.uint(off as u64).unwrap();
+        if let Some(len) = len {
+            encoder.text("len").unwrap();
+            encoder.uint(len as u64).unwrap();
+        }
+        encoder.text("data").unwrap();
+        encoder.bytes(data).unwrap();
+        encoder.encoded().to_vec()
+    }
+
+    /// Returns `off` of the response.
+    fn upload(server: &mut Server<MockFlash>, off: usize, len: Option<usize>, data: &[u8]) -> u64 {
+        let body = request(
+            server,
+            OP_WRITE,
+            GROUP_IMAGE,
+            IMAGE_UPLOAD,
+            &upload_chunk(off, len, data),
+        );
+        assert_eq!(rc(&body), Some(0));
+        let mut off = None;
+        for_each_entry(&body, |key, decoder| {
+            match key {
+                "off" => off = Some(decoder.uint()?),
+                _ => decoder.skip()?,
+            }
+            Ok(())
+        })
+        .unwrap();
+        off.unwrap()
+    }
+
+    fn image_test(server: &mut Server<MockFlash>, hash: Option<&[u8]>, confirm: bool) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.map(1 + hash.is_some() as u64).unwrap();
+        if let Some(hash) = hash {
+            encoder.text("hash").unwrap();
+            encoder.bytes(hash).unwrap();
+        }
+        encoder.text("confirm").unwrap();
+        encoder
+            .simple(if confirm { SIMPLE_TRUE } else { SIMPLE_FALSE })
+            .unwrap();
+        let body = encoder.encoded().to_vec();
+        request(server, OP_WRITE, GROUP_IMAGE, IMAGE_STATE, &body)
+    }
+
+    #[test]
+    fn test_recorded() {
+        let mut server = server();
+        assert_eq!(
+            exchange(&mut server, ECHO_REQUEST),
+            (ECHO_RESPONSE.to_vec(), false)
+        );
+        assert_eq!(
+            exchange(&mut server, LONG_ECHO_REQUEST),
+            (LONG_ECHO_RESPONSE.to_vec(), false)
+        );
+        assert_eq!(
+            exchange(&mut server, UNKNOWN_REQUEST),
+            (UNKNOWN_RESPONSE.to_vec(), false)
+        );
+        assert_eq!(
+            exchange(&mut server, RESET_REQUEST),
+            (RESET_RESPONSE.to_vec(), true)
+        );
+
+        // log output around frames and a carriage return are skipped
+        let mut stream = b"booting\r\n".to_vec();
+        stream.extend_from_slice(&ECHO_REQUEST[..ECHO_REQUEST.len() - 1]);
+        stream.extend_from_slice(b"\r\nC");
+        assert_eq!(
+            exchange(&mut server, &stream),
+            (ECHO_RESPONSE.to_vec(), false)
+        );
+
+        let (output, _) = exchange(&mut server, STATE_READ_REQUEST);
+        let mut decoder = FrameDecoder::new();
+        let packet = output
+            .iter()
+            .find_map(|&b| decoder.push(b).map(|p| p.to_vec()))
+            .unwrap();
+        assert_eq!(&packet[..2], &[OP_READ_RSP, 0]);
+        let images = images(&packet[HEADER_LENGTH..]);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].slot, 0);
+        assert_eq!(images[0].version, "0.0.0+00000011");
+        assert_eq!(images[0].flags, [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let mut server = server();
+        // bad crc
+        let mut frames = ECHO_REQUEST.to_vec();
+        let n = frames.len();
+        frames[n - 3] ^= 1;
+        assert_eq!(exchange(&mut server, &frames), (Vec::new(), false));
+        // continuation without a first frame
+        assert_eq!(
+            exchange(&mut server, &LONG_ECHO_REQUEST[127..]).0,
+            Vec::new()
+        );
+        // a missing continuation frame
+        let mut frames = LONG_ECHO_REQUEST[..127].to_vec();
+        frames.extend_from_slice(&LONG_ECHO_REQUEST[254..]);
+        assert_eq!(exchange(&mut server, &frames).0, Vec::new());
+        // anything between the frames of a packet drops it
+        let mut frames = LONG_ECHO_REQUEST[..127].to_vec();
+        frames.push(b'C');
+        frames.extend_from_slice(&LONG_ECHO_REQUEST[127..]);
+        assert_eq!(exchange(&mut server, &frames).0, Vec::new());
+        // a new first frame restarts the packet
+        let mut frames = LONG_ECHO_REQUEST[..127].to_vec();
+        frames.extend_from_slice(ECHO_REQUEST);
+        assert_eq!(exchange(&mut server, &frames).0, ECHO_RESPONSE);
+        // a character that is not base64
+        let mut frames = ECHO_REQUEST.to_vec();
+        frames[10] = b'*';
+        assert_eq!(exchange(&mut server, &frames).0, Vec::new());
+
+        let mut buf = [0u8; MTU];
+        assert_eq!(server.handle(&[0; 7], &mut buf), Err(SmpError::Truncated));
+        let packet = [OP_WRITE, 0, 0, 1, 0, 0, 0, 0];
+        assert_eq!(server.handle(&packet, &mut buf), Err(SmpError::Truncated));
+        let packet = [OP_READ_RSP, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            server.handle(&packet, &mut buf),
+            Err(SmpError::NotRequest(OP_READ_RSP))
+        );
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut server = server();
+        let image = image(0x1c80, 0x22);
+        let mut off = 0;
+        for data in image.chunks(500) {
+            let len = (off == 0).then_some(image.len());
+            off = upload(&mut server, off, len, data) as usize;
+        }
+        assert_eq!(off, image.len());
+        let secondary = crate::swap::tests::TEST_LAYOUT.secondary.offset as usize;
+        assert_eq!(
+            &server.client().flash().data()[secondary..secondary + image.len()],
+            &image[..]
+        );
+
+        let body = request(&mut server, OP_READ, GROUP_IMAGE, IMAGE_STATE, &[]);
+        let images = images(&body);
+        let hash = &image[153..185];
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[1].slot, 1);
+        assert_eq!(images[1].version, "0.0.0+00000022");
+        assert_eq!(images[1].hash, hash);
+        assert_eq!(images[1].flags, [false, true, false, false]);
+
+        let images = self::images(&image_test(&mut server, Some(hash), true));
+        assert_eq!(images[1].flags, [false, true, false, true]);
+        let images = self::images(&image_test(&mut server, Some(hash), false));
+        assert_eq!(images[1].flags, [false, true, false, false]);
+
+        assert_eq!(boot(server.client().flash()), Ok(true));
+        assert_eq!(
+            rc(&image_test(&mut server, None, false)),
+            Some(ErrorCode::Invalid as u64)
+        );
+        let images = self::images(&request(
+            &mut server,
+            OP_READ,
+            GROUP_IMAGE,
+            IMAGE_STATE,
+            &[],
+        ));
+        assert_eq!(images[0].hash, hash);
+        assert_eq!(images[0].flags, [true, false, false, false]);
+        // the previous image is needed for a revert
+        assert_eq!(images[1].flags, [false, false, false, false]);
+        let body = upload_chunk(0, Some(image.len()), &image[..100]);
+        let body = request(&mut server, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, &body);
+        assert_eq!(rc(&body), Some(ErrorCode::BadState as u64));
+
+        let images = self::images(&image_test(&mut server, Some(hash), true));
+        assert_eq!(images[0].flags, [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_upload_errors() {
+        let mut server = server();
+        let image = image(0x0300, 0x22);
+        let write = |server: &mut Server<MockFlash>, body: &[u8]| {
+            rc(&request(server, OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, body))
+        };
+        let bad_state = Some(ErrorCode::BadState as u64);
+        let invalid = Some(ErrorCode::Invalid as u64);
+        assert_eq!(
+            write(&mut server, &upload_chunk(100, None, &image[100..200])),
+            bad_state
+        );
+        assert_eq!(
+            write(&mut server, &upload_chunk(0, None, &image[..100])),
+            invalid
+        );
+        assert_eq!(
+            write(&mut server, &upload_chunk(0, Some(0x3001), &image[..100])),
+            Some(ErrorCode::NoMemory as u64)
+        );
+        assert_eq!(write(&mut server, &[0xa1, MAJOR_TEXT << 5 | 1]), invalid);
+
+        assert_eq!(
+            upload(&mut server, 0, Some(image.len()), &image[..100]),
+            100
+        );
+        // a repeated or skipped chunk is answered with the offset to resume from
+        assert_eq!(upload(&mut server, 0x80, None, &image[0x80..0x100]), 100);
+        assert_eq!(upload(&mut server, 200, None, &image[200..300]), 100);
+        assert_eq!(upload(&mut server, 100, None, &image[100..600]), 600);
+        assert_eq!(
+            upload(&mut server, 600, None, &image[600..]),
+            image.len() as u64
+        );
+        assert_eq!(
+            write(&mut server, &upload_chunk(100, None, &image[100..200])),
+            bad_state
+        );
+
+        // an incomplete image is not installed
+        assert_eq!(
+            upload(&mut server, 0, Some(image.len()), &image[..100]),
+            100
+        );
+        let hash = &image[153..185];
+        assert_eq!(
+            rc(&image_test(&mut server, Some(hash), false)),
+            Some(ErrorCode::NoEntry as u64)
+        );
+        assert_eq!(
+            rc(&image_test(&mut server, Some(&[0xff; 32]), false)),
+            Some(ErrorCode::NoEntry as u64)
+        );
+        assert_eq!(upload(&mut server, 100, None, &image[100..600]), 600);
+        assert_eq!(
+            upload(&mut server, 600, None, &image[600..image.len() - 1]),
+            image.len() as u64 - 1
+        );
+        assert_eq!(
+            rc(&image_test(&mut server, Some(hash), false)),
+            Some(ErrorCode::Corrupt as u64)
+        );
+        assert_eq!(
+            write(&mut server, &upload_chunk(image.len() - 1, None, &[0, 0])),
+            invalid
+        );
+    }
+}
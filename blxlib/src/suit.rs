@@ -0,0 +1,1078 @@
+//! SUIT manifests (RFC 9124, draft-ietf-suit-manifest) for single component
+//! updates.
+//!
+//! A SUIT image in a slot is a `HEADER_LENGTH` locator, the payload (linked
+//! like the payload of a native image) and the SUIT envelope after it. The
+//! locator is `SUIT_IMAGE_MAGIC`, the offset and the length of the envelope;
+//! it is not authenticated, but only tells where to look.
+//!
+//! The envelope holds the manifest and its authentication wrapper: the
+//! SHA-256 digest of the manifest and a COSE_Sign1 signature of that digest.
+//! The command sequences of the manifest check the vendor and class IDs and
+//! the digest of the payload; `process` runs them.
+
+use crate::cbor::{
+    CborError, Decoder, Encoder, MAJOR_BYTES, MAJOR_SIMPLE, MAJOR_UINT, SIMPLE_NULL, SIMPLE_TRUE,
+};
+use crate::image_header::HEADER_LENGTH;
+use crate::sha256::{sha256, DIGEST_LENGTH};
+use core::fmt;
+
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+use crate::signature::SignatureError;
+#[cfg(feature = "ed25519")]
+use crate::signature::ED25519_PUBLIC_KEY_LENGTH;
+#[cfg(feature = "p256")]
+use crate::signature::P256_PUBLIC_KEY_LENGTH;
+
+pub const SUIT_IMAGE_MAGIC: u32 = 0x5355_4954; // "SUIT"
+
+/// UUID v5 of the vendor (DNS name "boot-k.example.com").
+pub const VENDOR_ID: [u8; 16] = [
+    0x9f, 0xde, 0x38, 0x6c, 0xa8, 0xaf, 0x55, 0x43, 0xb5, 0x11, 0x69, 0x8c, 0x83, 0x9c, 0x6e, 0xf1,
+];
+/// UUID v5 of the device class ("rp2040-pico" in the vendor namespace).
+pub const CLASS_ID: [u8; 16] = [
+    0x10, 0x28, 0xe3, 0xb4, 0x18, 0xf4, 0x55, 0xbc, 0x81, 0x20, 0x4f, 0xc8, 0xdf, 0x37, 0x0d, 0x67,
+];
+
+pub const TAG_ENVELOPE: u64 = 107;
+pub const TAG_MANIFEST: u64 = 1070;
+pub const TAG_COSE_SIGN1: u64 = 18;
+
+// SUIT_Envelope
+pub const ENVELOPE_AUTHENTICATION: u64 = 2;
+pub const ENVELOPE_MANIFEST: u64 = 3;
+
+// SUIT_Manifest
+pub const MANIFEST_VERSION: u64 = 1;
+pub const MANIFEST_SEQUENCE_NUMBER: u64 = 2;
+pub const MANIFEST_COMMON: u64 = 3;
+pub const MANIFEST_VALIDATE: u64 = 7;
+pub const MANIFEST_INVOKE: u64 = 9;
+pub const MANIFEST_INSTALL: u64 = 20;
+
+// SUIT_Common
+pub const COMMON_COMPONENTS: u64 = 2;
+pub const COMMON_SHARED_SEQUENCE: u64 = 4;
+
+// SUIT_Command_Sequence
+pub const CONDITION_VENDOR_ID: u64 = 1;
+pub const CONDITION_CLASS_ID: u64 = 2;
+pub const CONDITION_IMAGE_MATCH: u64 = 3;
+pub const CONDITION_ABORT: u64 = 14;
+pub const DIRECTIVE_SET_COMPONENT_INDEX: u64 = 12;
+pub const DIRECTIVE_WRITE: u64 = 18;
+pub const DIRECTIVE_SET_PARAMETERS: u64 = 19;
+pub const DIRECTIVE_OVERRIDE_PARAMETERS: u64 = 20;
+pub const DIRECTIVE_FETCH: u64 = 21;
+pub const DIRECTIVE_COPY: u64 = 22;
+pub const DIRECTIVE_INVOKE: u64 = 23;
+
+// SUIT_Parameters
+pub const PARAMETER_VENDOR_ID: u64 = 1;
+pub const PARAMETER_CLASS_ID: u64 = 2;
+pub const PARAMETER_IMAGE_DIGEST: u64 = 3;
+pub const PARAMETER_IMAGE_SIZE: u64 = 14;
+pub const PARAMETER_URI: u64 = 21;
+
+// COSE
+pub const COSE_HEADER_ALG: i64 = 1;
+pub const COSE_ALG_SHA256: i64 = -16;
+pub const COSE_ALG_ES256: i64 = -7;
+pub const COSE_ALG_EDDSA: i64 = -8;
+
+const LOCATOR_LENGTH: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuitError {
+    Cbor(CborError),
+    BadMagic(u32),
+    /// The envelope is not within the slot after the locator.
+    BadLocator {
+        offset: u32,
+        length: u32,
+    },
+    /// A required member of the envelope or manifest is missing.
+    Missing(&'static str),
+    UnsupportedManifestVersion(u64),
+    /// Not SHA-256 (`COSE_ALG_SHA256`).
+    UnsupportedDigest(i64),
+    /// Only manifests with a single component are supported.
+    UnsupportedComponents,
+    UnsupportedCommand(u64),
+    ManifestDigest,
+    /// A condition of the command sequences failed.
+    ConditionFailed(u64),
+    /// The image size is larger than the payload area.
+    ImageTooLarge {
+        max: u64,
+        actual: u64,
+    },
+    /// The condition (vendor ID, class ID or image match) has not passed in
+    /// any command sequence.
+    MissingCondition(u64),
+}
+
+impl From<CborError> for SuitError {
+    fn from(e: CborError) -> Self {
+        SuitError::Cbor(e)
+    }
+}
+
+impl fmt::Display for SuitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuitError::Cbor(e) => write!(f, "suit: {}", e),
+            SuitError::BadMagic(magic) => write!(f, "suit magic is not correct: {:08x}", magic),
+            SuitError::BadLocator { offset, length } => write!(
+                f,
+                "suit envelope is not in the slot: offset={:08x} length={:08x}",
+                offset, length
+            ),
+            SuitError::Missing(member) => write!(f, "suit {} is missing", member),
+            SuitError::UnsupportedManifestVersion(version) => {
+                write!(f, "suit manifest version is not supported: {}", version)
+            }
+            SuitError::UnsupportedDigest(alg) => {
+                write!(f, "suit digest algorithm is not supported: {}", alg)
+            }
+            SuitError::UnsupportedComponents => {
+                write!(f, "suit manifest must have a single component")
+            }
+            SuitError::UnsupportedCommand(command) => {
+                write!(f, "suit command is not supported: {}", command)
+            }
+            SuitError::ManifestDigest => write!(f, "suit manifest digest is not correct"),
+            SuitError::ConditionFailed(condition) => {
+                write!(f, "suit condition {} failed", condition)
+            }
+            SuitError::ImageTooLarge { max, actual } => write!(
+                f,
+                "suit image size is too large: max={:08x} actual={:08x}",
+                max, actual
+            ),
+            SuitError::MissingCondition(condition) => {
+                write!(f, "suit manifest does not check condition {}", condition)
+            }
+        }
+    }
+}
+
+impl core::error::Error for SuitError {}
+
+/// Decodes an encoded `SUIT_Digest` (`[algorithm, bytes]`), SHA-256 only.
+fn decode_digest(buf: &[u8]) -> Result<&[u8; DIGEST_LENGTH], SuitError> {
+    let mut dec = Decoder::new(buf);
+    dec.array()?;
+    let alg = dec.int()?;
+    let offset = dec.position();
+    if alg != COSE_ALG_SHA256 {
+        return Err(SuitError::UnsupportedDigest(alg));
+    }
+    dec.bytes()?
+        .try_into()
+        .map_err(|_| SuitError::Cbor(CborError::Truncated { offset }))
+}
+
+/// The members of a SUIT envelope that are used here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    /// Encoded `SUIT_Digest` of the manifest.
+    digest: &'a [u8],
+    /// Encoded `COSE_Sign1` of the digest, if signed.
+    signature: Option<&'a [u8]>,
+    /// Encoded manifest, exactly as digested.
+    manifest: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SuitError> {
+        let mut dec = Decoder::new(buf);
+        dec.optional_tag(TAG_ENVELOPE)?;
+        let mut auth = None;
+        let mut manifest = None;
+        for _ in 0..dec.map()? {
+            // integrated payloads have text keys
+            if dec.peek_major()? != MAJOR_UINT {
+                dec.skip()?;
+                dec.skip()?;
+                continue;
+            }
+            match dec.uint()? {
+                ENVELOPE_AUTHENTICATION => auth = Some(dec.bytes()?),
+                ENVELOPE_MANIFEST => manifest = Some(dec.bytes()?),
+                _ => dec.skip()?,
+            }
+        }
+        let auth = auth.ok_or(SuitError::Missing("authentication wrapper"))?;
+        let manifest = manifest.ok_or(SuitError::Missing("manifest"))?;
+
+        // [digest, * authentication block]
+        let mut dec = Decoder::new(auth);
+        let blocks = dec.array()?;
+        if blocks == 0 {
+            return Err(SuitError::Missing("manifest digest"));
+        }
+        let digest = dec.bytes()?;
+        let signature = if blocks > 1 { Some(dec.bytes()?) } else { None };
+        Ok(Envelope {
+            digest,
+            signature,
+            manifest,
+        })
+    }
+
+    /// SHA-256 of the manifest as recorded in the authentication wrapper.
+    pub fn manifest_digest(&self) -> Result<&'a [u8; DIGEST_LENGTH], SuitError> {
+        decode_digest(self.digest)
+    }
+
+    /// Checks the recorded digest against the manifest. A signature of the
+    /// digest is checked with `verify_ed25519` or `verify_p256`.
+    pub fn check_digest(&self) -> Result<(), SuitError> {
+        if *self.manifest_digest()? != sha256(self.manifest) {
+            return Err(SuitError::ManifestDigest);
+        }
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> Result<Manifest<'a>, SuitError> {
+        Manifest::parse(self.manifest)
+    }
+
+    /// The COSE algorithm of the signature, `None` if the envelope is not signed.
+    pub fn signature_alg(&self) -> Option<i64> {
+        CoseSign1::parse(self.signature?)
+            .ok()
+            .map(|sign1| sign1.alg)
+    }
+}
+
+/// The members of a SUIT manifest that are used here. The command sequences
+/// are kept encoded and run by `process`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Manifest<'a> {
+    pub sequence_number: u64,
+    shared: Option<&'a [u8]>,
+    install: Option<&'a [u8]>,
+    validate: Option<&'a [u8]>,
+    invoke: Option<&'a [u8]>,
+}
+
+impl<'a> Manifest<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SuitError> {
+        let mut dec = Decoder::new(buf);
+        dec.optional_tag(TAG_MANIFEST)?;
+        let mut manifest = Manifest::default();
+        let mut version = None;
+        let mut sequence_number = None;
+        for _ in 0..dec.map()? {
+            match dec.uint()? {
+                MANIFEST_VERSION => version = Some(dec.uint()?),
+                MANIFEST_SEQUENCE_NUMBER => sequence_number = Some(dec.uint()?),
+                MANIFEST_COMMON => manifest.shared = parse_common(dec.bytes()?)?,
+                MANIFEST_VALIDATE => manifest.validate = Some(dec.bytes()?),
+                MANIFEST_INVOKE => manifest.invoke = Some(dec.bytes()?),
+                // a severed install sequence is a digest instead of a bstr
+                MANIFEST_INSTALL if dec.peek_major()? != MAJOR_BYTES => {
+                    return Err(SuitError::UnsupportedCommand(MANIFEST_INSTALL));
+                }
+                MANIFEST_INSTALL => manifest.install = Some(dec.bytes()?),
+                _ => dec.skip()?,
+            }
+        }
+        match version {
+            Some(1) => {}
+            Some(version) => return Err(SuitError::UnsupportedManifestVersion(version)),
+            None => return Err(SuitError::Missing("manifest version")),
+        }
+        manifest.sequence_number =
+            sequence_number.ok_or(SuitError::Missing("manifest sequence number"))?;
+        Ok(manifest)
+    }
+
+    pub fn has_install(&self) -> bool {
+        self.install.is_some()
+    }
+}
+
+/// Checks that there is a single component and returns the shared sequence.
+fn parse_common(buf: &[u8]) -> Result<Option<&[u8]>, SuitError> {
+    let mut dec = Decoder::new(buf);
+    let mut shared = None;
+    let mut components = 0;
+    for _ in 0..dec.map()? {
+        match dec.uint()? {
+            COMMON_COMPONENTS => {
+                components = dec.array()?;
+                for _ in 0..components {
+                    dec.skip()?;
+                }
+            }
+            COMMON_SHARED_SEQUENCE => shared = Some(dec.bytes()?),
+            _ => dec.skip()?,
+        }
+    }
+    if components != 1 {
+        return Err(SuitError::UnsupportedComponents);
+    }
+    Ok(shared)
+}
+
+/// What the command sequences have set and checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// A vendor-ID condition has passed.
+    pub vendor_matched: bool,
+    /// A class-ID condition has passed.
+    pub class_matched: bool,
+    /// An image-match condition has checked the payload.
+    pub image_matched: bool,
+    /// The install sequence fetches, copies or writes the payload.
+    pub install: bool,
+    pub invoke: bool,
+    pub image_size: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Parameters<'a> {
+    vendor_id: Option<&'a [u8]>,
+    class_id: Option<&'a [u8]>,
+    image_digest: Option<&'a [u8]>,
+    image_size: Option<u64>,
+}
+
+fn set_parameters<'a>(
+    dec: &mut Decoder<'a>,
+    params: &mut Parameters<'a>,
+    overwrite: bool,
+) -> Result<(), SuitError> {
+    fn set<T>(param: &mut Option<T>, value: T, overwrite: bool) {
+        if overwrite || param.is_none() {
+            *param = Some(value);
+        }
+    }
+
+    for _ in 0..dec.map()? {
+        match dec.uint()? {
+            PARAMETER_VENDOR_ID => set(&mut params.vendor_id, dec.bytes()?, overwrite),
+            PARAMETER_CLASS_ID => set(&mut params.class_id, dec.bytes()?, overwrite),
+            PARAMETER_IMAGE_DIGEST => set(&mut params.image_digest, dec.bytes()?, overwrite),
+            PARAMETER_IMAGE_SIZE => set(&mut params.image_size, dec.uint()?, overwrite),
+            // where the payload comes from: it is in the slot already
+            _ => dec.skip()?,
+        }
+    }
+    Ok(())
+}
+
+/// Runs one command sequence (`[command, argument, ...]`).
+fn run<'a>(
+    sequence: &'a [u8],
+    params: &mut Parameters<'a>,
+    payload: &[u8],
+    report: &mut Report,
+) -> Result<(), SuitError> {
+    let mut dec = Decoder::new(sequence);
+    let len = dec.array()?;
+    if len % 2 != 0 {
+        // the argument of the last command is missing
+        return Err(CborError::Truncated {
+            offset: sequence.len(),
+        }
+        .into());
+    }
+    for _ in 0..len / 2 {
+        let command = dec.uint()?;
+        let id_matches = |id: Option<&[u8]>, expected: &[u8]| id == Some(expected);
+        let passed = match command {
+            CONDITION_VENDOR_ID => {
+                let passed = id_matches(params.vendor_id, &VENDOR_ID);
+                report.vendor_matched |= passed;
+                passed
+            }
+            CONDITION_CLASS_ID => {
+                let passed = id_matches(params.class_id, &CLASS_ID);
+                report.class_matched |= passed;
+                passed
+            }
+            CONDITION_IMAGE_MATCH => {
+                let expected = decode_digest(
+                    params
+                        .image_digest
+                        .ok_or(SuitError::Missing("image digest"))?,
+                )?;
+                let size = params.image_size.ok_or(SuitError::Missing("image size"))?;
+                let image = usize::try_from(size)
+                    .ok()
+                    .and_then(|size| payload.get(..size))
+                    .ok_or(SuitError::ImageTooLarge {
+                        max: payload.len() as u64,
+                        actual: size,
+                    })?;
+                report.image_size = size;
+                report.image_matched = sha256(image) == *expected;
+                report.image_matched
+            }
+            CONDITION_ABORT => false,
+            DIRECTIVE_SET_COMPONENT_INDEX => {
+                // the only component: 0 or true (all)
+                let index_ok = match dec.peek_major()? {
+                    MAJOR_SIMPLE => dec.simple()? == SIMPLE_TRUE,
+                    _ => dec.uint()? == 0,
+                };
+                if !index_ok {
+                    return Err(SuitError::UnsupportedComponents);
+                }
+                continue;
+            }
+            DIRECTIVE_SET_PARAMETERS | DIRECTIVE_OVERRIDE_PARAMETERS => {
+                set_parameters(&mut dec, params, command == DIRECTIVE_OVERRIDE_PARAMETERS)?;
+                continue;
+            }
+            DIRECTIVE_FETCH | DIRECTIVE_COPY | DIRECTIVE_WRITE => {
+                report.install = true;
+                true
+            }
+            DIRECTIVE_INVOKE => {
+                report.invoke = true;
+                true
+            }
+            _ => return Err(SuitError::UnsupportedCommand(command)),
+        };
+        // the reporting policy of conditions and directives
+        dec.skip()?;
+        if !passed {
+            return Err(SuitError::ConditionFailed(command));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the install, validate and invoke sequences of `manifest`, each after
+/// the shared sequence, against `payload` (which may be longer than the image).
+pub fn process(manifest: &Manifest, payload: &[u8]) -> Result<Report, SuitError> {
+    let mut report = Report::default();
+    for sequence in [manifest.install, manifest.validate, manifest.invoke]
+        .into_iter()
+        .flatten()
+    {
+        let mut params = Parameters::default();
+        if let Some(shared) = manifest.shared {
+            run(shared, &mut params, payload, &mut report)?;
+        }
+        run(sequence, &mut params, payload, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// A SUIT image at the start of a slot (see the module documentation).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SuitImage<'a> {
+    pub envelope: Envelope<'a>,
+    pub manifest: Manifest<'a>,
+    /// The slot between the locator and the envelope.
+    payload: &'a [u8],
+    size: usize,
+}
+
+impl<'a> SuitImage<'a> {
+    pub fn parse(slot: &'a [u8]) -> Result<Self, SuitError> {
+        let word = |i: usize| {
+            slot.get(i..i + 4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+                .ok_or(CborError::Truncated { offset: i })
+        };
+        let magic = word(0)?;
+        if magic != SUIT_IMAGE_MAGIC {
+            return Err(SuitError::BadMagic(magic));
+        }
+        let (offset, length) = (word(4)?, word(8)?);
+        let bad_locator = SuitError::BadLocator { offset, length };
+        let start = offset as usize;
+        if start < HEADER_LENGTH as usize {
+            return Err(bad_locator);
+        }
+        let end = start.checked_add(length as usize).ok_or(bad_locator)?;
+        let envelope = slot.get(start..end).ok_or(bad_locator)?;
+        let envelope = Envelope::parse(envelope)?;
+        Ok(SuitImage {
+            envelope,
+            manifest: envelope.manifest()?,
+            payload: &slot[HEADER_LENGTH as usize..start],
+            size: end,
+        })
+    }
+
+    /// The locator for an envelope of `length` bytes after `payload_length`
+    /// bytes of payload. The rest of the `HEADER_LENGTH` bytes are zero.
+    pub fn locator(payload_length: u32, length: u32) -> [u8; LOCATOR_LENGTH] {
+        let mut buf = [0u8; LOCATOR_LENGTH];
+        buf[0..4].copy_from_slice(&SUIT_IMAGE_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&(HEADER_LENGTH as u32 + payload_length).to_le_bytes());
+        buf[8..12].copy_from_slice(&length.to_le_bytes());
+        buf
+    }
+
+    /// Bytes from the start of the slot to the end of the envelope.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The first word of the manifest digest.
+    pub fn id(&self) -> u32 {
+        self.envelope.manifest_digest().map_or(0, |digest| {
+            u32::from_le_bytes(digest[..4].try_into().unwrap())
+        })
+    }
+
+    /// Checks the manifest digest and runs the manifest against the payload.
+    /// The vendor-ID, class-ID and image-match conditions must all pass, so
+    /// that the image is meant for this device.
+    pub fn validate(&self) -> Result<Report, SuitError> {
+        self.envelope.check_digest()?;
+        let report = process(&self.manifest, self.payload)?;
+        let required = [
+            (report.vendor_matched, CONDITION_VENDOR_ID),
+            (report.class_matched, CONDITION_CLASS_ID),
+            (report.image_matched, CONDITION_IMAGE_MATCH),
+        ];
+        if let Some(&(_, condition)) = required.iter().find(|(passed, _)| !passed) {
+            return Err(SuitError::MissingCondition(condition));
+        }
+        Ok(report)
+    }
+}
+
+/// The parts of a `COSE_Sign1` that are needed to verify it.
+#[cfg_attr(not(any(feature = "ed25519", feature = "p256")), allow(dead_code))]
+struct CoseSign1<'a> {
+    protected: &'a [u8],
+    alg: i64,
+    signature: &'a [u8],
+}
+
+impl<'a> CoseSign1<'a> {
+    fn parse(buf: &'a [u8]) -> Result<Self, CborError> {
+        let mut dec = Decoder::new(buf);
+        dec.optional_tag(TAG_COSE_SIGN1)?;
+        dec.array()?;
+        let protected = dec.bytes()?;
+        dec.skip()?;
+        // the payload is detached (the manifest digest) or included
+        if dec.peek_major()? == MAJOR_SIMPLE {
+            dec.simple()?;
+        } else {
+            dec.bytes()?;
+        }
+        let signature = dec.bytes()?;
+
+        let mut alg = 0;
+        let mut header = Decoder::new(protected);
+        for _ in 0..header.map()? {
+            if header.int()? == COSE_HEADER_ALG {
+                alg = header.int()?;
+            } else {
+                header.skip()?;
+            }
+        }
+        Ok(CoseSign1 {
+            protected,
+            alg,
+            signature,
+        })
+    }
+}
+
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+const SIG_STRUCTURE_MAX_LENGTH: usize = 128;
+
+/// The signature of `envelope` with `alg` and the signed `Sig_structure`.
+#[cfg(any(feature = "ed25519", feature = "p256"))]
+fn signed<'a>(
+    envelope: &Envelope<'a>,
+    alg: i64,
+    buf: &mut [u8; SIG_STRUCTURE_MAX_LENGTH],
+) -> Result<(&'a [u8], usize), SignatureError> {
+    let sign1 = envelope
+        .signature
+        .and_then(|sign1| CoseSign1::parse(sign1).ok())
+        .ok_or(SignatureError::Missing)?;
+    if sign1.alg != alg {
+        return Err(SignatureError::Missing);
+    }
+    let len = encode_sig_structure(buf, sign1.protected, envelope.digest)
+        .map_err(|_| SignatureError::Mismatch)?;
+    Ok((sign1.signature, len))
+}
+
+/// Checks the EdDSA `COSE_Sign1` of the manifest digest. Only meaningful for
+/// an envelope that has passed `Envelope::check_digest`.
+#[cfg(feature = "ed25519")]
+pub fn verify_ed25519(
+    envelope: &Envelope,
+    public_key: &[u8; ED25519_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let mut buf = [0u8; SIG_STRUCTURE_MAX_LENGTH];
+    let (sig, len) = signed(envelope, COSE_ALG_EDDSA, &mut buf)?;
+    let sig = Signature::from_slice(sig).map_err(|_| SignatureError::Mismatch)?;
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    key.verify_strict(&buf[..len], &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// Checks the ES256 `COSE_Sign1` of the manifest digest, see `verify_ed25519`.
+#[cfg(feature = "p256")]
+pub fn verify_p256(
+    envelope: &Envelope,
+    public_key: &[u8; P256_PUBLIC_KEY_LENGTH],
+) -> Result<(), SignatureError> {
+    use p256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    let mut buf = [0u8; SIG_STRUCTURE_MAX_LENGTH];
+    let (sig, len) = signed(envelope, COSE_ALG_ES256, &mut buf)?;
+    // COSE uses the fixed size r || s encoding
+    let sig = Signature::from_slice(sig).map_err(|_| SignatureError::Mismatch)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| SignatureError::InvalidPublicKey)?;
+    key.verify_prehash(&sha256(&buf[..len]), &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+// Encoders for `bintool` and the tests. Each returns the length used in `buf`.
+
+/// Encodes a SHA-256 `SUIT_Digest`.
+pub fn encode_digest(buf: &mut [u8], digest: &[u8; DIGEST_LENGTH]) -> Result<usize, CborError> {
+    let mut enc = Encoder::new(buf);
+    enc.array(2)?;
+    enc.int(COSE_ALG_SHA256)?;
+    enc.bytes(digest)?;
+    Ok(enc.encoded().len())
+}
+
+/// Encodes a manifest that installs, validates and invokes a single
+/// component: the payload with `image_digest` and `image_size` for a device
+/// with `VENDOR_ID` and `CLASS_ID`.
+pub fn encode_manifest(
+    buf: &mut [u8],
+    sequence_number: u64,
+    image_digest: &[u8; DIGEST_LENGTH],
+    image_size: u64,
+) -> Result<usize, CborError> {
+    const REPORT_ALL: u64 = 15;
+
+    let mut digest = [0u8; 48];
+    let digest_len = encode_digest(&mut digest, image_digest)?;
+    let mut shared = [0u8; 128];
+    let mut enc = Encoder::new(&mut shared);
+    enc.array(6)?;
+    enc.uint(DIRECTIVE_OVERRIDE_PARAMETERS)?;
+    enc.map(4)?;
+    enc.uint(PARAMETER_VENDOR_ID)?;
+    enc.bytes(&VENDOR_ID)?;
+    enc.uint(PARAMETER_CLASS_ID)?;
+    enc.bytes(&CLASS_ID)?;
+    enc.uint(PARAMETER_IMAGE_DIGEST)?;
+    enc.bytes(&digest[..digest_len])?;
+    enc.uint(PARAMETER_IMAGE_SIZE)?;
+    enc.uint(image_size)?;
+    for condition in [CONDITION_VENDOR_ID, CONDITION_CLASS_ID] {
+        enc.uint(condition)?;
+        enc.uint(REPORT_ALL)?;
+    }
+    let shared_len = enc.encoded().len();
+
+    let mut common = [0u8; 160];
+    let mut enc = Encoder::new(&mut common);
+    enc.map(2)?;
+    enc.uint(COMMON_COMPONENTS)?;
+    enc.array(1)?;
+    enc.array(1)?;
+    enc.bytes(&[0])?;
+    enc.uint(COMMON_SHARED_SEQUENCE)?;
+    enc.bytes(&shared[..shared_len])?;
+    let common_len = enc.encoded().len();
+
+    // the payload is fetched from an integrated payload "#app" in the
+    // SUIT model; here it already is in the slot
+    let mut install = [0u8; 32];
+    let mut enc = Encoder::new(&mut install);
+    enc.array(6)?;
+    enc.uint(DIRECTIVE_OVERRIDE_PARAMETERS)?;
+    enc.map(1)?;
+    enc.uint(PARAMETER_URI)?;
+    enc.text("#app")?;
+    for command in [DIRECTIVE_FETCH, CONDITION_IMAGE_MATCH] {
+        enc.uint(command)?;
+        enc.uint(REPORT_ALL)?;
+    }
+    let install_len = enc.encoded().len();
+
+    let mut enc = Encoder::new(buf);
+    enc.tag(TAG_MANIFEST)?;
+    enc.map(6)?;
+    enc.uint(MANIFEST_VERSION)?;
+    enc.uint(1)?;
+    enc.uint(MANIFEST_SEQUENCE_NUMBER)?;
+    enc.uint(sequence_number)?;
+    enc.uint(MANIFEST_COMMON)?;
+    enc.bytes(&common[..common_len])?;
+    enc.uint(MANIFEST_VALIDATE)?;
+    enc.bytes(&[0x82, CONDITION_IMAGE_MATCH as u8, REPORT_ALL as u8])?;
+    enc.uint(MANIFEST_INVOKE)?;
+    enc.bytes(&[0x82, DIRECTIVE_INVOKE as u8, REPORT_ALL as u8])?;
+    enc.uint(MANIFEST_INSTALL)?;
+    enc.bytes(&install[..install_len])?;
+    Ok(enc.encoded().len())
+}
+
+/// Encodes the protected header of a `COSE_Sign1` with `alg`.
+pub fn encode_protected(buf: &mut [u8], alg: i64) -> Result<usize, CborError> {
+    let mut enc = Encoder::new(buf);
+    enc.map(1)?;
+    enc.int(COSE_HEADER_ALG)?;
+    enc.int(alg)?;
+    Ok(enc.encoded().len())
+}
+
+/// Encodes the COSE `Sig_structure` of a `COSE_Sign1` with the `protected`
+/// header over the encoded manifest `digest`; this is what is signed.
+pub fn encode_sig_structure(
+    buf: &mut [u8],
+    protected: &[u8],
+    digest: &[u8],
+) -> Result<usize, CborError> {
+    let mut enc = Encoder::new(buf);
+    enc.array(4)?;
+    enc.text("Signature1")?;
+    enc.bytes(protected)?;
+    enc.bytes(&[])?;
+    enc.bytes(digest)?;
+    Ok(enc.encoded().len())
+}
+
+/// Encodes a `COSE_Sign1` with a detached payload (the manifest digest).
+pub fn encode_sign1(
+    buf: &mut [u8],
+    protected: &[u8],
+    signature: &[u8],
+) -> Result<usize, CborError> {
+    let mut enc = Encoder::new(buf);
+    enc.tag(TAG_COSE_SIGN1)?;
+    enc.array(4)?;
+    enc.bytes(protected)?;
+    enc.map(0)?;
+    enc.simple(SIMPLE_NULL)?;
+    enc.bytes(signature)?;
+    Ok(enc.encoded().len())
+}
+
+/// Encodes an envelope of the encoded `manifest`, its encoded `digest` and
+/// optionally an encoded `COSE_Sign1`.
+pub fn encode_envelope(
+    buf: &mut [u8],
+    digest: &[u8],
+    sign1: Option<&[u8]>,
+    manifest: &[u8],
+) -> Result<usize, CborError> {
+    let mut auth = [0u8; 256];
+    let mut enc = Encoder::new(&mut auth);
+    enc.array(1 + sign1.is_some() as u64)?;
+    enc.bytes(digest)?;
+    if let Some(sign1) = sign1 {
+        enc.bytes(sign1)?;
+    }
+    let auth_len = enc.encoded().len();
+
+    let mut enc = Encoder::new(buf);
+    enc.tag(TAG_ENVELOPE)?;
+    enc.map(2)?;
+    enc.uint(ENVELOPE_AUTHENTICATION)?;
+    enc.bytes(&auth[..auth_len])?;
+    enc.uint(ENVELOPE_MANIFEST)?;
+    enc.bytes(manifest)?;
+    Ok(enc.encoded().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    type Signer<'a> = dyn Fn(&[u8]) -> Vec<u8> + 'a;
+
+    /// A slot with `payload` and an envelope of its manifest, signed with
+    /// `sign` (the protected header and a signer of the `Sig_structure`).
+    fn build_slot(
+        payload: &[u8],
+        sequence_number: u64,
+        patch_manifest: impl Fn(&mut [u8]),
+        sign: Option<(i64, &Signer<'_>)>,
+    ) -> Vec<u8> {
+        let mut manifest = [0u8; 512];
+        let manifest_len = encode_manifest(
+            &mut manifest,
+            sequence_number,
+            &sha256(payload),
+            payload.len() as u64,
+        )
+        .unwrap();
+        let manifest = &mut manifest[..manifest_len];
+        patch_manifest(manifest);
+        let mut digest = [0u8; 48];
+        let digest_len = encode_digest(&mut digest, &sha256(manifest)).unwrap();
+        let digest = &digest[..digest_len];
+
+        let mut sign1 = [0u8; 160];
+        let sign1 = sign.map(|(alg, signer)| {
+            let mut protected = [0u8; 8];
+            let protected_len = encode_protected(&mut protected, alg).unwrap();
+            let mut sig_structure = [0u8; 128];
+            let len = encode_sig_structure(&mut sig_structure, &protected[..protected_len], digest)
+                .unwrap();
+            let signature = signer(&sig_structure[..len]);
+            let len = encode_sign1(&mut sign1, &protected[..protected_len], &signature).unwrap();
+            &sign1[..len]
+        });
+
+        let mut envelope = [0u8; 1024];
+        let envelope_len = encode_envelope(&mut envelope, digest, sign1, manifest).unwrap();
+
+        let mut slot = SuitImage::locator(payload.len() as u32, envelope_len as u32).to_vec();
+        slot.resize(HEADER_LENGTH as usize, 0);
+        slot.extend_from_slice(payload);
+        slot.extend_from_slice(&envelope[..envelope_len]);
+        slot
+    }
+
+    fn patch(manifest: &mut [u8], from: &[u8], to: &[u8]) {
+        let offset = manifest
+            .windows(from.len())
+            .position(|w| w == from)
+            .unwrap();
+        manifest[offset..offset + to.len()].copy_from_slice(to);
+    }
+
+    #[test]
+    fn test_validate() {
+        let payload = [0x6bu8; 1000];
+        let mut slot = build_slot(&payload, 5, |_| {}, None);
+        let size = slot.len();
+        slot.resize(0x2000, 0xff);
+
+        let image = SuitImage::parse(&slot).unwrap();
+        assert_eq!(image.size(), size);
+        assert_eq!(image.manifest.sequence_number, 5);
+        assert!(image.manifest.has_install());
+        assert_eq!(image.envelope.signature_alg(), None);
+        assert_eq!(
+            image.id().to_le_bytes(),
+            image.envelope.manifest_digest().unwrap()[..4]
+        );
+        assert_eq!(
+            image.validate(),
+            Ok(Report {
+                vendor_matched: true,
+                class_matched: true,
+                image_matched: true,
+                install: true,
+                invoke: true,
+                image_size: 1000,
+            })
+        );
+
+        let mut tampered = slot.clone();
+        tampered[HEADER_LENGTH as usize + 10] ^= 0x01;
+        assert_eq!(
+            SuitImage::parse(&tampered).unwrap().validate(),
+            Err(SuitError::ConditionFailed(CONDITION_IMAGE_MATCH))
+        );
+
+        // the manifest is covered by the digest
+        let mut tampered = slot.clone();
+        tampered[size - 1] ^= 0x01;
+        assert_eq!(
+            SuitImage::parse(&tampered).unwrap().validate(),
+            Err(SuitError::ManifestDigest)
+        );
+
+        let mut other_vendor = VENDOR_ID;
+        other_vendor[0] ^= 0x01;
+        let slot = build_slot(&payload, 5, |m| patch(m, &VENDOR_ID, &other_vendor), None);
+        assert_eq!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::ConditionFailed(CONDITION_VENDOR_ID))
+        );
+
+        let mut other_class = CLASS_ID;
+        other_class[15] ^= 0x01;
+        let slot = build_slot(&payload, 5, |m| patch(m, &CLASS_ID, &other_class), None);
+        assert_eq!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::ConditionFailed(CONDITION_CLASS_ID))
+        );
+
+        // the shared sequence checks the class ID twice instead of the vendor
+        // ID and the class ID (`[.., 1, 15, 2, 15]`), and vice versa
+        let slot = build_slot(
+            &payload,
+            5,
+            |m| patch(m, &[0x01, 0x0f, 0x02, 0x0f], &[0x02, 0x0f, 0x02, 0x0f]),
+            None,
+        );
+        assert_eq!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::MissingCondition(CONDITION_VENDOR_ID))
+        );
+        let slot = build_slot(
+            &payload,
+            5,
+            |m| patch(m, &[0x01, 0x0f, 0x02, 0x0f], &[0x01, 0x0f, 0x01, 0x0f]),
+            None,
+        );
+        assert_eq!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::MissingCondition(CONDITION_CLASS_ID))
+        );
+
+        // the shared sequence claims 5 items, so its last command has no
+        // argument
+        let slot = build_slot(
+            &payload,
+            5,
+            |m| patch(m, &[0x86, 0x14], &[0x85, 0x14]),
+            None,
+        );
+        assert!(matches!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::Cbor(CborError::Truncated { .. }))
+        ));
+
+        // an image size beyond the payload (1000 is 0x19 0x03 0xe8)
+        let slot = build_slot(
+            &payload,
+            5,
+            |m| patch(m, &[0x19, 0x03, 0xe8], &[0x19, 0x13, 0xe8]),
+            None,
+        );
+        assert_eq!(
+            SuitImage::parse(&slot).unwrap().validate(),
+            Err(SuitError::ImageTooLarge {
+                max: 1000,
+                actual: 0x13e8
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let slot = build_slot(&[0x6bu8; 100], 0, |_| {}, None);
+        assert_eq!(
+            SuitImage::parse(&[0xffu8; 300]),
+            Err(SuitError::BadMagic(0xffff_ffff))
+        );
+
+        let mut bad = slot.clone();
+        bad[4..8].copy_from_slice(&16u32.to_le_bytes());
+        assert!(matches!(
+            SuitImage::parse(&bad),
+            Err(SuitError::BadLocator { offset: 16, .. })
+        ));
+
+        assert!(matches!(
+            SuitImage::parse(&slot[..slot.len() - 1]),
+            Err(SuitError::BadLocator { .. })
+        ));
+
+        let slot = build_slot(
+            &[0x6bu8; 100],
+            0,
+            |m| patch(m, &[0x01, 0x01], &[0x01, 0x02]),
+            None,
+        );
+        assert_eq!(
+            SuitImage::parse(&slot),
+            Err(SuitError::UnsupportedManifestVersion(2))
+        );
+
+        let mut envelope = [0u8; 16];
+        let mut enc = Encoder::new(&mut envelope);
+        enc.map(0).unwrap();
+        let len = enc.encoded().len();
+        assert_eq!(
+            Envelope::parse(&envelope[..len]),
+            Err(SuitError::Missing("authentication wrapper"))
+        );
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_verify_ed25519() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let signer = |msg: &[u8]| key.sign(msg).to_bytes().to_vec();
+        let slot = build_slot(&[0x6bu8; 100], 1, |_| {}, Some((COSE_ALG_EDDSA, &signer)));
+        let image = SuitImage::parse(&slot).unwrap();
+        let public_key = key.verifying_key().to_bytes();
+        assert_eq!(image.envelope.signature_alg(), Some(COSE_ALG_EDDSA));
+        assert_eq!(verify_ed25519(&image.envelope, &public_key), Ok(()));
+
+        let wrong_key = SigningKey::from_bytes(&[8u8; 32])
+            .verifying_key()
+            .to_bytes();
+        assert_eq!(
+            verify_ed25519(&image.envelope, &wrong_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        // a different manifest has a different digest
+        let other = build_slot(&[0x6bu8; 100], 2, |_| {}, None);
+        let mut envelope = SuitImage::parse(&other).unwrap().envelope;
+        envelope.signature = image.envelope.signature;
+        assert_eq!(
+            verify_ed25519(&envelope, &public_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        assert_eq!(
+            verify_ed25519(&SuitImage::parse(&other).unwrap().envelope, &public_key),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_verify_p256() {
+        use p256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let signer = |msg: &[u8]| {
+            let sig: Signature = key.sign_prehash(&sha256(msg)).unwrap();
+            sig.to_bytes().to_vec()
+        };
+        let slot = build_slot(&[0x6bu8; 100], 1, |_| {}, Some((COSE_ALG_ES256, &signer)));
+        let image = SuitImage::parse(&slot).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let public_key: [u8; P256_PUBLIC_KEY_LENGTH] = point.as_bytes().try_into().unwrap();
+        assert_eq!(image.envelope.signature_alg(), Some(COSE_ALG_ES256));
+        assert_eq!(verify_p256(&image.envelope, &public_key), Ok(()));
+
+        let other = SigningKey::from_slice(&[8u8; 32]).unwrap();
+        let point = other.verifying_key().to_encoded_point(false);
+        let wrong_key: [u8; P256_PUBLIC_KEY_LENGTH] = point.as_bytes().try_into().unwrap();
+        assert_eq!(
+            verify_p256(&image.envelope, &wrong_key),
+            Err(SignatureError::Mismatch)
+        );
+
+        #[cfg(feature = "ed25519")]
+        assert_eq!(
+            verify_ed25519(&image.envelope, &[0u8; ED25519_PUBLIC_KEY_LENGTH]),
+            Err(SignatureError::Missing)
+        );
+    }
+}
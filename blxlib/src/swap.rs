@@ -0,0 +1,1140 @@
+//! Power-fail-safe swap of the primary and secondary slots.
+//!
+//! Sector `i` of the slots is swapped in three steps through the first sector
+//! of the scratch partition:
+//!
+//! 0. scratch <- secondary\[i\]
+//! 1. secondary\[i\] <- primary\[i\]
+//! 2. primary\[i\] <- scratch
+//!
+//! The status is kept in one of the last two sectors of the scratch partition:
+//! a header page followed by one progress byte per step, programmed to 0x00
+//! when the step is done. The source of the first unfinished step is always
+//! intact, so an interrupted swap is resumed by redoing that step. Afterwards
+//! the new image is in the primary slot and the old one in the secondary slot.
+//!
+//! A new swap is recorded in the other status sector with the next sequence
+//! number, so the previous record stays valid until the new header is written.
+//!
+//! A `SwapType::Test` image is started once (`begin_trial`, which programs the
+//! `trial` byte of the header page) and must be confirmed (`confirm`, the
+//! `image_ok` byte) before the next reset, otherwise the bootloader swaps the
+//! previous image back with `revert`.
+//!
+//! The application chooses how the image in the secondary slot is installed
+//! with a request record in the sector before the status sectors (`request`).
+//! Without one it is installed as a test.
+//!
+//! For an encrypted image (see `encrypt`) the status also keeps the wrapped
+//! key, and steps 1 and 2 run the payload area of the sector through the
+//! cipher: the new image is decrypted into the primary slot and the old one is
+//! encrypted with the same key on its way out, so that a revert restores both.
+//!
+//! A compressed image (see `compress`) cannot be swapped. `SwapType::Overwrite`
+//! decompresses it over the primary slot in a single step instead, which is
+//! redone from the start if it is interrupted and only marked done once the
+//! primary slot matches `payload_crc` and `payload_digest`. The previous image
+//! is lost, so there is no test install; the compressed image stays in the
+//! secondary slot (`SwapState::is_installed`). If the result does not match,
+//! the `failed` byte of the header page is programmed instead and the
+//! overwrite is not retried (`SwapState::Failed`).
+//!
+//! The bootloader counts each resume of the recorded swap in the header page
+//! (`count_attempt`), so that a swap that cannot finish is not retried forever.
+
+use crate::compress::{self, Decoder};
+use crate::crc32::crc32;
+use crate::encrypt::{PayloadCipher, WRAPPED_KEY_LENGTH};
+use crate::flash::{self, Flash, FlashReader, ERASED};
+use crate::image::ImageInfo;
+use crate::image_header::{ImageHeader, HEADER_LENGTH};
+use crate::layout::{FlashLayout, LAYOUT, PAGE_SIZE, SECTOR_SIZE};
+
+pub const SWAP_MAGIC: u32 = 0x5357_4150; // "SWAP"
+pub const REQUEST_MAGIC: u32 = 0x5245_5155; // "REQU"
+const STATUS_HEADER_LENGTH: usize = 76;
+const REQUEST_LENGTH: usize = 16;
+/// Offsets of `image_ok`, `trial` and `failed` in the header page.
+const IMAGE_OK_OFFSET: usize = 0x80;
+const TRIAL_OFFSET: usize = 0x81;
+const FAILED_OFFSET: usize = 0x82;
+/// One byte per resume of the swap (`count_attempt`), from this offset.
+const ATTEMPTS_OFFSET: usize = 0x90;
+const ATTEMPTS_LENGTH: usize = 0x10;
+const STEPS_PER_SECTOR: u32 = 3;
+/// Progress bytes that fit in the status sector after the header page.
+pub const MAX_STEPS: u32 = SECTOR_SIZE - PAGE_SIZE;
+
+const _: () = assert!(STEPS_PER_SECTOR * LAYOUT.primary.sectors() <= MAX_STEPS);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapStatus {
+    /// Number of sectors to swap from the beginning of the slots.
+    pub sectors: u32,
+    /// `ImageInfo::id` of the primary (old) image when the swap started.
+    pub primary_id: u32,
+    /// `ImageInfo::id` of the secondary (new) image when the swap started.
+    pub secondary_id: u32,
+    pub swap_type: SwapType,
+    /// `encrypt::wrapped_key` of an encrypted secondary image.
+    pub wrapped_key: Option<[u8; WRAPPED_KEY_LENGTH]>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapType {
+    /// The new image is reverted unless it is confirmed before the next reset.
+    Test = 1,
+    /// The new image stays.
+    Permanent = 2,
+    /// An unconfirmed test image is swapped out for the previous one.
+    Revert = 3,
+    /// A compressed image is decompressed over the previous one and stays.
+    Overwrite = 4,
+}
+
+impl TryFrom<u8> for SwapType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        match value {
+            1 => Ok(SwapType::Test),
+            2 => Ok(SwapType::Permanent),
+            3 => Ok(SwapType::Revert),
+            4 => Ok(SwapType::Overwrite),
+            _ => Err(value),
+        }
+    }
+}
+
+impl SwapStatus {
+    /// Covers both images. `primary` is `None` if the primary slot has no valid header.
+    pub fn new(
+        layout: &FlashLayout,
+        primary: Option<&ImageInfo>,
+        secondary: &ImageInfo,
+        swap_type: SwapType,
+    ) -> Self {
+        let sectors = |info: &ImageInfo| {
+            info.size
+                .div_ceil(SECTOR_SIZE)
+                .min(layout.primary.sectors())
+        };
+        SwapStatus {
+            sectors: primary.map_or(0, sectors).max(sectors(secondary)),
+            primary_id: primary.map_or(0xffff_ffff, |info| info.id),
+            secondary_id: secondary.id,
+            swap_type,
+            wrapped_key: None,
+        }
+    }
+
+    pub const fn steps(&self) -> u32 {
+        match self.swap_type {
+            SwapType::Overwrite => 1,
+            _ => self.sectors * STEPS_PER_SECTOR,
+        }
+    }
+
+    fn to_bytes(self, seq: u32) -> [u8; STATUS_HEADER_LENGTH] {
+        let mut buf = [0u8; STATUS_HEADER_LENGTH];
+        buf[0..4].copy_from_slice(&SWAP_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&seq.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.sectors.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.primary_id.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.secondary_id.to_le_bytes());
+        buf[20] = self.swap_type as u8;
+        if let Some(wrapped_key) = &self.wrapped_key {
+            buf[21] = 1;
+            buf[24..72].copy_from_slice(wrapped_key);
+        }
+        let crc = crc32(&buf[..72]);
+        buf[72..76].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Returns the sequence number and the status.
+    fn from_bytes(buf: &[u8; STATUS_HEADER_LENGTH]) -> Option<(u32, Self)> {
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if word(0) != SWAP_MAGIC || word(72) != crc32(&buf[..72]) {
+            return None;
+        }
+        let status = SwapStatus {
+            sectors: word(8),
+            primary_id: word(12),
+            secondary_id: word(16),
+            swap_type: SwapType::try_from(buf[20]).ok()?,
+            wrapped_key: (buf[21] != 0).then(|| buf[24..72].try_into().unwrap()),
+        };
+        Some((word(4), status))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapState {
+    /// No swap is recorded.
+    Idle,
+    /// Interrupted; `step` is the first unfinished step.
+    InProgress { status: SwapStatus, step: u32 },
+    Complete {
+        status: SwapStatus,
+        /// The image in the primary slot has been started as a test.
+        trial: bool,
+        /// The image in the primary slot has been confirmed.
+        confirmed: bool,
+    },
+    /// An overwrite whose result did not validate. The primary slot holds no
+    /// usable image and the overwrite is not resumed.
+    Failed { status: SwapStatus },
+}
+
+impl SwapState {
+    /// True if the secondary slot holds the image a completed swap moved out
+    /// of the primary slot, i.e. there is nothing new to install.
+    pub fn is_swapped_out(&self, secondary_id: u32) -> bool {
+        matches!(self, SwapState::Complete { status, .. } if status.primary_id == secondary_id)
+    }
+
+    /// True if the secondary slot holds the compressed image that a completed
+    /// overwrite installed.
+    pub fn is_installed(&self, secondary_id: u32) -> bool {
+        matches!(
+            self,
+            SwapState::Complete { status, .. }
+                if status.swap_type == SwapType::Overwrite && status.secondary_id == secondary_id
+        )
+    }
+
+    pub fn status(&self) -> Option<&SwapStatus> {
+        match self {
+            SwapState::Idle => None,
+            SwapState::InProgress { status, .. }
+            | SwapState::Complete { status, .. }
+            | SwapState::Failed { status } => Some(status),
+        }
+    }
+
+    /// True if the secondary slot holds the compressed image whose overwrite failed.
+    pub fn is_failed(&self, secondary_id: u32) -> bool {
+        matches!(self, SwapState::Failed { status } if status.secondary_id == secondary_id)
+    }
+
+    /// True for a completed test swap whose image has been started but not confirmed.
+    pub fn needs_revert(&self) -> bool {
+        matches!(
+            self,
+            SwapState::Complete {
+                status,
+                trial: true,
+                confirmed: false
+            } if status.swap_type == SwapType::Test
+        )
+    }
+
+    fn is_unconfirmed_test(&self) -> bool {
+        matches!(
+            self,
+            SwapState::Complete {
+                status,
+                confirmed: false,
+                ..
+            } if status.swap_type == SwapType::Test
+        )
+    }
+}
+
+const fn status_offsets(layout: &FlashLayout) -> [u32; 2] {
+    let end = layout.scratch.end();
+    [end - 2 * SECTOR_SIZE, end - SECTOR_SIZE]
+}
+
+const fn request_offset(layout: &FlashLayout) -> u32 {
+    layout.scratch.end() - 3 * SECTOR_SIZE
+}
+
+/// Offset of the progress page that holds the byte of `step`.
+const fn progress_offset(status_offset: u32, step: u32) -> u32 {
+    status_offset + PAGE_SIZE + step / PAGE_SIZE * PAGE_SIZE
+}
+
+/// The status sector with the newest valid header: `(offset, seq, status)`.
+fn active_record<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+) -> Result<Option<(u32, u32, SwapStatus)>, F::Error> {
+    let mut active: Option<(u32, u32, SwapStatus)> = None;
+    for offset in status_offsets(layout) {
+        let mut header = [0u8; STATUS_HEADER_LENGTH];
+        flash.read(offset, &mut header)?;
+        match SwapStatus::from_bytes(&header) {
+            Some((seq, status))
+                if status.sectors <= layout.primary.sectors()
+                    && status.steps() <= MAX_STEPS
+                    && active.is_none_or(|(_, active_seq, _)| seq > active_seq) =>
+            {
+                active = Some((offset, seq, status));
+            }
+            _ => {}
+        }
+    }
+    Ok(active)
+}
+
+pub fn read_state<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<SwapState, F::Error> {
+    let Some((offset, _, status)) = active_record(flash, layout)? else {
+        return Ok(SwapState::Idle);
+    };
+    let mut page = [0u8; PAGE_SIZE as usize];
+    flash.read(offset, &mut page)?;
+    let trial = page[TRIAL_OFFSET] != ERASED;
+    let confirmed = page[IMAGE_OK_OFFSET] != ERASED;
+    if page[FAILED_OFFSET] != ERASED {
+        return Ok(SwapState::Failed { status });
+    }
+
+    for step in 0..status.steps() {
+        if step % PAGE_SIZE == 0 {
+            flash.read(progress_offset(offset, step), &mut page)?;
+        }
+        // a torn program leaves something other than 0x00, which still means done
+        if page[(step % PAGE_SIZE) as usize] == ERASED {
+            return Ok(SwapState::InProgress { status, step });
+        }
+    }
+    Ok(SwapState::Complete {
+        status,
+        trial,
+        confirmed,
+    })
+}
+
+/// Records a new swap. The slots are not touched yet.
+pub fn start<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    status: &SwapStatus,
+) -> Result<(), F::Error> {
+    let [first, second] = status_offsets(layout);
+    let (offset, seq) = match active_record(flash, layout)? {
+        Some((active, seq, _)) if active == first => (second, seq.wrapping_add(1)),
+        Some((_, seq, _)) => (first, seq.wrapping_add(1)),
+        None => (first, 0),
+    };
+    flash.erase_sector(offset)?;
+    let mut page = [ERASED; PAGE_SIZE as usize];
+    page[..STATUS_HEADER_LENGTH].copy_from_slice(&status.to_bytes(seq));
+    flash.program_page(offset, &page)
+}
+
+/// Runs the steps from `step` to the end. `cipher` is the cipher of
+/// `status.wrapped_key`, if there is one. An overwrite whose result does not
+/// validate is marked failed (`SwapState::Failed`).
+pub fn resume<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    status: &SwapStatus,
+    step: u32,
+    cipher: Option<&dyn PayloadCipher>,
+) -> Result<(), F::Error> {
+    let Some((offset, _, _)) = active_record(flash, layout)? else {
+        return Ok(());
+    };
+    for step in step..status.steps() {
+        if status.swap_type == SwapType::Overwrite {
+            if !overwrite(flash, layout, status)? {
+                return set_flag(flash, layout, FAILED_OFFSET);
+            }
+        } else {
+            run_step(flash, layout, step, cipher)?;
+        }
+        mark_done(flash, offset, step)?;
+    }
+    Ok(())
+}
+
+/// Number of times the recorded swap has been resumed (`count_attempt`).
+pub fn attempts<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<u32, F::Error> {
+    let Some((offset, _, _)) = active_record(flash, layout)? else {
+        return Ok(0);
+    };
+    let mut page = [0u8; PAGE_SIZE as usize];
+    flash.read(offset, &mut page)?;
+    let counted = &page[ATTEMPTS_OFFSET..][..ATTEMPTS_LENGTH];
+    Ok(counted.iter().filter(|&&b| b != ERASED).count() as u32)
+}
+
+/// Counts a resume of the recorded swap. The count stops at 16.
+pub fn count_attempt<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<(), F::Error> {
+    let attempts = attempts(flash, layout)? as usize;
+    if attempts == ATTEMPTS_LENGTH {
+        return Ok(());
+    }
+    set_flag(flash, layout, ATTEMPTS_OFFSET + attempts)
+}
+
+/// Swaps the first `status.sectors` sectors of the primary and secondary slots,
+/// or overwrites them in the primary slot for `SwapType::Overwrite`.
+pub fn swap<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    status: &SwapStatus,
+    cipher: Option<&dyn PayloadCipher>,
+) -> Result<(), F::Error> {
+    start(flash, layout, status)?;
+    resume(flash, layout, status, 0, cipher)
+}
+
+/// Swaps the previous image back after an unconfirmed test swap (`SwapState::needs_revert`).
+pub fn revert<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    status: &SwapStatus,
+    cipher: Option<&dyn PayloadCipher>,
+) -> Result<(), F::Error> {
+    let revert = SwapStatus {
+        sectors: status.sectors,
+        primary_id: status.secondary_id,
+        secondary_id: status.primary_id,
+        swap_type: SwapType::Revert,
+        wrapped_key: status.wrapped_key,
+    };
+    swap(flash, layout, &revert, cipher)
+}
+
+/// Records that the image in the primary slot is about to be started as a
+/// test. Returns false (and does nothing) unless it is an unconfirmed test
+/// image that has not been started yet.
+pub fn begin_trial<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<bool, F::Error> {
+    let state = read_state(flash, layout)?;
+    if !state.is_unconfirmed_test() || state.needs_revert() {
+        return Ok(false);
+    }
+    set_flag(flash, layout, TRIAL_OFFSET)?;
+    Ok(true)
+}
+
+/// Confirms the image in the primary slot so that it is not reverted.
+/// Does nothing unless it is an unconfirmed test image.
+pub fn confirm<F: Flash>(flash: &mut F, layout: &FlashLayout) -> Result<(), F::Error> {
+    if !read_state(flash, layout)?.is_unconfirmed_test() {
+        return Ok(());
+    }
+    set_flag(flash, layout, IMAGE_OK_OFFSET)
+}
+
+/// Requests that the image in the secondary slot is installed as `swap_type`
+/// (`Test` or `Permanent`) on the next boot. The request only applies to the
+/// image with `ImageInfo::id` `secondary_id`.
+pub fn request<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    secondary_id: u32,
+    swap_type: SwapType,
+) -> Result<(), F::Error> {
+    let offset = request_offset(layout);
+    flash.erase_sector(offset)?;
+    let mut page = [ERASED; PAGE_SIZE as usize];
+    page[0..4].copy_from_slice(&REQUEST_MAGIC.to_le_bytes());
+    page[4..8].copy_from_slice(&secondary_id.to_le_bytes());
+    page[8] = swap_type as u8;
+    let crc = crc32(&page[..12]);
+    page[12..REQUEST_LENGTH].copy_from_slice(&crc.to_le_bytes());
+    flash.program_page(offset, &page)
+}
+
+/// How the image in the secondary slot with id `secondary_id` is to be
+/// installed: `SwapType::Permanent` if requested, otherwise `SwapType::Test`.
+pub fn requested_type<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    secondary_id: u32,
+) -> Result<SwapType, F::Error> {
+    let mut buf = [0u8; REQUEST_LENGTH];
+    flash.read(request_offset(layout), &mut buf)?;
+    let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+    let valid = word(0) == REQUEST_MAGIC && word(12) == crc32(&buf[..12]);
+    if valid && word(4) == secondary_id && buf[8] == SwapType::Permanent as u8 {
+        Ok(SwapType::Permanent)
+    } else {
+        Ok(SwapType::Test)
+    }
+}
+
+fn set_flag<F: Flash>(flash: &mut F, layout: &FlashLayout, flag: usize) -> Result<(), F::Error> {
+    let Some((offset, _, _)) = active_record(flash, layout)? else {
+        return Ok(());
+    };
+    let mut page = [0u8; PAGE_SIZE as usize];
+    flash.read(offset, &mut page)?;
+    page[flag] = 0x00;
+    flash.program_page(offset, &page)
+}
+
+fn run_step<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    step: u32,
+    cipher: Option<&dyn PayloadCipher>,
+) -> Result<(), F::Error> {
+    let sector = step / STEPS_PER_SECTOR * SECTOR_SIZE;
+    let primary = layout.primary.offset + sector;
+    let secondary = layout.secondary.offset + sector;
+    let buffer = layout.scratch.offset;
+    let cipher = cipher.map(|cipher| (cipher, sector));
+    match step % STEPS_PER_SECTOR {
+        0 => copy_sector(flash, buffer, secondary, None),
+        1 => copy_sector(flash, secondary, primary, cipher),
+        _ => copy_sector(flash, primary, buffer, cipher),
+    }
+}
+
+/// Decompresses the image in the secondary slot over the first
+/// `status.sectors` sectors of the primary slot. Returns true if the result
+/// validates.
+fn overwrite<F: Flash>(
+    flash: &mut F,
+    layout: &FlashLayout,
+    status: &SwapStatus,
+) -> Result<bool, F::Error> {
+    let mut header = [0u8; HEADER_LENGTH as usize];
+    flash.read(layout.secondary.offset, &mut header)?;
+    let Ok(ih) = ImageHeader::try_from(&header[..]) else {
+        return Ok(false);
+    };
+    let Some(compressed_length) = compress::compressed_length(&ih) else {
+        return Ok(false);
+    };
+    let start = layout.secondary.offset + HEADER_LENGTH as u32;
+    let mut input = FlashReader::new(start, compressed_length.min(layout.secondary.end() - start));
+    let mut decoder = Decoder::new();
+    let mut page = [0u8; PAGE_SIZE as usize];
+    for sector in 0..status.sectors {
+        let sector = layout.primary.offset + sector * SECTOR_SIZE;
+        flash.erase_sector(sector)?;
+        for offset in (sector..sector + SECTOR_SIZE).step_by(PAGE_SIZE as usize) {
+            page.fill(ERASED);
+            let slot_offset = (offset - layout.primary.offset) as usize;
+            if slot_offset < HEADER_LENGTH as usize {
+                page.copy_from_slice(&header[slot_offset..][..PAGE_SIZE as usize]);
+            } else {
+                // a corrupt payload fails the digests below
+                let _ = decoder.read(&mut input.bytes(flash), &mut page);
+            }
+            if page.iter().any(|&b| b != ERASED) {
+                flash.program_page(offset, &page)?;
+            }
+        }
+    }
+    input.finish()?;
+
+    let payload = layout.primary.offset + HEADER_LENGTH as u32;
+    if ih.image_length > layout.primary.end() - payload {
+        return Ok(false);
+    }
+    let digests = flash::digests(flash, payload, ih.image_length)?;
+    Ok(ih
+        .validate_with(ih.image_length as usize, |_| digests)
+        .is_ok())
+}
+
+fn mark_done<F: Flash>(flash: &mut F, status_offset: u32, step: u32) -> Result<(), F::Error> {
+    let offset = progress_offset(status_offset, step);
+    let mut page = [0u8; PAGE_SIZE as usize];
+    flash.read(offset, &mut page)?;
+    page[(step % PAGE_SIZE) as usize] = 0x00;
+    flash.program_page(offset, &page)
+}
+
+/// Copies a sector, running the part after the header through `cipher` if
+/// given with the offset of the sector in the slot.
+fn copy_sector<F: Flash>(
+    flash: &mut F,
+    to: u32,
+    from: u32,
+    cipher: Option<(&dyn PayloadCipher, u32)>,
+) -> Result<(), F::Error> {
+    const _: () = assert!((HEADER_LENGTH as u32).is_multiple_of(PAGE_SIZE));
+
+    flash.erase_sector(to)?;
+    let mut page = [0u8; PAGE_SIZE as usize];
+    for offset in (0..SECTOR_SIZE).step_by(PAGE_SIZE as usize) {
+        flash.read(from + offset, &mut page)?;
+        if let Some((cipher, sector)) = cipher {
+            if let Some(payload_offset) = (sector + offset).checked_sub(HEADER_LENGTH as u32) {
+                cipher.apply(payload_offset, &mut page);
+            }
+        }
+        // erased pages need no programming
+        if page.iter().any(|&b| b != ERASED) {
+            flash.program_page(to + offset, &page)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::encrypt;
+    use crate::flash::mock::{MockFlash, MockFlashError};
+    use crate::layout::Partition;
+    use crate::sha256::sha256;
+    use crate::tlv::{TlvWriter, TLV_ENC_X25519, TLV_LZ4};
+    use std::vec;
+    use std::vec::Vec;
+
+    pub(crate) const TEST_LAYOUT: FlashLayout = FlashLayout {
+        bootloader: Partition::new(0x0000, 0x1000),
+        primary: Partition::new(0x1000, 0x3000),
+        secondary: Partition::new(0x4000, 0x3000),
+        scratch: Partition::new(0x7000, 0x4000),
+        rollback: Partition::new(0xb000, 0x2000),
+    };
+    pub(crate) const TEST_FLASH_SIZE: u32 = 0xd000;
+
+    fn slot(flash: &MockFlash, partition: Partition) -> &[u8] {
+        &flash.data()[partition.offset as usize..partition.end() as usize]
+    }
+
+    /// Writes an image with a valid header and `image_length` bytes of `seed`ed payload.
+    pub(crate) fn write_image(
+        flash: &mut MockFlash,
+        partition: Partition,
+        image_length: u32,
+        seed: u8,
+    ) {
+        let mut ih = ImageHeader::new();
+        ih.image_length = image_length;
+        ih.iv_build = seed as u32;
+        ih.crc32 = ih.calc_crc32();
+        let data = &mut flash.data_mut()[partition.offset as usize..];
+        data[..HEADER_LENGTH as usize].copy_from_slice(&ih.to_bytes());
+        for i in 0..image_length as usize {
+            data[HEADER_LENGTH as usize + i] = (i as u8).wrapping_mul(seed) ^ seed;
+        }
+    }
+
+    /// Stands in for `encrypt::ImageCipher`: the keystream is the first byte
+    /// of the wrapped key and the offset.
+    struct XorCipher(u8);
+
+    impl PayloadCipher for XorCipher {
+        fn apply(&self, offset: u32, buf: &mut [u8]) {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b ^= self.0 ^ (offset as usize + i) as u8;
+            }
+        }
+    }
+
+    fn cipher(status: &SwapStatus) -> Option<XorCipher> {
+        status.wrapped_key.map(|key| XorCipher(key[0]))
+    }
+
+    /// Adds a wrapped key to the image in `partition` and encrypts its payload.
+    fn encrypt_image(flash: &mut MockFlash, partition: Partition, key: u8) {
+        let mut ih = read_header(flash, partition).unwrap();
+        TlvWriter::new(&mut ih.tlv)
+            .push(TLV_ENC_X25519, &[key; encrypt::WRAPPED_KEY_LENGTH])
+            .unwrap();
+        ih.crc32 = ih.calc_crc32();
+        let start = partition.offset as usize;
+        let data = &mut flash.data_mut()[start..];
+        data[..HEADER_LENGTH as usize].copy_from_slice(&ih.to_bytes());
+        let payload = &mut data[HEADER_LENGTH as usize..][..ih.image_length as usize];
+        XorCipher(key).apply(0, payload);
+    }
+
+    /// Compresses the payload of the image in `partition` and sets its
+    /// checksums. Returns the image as it is once installed.
+    fn compress_image(flash: &mut MockFlash, partition: Partition) -> Vec<u8> {
+        let mut ih = read_header(flash, partition).unwrap();
+        let start = partition.offset as usize + HEADER_LENGTH as usize;
+        let plain = flash.data()[start..][..ih.image_length as usize].to_vec();
+        let mut block = vec![0u8; plain.len() + plain.len() / 255 + 16];
+        let len = compress::compress(&plain, &mut block).unwrap();
+        ih.payload_crc = crc32(&plain);
+        ih.payload_digest = sha256(&plain);
+        TlvWriter::new(&mut ih.tlv)
+            .push(TLV_LZ4, &(len as u32).to_le_bytes())
+            .unwrap();
+        ih.crc32 = ih.calc_crc32();
+        let data = &mut flash.data_mut()[partition.offset as usize..partition.end() as usize];
+        data.fill(ERASED);
+        data[..HEADER_LENGTH as usize].copy_from_slice(&ih.to_bytes());
+        data[HEADER_LENGTH as usize..][..len].copy_from_slice(&block[..len]);
+
+        let mut installed = ih.to_bytes().to_vec();
+        installed.extend_from_slice(&plain);
+        installed
+    }
+
+    /// 3 sectors in the primary slot, 2 in the secondary slot.
+    fn flash_with_images() -> MockFlash {
+        let mut flash = MockFlash::new(TEST_FLASH_SIZE);
+        write_image(&mut flash, TEST_LAYOUT.primary, 0x2800, 0x11);
+        write_image(&mut flash, TEST_LAYOUT.secondary, 0x1800, 0x22);
+        flash
+    }
+
+    pub(crate) fn read_header(flash: &mut MockFlash, partition: Partition) -> Option<ImageHeader> {
+        let mut buf = [0u8; HEADER_LENGTH as usize];
+        flash.read(partition.offset, &mut buf).ok()?;
+        let ih = ImageHeader::try_from(&buf[..]).ok()?;
+        (ih.calc_crc32() == ih.crc32).then_some(ih)
+    }
+
+    fn status(flash: &mut MockFlash) -> SwapStatus {
+        let primary = read_header(flash, TEST_LAYOUT.primary).map(|ih| ImageInfo::from(&ih));
+        let secondary = ImageInfo::from(&read_header(flash, TEST_LAYOUT.secondary).unwrap());
+        SwapStatus::new(&TEST_LAYOUT, primary.as_ref(), &secondary, SwapType::Test)
+    }
+
+    /// What the bootloader does before it jumps to the primary slot.
+    /// Returns true for a trial boot of an unconfirmed test image.
+    pub(crate) fn boot(flash: &mut MockFlash) -> Result<bool, MockFlashError> {
+        let state = match read_state(flash, &TEST_LAYOUT)? {
+            SwapState::InProgress { status, step } => {
+                let cipher = cipher(&status);
+                resume(
+                    flash,
+                    &TEST_LAYOUT,
+                    &status,
+                    step,
+                    cipher.as_ref().map(|c| c as _),
+                )?;
+                read_state(flash, &TEST_LAYOUT)?
+            }
+            state if state.needs_revert() => {
+                let status = state.status().unwrap();
+                let cipher = cipher(status);
+                revert(flash, &TEST_LAYOUT, status, cipher.as_ref().map(|c| c as _))?;
+                read_state(flash, &TEST_LAYOUT)?
+            }
+            state => state,
+        };
+        let primary = read_header(flash, TEST_LAYOUT.primary).map(|ih| ImageInfo::from(&ih));
+        let secondary = read_header(flash, TEST_LAYOUT.secondary);
+        if let Some(ih) = secondary.filter(|ih| {
+            !state.is_swapped_out(ih.crc32)
+                && !state.is_installed(ih.crc32)
+                && !state.is_failed(ih.crc32)
+        }) {
+            let secondary = ImageInfo::from(&ih);
+            let swap_type = if compress::compressed_length(&ih).is_some() {
+                SwapType::Overwrite
+            } else {
+                requested_type(flash, &TEST_LAYOUT, secondary.id)?
+            };
+            let mut status = SwapStatus::new(&TEST_LAYOUT, primary.as_ref(), &secondary, swap_type);
+            status.wrapped_key = encrypt::wrapped_key(&ih);
+            let cipher = cipher(&status);
+            swap(
+                flash,
+                &TEST_LAYOUT,
+                &status,
+                cipher.as_ref().map(|c| c as _),
+            )?;
+        }
+        begin_trial(flash, &TEST_LAYOUT)
+    }
+
+    fn assert_swapped(flash: &MockFlash, before: &MockFlash) {
+        assert_eq!(
+            slot(flash, TEST_LAYOUT.primary),
+            slot(before, TEST_LAYOUT.secondary)
+        );
+        assert_eq!(
+            slot(flash, TEST_LAYOUT.secondary),
+            slot(before, TEST_LAYOUT.primary)
+        );
+    }
+
+    fn assert_slots_eq(flash: &MockFlash, before: &MockFlash) {
+        for partition in [TEST_LAYOUT.primary, TEST_LAYOUT.secondary] {
+            assert_eq!(slot(flash, partition), slot(before, partition));
+        }
+    }
+
+    /// Loses power at every erase/program of a boot from `before`, boots again
+    /// and checks that the result is the same as without the power loss.
+    /// Losing power while `begin_trial` records the trial is checked separately.
+    fn check_power_loss(before: &MockFlash) {
+        let mut reference = before.clone();
+        let trial = boot(&mut reference).unwrap();
+        let ops = reference.ops() - before.ops() - trial as usize;
+
+        for torn in [false, true] {
+            for n in 0..ops {
+                let mut flash = before.clone();
+                flash.torn = torn;
+                flash.lose_power_after(n);
+                assert_eq!(boot(&mut flash), Err(MockFlashError::PowerLoss));
+                flash.power_cycle();
+                assert_eq!(boot(&mut flash), Ok(trial));
+                assert_eq!(flash.data(), reference.data());
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap() {
+        let before = flash_with_images();
+        let mut flash = before.clone();
+        let status = status(&mut flash);
+        assert_eq!(status.sectors, 3);
+        assert_eq!(read_state(&mut flash, &TEST_LAYOUT), Ok(SwapState::Idle));
+
+        swap(&mut flash, &TEST_LAYOUT, &status, None).unwrap();
+        assert_swapped(&flash, &before);
+
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(
+            state,
+            SwapState::Complete {
+                status,
+                trial: false,
+                confirmed: false
+            }
+        );
+        assert!(state.is_swapped_out(status.primary_id));
+        assert!(!state.is_swapped_out(status.secondary_id));
+        assert!(!state.needs_revert());
+
+        assert_eq!(begin_trial(&mut flash, &TEST_LAYOUT), Ok(true));
+        assert!(read_state(&mut flash, &TEST_LAYOUT).unwrap().needs_revert());
+        assert_eq!(begin_trial(&mut flash, &TEST_LAYOUT), Ok(false));
+    }
+
+    #[test]
+    fn test_confirm() {
+        let before = flash_with_images();
+        let mut flash = before.clone();
+        assert_eq!(boot(&mut flash), Ok(true));
+        confirm(&mut flash, &TEST_LAYOUT).unwrap();
+        let ops = flash.ops();
+        confirm(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(flash.ops(), ops);
+
+        // the old image in the secondary slot is not swapped back
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_eq!(flash.ops(), ops);
+        assert_swapped(&flash, &before);
+
+        // a new update is installed over the completed swap
+        let secondary = TEST_LAYOUT.secondary;
+        flash.data_mut()[secondary.offset as usize..secondary.end() as usize].fill(ERASED);
+        write_image(&mut flash, TEST_LAYOUT.secondary, 0x0800, 0x33);
+        let update = flash.clone();
+        assert_eq!(boot(&mut flash), Ok(true));
+        assert_swapped(&flash, &update);
+    }
+
+    #[test]
+    fn test_revert() {
+        let before = flash_with_images();
+        let mut flash = before.clone();
+        assert_eq!(boot(&mut flash), Ok(true));
+        // not confirmed
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_slots_eq(&flash, &before);
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(state.status().unwrap().swap_type, SwapType::Revert);
+        assert!(!state.needs_revert());
+
+        // the rejected image stays in the secondary slot
+        let ops = flash.ops();
+        assert_eq!(boot(&mut flash), Ok(false));
+        confirm(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(flash.ops(), ops);
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let plain = flash_with_images();
+        let mut before = plain.clone();
+        encrypt_image(&mut before, TEST_LAYOUT.secondary, 0x5a);
+        let image_end = |flash: &MockFlash, partition: Partition| {
+            let ih = read_header(&mut flash.clone(), partition).unwrap();
+            partition.offset as usize + HEADER_LENGTH as usize + ih.image_length as usize
+        };
+
+        let mut flash = before.clone();
+        assert_eq!(boot(&mut flash), Ok(true));
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        let status = state.status().unwrap();
+        assert_eq!(
+            status.wrapped_key,
+            Some([0x5a; encrypt::WRAPPED_KEY_LENGTH])
+        );
+
+        // the new image is decrypted, only its header has changed
+        let primary = TEST_LAYOUT.primary.offset as usize;
+        let secondary = TEST_LAYOUT.secondary.offset as usize;
+        let end = image_end(&flash, TEST_LAYOUT.primary);
+        let header = HEADER_LENGTH as usize;
+        assert_eq!(
+            flash.data()[primary + header..end],
+            plain.data()[secondary + header..secondary + end - primary]
+        );
+        assert_eq!(
+            flash.data()[primary..primary + header],
+            before.data()[secondary..secondary + header]
+        );
+
+        // the old image is encrypted with the same key
+        let swapped = status.sectors as usize * SECTOR_SIZE as usize;
+        let mut old = flash.data()[secondary..secondary + swapped].to_vec();
+        assert_ne!(old, plain.data()[primary..primary + swapped]);
+        XorCipher(0x5a).apply(0, &mut old[header..]);
+        assert_eq!(old, plain.data()[primary..primary + swapped]);
+
+        // not confirmed: both are back as they were
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_slots_eq(&flash, &before);
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(state.status().unwrap().swap_type, SwapType::Revert);
+        assert_eq!(state.status().unwrap().wrapped_key, status.wrapped_key);
+
+        check_power_loss(&before);
+        let mut installed = before.clone();
+        boot(&mut installed).unwrap();
+        check_power_loss(&installed);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let mut before = flash_with_images();
+        let installed = compress_image(&mut before, TEST_LAYOUT.secondary);
+        let mut flash = before.clone();
+        assert_eq!(boot(&mut flash), Ok(false));
+        let primary = slot(&flash, TEST_LAYOUT.primary);
+        assert_eq!(primary[..installed.len()], installed[..]);
+        assert!(primary[installed.len()..].iter().all(|&b| b == ERASED));
+        assert_eq!(
+            slot(&flash, TEST_LAYOUT.secondary),
+            slot(&before, TEST_LAYOUT.secondary)
+        );
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert!(matches!(
+            state,
+            SwapState::Complete { status, trial: false, .. }
+                if status.swap_type == SwapType::Overwrite && status.sectors == 3
+        ));
+        let ih = read_header(&mut flash, TEST_LAYOUT.secondary).unwrap();
+        assert!(state.is_installed(ih.crc32));
+
+        // installed once
+        let after = flash.clone();
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_eq!(flash.data(), after.data());
+
+        check_power_loss(&before);
+
+        // marked failed and not decompressed again
+        let mut flash = before.clone();
+        let offset = TEST_LAYOUT.secondary.offset + HEADER_LENGTH as u32 + 0x10;
+        flash.data_mut()[offset as usize] ^= 0x01;
+        assert_eq!(boot(&mut flash), Ok(false));
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert!(
+            matches!(state, SwapState::Failed { status } if status.swap_type == SwapType::Overwrite)
+        );
+        assert!(state.is_failed(ih.crc32));
+        let after = flash.clone();
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_eq!(flash.data(), after.data());
+
+        // until there is another image
+        write_image(&mut flash, TEST_LAYOUT.secondary, 0x1800, 0x5a);
+        compress_image(&mut flash, TEST_LAYOUT.secondary);
+        assert_eq!(boot(&mut flash), Ok(false));
+        let ih = read_header(&mut flash, TEST_LAYOUT.secondary).unwrap();
+        assert!(read_state(&mut flash, &TEST_LAYOUT)
+            .unwrap()
+            .is_installed(ih.crc32));
+    }
+
+    #[test]
+    fn test_attempts() {
+        let mut flash = flash_with_images();
+        assert_eq!(attempts(&mut flash, &TEST_LAYOUT), Ok(0));
+        count_attempt(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(attempts(&mut flash, &TEST_LAYOUT), Ok(0));
+
+        let status = status(&mut flash);
+        start(&mut flash, &TEST_LAYOUT, &status).unwrap();
+        for _ in 0..20 {
+            count_attempt(&mut flash, &TEST_LAYOUT).unwrap();
+        }
+        assert_eq!(attempts(&mut flash, &TEST_LAYOUT), Ok(16));
+        assert_eq!(
+            read_state(&mut flash, &TEST_LAYOUT),
+            Ok(SwapState::InProgress { status, step: 0 })
+        );
+
+        // a new swap starts again from 0
+        start(&mut flash, &TEST_LAYOUT, &status).unwrap();
+        assert_eq!(attempts(&mut flash, &TEST_LAYOUT), Ok(0));
+    }
+
+    #[test]
+    fn test_resume() {
+        let before = flash_with_images();
+        let mut flash = before.clone();
+        let status = status(&mut flash);
+        start(&mut flash, &TEST_LAYOUT, &status).unwrap();
+        for done in 0..status.steps() {
+            let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+            assert_eq!(state, SwapState::InProgress { status, step: done });
+            run_step(&mut flash, &TEST_LAYOUT, done, None).unwrap();
+            mark_done(&mut flash, status_offsets(&TEST_LAYOUT)[0], done).unwrap();
+        }
+        assert_swapped(&flash, &before);
+        assert!(matches!(
+            read_state(&mut flash, &TEST_LAYOUT),
+            Ok(SwapState::Complete { .. })
+        ));
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let before = flash_with_images();
+        check_power_loss(&before);
+
+        // while reverting
+        let mut installed = before.clone();
+        boot(&mut installed).unwrap();
+        check_power_loss(&installed);
+
+        // while recording the trial or confirming: the image is either
+        // started (again) or reverted, never lost
+        let mut swapped = before.clone();
+        let status = status(&mut swapped);
+        swap(&mut swapped, &TEST_LAYOUT, &status, None).unwrap();
+        type Op = fn(&mut MockFlash, &FlashLayout) -> Result<(), MockFlashError>;
+        let ops: [(&MockFlash, Op); 2] = [
+            (&swapped, |flash, layout| {
+                begin_trial(flash, layout).map(|_| ())
+            }),
+            (&installed, confirm),
+        ];
+        for (flash, op) in ops {
+            for torn in [false, true] {
+                let mut flash = flash.clone();
+                flash.torn = torn;
+                flash.lose_power_after(0);
+                assert_eq!(op(&mut flash, &TEST_LAYOUT), Err(MockFlashError::PowerLoss));
+                flash.power_cycle();
+                boot(&mut flash).unwrap();
+                let primary = slot(&flash, TEST_LAYOUT.primary);
+                assert!(
+                    primary == slot(&before, TEST_LAYOUT.primary)
+                        || primary == slot(&before, TEST_LAYOUT.secondary)
+                );
+            }
+        }
+    }
+
+    /// Loses power during the boot that resumes an interrupted swap, too.
+    #[test]
+    fn test_power_loss_twice() {
+        let before = flash_with_images();
+        let mut reference = before.clone();
+        boot(&mut reference).unwrap();
+
+        for first in 0..reference.ops() - 1 {
+            let mut interrupted = before.clone();
+            interrupted.torn = true;
+            interrupted.lose_power_after(first);
+            assert_eq!(boot(&mut interrupted), Err(MockFlashError::PowerLoss));
+            interrupted.power_cycle();
+
+            let mut resumed = interrupted.clone();
+            boot(&mut resumed).unwrap();
+            for second in 0..resumed.ops() - interrupted.ops() - 1 {
+                let mut flash = interrupted.clone();
+                flash.lose_power_after(second);
+                assert_eq!(boot(&mut flash), Err(MockFlashError::PowerLoss));
+                flash.power_cycle();
+                assert_eq!(boot(&mut flash), Ok(true));
+                assert_swapped(&flash, &before);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupt_status() {
+        let mut flash = flash_with_images();
+        let status = status(&mut flash);
+        start(&mut flash, &TEST_LAYOUT, &status).unwrap();
+        flash.data_mut()[status_offsets(&TEST_LAYOUT)[0] as usize + 8] ^= 0x01;
+        assert_eq!(read_state(&mut flash, &TEST_LAYOUT), Ok(SwapState::Idle));
+    }
+
+    #[test]
+    fn test_request() {
+        let mut flash = flash_with_images();
+        let secondary = read_header(&mut flash, TEST_LAYOUT.secondary).unwrap();
+        assert_eq!(
+            requested_type(&mut flash, &TEST_LAYOUT, secondary.crc32),
+            Ok(SwapType::Test)
+        );
+        // a request for another image is ignored
+        request(
+            &mut flash,
+            &TEST_LAYOUT,
+            !secondary.crc32,
+            SwapType::Permanent,
+        )
+        .unwrap();
+        assert_eq!(
+            requested_type(&mut flash, &TEST_LAYOUT, secondary.crc32),
+            Ok(SwapType::Test)
+        );
+        request(
+            &mut flash,
+            &TEST_LAYOUT,
+            secondary.crc32,
+            SwapType::Permanent,
+        )
+        .unwrap();
+        assert_eq!(
+            requested_type(&mut flash, &TEST_LAYOUT, secondary.crc32),
+            Ok(SwapType::Permanent)
+        );
+
+        // a permanent image has no trial and is not reverted
+        let before = flash.clone();
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_swapped(&flash, &before);
+        assert_eq!(boot(&mut flash), Ok(false));
+        assert_swapped(&flash, &before);
+        let state = read_state(&mut flash, &TEST_LAYOUT).unwrap();
+        assert_eq!(state.status().unwrap().swap_type, SwapType::Permanent);
+    }
+
+    #[test]
+    fn test_status_new() {
+        let mut old = ImageHeader::new();
+        old.image_length = 0x1000;
+        old.crc32 = 0x1111_1111;
+        let mut new = ImageHeader::new();
+        new.image_length = 0x2100 - HEADER_LENGTH as u32;
+        new.crc32 = 0x2222_2222;
+
+        let status = SwapStatus::new(
+            &LAYOUT,
+            Some(&ImageInfo::from(&old)),
+            &ImageInfo::from(&new),
+            SwapType::Permanent,
+        );
+        assert_eq!(status.sectors, 3);
+        assert_eq!(status.primary_id, 0x1111_1111);
+        assert_eq!(status.secondary_id, 0x2222_2222);
+
+        // garbage lengths are limited to the slot
+        new.image_length = u32::MAX;
+        let status = SwapStatus::new(&LAYOUT, None, &ImageInfo::from(&new), SwapType::Test);
+        assert_eq!(status.sectors, LAYOUT.primary.sectors());
+        assert_eq!(status.primary_id, 0xffff_ffff);
+    }
+}
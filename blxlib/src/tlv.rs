@@ -0,0 +1,288 @@
+//! Type-length-value area of the image header (`ImageHeader::tlv`, since hv 0.5).
+//!
+//! Each entry is a tag byte, a length byte and `length` bytes of value. The
+//! area ends at its last byte or at a `TLV_END` (0x00) or erased (0xff) tag,
+//! so the zero padding of older headers is an empty area. Being part of the
+//! header, the entries are covered by `crc32` and the signature.
+
+use crate::encrypt::WRAPPED_KEY_LENGTH;
+use crate::version::ImageVersion;
+use core::fmt;
+
+pub const TLV_END: u8 = 0x00;
+/// Build time, seconds since the Unix epoch (u64).
+pub const TLV_BUILD_TIME: u8 = 0x01;
+/// Application defined flags (u32).
+pub const TLV_FLAGS: u8 = 0x02;
+/// Dependency on a minimum image version (`ImageVersion`).
+pub const TLV_DEPENDENCY: u8 = 0x03;
+/// Full commit hash of the build (20 bytes).
+pub const TLV_COMMIT: u8 = 0x04;
+/// Image key wrapped for the device (`encrypt::WRAPPED_KEY_LENGTH` bytes).
+pub const TLV_ENC_X25519: u8 = 0x05;
+/// The payload is compressed (see `compress`); compressed length (u32).
+pub const TLV_LZ4: u8 = 0x06;
+
+const TLV_ERASED: u8 = 0xff;
+const ENTRY_HEADER_LENGTH: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlvError {
+    /// The entry at `offset` extends beyond the area.
+    Truncated { offset: usize },
+    /// Not enough room left for the entry.
+    Full,
+    /// The tag is reserved for the end of the area.
+    ReservedTag(u8),
+}
+
+impl fmt::Display for TlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlvError::Truncated { offset } => write!(f, "tlv at {} is truncated", offset),
+            TlvError::Full => write!(f, "tlv area is full"),
+            TlvError::ReservedTag(tag) => write!(f, "tlv tag {:02x} is reserved", tag),
+        }
+    }
+}
+
+impl core::error::Error for TlvError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub value: &'a [u8],
+}
+
+/// A decoded entry. Known tags with an unexpected length are `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlvValue<'a> {
+    BuildTime(u64),
+    Flags(u32),
+    Dependency(ImageVersion),
+    Commit(&'a [u8; 20]),
+    EncX25519(&'a [u8; WRAPPED_KEY_LENGTH]),
+    Lz4(u32),
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> Tlv<'a> {
+    pub fn decode(&self) -> TlvValue<'a> {
+        let value = self.value;
+        match (self.tag, value.len()) {
+            (TLV_BUILD_TIME, 8) => {
+                TlvValue::BuildTime(u64::from_le_bytes(value.try_into().unwrap()))
+            }
+            (TLV_FLAGS, 4) => TlvValue::Flags(u32::from_le_bytes(value.try_into().unwrap())),
+            (TLV_DEPENDENCY, 8) => TlvValue::Dependency(ImageVersion::new(
+                value[0],
+                value[1],
+                u16::from_le_bytes([value[2], value[3]]),
+                u32::from_le_bytes(value[4..8].try_into().unwrap()),
+            )),
+            (TLV_COMMIT, 20) => TlvValue::Commit(value.try_into().unwrap()),
+            (TLV_ENC_X25519, WRAPPED_KEY_LENGTH) => TlvValue::EncX25519(value.try_into().unwrap()),
+            (TLV_LZ4, 4) => TlvValue::Lz4(u32::from_le_bytes(value.try_into().unwrap())),
+            (tag, _) => TlvValue::Unknown(tag, value),
+        }
+    }
+}
+
+/// Iterates over the entries of a TLV area. A truncated entry is returned as
+/// an error and ends the iteration.
+#[derive(Clone, Debug)]
+pub struct TlvIter<'a> {
+    area: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> TlvIter<'a> {
+    pub fn new(area: &'a [u8]) -> Self {
+        TlvIter { area, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<Tlv<'a>, TlvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let tag = *self.area.get(offset)?;
+        // nothing follows the end or an error
+        self.offset = self.area.len();
+        if tag == TLV_END || tag == TLV_ERASED {
+            return None;
+        }
+        let start = offset + ENTRY_HEADER_LENGTH;
+        let value = self
+            .area
+            .get(offset + 1)
+            .and_then(|&len| self.area.get(start..start + len as usize));
+        let Some(value) = value else {
+            return Some(Err(TlvError::Truncated { offset }));
+        };
+        self.offset = start + value.len();
+        Some(Ok(Tlv { tag, value }))
+    }
+}
+
+/// Looks up the first entry with `tag`.
+pub fn find(area: &[u8], tag: u8) -> Option<Tlv<'_>> {
+    TlvIter::new(area)
+        .map_while(Result::ok)
+        .find(|tlv| tlv.tag == tag)
+}
+
+/// Builds a TLV area, e.g. `bintool` filling `ImageHeader::tlv`. The rest of
+/// the area stays `TLV_END`.
+pub struct TlvWriter<'a> {
+    area: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TlvWriter<'a> {
+    /// Clears `area`.
+    pub fn new(area: &'a mut [u8]) -> Self {
+        area.fill(TLV_END);
+        TlvWriter { area, len: 0 }
+    }
+
+    /// Continues after the entries already in `area`.
+    pub fn append(area: &'a mut [u8]) -> Self {
+        let len = TlvIter::new(area)
+            .map_while(Result::ok)
+            .map(|tlv| ENTRY_HEADER_LENGTH + tlv.value.len())
+            .sum();
+        TlvWriter { area, len }
+    }
+
+    pub fn push(&mut self, tag: u8, value: &[u8]) -> Result<(), TlvError> {
+        if tag == TLV_END || tag == TLV_ERASED {
+            return Err(TlvError::ReservedTag(tag));
+        }
+        let end = self.len + ENTRY_HEADER_LENGTH + value.len();
+        if value.len() > u8::MAX as usize || end > self.area.len() {
+            return Err(TlvError::Full);
+        }
+        self.area[self.len] = tag;
+        self.area[self.len + 1] = value.len() as u8;
+        self.area[self.len + ENTRY_HEADER_LENGTH..end].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+
+    pub fn push_build_time(&mut self, secs: u64) -> Result<(), TlvError> {
+        self.push(TLV_BUILD_TIME, &secs.to_le_bytes())
+    }
+
+    pub fn push_flags(&mut self, flags: u32) -> Result<(), TlvError> {
+        self.push(TLV_FLAGS, &flags.to_le_bytes())
+    }
+
+    pub fn push_dependency(&mut self, version: ImageVersion) -> Result<(), TlvError> {
+        let mut value = [0u8; 8];
+        value[0] = version.major;
+        value[1] = version.minor;
+        value[2..4].copy_from_slice(&version.patch.to_le_bytes());
+        value[4..8].copy_from_slice(&version.build.to_le_bytes());
+        self.push(TLV_DEPENDENCY, &value)
+    }
+
+    pub fn push_commit(&mut self, commit: &[u8; 20]) -> Result<(), TlvError> {
+        self.push(TLV_COMMIT, commit)
+    }
+
+    /// Bytes used so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut area = [0xaau8; 63];
+        let mut writer = TlvWriter::new(&mut area);
+        assert!(writer.is_empty());
+        writer.push_build_time(1_700_000_000).unwrap();
+        writer.push_flags(0x8000_0001).unwrap();
+        writer
+            .push_dependency(ImageVersion::new(1, 2, 3, 0xabcd))
+            .unwrap();
+        writer.push_commit(&[0x11; 20]).unwrap();
+        writer.push(0x42, &[1, 2, 3]).unwrap();
+        assert_eq!(writer.len(), 10 + 6 + 10 + 22 + 5);
+        assert_eq!(writer.push(0x43, &[0; 20]), Err(TlvError::Full));
+        assert_eq!(
+            writer.push(TLV_END, &[]),
+            Err(TlvError::ReservedTag(TLV_END))
+        );
+
+        let values: Vec<TlvValue> = TlvIter::new(&area)
+            .map(|tlv| tlv.unwrap().decode())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                TlvValue::BuildTime(1_700_000_000),
+                TlvValue::Flags(0x8000_0001),
+                TlvValue::Dependency(ImageVersion::new(1, 2, 3, 0xabcd)),
+                TlvValue::Commit(&[0x11; 20]),
+                TlvValue::Unknown(0x42, &[1, 2, 3]),
+            ]
+        );
+        assert_eq!(find(&area, TLV_FLAGS).unwrap().value, &[1, 0, 0, 0x80]);
+        assert_eq!(find(&area, 0x43), None);
+
+        let mut writer = TlvWriter::append(&mut area);
+        assert_eq!(writer.len(), 10 + 6 + 10 + 22 + 5);
+        writer.push(0x43, &[4; 8]).unwrap();
+        assert_eq!(writer.push(0x43, &[]), Err(TlvError::Full));
+        assert_eq!(find(&area, 0x43).unwrap().value, &[4; 8]);
+        assert_eq!(TlvWriter::append(&mut [0xffu8; 63]).len(), 0);
+    }
+
+    #[test]
+    fn test_iter() {
+        // zero padding of an older header and an erased area are empty
+        assert_eq!(TlvIter::new(&[0u8; 63]).count(), 0);
+        assert_eq!(TlvIter::new(&[0xffu8; 63]).count(), 0);
+        assert_eq!(TlvIter::new(&[]).count(), 0);
+
+        // a known tag with an unexpected length is not decoded
+        let area = [TLV_FLAGS, 2, 0x34, 0x12, TLV_END, 0x99];
+        let tlvs: Vec<_> = TlvIter::new(&area).collect();
+        assert_eq!(
+            tlvs,
+            [Ok(Tlv {
+                tag: TLV_FLAGS,
+                value: &[0x34, 0x12]
+            })]
+        );
+        assert_eq!(
+            tlvs[0].unwrap().decode(),
+            TlvValue::Unknown(TLV_FLAGS, &[0x34, 0x12])
+        );
+
+        // an entry that fills the area exactly
+        let area = [0x42, 2, 1, 2];
+        assert_eq!(TlvIter::new(&area).count(), 1);
+
+        let area = [0x42, 1, 1, 0x43, 3, 1, 2];
+        let tlvs: Vec<_> = TlvIter::new(&area).collect();
+        assert_eq!(tlvs.len(), 2);
+        assert_eq!(tlvs[1], Err(TlvError::Truncated { offset: 3 }));
+
+        let area = [0x42, 1, 1, 0x43];
+        let tlvs: Vec<_> = TlvIter::new(&area).collect();
+        assert_eq!(tlvs[1], Err(TlvError::Truncated { offset: 3 }));
+    }
+}
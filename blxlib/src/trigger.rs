@@ -0,0 +1,181 @@
+//! Triggers that keep the bootloader in recovery mode instead of booting:
+//!
+//! - a button that pulls a GPIO low while the chip resets,
+//! - `SCRATCH_MAGIC` in the watchdog scratch register at `SCRATCH_ADDR`, left
+//!   by the application (`BootClient::reboot_to_recovery`),
+//! - a second reset while the bootloader waits after a reset, detected with
+//!   `DOUBLE_RESET_MAGIC` in a RAM word that is not initialized at startup.
+//!
+//! The bootloader reads the inputs, clears the scratch register and the RAM
+//! word, and acts on `check`.
+
+/// Left in `SCRATCH_ADDR` by the application to stay in the bootloader.
+pub const SCRATCH_MAGIC: u32 = 0xb007_4ec0;
+/// Watchdog SCRATCH0, which survives a soft reset. The bootrom uses
+/// SCRATCH4..7 for its own reboot.
+pub const SCRATCH_ADDR: u32 = 0x4005_800c;
+/// In the RAM word while the bootloader waits for a second reset.
+pub const DOUBLE_RESET_MAGIC: u32 = 0xd0b1_e4e5;
+
+/// The triggers that are enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TriggerConfig {
+    pub button: bool,
+    pub scratch: bool,
+    pub double_reset: bool,
+}
+
+impl TriggerConfig {
+    pub const ALL: Self = TriggerConfig {
+        button: true,
+        scratch: true,
+        double_reset: true,
+    };
+    pub const NONE: Self = TriggerConfig {
+        button: false,
+        scratch: false,
+        double_reset: false,
+    };
+}
+
+/// State read at reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TriggerInputs {
+    /// The button GPIO is low.
+    pub button: bool,
+    /// Value of the watchdog scratch register.
+    pub scratch: u32,
+    /// Value of the RAM word.
+    pub double_reset: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Button,
+    Scratch,
+    DoubleReset,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// The first enabled trigger that fired.
+    pub trigger: Option<Trigger>,
+    /// Put `DOUBLE_RESET_MAGIC` in the RAM word while waiting for a second
+    /// reset, then clear it.
+    pub arm_double_reset: bool,
+}
+
+pub fn check(config: &TriggerConfig, inputs: &TriggerInputs) -> Decision {
+    let trigger = if config.button && inputs.button {
+        Some(Trigger::Button)
+    } else if config.scratch && inputs.scratch == SCRATCH_MAGIC {
+        Some(Trigger::Scratch)
+    } else if config.double_reset && inputs.double_reset == DOUBLE_RESET_MAGIC {
+        Some(Trigger::DoubleReset)
+    } else {
+        None
+    };
+    Decision {
+        trigger,
+        // a third reset boots normally
+        arm_double_reset: config.double_reset && trigger.is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let none = TriggerInputs::default();
+        let button = TriggerInputs {
+            button: true,
+            ..none
+        };
+        let scratch = TriggerInputs {
+            scratch: SCRATCH_MAGIC,
+            ..none
+        };
+        let double_reset = TriggerInputs {
+            double_reset: DOUBLE_RESET_MAGIC,
+            ..none
+        };
+        let all = TriggerInputs {
+            button: true,
+            scratch: SCRATCH_MAGIC,
+            double_reset: DOUBLE_RESET_MAGIC,
+        };
+        let decision = |trigger, arm_double_reset| Decision {
+            trigger,
+            arm_double_reset,
+        };
+        let cases = [
+            (TriggerConfig::ALL, none, decision(None, true)),
+            (
+                TriggerConfig::ALL,
+                button,
+                decision(Some(Trigger::Button), false),
+            ),
+            (
+                TriggerConfig::ALL,
+                scratch,
+                decision(Some(Trigger::Scratch), false),
+            ),
+            (
+                TriggerConfig::ALL,
+                double_reset,
+                decision(Some(Trigger::DoubleReset), false),
+            ),
+            (
+                TriggerConfig::ALL,
+                all,
+                decision(Some(Trigger::Button), false),
+            ),
+            (TriggerConfig::NONE, all, decision(None, false)),
+            (
+                TriggerConfig {
+                    button: false,
+                    ..TriggerConfig::ALL
+                },
+                all,
+                decision(Some(Trigger::Scratch), false),
+            ),
+            (
+                TriggerConfig {
+                    double_reset: false,
+                    ..TriggerConfig::ALL
+                },
+                double_reset,
+                decision(None, false),
+            ),
+            (
+                TriggerConfig {
+                    scratch: true,
+                    ..TriggerConfig::NONE
+                },
+                double_reset,
+                decision(None, false),
+            ),
+            // only the exact magic values count
+            (
+                TriggerConfig::ALL,
+                TriggerInputs {
+                    scratch: SCRATCH_MAGIC ^ 1,
+                    double_reset: !DOUBLE_RESET_MAGIC,
+                    ..none
+                },
+                decision(None, true),
+            ),
+        ];
+        for (config, inputs, expected) in cases {
+            assert_eq!(
+                check(&config, &inputs),
+                expected,
+                "{:?} {:?}",
+                config,
+                inputs
+            );
+        }
+    }
+}
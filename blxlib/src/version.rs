@@ -0,0 +1,171 @@
+//! Image version (`ImageHeader::iv_*`), written and parsed as
+//! `major.minor.patch+build` with `build` in hex (the abbreviated commit hash).
+
+use core::cmp::Ordering;
+use core::fmt;
+use core::str::FromStr;
+
+/// Ordered by `major`, `minor`, `patch` and then `build`, so that `Ord` agrees
+/// with `Eq`. `build` is a commit hash, so use `cmp_precedence` to find the
+/// newer of two images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u16,
+    pub build: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseVersionError {
+    /// Not `major.minor.patch` with an optional `+build`.
+    BadFormat,
+    BadNumber,
+    BadBuild,
+}
+
+impl fmt::Display for ParseVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseVersionError::BadFormat => write!(f, "version is not major.minor.patch[+build]"),
+            ParseVersionError::BadNumber => write!(f, "version number is not valid"),
+            ParseVersionError::BadBuild => write!(f, "build is not up to 8 hex digits"),
+        }
+    }
+}
+
+impl core::error::Error for ParseVersionError {}
+
+impl ImageVersion {
+    pub const fn new(major: u8, minor: u8, patch: u16, build: u32) -> Self {
+        ImageVersion {
+            major,
+            minor,
+            patch,
+            build,
+        }
+    }
+
+    /// Compares `major.minor.patch` only, like semver precedence ignores build metadata.
+    pub fn cmp_precedence(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch))
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}+{:08x}",
+            self.major, self.minor, self.patch, self.build
+        )
+    }
+}
+
+impl FromStr for ImageVersion {
+    type Err = ParseVersionError;
+
+    /// Parses `1.2.3` or `1.2.3+deadbeef`. Pre-release versions are not supported.
+    fn from_str(s: &str) -> Result<Self, ParseVersionError> {
+        let (release, build) = match s.split_once('+') {
+            Some((release, build)) => {
+                // from_str_radix would also accept a leading '+'
+                if build.is_empty()
+                    || build.len() > 8
+                    || !build.bytes().all(|b| b.is_ascii_hexdigit())
+                {
+                    return Err(ParseVersionError::BadBuild);
+                }
+                let build =
+                    u32::from_str_radix(build, 16).map_err(|_| ParseVersionError::BadBuild)?;
+                (release, build)
+            }
+            None => (s, 0),
+        };
+        let mut numbers = release.split('.');
+        let (Some(major), Some(minor), Some(patch), None) = (
+            numbers.next(),
+            numbers.next(),
+            numbers.next(),
+            numbers.next(),
+        ) else {
+            return Err(ParseVersionError::BadFormat);
+        };
+        Ok(ImageVersion {
+            major: parse_number(major)?,
+            minor: parse_number(minor)?,
+            patch: parse_number(patch)?,
+            build,
+        })
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, ParseVersionError> {
+    // from_str would also accept a leading '+'
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseVersionError::BadNumber);
+    }
+    s.parse().map_err(|_| ParseVersionError::BadNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn test_display() {
+        let v = ImageVersion::new(1, 2, 3, 0xdeadbeef);
+        assert_eq!(format!("{}", v), "1.2.3+deadbeef");
+        assert_eq!(format!("{}", ImageVersion::default()), "0.0.0+00000000");
+        assert_eq!(format!("{}", v).parse(), Ok(v));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("1.2.3".parse(), Ok(ImageVersion::new(1, 2, 3, 0)));
+        assert_eq!("0.1.0+0a1b".parse(), Ok(ImageVersion::new(0, 1, 0, 0x0a1b)));
+        assert_eq!(
+            "255.255.65535+ffffffff".parse(),
+            Ok(ImageVersion::new(255, 255, 65535, 0xffff_ffff))
+        );
+
+        for (s, e) in [
+            ("", ParseVersionError::BadFormat),
+            ("1.2", ParseVersionError::BadFormat),
+            ("1.2.3.4", ParseVersionError::BadFormat),
+            ("1.2.3-rc1", ParseVersionError::BadNumber),
+            ("1..3", ParseVersionError::BadNumber),
+            ("-1.2.3", ParseVersionError::BadNumber),
+            ("+1.2.3", ParseVersionError::BadBuild),
+            ("1.+2.3", ParseVersionError::BadBuild),
+            ("1.2.3++ff", ParseVersionError::BadBuild),
+            ("256.0.0", ParseVersionError::BadNumber),
+            ("1.2.65536", ParseVersionError::BadNumber),
+            ("1.2.3+", ParseVersionError::BadBuild),
+            ("1.2.3+xyz", ParseVersionError::BadBuild),
+            ("1.2.3+123456789", ParseVersionError::BadBuild),
+        ] {
+            assert_eq!(s.parse::<ImageVersion>(), Err(e), "{}", s);
+        }
+        assert_eq!(parse_number::<u8>("+1"), Err(ParseVersionError::BadNumber));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let v = |s: &str| s.parse::<ImageVersion>().unwrap();
+        assert!(v("1.2.3") < v("1.2.4"));
+        assert!(v("1.2.10") > v("1.2.9"));
+        assert!(v("1.10.0") > v("1.9.99"));
+        assert!(v("2.0.0") > v("1.255.65535"));
+
+        // the build only breaks ties
+        assert!(v("1.2.3+2") > v("1.2.3+1"));
+        assert!(v("1.2.3+ffffffff") < v("1.2.4+0"));
+        assert_eq!(v("1.2.3+2").cmp_precedence(&v("1.2.3+1")), Ordering::Equal);
+        assert_eq!(
+            v("1.2.3+ffffffff").cmp_precedence(&v("1.3.0")),
+            Ordering::Less
+        );
+    }
+}
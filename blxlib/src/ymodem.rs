@@ -0,0 +1,626 @@
+//! YMODEM file transfers, for the bootloader's serial recovery mode, with
+//! XMODEM-1K as fallback.
+//!
+//! Both ends are state machines without I/O: bytes that arrive are pushed,
+//! a timeout is reported by calling `timeout`, and what to send back is
+//! returned. Packets carry 128 (`SOH`) or 1024 (`STX`) bytes and a CRC-16.
+//!
+//! ```text
+//! receiver             sender
+//! C               ->
+//!                 <-   SOH 00 ff name NUL size NUL ... crc     (YMODEM only)
+//! ACK C           ->
+//!                 <-   STX 01 fe data[1024] crc
+//! ACK             ->   ...
+//!                 <-   EOT
+//! NAK             ->                                           (XMODEM: ACK, done)
+//!                 <-   EOT
+//! ACK C           ->
+//!                 <-   SOH 00 ff NUL[128] crc                  (end of batch)
+//! ACK             ->
+//! ```
+//!
+//! A receiver that gets block 1 instead of block 0 continues as an XMODEM-1K
+//! receiver; the data is then padded with `SUB` to a whole block.
+
+use core::fmt;
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+pub const SUB: u8 = 0x1a;
+/// Sent by the receiver to ask for CRC-16 packets.
+pub const CRC: u8 = b'C';
+
+/// Aborts a transfer from either end.
+pub const CANCEL: &[u8] = &[CAN, CAN];
+/// Errors and timeouts in a row after which a transfer is given up.
+pub const MAX_RETRIES: u32 = 10;
+pub const MAX_PACKET_LENGTH: usize = 3 + 1024 + 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModemError {
+    /// The other end sent `CAN CAN`.
+    Cancelled,
+    /// `MAX_RETRIES` errors or timeouts in a row.
+    TooManyRetries,
+    /// A block out of sequence, or a second file.
+    UnexpectedBlock(u8),
+}
+
+impl fmt::Display for ModemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModemError::Cancelled => write!(f, "transfer cancelled"),
+            ModemError::TooManyRetries => write!(f, "too many errors"),
+            ModemError::UnexpectedBlock(block) => write!(f, "unexpected block {}", block),
+        }
+    }
+}
+
+impl core::error::Error for ModemError {}
+
+/// CRC-16/XMODEM.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// What the receiver got. Send `reply()` once it is handled; for `Data`
+/// that is after it is stored, so that the sender waits.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// Nothing for the caller.
+    Reply(&'static [u8]),
+    /// The YMODEM file header.
+    File { name: &'a [u8], size: Option<u32> },
+    /// The next data of the file.
+    Data(&'a [u8]),
+    /// The file is complete.
+    End,
+}
+
+impl Event<'_> {
+    pub fn reply(&self) -> &'static [u8] {
+        match self {
+            Event::Reply(reply) => reply,
+            Event::File { .. } => &[ACK, CRC],
+            Event::Data(_) | Event::End => &[ACK],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Nothing received yet.
+    Start,
+    Ymodem,
+    Xmodem,
+    /// After the first `EOT` of a YMODEM file.
+    Eot,
+    /// Waiting for the empty header that ends the batch.
+    BatchEnd,
+}
+
+pub struct Receiver {
+    mode: Mode,
+    /// Length of the packet being received, 0 between packets.
+    packet_len: usize,
+    received: usize,
+    packet: [u8; MAX_PACKET_LENGTH],
+    expected: u8,
+    /// Bytes of the file left, if the header has the size.
+    remaining: Option<u32>,
+    retries: u32,
+    cancel: bool,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Receiver {
+            mode: Mode::Start,
+            packet_len: 0,
+            received: 0,
+            packet: [0u8; MAX_PACKET_LENGTH],
+            expected: 0,
+            remaining: None,
+            retries: 0,
+            cancel: false,
+        }
+    }
+
+    /// Nothing arrived for a while (a second). Until the sender starts, this
+    /// asks for the transfer again without giving up.
+    pub fn timeout(&mut self) -> Result<&'static [u8], ModemError> {
+        self.packet_len = 0;
+        if self.mode == Mode::Start {
+            return Ok(&[CRC]);
+        }
+        self.retry()?;
+        Ok(if self.mode == Mode::BatchEnd || self.expected == 1 {
+            &[CRC]
+        } else {
+            &[NAK]
+        })
+    }
+
+    pub fn push(&mut self, byte: u8) -> Result<Option<Event<'_>>, ModemError> {
+        if self.packet_len > 0 {
+            self.packet[self.received] = byte;
+            self.received += 1;
+            if self.received < self.packet_len {
+                return Ok(None);
+            }
+            self.packet_len = 0;
+            return self.packet().map(Some);
+        }
+        let cancel = core::mem::replace(&mut self.cancel, byte == CAN);
+        match byte {
+            SOH | STX => {
+                self.packet[0] = byte;
+                self.received = 1;
+                self.packet_len = if byte == SOH {
+                    3 + 128 + 2
+                } else {
+                    MAX_PACKET_LENGTH
+                };
+                Ok(None)
+            }
+            EOT => Ok(Some(match self.mode {
+                Mode::Ymodem => {
+                    self.mode = Mode::Eot;
+                    Event::Reply(&[NAK])
+                }
+                Mode::Eot => {
+                    self.mode = Mode::BatchEnd;
+                    Event::Reply(&[ACK, CRC])
+                }
+                Mode::Xmodem => Event::End,
+                _ => Event::Reply(&[ACK]),
+            })),
+            CAN if cancel => Err(ModemError::Cancelled),
+            _ => Ok(None),
+        }
+    }
+
+    fn retry(&mut self) -> Result<(), ModemError> {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            return Err(ModemError::TooManyRetries);
+        }
+        Ok(())
+    }
+
+    fn packet(&mut self) -> Result<Event<'_>, ModemError> {
+        let len = self.received;
+        let block = self.packet[1];
+        let crc = u16::from_be_bytes([self.packet[len - 2], self.packet[len - 1]]);
+        if block != !self.packet[2] || crc != crc16(&self.packet[3..len - 2]) {
+            self.retry()?;
+            return Ok(Event::Reply(&[NAK]));
+        }
+        let data = &self.packet[3..len - 2];
+        match (self.mode, block) {
+            (Mode::Start, 0) => {
+                self.mode = Mode::Ymodem;
+                self.expected = 1;
+                self.retries = 0;
+                let name_len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                let size = data[(name_len + 1).min(data.len())..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .try_fold(None, |size: Option<u32>, b| {
+                        size.unwrap_or(0)
+                            .checked_mul(10)?
+                            .checked_add((b - b'0') as u32)
+                            .map(Some)
+                    })
+                    .flatten();
+                self.remaining = size;
+                return Ok(Event::File {
+                    name: &data[..name_len],
+                    size,
+                });
+            }
+            (Mode::Start, 1) => {
+                self.mode = Mode::Xmodem;
+                self.expected = 1;
+            }
+            (Mode::BatchEnd, 0) if data[0] == 0 => return Ok(Event::End),
+            _ => {}
+        }
+        if block == self.expected.wrapping_sub(1) && self.mode != Mode::BatchEnd {
+            // the sender did not get the ACK
+            return Ok(Event::Reply(&[ACK]));
+        }
+        if block != self.expected || !matches!(self.mode, Mode::Ymodem | Mode::Xmodem) {
+            return Err(ModemError::UnexpectedBlock(block));
+        }
+        self.expected = block.wrapping_add(1);
+        self.retries = 0;
+        let n = match &mut self.remaining {
+            Some(remaining) => {
+                let n = (*remaining).min(data.len() as u32);
+                *remaining -= n;
+                n as usize
+            }
+            None => data.len(),
+        };
+        Ok(Event::Data(&data[..n]))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SendState {
+    Start,
+    Header,
+    /// The header is acknowledged, waiting for `C`.
+    HeaderAcked,
+    Data,
+    Eot,
+    BatchEnd,
+    Final,
+    Done,
+}
+
+/// Sends one file with YMODEM in 1 KiB blocks.
+pub struct Sender<'a> {
+    name: &'a [u8],
+    data: &'a [u8],
+    state: SendState,
+    /// Offset in `data` of the packet in flight.
+    offset: usize,
+    block: u8,
+    packet: [u8; MAX_PACKET_LENGTH],
+    packet_len: usize,
+    retries: u32,
+    cancel: bool,
+}
+
+impl<'a> Sender<'a> {
+    pub fn new(name: &'a [u8], data: &'a [u8]) -> Self {
+        Sender {
+            name,
+            data,
+            state: SendState::Start,
+            offset: 0,
+            block: 0,
+            packet: [0u8; MAX_PACKET_LENGTH],
+            packet_len: 0,
+            retries: 0,
+            cancel: false,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == SendState::Done
+    }
+
+    /// Bytes of the file the receiver has acknowledged.
+    pub fn acknowledged(&self) -> usize {
+        match self.state {
+            SendState::Data => self.offset,
+            SendState::Start | SendState::Header | SendState::HeaderAcked => 0,
+            _ => self.data.len(),
+        }
+    }
+
+    /// Nothing arrived for a while (ten seconds): sends the last packet again.
+    pub fn timeout(&mut self) -> Result<&[u8], ModemError> {
+        if self.state == SendState::Start {
+            return Ok(&[]);
+        }
+        self.retry()?;
+        Ok(&self.packet[..self.packet_len])
+    }
+
+    /// Handles a byte from the receiver and returns what to send.
+    pub fn push(&mut self, byte: u8) -> Result<&[u8], ModemError> {
+        let cancel = core::mem::replace(&mut self.cancel, byte == CAN);
+        if byte == CAN && cancel {
+            return Err(ModemError::Cancelled);
+        }
+        let resend = match (self.state, byte) {
+            (SendState::Start, CRC) => {
+                self.header(true);
+                self.state = SendState::Header;
+                false
+            }
+            (SendState::Header, ACK) => {
+                self.state = SendState::HeaderAcked;
+                self.packet_len = 0;
+                false
+            }
+            (SendState::HeaderAcked, CRC) => {
+                self.state = SendState::Data;
+                self.block = 1;
+                self.data_packet();
+                false
+            }
+            (SendState::Data, ACK) => {
+                self.offset += self.packet_len - 5;
+                if self.offset < self.data.len() {
+                    self.block = self.block.wrapping_add(1);
+                    self.data_packet();
+                } else {
+                    self.offset = self.data.len();
+                    self.state = SendState::Eot;
+                    self.packet[0] = EOT;
+                    self.packet_len = 1;
+                }
+                false
+            }
+            (SendState::Eot, ACK) => {
+                self.state = SendState::BatchEnd;
+                self.packet_len = 0;
+                false
+            }
+            (SendState::BatchEnd, CRC) => {
+                self.header(false);
+                self.state = SendState::Final;
+                false
+            }
+            (SendState::Final, ACK) => {
+                self.state = SendState::Done;
+                self.packet_len = 0;
+                false
+            }
+            (SendState::Header | SendState::Data | SendState::Eot | SendState::Final, NAK) => true,
+            (SendState::Header | SendState::Final, CRC) => true,
+            _ => return Ok(&[]),
+        };
+        if resend {
+            self.retry()?;
+        } else {
+            self.retries = 0;
+        }
+        Ok(&self.packet[..self.packet_len])
+    }
+
+    fn retry(&mut self) -> Result<(), ModemError> {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            return Err(ModemError::TooManyRetries);
+        }
+        Ok(())
+    }
+
+    /// Block 0 with the file name and size, or empty to end the batch.
+    fn header(&mut self, file: bool) {
+        let mut data = [0u8; 1024];
+        let mut len = 0;
+        if file {
+            let name = &self.name[..self.name.len().min(1000)];
+            data[..name.len()].copy_from_slice(name);
+            len = name.len() + 1;
+            let mut digits = [0u8; 10];
+            let mut n = self.data.len();
+            let mut i = digits.len();
+            loop {
+                i -= 1;
+                digits[i] = b'0' + (n % 10) as u8;
+                n /= 10;
+                if n == 0 {
+                    break;
+                }
+            }
+            data[len..len + digits.len() - i].copy_from_slice(&digits[i..]);
+            len += digits.len() - i + 1;
+        }
+        let size = if len <= 128 { 128 } else { 1024 };
+        self.block = 0;
+        self.make_packet(&data[..size]);
+    }
+
+    fn data_packet(&mut self) {
+        let rest = &self.data[self.offset..];
+        let size = if rest.len() <= 128 { 128 } else { 1024 };
+        let n = rest.len().min(size);
+        let mut data = [SUB; 1024];
+        data[..n].copy_from_slice(&rest[..n]);
+        self.make_packet(&data[..size]);
+    }
+
+    fn make_packet(&mut self, data: &[u8]) {
+        let len = data.len();
+        self.packet[0] = if len == 128 { SOH } else { STX };
+        self.packet[1] = self.block;
+        self.packet[2] = !self.block;
+        self.packet[3..3 + len].copy_from_slice(data);
+        self.packet[3 + len..5 + len].copy_from_slice(&crc16(data).to_be_bytes());
+        self.packet_len = len + 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    struct Received {
+        name: Vec<u8>,
+        size: Option<u32>,
+        data: Vec<u8>,
+    }
+
+    /// Sends `data` from a `Sender` to a `Receiver`. `line` may change what
+    /// the sender sends each time, a packet or nothing is lost on a timeout.
+    fn transfer(
+        data: &[u8],
+        mut line: impl FnMut(usize, &mut Vec<u8>),
+    ) -> Result<Received, ModemError> {
+        let mut rx = Receiver::new();
+        let mut tx = Sender::new(b"app.base", data);
+        let mut received = Received {
+            name: Vec::new(),
+            size: None,
+            data: Vec::new(),
+        };
+        let mut to_tx = rx.timeout()?.to_vec();
+        for n in 0.. {
+            let mut to_rx = Vec::new();
+            for b in to_tx.drain(..) {
+                to_rx.extend_from_slice(tx.push(b)?);
+            }
+            line(n, &mut to_rx);
+            if to_rx.is_empty() {
+                if tx.is_done() {
+                    break;
+                }
+                to_tx.extend_from_slice(rx.timeout()?);
+                continue;
+            }
+            for b in to_rx {
+                let Some(event) = rx.push(b)? else {
+                    continue;
+                };
+                match &event {
+                    Event::File { name, size } => {
+                        received.name = name.to_vec();
+                        received.size = *size;
+                    }
+                    Event::Data(data) => received.data.extend_from_slice(data),
+                    Event::Reply(_) | Event::End => {}
+                }
+                to_tx.extend_from_slice(event.reply());
+            }
+        }
+        assert_eq!(tx.acknowledged(), data.len());
+        Ok(received)
+    }
+
+    /// An XMODEM packet.
+    fn packet(block: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::from([if data.len() == 128 { SOH } else { STX }, block, !block]);
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&crc16(data).to_be_bytes());
+        packet
+    }
+
+    fn push_all<'a>(rx: &'a mut Receiver, bytes: &[u8]) -> Result<Option<Event<'a>>, ModemError> {
+        let (last, bytes) = bytes.split_last().unwrap();
+        for &b in bytes {
+            assert_eq!(rx.push(b)?, None);
+        }
+        rx.push(*last)
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn test_transfer() {
+        for len in [0, 1, 128, 129, 1024, 3000, 300 * 1024] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7 + i / 251) as u8).collect();
+            let received = transfer(&data, |_, _| {}).unwrap();
+            assert_eq!(received.name, b"app.base");
+            assert_eq!(received.size, Some(len as u32));
+            assert_eq!(received.data, data);
+        }
+    }
+
+    #[test]
+    fn test_transfer_errors() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 3) as u8).collect();
+        // a corrupt header, a corrupt and a lost data packet, a lost EOT
+        let received = transfer(&data, |n, bytes| match n {
+            0 | 3 => bytes[40] ^= 0x01,
+            5 | 9 => bytes.clear(),
+            _ => {}
+        })
+        .unwrap();
+        assert_eq!(received.data, data);
+
+        assert_eq!(
+            transfer(&data, |n, bytes| if n > 2 {
+                bytes[10] ^= 0x01
+            })
+            .err(),
+            Some(ModemError::TooManyRetries)
+        );
+    }
+
+    #[test]
+    fn test_xmodem() {
+        let mut rx = Receiver::new();
+        assert_eq!(rx.timeout(), Ok(&[CRC][..]));
+        let mut data = [SUB; 1024];
+        data[..5].copy_from_slice(b"hello");
+        let packet1 = packet(1, &data);
+        assert_eq!(push_all(&mut rx, &packet1), Ok(Some(Event::Data(&data))));
+        // the ACK was lost
+        assert_eq!(push_all(&mut rx, &packet1), Ok(Some(Event::Reply(&[ACK]))));
+        assert_eq!(rx.timeout(), Ok(&[NAK][..]));
+        let packet2 = packet(2, &[0x55; 128]);
+        assert_eq!(
+            push_all(&mut rx, &packet2),
+            Ok(Some(Event::Data(&[0x55; 128])))
+        );
+        assert_eq!(rx.push(EOT), Ok(Some(Event::End)));
+    }
+
+    #[test]
+    fn test_receiver_errors() {
+        let mut rx = Receiver::new();
+        assert_eq!(rx.push(CAN), Ok(None));
+        assert_eq!(rx.push(CAN), Err(ModemError::Cancelled));
+
+        let mut rx = Receiver::new();
+        assert_eq!(
+            push_all(&mut rx, &packet(1, &[0; 128])),
+            Ok(Some(Event::Data(&[0; 128])))
+        );
+        assert_eq!(
+            push_all(&mut rx, &packet(3, &[0; 128])),
+            Err(ModemError::UnexpectedBlock(3))
+        );
+
+        // a second file
+        let mut rx = Receiver::new();
+        let mut header = [0u8; 128];
+        header[..6].copy_from_slice(b"a\x001 2\x00");
+        assert_eq!(
+            push_all(&mut rx, &packet(0, &header)),
+            Ok(Some(Event::File {
+                name: b"a",
+                size: Some(1)
+            }))
+        );
+        push_all(&mut rx, &packet(1, &[0x31; 128])).unwrap();
+        assert_eq!(rx.push(EOT), Ok(Some(Event::Reply(&[NAK]))));
+        assert_eq!(rx.push(EOT), Ok(Some(Event::Reply(&[ACK, CRC]))));
+        assert_eq!(
+            push_all(&mut rx, &packet(0, &header)),
+            Err(ModemError::UnexpectedBlock(0))
+        );
+
+        let mut rx = Receiver::new();
+        let mut bad = packet(1, &[0; 128]);
+        bad[5] = 1;
+        for _ in 1..MAX_RETRIES {
+            assert_eq!(push_all(&mut rx, &bad), Ok(Some(Event::Reply(&[NAK]))));
+        }
+        assert_eq!(push_all(&mut rx, &bad), Err(ModemError::TooManyRetries));
+    }
+}
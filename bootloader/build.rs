@@ -1,31 +1,59 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script generates `memory.x` from `blxlib::layout::LAYOUT`
+//! into a directory where the linker can always find it at build time.
+//!
+//! boot2 (`BOOT_LOADER_RAM_MEMCPY`) copies the bootloader partition after
+//! `.boot2` into the beginning of SRAM and runs it from there, so `FLASH`
+//! (the code) is placed in SRAM and `RAM` takes the rest of it.
+//! Cargo re-runs this script whenever `blxlib` changes.
 
+use blxlib::layout::{BOOT2_SIZE, LAYOUT, SRAM_BASE, SRAM_SIZE};
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+fn memory_x() -> String {
+    let boot2_origin = LAYOUT.bootloader.addr();
+    let code_origin = SRAM_BASE;
+    const {
+        assert!(
+            LAYOUT.bootloader.size < SRAM_SIZE,
+            "bootloader partition does not fit in SRAM"
+        )
+    };
+    let code_length = LAYOUT.bootloader.size - BOOT2_SIZE;
+    let ram_origin = SRAM_BASE + LAYOUT.bootloader.size;
+    let ram_length = SRAM_SIZE - LAYOUT.bootloader.size;
+
+    format!(
+        "/* Generated by build.rs from blxlib::layout::LAYOUT. Do not edit. */
+MEMORY {{
+    BOOT2 : ORIGIN = 0x{boot2_origin:08x}, LENGTH = 0x{BOOT2_SIZE:x}
+    FLASH : ORIGIN = 0x{code_origin:08x}, LENGTH = 0x{code_length:x}
+    RAM   : ORIGIN = 0x{ram_origin:08x}, LENGTH = 0x{ram_length:x}
+}}
+
+EXTERN(BOOT2_FIRMWARE)
+
+SECTIONS {{
+    /* ### Boot loader */
+    .boot2 ORIGIN(BOOT2) :
+    {{
+        KEEP(*(.boot2));
+    }} > BOOT2
+}} INSERT BEFORE .text;
+"
+    )
+}
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x().as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
-
-    // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=build.rs");
 }
@@ -1,20 +0,0 @@
-MEMORY {
-    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-/*
-    FLASH : ORIGIN = 0x10000100, LENGTH = 0x20000 - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
-*/
-    FLASH : ORIGIN = 0x20000000, LENGTH = 0x1ff00
-    RAM   : ORIGIN = 0x20020000, LENGTH = 0x20040000-0x20020000
-
-}
-
-EXTERN(BOOT2_FIRMWARE)
-
-SECTIONS {
-    /* ### Boot loader */
-    .boot2 ORIGIN(BOOT2) :
-    {
-        KEEP(*(.boot2));
-    } > BOOT2
-} INSERT BEFORE .text;
@@ -0,0 +1,6 @@
+//! Generated by `bintool -c getpriv`. Do not edit.
+
+pub const X25519_PRIVATE_KEY: [u8; 32] = [
+    0x27, 0x48, 0x39, 0x13, 0x39, 0x1b, 0x8b, 0x0f, 0xac, 0x88, 0x1f, 0xe5, 0x69, 0xcd, 0x5b, 0x90,
+    0xd1, 0xa4, 0x8c, 0x4c, 0xb4, 0xe4, 0x99, 0x76, 0x5c, 0x0e, 0x00, 0x61, 0xd6, 0xfc, 0x80, 0x9e,
+];
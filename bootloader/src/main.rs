@@ -5,23 +5,54 @@
 #![no_main]
 
 use blxlib::{
-    crc32,
+    app::BootClient,
+    boot::{self, BootAction, BootInputs, SlotState, SwapPhase, UpdateError},
+    encrypt::{PayloadCipher, WRAPPED_KEY_LENGTH},
+    flash::Flash,
+    image::{self, Image, ImageFormat},
     image_header::{self, ImageHeader},
+    layout::{LAYOUT, SECTOR_SIZE},
+    mcuboot::{McubootImage, Trailer},
+    recovery::{Received, Recovery, RecoveryError},
+    rollback,
+    shell::{self, Action, Input, LineEditor, Shell, Slot},
+    signature,
+    smp::{self, FrameDecoder, Server},
+    suit::SuitImage,
+    swap::{self, SwapState, SwapStatus, SwapType},
+    trigger::{self, TriggerConfig, TriggerInputs, DOUBLE_RESET_MAGIC},
+    ymodem,
 };
 use core::arch::asm;
+use core::convert::Infallible;
 use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::ptr;
 use cortex_m_rt::entry;
 use defmt_rtt as _;
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::watchdog::WatchdogEnable;
 use panic_probe as _;
 
+#[cfg(feature = "encrypt")]
+mod enckey_x25519;
+#[cfg(feature = "ed25519")]
+mod pubkey_ed25519;
+#[cfg(feature = "p256")]
+mod pubkey_p256;
+mod rom_flash;
+
+use rom_flash::RomFlash;
+
 use rp2040_hal::{
     clocks::{init_clocks_and_plls, Clock},
-    fugit::RateExtU32, // time calculation library
+    fugit::{ExtU32, RateExtU32}, // time calculation library
     gpio::Pins,
     pac,
     sio::Sio,
-    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
-    watchdog::Watchdog,
+    uart::{DataBits, Enabled, StopBits, UartConfig, UartDevice, UartPeripheral, ValidUartPinout},
+    watchdog::{ScratchRegister, Watchdog},
+    Timer,
 };
 
 #[link_section = ".boot2"]
@@ -29,125 +60,391 @@ use rp2040_hal::{
 pub static BOOT_LOADER: [u8; 256] = rp2040_boot2::BOOT_LOADER_RAM_MEMCPY;
 // pub static BOOT_LOADER: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 
-fn ih_print<
+/// A test image must confirm itself and stop the watchdog within this time
+/// (at most 0x7f_ffff us, see RP2040-E1), otherwise it is reverted.
+const TRIAL_TIMEOUT_US: u32 = 8_000_000;
+
+/// The recovery receiver asks for the transfer again after this long without data.
+const RECOVERY_TIMEOUT_US: u32 = 1_000_000;
+
+/// A key pressed within this time after reset enters the shell. A second
+/// reset within this time enters recovery mode.
+const SHELL_TIMEOUT_US: u32 = 1_000_000;
+
+/// Ways to stay in the bootloader for serial recovery (see `blxlib::trigger`).
+/// The button is between GP22 and GND.
+const RECOVERY_TRIGGERS: TriggerConfig = TriggerConfig::ALL;
+
+/// Serial recovery when there is no bootable image or a trigger requests it.
+/// Without it the bootloader halts (see `blxlib::boot`).
+const SERIAL_RECOVERY: bool = true;
+
+/// `DOUBLE_RESET_MAGIC` while a second reset enters recovery mode. `.uninit`
+/// is not initialized at startup, so the value survives a reset.
+#[link_section = ".uninit.DOUBLE_RESET"]
+static mut DOUBLE_RESET: MaybeUninit<u32> = MaybeUninit::uninit();
+
+fn double_reset() -> *mut u32 {
+    ptr::addr_of_mut!(DOUBLE_RESET).cast()
+}
+
+fn mcuboot_print<
+    S: rp2040_hal::uart::State,
+    D: rp2040_hal::uart::UartDevice,
+    P: rp2040_hal::uart::ValidUartPinout<D>,
+>(
+    image: &McubootImage,
+    uart: &mut UartPeripheral<S, D, P>,
+) where
+    UartPeripheral<S, D, P>: Write,
+{
+    let header = &image.header;
+    writeln!(uart, "mcuboot hdr_size: {}\r", header.hdr_size).unwrap();
+    writeln!(uart, "iv: {}\r", header.version).unwrap();
+    writeln!(uart, "img_size: {:08x}\r", header.img_size).unwrap();
+    writeln!(uart, "protect_tlv_size: {}\r", header.protect_tlv_size).unwrap();
+    for (tlv_type, value) in image.protected_tlvs().chain(image.tlvs()) {
+        writeln!(uart, "tlv: {:04x} len={}\r", tlv_type, value.len()).unwrap();
+    }
+    writeln!(uart, "security_counter: {}\r", image.security_counter()).unwrap();
+}
+
+fn suit_print<
     S: rp2040_hal::uart::State,
     D: rp2040_hal::uart::UartDevice,
     P: rp2040_hal::uart::ValidUartPinout<D>,
 >(
-    ih: &ImageHeader,
+    image: &SuitImage,
     uart: &mut UartPeripheral<S, D, P>,
 ) where
     UartPeripheral<S, D, P>: Write,
 {
-    writeln!(uart, "header_magic: {:08x}\r", ih.header_magic).unwrap();
-    writeln!(uart, "header_length: {}\r", ih.header_length).unwrap();
-    writeln!(uart, "hv: {}.{}\r", ih.hv_major, ih.hv_minor).unwrap();
+    writeln!(uart, "suit size: {:08x}\r", image.size()).unwrap();
+    writeln!(
+        uart,
+        "sequence_number: {}\r",
+        image.manifest.sequence_number
+    )
+    .unwrap();
     writeln!(
         uart,
-        "iv: {}.{}.{}-{:08x}\r",
-        ih.iv_major, ih.iv_minor, ih.iv_patch, ih.iv_build
+        "signature_alg: {:?}\r",
+        image.envelope.signature_alg()
     )
     .unwrap();
-    writeln!(uart, "image_length: {:08x}\r", ih.image_length).unwrap();
-    writeln!(uart, "payload_crc: {:08x}\r", ih.payload_crc).unwrap();
-    writeln!(uart, "crc32: {:08x}\r", ih.crc32).unwrap();
 }
 
-fn ih_validate<
+fn img_print<
+    S: rp2040_hal::uart::State,
+    D: rp2040_hal::uart::UartDevice,
+    P: rp2040_hal::uart::ValidUartPinout<D>,
+>(
+    image: &Image,
+    uart: &mut UartPeripheral<S, D, P>,
+) where
+    UartPeripheral<S, D, P>: Write,
+{
+    match image {
+        Image::Native(ih, _) => shell::print_header(ih, uart).unwrap(),
+        Image::Mcuboot(image) => mcuboot_print(image, uart),
+        Image::Suit(image) => suit_print(image, uart),
+    }
+}
+
+fn img_validate<
     S: rp2040_hal::uart::State,
     D: rp2040_hal::uart::UartDevice,
     P: rp2040_hal::uart::ValidUartPinout<D>,
 >(
-    ih: &ImageHeader,
-    start_address: u32,
+    image: &Image,
     uart: &mut UartPeripheral<S, D, P>,
 ) -> bool
 where
     UartPeripheral<S, D, P>: Write,
 {
-    // validate header
-    if ih.header_magic != image_header::IMAGE_HEADER_MAGIC {
-        writeln!(
-            uart,
-            "header_magic is not correct: {:08x}\r",
-            ih.header_magic
-        )
-        .unwrap();
-        return false;
+    match image.validate() {
+        Ok(()) => true,
+        Err(e) => {
+            writeln!(uart, "{}\r", e).unwrap();
+            false
+        }
     }
-    if ih.header_length != image_header::HEADER_LENGTH {
-        writeln!(
-            uart,
-            "header_length is not correct: {:08x}\r",
-            ih.header_length
-        )
-        .unwrap();
-        return false;
+}
+
+/// `img_validate` for the update slot, where a compressed image is
+/// decompressed and an encrypted image is decrypted on the fly.
+fn update_validate<
+    S: rp2040_hal::uart::State,
+    D: rp2040_hal::uart::UartDevice,
+    P: rp2040_hal::uart::ValidUartPinout<D>,
+>(
+    image: &Image,
+    uart: &mut UartPeripheral<S, D, P>,
+) -> bool
+where
+    UartPeripheral<S, D, P>: Write,
+{
+    if image.compressed_length().is_some() {
+        return match image.validate_compressed() {
+            Ok(()) => true,
+            Err(e) => {
+                writeln!(uart, "{}\r", e).unwrap();
+                false
+            }
+        };
     }
-    let calc_crc32 = ih.calc_crc32();
-    if ih.crc32 != calc_crc32 {
-        writeln!(
-            uart,
-            "crc32 is not correct: header={:08x} calc={:08x}\r",
-            ih.crc32, calc_crc32
-        )
-        .unwrap();
-        return false;
+    let Some(wrapped_key) = image.wrapped_key() else {
+        return img_validate(image, uart);
+    };
+    let result = with_cipher(Some(&wrapped_key), |cipher| {
+        cipher.map(|cipher| image.validate_encrypted(cipher))
+    });
+    match result {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            writeln!(uart, "{}\r", e).unwrap();
+            false
+        }
+        None => {
+            writeln!(
+                uart,
+                "image is encrypted, not supported by this bootloader\r"
+            )
+            .unwrap();
+            false
+        }
     }
-    let slice = core::ptr::slice_from_raw_parts(
-        (start_address as usize + image_header::HEADER_LENGTH as usize) as *const u8,
-        ih.image_length as usize,
-    );
-    let payload_crc = crc32::crc32(unsafe { &*slice });
-    if ih.payload_crc != payload_crc {
-        writeln!(
-            uart,
-            "payload_crc is not correct: header={:08x} calc={:08x}\r",
-            ih.payload_crc, payload_crc
-        )
-        .unwrap();
-        return false;
+}
+
+/// Calls `f` with the cipher of an image whose key is `wrapped_key`, `None`
+/// for a plain image or without the `encrypt` feature.
+#[cfg_attr(not(feature = "encrypt"), allow(unused_variables))]
+fn with_cipher<R>(
+    wrapped_key: Option<&[u8; WRAPPED_KEY_LENGTH]>,
+    f: impl FnOnce(Option<&dyn PayloadCipher>) -> R,
+) -> R {
+    #[cfg(feature = "encrypt")]
+    if let Some(wrapped_key) = wrapped_key {
+        let key = blxlib::encrypt::unwrap_key(wrapped_key, &enckey_x25519::X25519_PRIVATE_KEY);
+        return f(Some(&blxlib::encrypt::ImageCipher::new(&key)));
     }
-    true
+    f(None)
 }
 
-fn halt() -> ! {
+fn img_verify<
+    S: rp2040_hal::uart::State,
+    D: rp2040_hal::uart::UartDevice,
+    P: rp2040_hal::uart::ValidUartPinout<D>,
+>(
+    image: &Image,
+    uart: &mut UartPeripheral<S, D, P>,
+) -> bool
+where
+    UartPeripheral<S, D, P>: Write,
+{
+    let result = match image.sig_alg() {
+        #[cfg(feature = "ed25519")]
+        image_header::SIG_ALG_ED25519 => image.verify_ed25519(&pubkey_ed25519::ED25519_PUBLIC_KEY),
+        #[cfg(feature = "p256")]
+        image_header::SIG_ALG_ECDSA_P256 => image.verify_p256(&pubkey_p256::ECDSA_P256_PUBLIC_KEY),
+        sig_alg => Err(signature::SignatureError::UnsupportedAlgorithm(sig_alg)),
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            writeln!(uart, "{}\r", e).unwrap();
+            false
+        }
+    }
+}
+
+fn read_byte<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    timer: &Timer,
+    timeout_us: u32,
+) -> Option<u8> {
+    let start = timer.get_counter_low();
+    let mut buf = [0u8];
+    while timer.get_counter_low().wrapping_sub(start) < timeout_us {
+        if uart.read_raw(&mut buf).is_ok() {
+            return Some(buf[0]);
+        }
+    }
+    None
+}
+
+/// Receives an image into the update slot with YMODEM (or XMODEM-1K),
+/// validates it and requests a permanent install. An SMP request before the
+/// transfer starts switches the console to `serve_smp`, whatever the state of
+/// the images, so that mcumgr can also confirm a test image.
+fn recovery_receive<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    timer: &Timer,
+    server: &mut Server<RomFlash>,
+) -> Result<ImageHeader, RecoveryError<Infallible>> {
+    let mut recovery = Recovery::new();
+    let mut frames = FrameDecoder::new();
     loop {
-        cortex_m::asm::wfi();
+        let Some(byte) = read_byte(uart, timer, RECOVERY_TIMEOUT_US) else {
+            uart.write_full_blocking(recovery.timeout()?);
+            continue;
+        };
+        if !recovery.is_started() {
+            if let Some(packet) = frames.push(byte) {
+                let mut request = [0u8; smp::MTU];
+                request[..packet.len()].copy_from_slice(packet);
+                serve_smp(uart, timer, server, &request[..packet.len()]);
+            }
+            if frames.is_receiving() {
+                continue;
+            }
+        }
+        match recovery.push(server.client(), byte)? {
+            None => {}
+            Some(Received::Reply(reply)) => uart.write_full_blocking(reply),
+            Some(Received::End(reply)) => {
+                uart.write_full_blocking(reply);
+                return Ok(recovery.finish(server.client())?);
+            }
+        }
     }
 }
 
-fn copy_image(_to_addr: u32, _from_addr: u32, _size: u32) {
-    //     ldr r0, =ROM_FN_TABLE
-    //     ldrh r0, [r0]
-    //     ldr r2, =ROM_TABLE_LOOKUP
-    //     ldrh r2, [r2]
-
-    //     // Query the bootrom function pointer
-    //     ldr r1, =0x3443 // 'C','4' for _memcpy44
-    //     blx r2
-
-    //     //uint8_t *_memcpy44(uint32_t *dest, uint32_t *src, uint32_t n)
-    //     mov r3, r0
-    //     ldr r0, =DST
-    //     ldr r1, =SRC
-    //     ldr r2, =LEN
-    //     blx r3
-    unsafe {
-        asm!(
-            "ldr r0, =0x00000014",
-            "ldrh r0, [r0]",
-            "ldr r2, =0x00000018",
-            "ldrh r2, [r2]",
-            "ldr r1, =0x3443",
-            "blx r2",
-            "mov r3, r0",
-            "ldr r0, =0x10020000",
-            "ldr r1, =0x10100000",
-            "ldr r2, =0xe0000",
-            "blx r3",
-        );
+/// Serves mcumgr's SMP (`mcumgr --conntype serial`), starting with
+/// `request`, until a reset request.
+fn serve_smp<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    timer: &Timer,
+    server: &mut Server<RomFlash>,
+    request: &[u8],
+) -> ! {
+    let mut buf = [0u8; smp::MTU];
+    smp_respond(uart, server, request, &mut buf);
+    let mut frames = FrameDecoder::new();
+    loop {
+        let Some(byte) = read_byte(uart, timer, RECOVERY_TIMEOUT_US) else {
+            continue;
+        };
+        if let Some(packet) = frames.push(byte) {
+            smp_respond(uart, server, packet, &mut buf);
+        }
+    }
+}
+
+fn smp_respond<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    server: &mut Server<RomFlash>,
+    packet: &[u8],
+    buf: &mut [u8],
+) {
+    let Ok(response) = server.handle(packet, buf) else {
+        return;
     };
+    smp::encode_frames(&buf[..response.length], |line| {
+        uart.write_full_blocking(line)
+    });
+    if response.reset {
+        while uart.uart_is_busy() {}
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// Serial recovery when there is no bootable image or a trigger requests it:
+/// receives an image on the UART (`bintool -c ymodem`) and resets to install
+/// it permanently. The image is checked like any update before it is
+/// installed, also while a test image waits for its trial boot (see
+/// `blxlib::recovery`). mcumgr can upload, test and confirm images over SMP
+/// instead.
+fn recovery<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    timer: &Timer,
+) -> ! {
+    let mut server = Server::new(BootClient::new(RomFlash, LAYOUT));
+    loop {
+        uart.write_full_blocking(
+            b"bootloader: RECOVERY MODE, send an image with YMODEM or mcumgr ***\r\n",
+        );
+        match recovery_receive(uart, timer, &mut server) {
+            Ok(ih) => {
+                // lets the sender finish the batch before the messages
+                read_byte(uart, timer, RECOVERY_TIMEOUT_US);
+                writeln!(
+                    uart,
+                    "\r\nbootloader: recovery image {} received\r",
+                    ih.version()
+                )
+                .unwrap();
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+            Err(e) => {
+                uart.write_full_blocking(ymodem::CANCEL);
+                writeln!(uart, "\r\nbootloader: recovery failed: {}\r", e).unwrap();
+                // waits until the sender has given up before asking again
+                while read_byte(uart, timer, RECOVERY_TIMEOUT_US).is_some() {}
+            }
+        }
+    }
+}
+
+/// The command shell. Returns on `boot`.
+fn run_shell<D: UartDevice, P: ValidUartPinout<D>>(
+    uart: &mut UartPeripheral<Enabled, D, P>,
+    timer: &Timer,
+) {
+    let mut shell = Shell::new(BootClient::new(RomFlash, LAYOUT));
+    let mut editor = LineEditor::new();
+    uart.write_full_blocking(shell::PROMPT.as_bytes());
+    loop {
+        let Some(byte) = read_byte(uart, timer, SHELL_TIMEOUT_US) else {
+            continue;
+        };
+        let line = match editor.push(byte) {
+            Input::None => continue,
+            Input::Echo(b) => {
+                uart.write_full_blocking(&[b]);
+                continue;
+            }
+            Input::Erase => {
+                uart.write_full_blocking(b"\x08 \x08");
+                continue;
+            }
+            Input::Line(line) => line,
+        };
+        uart.write_full_blocking(b"\r\n");
+        match shell.run(line, uart).unwrap() {
+            Action::None => {}
+            Action::Validate(slot) => {
+                let addr = match slot {
+                    Slot::Primary => image_header::APP_BASE_ADDR,
+                    Slot::Secondary => image_header::APP_UPDATE_ADDR,
+                };
+                let valid = match Image::parse(image::slot_from_addr(addr)) {
+                    Ok(image) if slot == Slot::Secondary => {
+                        update_validate(&image, uart) && img_verify(&image, uart)
+                    }
+                    Ok(image) => img_validate(&image, uart) && img_verify(&image, uart),
+                    Err(e) => {
+                        writeln!(uart, "{}\r", e).unwrap();
+                        false
+                    }
+                };
+                let result = if valid { "OK" } else { "FAIL" };
+                writeln!(uart, "slot {}: {}\r", slot, result).unwrap();
+            }
+            Action::Boot => return,
+            Action::Reboot => {
+                while uart.uart_is_busy() {}
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+        uart.write_full_blocking(shell::PROMPT.as_bytes());
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
 }
 
 fn xip_enable() {
@@ -221,6 +518,7 @@ fn main() -> ! {
     .unwrap();
 
     let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
 
     let pins = Pins::new(
         pac.IO_BANK0,
@@ -249,6 +547,30 @@ fn main() -> ! {
     #[cfg(not(debug_assertions))]
     uart.write_full_blocking(b"bootloader release build\r\n");
 
+    let button = pins.gpio22.into_pull_up_input();
+    // lets the pull-up settle
+    delay.delay_us(10);
+    let inputs = TriggerInputs {
+        button: button.is_low().unwrap(),
+        scratch: watchdog.read_scratch(ScratchRegister::Scratch0),
+        double_reset: unsafe { double_reset().read_volatile() },
+    };
+    watchdog.write_scratch(ScratchRegister::Scratch0, 0);
+    let decision = trigger::check(&RECOVERY_TRIGGERS, &inputs);
+    let armed = if decision.arm_double_reset {
+        DOUBLE_RESET_MAGIC
+    } else {
+        0
+    };
+    unsafe { double_reset().write_volatile(armed) };
+
+    uart.write_full_blocking(b"bootloader: press any key for the shell\r\n");
+    let key = read_byte(&mut uart, &timer, SHELL_TIMEOUT_US);
+    unsafe { double_reset().write_volatile(0) };
+    if key.is_some() {
+        run_shell(&mut uart, &timer);
+    }
+
     // This is the correct pin on the Raspberry Pico board. On other boards, even if they have an
     // on-board LED, it might need to be changed.
     // Notably, on the Pico W, the LED is not connected to any of the RP2040 GPIOs but to the cyw43 module instead. If you have
@@ -259,50 +581,200 @@ fn main() -> ! {
     let pc = cortex_m::register::pc::read();
     writeln!(uart, "PC={:08x}\r", pc).unwrap();
 
-    uart.write_full_blocking(b"bootloader: check base image\r\n");
-    let ih = image_header::load_from_addr(image_header::APP_BASE_ADDR);
-    ih_print(&ih, &mut uart);
-
-    if !ih_validate(&ih, image_header::APP_BASE_ADDR, &mut uart) {
-        uart.write_full_blocking(b"bootloader: FAIL: IMAGE VALIDATION ***\r\n");
-        halt();
-    }
+    let mut flash = RomFlash;
+    let min_counter = rollback::read_counter(&mut flash, &LAYOUT).unwrap();
+    writeln!(uart, "bootloader: security counter {}\r", min_counter).unwrap();
+
+    // decides again after each swap, until the base image is booted
+    let base = loop {
+        uart.write_full_blocking(b"bootloader: check swap status\r\n");
+        let swap_state = swap::read_state(&mut flash, &LAYOUT).unwrap();
+
+        uart.write_full_blocking(b"bootloader: check update image\r\n");
+        let update_slot = image::slot_from_addr(image_header::APP_UPDATE_ADDR);
+        let update = Image::parse(update_slot);
+        let secondary = match &update {
+            Ok(update) if swap_state.is_swapped_out(update.info().id) => {
+                uart.write_full_blocking(b"bootloader: update image is the previous image\r\n");
+                SlotState::Installed
+            }
+            Ok(update) if swap_state.is_installed(update.info().id) => {
+                uart.write_full_blocking(b"bootloader: update image is installed\r\n");
+                SlotState::Installed
+            }
+            Ok(update) if swap_state.is_failed(update.info().id) => {
+                uart.write_full_blocking(b"bootloader: update image failed to install\r\n");
+                SlotState::Invalid
+            }
+            Ok(update) => {
+                img_print(update, &mut uart);
+                if update_validate(update, &mut uart) && img_verify(update, &mut uart) {
+                    SlotState::valid(&update.info())
+                } else {
+                    SlotState::Invalid
+                }
+            }
+            Err(e) => {
+                writeln!(uart, "{}\r", e).unwrap();
+                SlotState::Empty
+            }
+        };
+
+        uart.write_full_blocking(b"bootloader: check base image\r\n");
+        let base = Image::parse(image::slot_from_addr(image_header::APP_BASE_ADDR));
+        let primary = match &base {
+            Ok(base) => {
+                img_print(base, &mut uart);
+                if img_validate(base, &mut uart) && img_verify(base, &mut uart) {
+                    SlotState::valid(&base.info())
+                } else {
+                    SlotState::Invalid
+                }
+            }
+            Err(e) => {
+                writeln!(uart, "{}\r", e).unwrap();
+                SlotState::Empty
+            }
+        };
+
+        let inputs = BootInputs {
+            swap: SwapPhase::from(&swap_state),
+            attempts: swap::attempts(&mut flash, &LAYOUT).unwrap(),
+            trigger: decision.trigger,
+            primary,
+            secondary,
+            min_counter,
+            recovery: SERIAL_RECOVERY,
+        };
+        let action = boot::decide(&inputs);
+        if let Err(e @ (UpdateError::RolledBack { .. } | UpdateError::Older { .. })) =
+            boot::check_update(&inputs)
+        {
+            writeln!(uart, "{}\r", e).unwrap();
+        }
+        match action {
+            BootAction::Resume => {
+                let SwapState::InProgress { status, step } = swap_state else {
+                    unreachable!()
+                };
+                writeln!(
+                    uart,
+                    "bootloader: RESUME SWAP {}/{} ***\r",
+                    step,
+                    status.steps()
+                )
+                .unwrap();
+                swap::count_attempt(&mut flash, &LAYOUT).unwrap();
+                with_cipher(status.wrapped_key.as_ref(), |cipher| {
+                    swap::resume(&mut flash, &LAYOUT, &status, step, cipher)
+                })
+                .unwrap();
+            }
+            BootAction::Revert => {
+                uart.write_full_blocking(b"bootloader: IMAGE NOT CONFIRMED, REVERT ***\r\n");
+                let status = swap_state.status().unwrap();
+                with_cipher(status.wrapped_key.as_ref(), |cipher| {
+                    swap::revert(&mut flash, &LAYOUT, status, cipher)
+                })
+                .unwrap();
+            }
+            BootAction::Install => {
+                let Ok(update) = update else { unreachable!() };
+                uart.write_full_blocking(b"bootloader: UPDATE IMAGE FOUND ***\r\n");
+                let info = update.info();
+                // an MCUboot tool may mark the image as pending in the slot trailer instead
+                let trailer = Trailer::from_slot(update_slot);
+                let swap_type = if update.compressed_length().is_some() {
+                    // the previous image is overwritten
+                    SwapType::Overwrite
+                } else if info.format == ImageFormat::Mcuboot && trailer.is_permanent() {
+                    SwapType::Permanent
+                } else {
+                    swap::requested_type(&mut flash, &LAYOUT, info.id).unwrap()
+                };
+                if swap_type == SwapType::Permanent {
+                    uart.write_full_blocking(b"bootloader: permanent update requested\r\n");
+                }
+                if swap_type == SwapType::Overwrite {
+                    uart.write_full_blocking(
+                        b"bootloader: decompressing update image (permanent)\r\n",
+                    );
+                }
+                let base_info = base.ok().map(|base| base.info());
+                let mut status = SwapStatus::new(&LAYOUT, base_info.as_ref(), &info, swap_type);
+                status.wrapped_key = update.wrapped_key();
+                if status.wrapped_key.is_some() {
+                    uart.write_full_blocking(b"bootloader: decrypting update image\r\n");
+                }
+                // the trailer would apply to the next image written to the slot
+                if trailer.magic && status.sectors < LAYOUT.secondary.sectors() {
+                    flash
+                        .erase_sector(LAYOUT.secondary.end() - SECTOR_SIZE)
+                        .unwrap();
+                }
+                with_cipher(status.wrapped_key.as_ref(), |cipher| {
+                    swap::swap(&mut flash, &LAYOUT, &status, cipher)
+                })
+                .unwrap();
+                if swap_type == SwapType::Overwrite {
+                    uart.write_full_blocking(b"bootloader: UPDATE IMAGE -> BASE IMAGE\r\n");
+                } else {
+                    uart.write_full_blocking(b"bootloader: UPDATE IMAGE <-> BASE IMAGE\r\n");
+                }
+            }
+            BootAction::Recovery(reason) => {
+                writeln!(uart, "bootloader: RECOVERY: {} ***\r", reason).unwrap();
+                recovery(&mut uart, &timer);
+            }
+            BootAction::Halt(reason) => {
+                writeln!(uart, "bootloader: FAIL: {} ***\r", reason).unwrap();
+                halt();
+            }
+            BootAction::Boot => {
+                let Ok(base) = base else { unreachable!() };
+                break base;
+            }
+        }
+    };
+    let info = base.info();
 
-    uart.write_full_blocking(b"bootloader: check update image\r\n");
-    let ih_update = image_header::load_from_addr(image_header::APP_UPDATE_ADDR);
-    ih_print(&ih_update, &mut uart);
+    uart.write_full_blocking(b"bootloader: app header validation pass\r\n");
 
-    if ih_validate(&ih_update, image_header::APP_UPDATE_ADDR, &mut uart) {
-        uart.write_full_blocking(b"bootloader: UPDATE IMAGE FOUND ***\r\n");
-        copy_image(
-            image_header::APP_BASE_ADDR,
-            image_header::APP_UPDATE_ADDR,
-            image_header::APP_SIZE,
-        );
-        uart.write_full_blocking(b"bootloader: UPDATE IMAGE -> BASE IMAGE\r\n");
-        // let ih = image_header::load_from_addr(image_header::APP_BASE_ADDR);
-        // ih_print(&ih, &mut uart);
+    let trial = swap::begin_trial(&mut flash, &LAYOUT).unwrap();
+    if trial {
+        uart.write_full_blocking(b"bootloader: TRIAL BOOT, waiting for confirmation ***\r\n");
+    } else if info.security_counter > min_counter {
+        // a test image is accepted once it is confirmed, so that it can still be reverted
+        rollback::update_counter(&mut flash, &LAYOUT, info.security_counter).unwrap();
+        writeln!(
+            uart,
+            "bootloader: security counter {} -> {}\r",
+            min_counter, info.security_counter
+        )
+        .unwrap();
     }
-
-    uart.write_full_blocking(b"bootloader: app header validation pass\r\n");
     uart.write_full_blocking(b"bootloader: boot application!!!\r\n");
 
     delay.delay_ms(500);
 
     xip_enable();
 
-    // exec => 0x10020100
+    if trial {
+        watchdog.start(TRIAL_TIMEOUT_US.micros());
+    }
+
+    // exec => APP_BASE_ADDR + HEADER_LENGTH (vector table of the application)
     // stack pointer => VTOR[0] (VTOR=0xe000ed08)
+    let vector_table = image_header::APP_BASE_ADDR + image_header::HEADER_LENGTH as u32;
     unsafe {
         asm!(
-            "ldr r0, =0x10020100",
             "ldr r1, =0xe000ed08",
             "str r0, [r1]",
             "ldmia r0, {{r0, r1}}",
             "msr msp, r0",
             "bx r1",
+            in("r0") vector_table,
+            options(noreturn),
         );
     };
-
-    halt();
 }
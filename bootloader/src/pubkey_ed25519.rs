@@ -0,0 +1,6 @@
+//! Generated by `bintool -c getpub`. Do not edit.
+
+pub const ED25519_PUBLIC_KEY: [u8; 32] = [
+    0x3a, 0x81, 0x9b, 0x34, 0x93, 0x8d, 0x98, 0x63, 0x53, 0xf6, 0xeb, 0x03, 0x28, 0xa8, 0x99, 0xab,
+    0xb2, 0x35, 0xf6, 0x9b, 0x93, 0xee, 0xbe, 0x07, 0x01, 0x49, 0x75, 0x72, 0xf0, 0xa3, 0x89, 0x78,
+];
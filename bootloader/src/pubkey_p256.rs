@@ -0,0 +1,9 @@
+//! Generated by `bintool -c getpub`. Do not edit.
+
+pub const ECDSA_P256_PUBLIC_KEY: [u8; 65] = [
+    0x04, 0xb8, 0x04, 0xee, 0x16, 0x0a, 0x35, 0x6f, 0x0c, 0xad, 0xc8, 0x69, 0xe6, 0xb9, 0x4e, 0xe5,
+    0x1f, 0xed, 0x1b, 0xf9, 0x52, 0xb3, 0x82, 0x96, 0x49, 0x53, 0x97, 0x41, 0xdb, 0xc0, 0x3d, 0x62,
+    0xbb, 0xc2, 0x7b, 0x44, 0x15, 0xb1, 0x53, 0x0f, 0x61, 0x73, 0x32, 0x90, 0x9c, 0xd9, 0x08, 0x4e,
+    0x95, 0xdf, 0xa3, 0xb5, 0xd6, 0x88, 0xff, 0x4a, 0x74, 0x47, 0x2d, 0x87, 0x49, 0xe3, 0x47, 0x26,
+    0x00,
+];
@@ -0,0 +1,62 @@
+//! `blxlib::flash::Flash` on the bootrom flash functions.
+//!
+//! XIP is unavailable while the flash is erased or programmed, so these must
+//! run from SRAM (the whole bootloader does, see `memory.x`) with interrupts
+//! disabled. Afterwards XIP is restored in the slow 03h read mode.
+
+use blxlib::flash::Flash;
+use blxlib::layout::{FLASH_BASE, PAGE_SIZE, SECTOR_SIZE};
+use core::convert::Infallible;
+use core::ptr;
+use rp2040_hal::rom_data;
+
+/// 64 KiB block erase, used by `flash_range_erase` where possible.
+const BLOCK_SIZE: u32 = 0x1_0000;
+const BLOCK_ERASE_CMD: u8 = 0xd8;
+
+pub struct RomFlash;
+
+impl RomFlash {
+    fn with_xip_disabled(f: impl FnOnce()) {
+        cortex_m::interrupt::free(|_| unsafe {
+            rom_data::connect_internal_flash();
+            rom_data::flash_exit_xip();
+            f();
+            rom_data::flash_flush_cache();
+            rom_data::flash_enter_cmd_xip();
+        });
+    }
+}
+
+impl Flash for RomFlash {
+    type Error = Infallible;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Infallible> {
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (FLASH_BASE + offset) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Infallible> {
+        Self::with_xip_disabled(|| unsafe {
+            rom_data::flash_range_erase(offset, SECTOR_SIZE as usize, BLOCK_SIZE, BLOCK_ERASE_CMD);
+        });
+        Ok(())
+    }
+
+    fn program_page(
+        &mut self,
+        offset: u32,
+        data: &[u8; PAGE_SIZE as usize],
+    ) -> Result<(), Infallible> {
+        Self::with_xip_disabled(|| unsafe {
+            rom_data::flash_range_program(offset, data.as_ptr(), data.len());
+        });
+        Ok(())
+    }
+}
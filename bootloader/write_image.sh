@@ -9,5 +9,8 @@ target_dir="../target/${arch}/${debug}"
 
 ./build_image.sh
 
-probe-rs download --chip RP2040 --protocol swd --format bin --base-address 0x10000000 --skip 0 ${target_dir}/boot2.bin
+# XIP address of the bootloader partition from blxlib::layout::LAYOUT
+base_address=$(cd ../bintool && cargo run -q -- -c layout | awk '$1 == "bootloader" { print $3 }')
+
+probe-rs download --chip RP2040 --protocol swd --format bin --base-address ${base_address} --skip 0 ${target_dir}/boot2.bin
 probe-rs reset --chip RP2040 --protocol swd